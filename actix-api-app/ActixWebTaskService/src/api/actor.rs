@@ -0,0 +1,49 @@
+use crate::api::auth::resolve_caller;
+use actix_web::{dev::Payload, Error, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+
+pub const ACTOR_HEADER: &str = "x-actor";
+
+// Who is calling the API, recorded in the task history. Authenticated callers are recorded under
+// their API key's name. Otherwise callers identify themselves with the X-Actor header (the worker
+// sends "worker"), and anything else is recorded as "api".
+#[derive(Clone, Debug)]
+pub struct Actor(String);
+
+// Keeps junk headers from bloating the history collection
+const MAX_ACTOR_LEN: usize = 64;
+
+impl Actor {
+    pub fn new(name: &str) -> Self {
+        Self(name.to_string())
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl FromRequest for Actor {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(name) = resolve_caller(req)
+            .ok()
+            .as_ref()
+            .and_then(|caller| caller.name())
+        {
+            return ready(Ok(Actor::new(name)));
+        }
+
+        let actor = req
+            .headers()
+            .get(ACTOR_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty() && value.len() <= MAX_ACTOR_LEN)
+            .map(Actor::new)
+            .unwrap_or_else(|| Actor::new("api"));
+        ready(Ok(actor))
+    }
+}
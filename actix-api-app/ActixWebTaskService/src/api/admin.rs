@@ -0,0 +1,217 @@
+use crate::{
+    api::{
+        auth::Caller,
+        task::{record_history, TaskError},
+    },
+    model::{
+        history::TaskHistoryEntry,
+        task::{Task, TaskEvent, TaskGlobalId, TaskState},
+    },
+    queue::TaskQueue,
+    repository::TaskRepository,
+};
+use actix_web::{
+    post,
+    web::{Data, Json, Query},
+};
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+// Tasks that haven't been touched for this long are considered orphaned
+const DEFAULT_REQUEUE_THRESHOLD_SECS: u64 = 60 * 60;
+// A task whose worker has been sending heartbeats is given up on much sooner once they stop.
+// Workers send one every 20 seconds by default.
+const DEFAULT_HEARTBEAT_TIMEOUT_SECS: u64 = 2 * 60;
+
+#[derive(Deserialize)]
+pub struct RequeueParams {
+    older_than_secs: Option<u64>,
+    heartbeat_timeout_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequeueReason {
+    // InProgress for longer than the threshold, most likely its worker died
+    StuckInProgress,
+    // InProgress and its worker's heartbeats stopped, it died or lost touch
+    HeartbeatLost,
+    // Still NotStarted but no longer waiting in the queue, e.g. the send failed on submit
+    MissingFromQueue,
+}
+
+#[derive(Serialize)]
+pub struct RequeuedTask {
+    task_global_id: TaskGlobalId,
+    reason: RequeueReason,
+}
+
+#[derive(Serialize)]
+pub struct RequeueFailure {
+    task_global_id: TaskGlobalId,
+    error: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct RequeueReport {
+    requeued: Vec<RequeuedTask>,
+    failed: Vec<RequeueFailure>,
+}
+
+impl RequeueReport {
+    fn record(
+        &mut self,
+        task_global_id: TaskGlobalId,
+        reason: RequeueReason,
+        result: Result<(), String>,
+    ) {
+        match result {
+            Ok(()) => self.requeued.push(RequeuedTask {
+                task_global_id,
+                reason,
+            }),
+            Err(error) => self.failed.push(RequeueFailure {
+                task_global_id,
+                error,
+            }),
+        }
+    }
+}
+
+// Pushes orphaned tasks back onto the queue. Only tasks that haven't been updated within the
+// threshold are considered, so tasks that were just submitted or just picked up are left alone.
+// Tasks in progress that are still getting heartbeats are left alone however long they take,
+// ones whose heartbeats stopped are requeued after heartbeat_timeout_secs.
+#[post("/admin/requeue")]
+pub async fn requeue_tasks(
+    task_repo: Data<dyn TaskRepository>,
+    task_queue: Data<dyn TaskQueue>,
+    params: Query<RequeueParams>,
+    caller: Caller,
+) -> Result<Json<RequeueReport>, TaskError> {
+    if !caller.is_privileged() {
+        return Err(TaskError::Forbidden);
+    }
+
+    let cutoff = ago(params
+        .older_than_secs
+        .unwrap_or(DEFAULT_REQUEUE_THRESHOLD_SECS));
+    let heartbeat_cutoff = ago(params
+        .heartbeat_timeout_secs
+        .unwrap_or(DEFAULT_HEARTBEAT_TIMEOUT_SECS));
+
+    let mut report = RequeueReport::default();
+
+    let repo = task_repo.get_ref();
+    let find_in_progress = |before| async move {
+        repo.find_stale_tasks(TaskState::InProgress, before)
+            .await
+            .map_err(|e| {
+                error!("Failed to find stuck tasks: {}", e);
+                TaskError::TaskQueryFailure
+            })
+    };
+    // Tasks that have had heartbeats are judged by those alone, the others by the threshold
+    let mut stuck_tasks: Vec<(Task, RequeueReason)> = find_in_progress(heartbeat_cutoff)
+        .await?
+        .into_iter()
+        .filter(|task| task.last_heartbeat_at.is_some())
+        .map(|task| (task, RequeueReason::HeartbeatLost))
+        .collect();
+    stuck_tasks.extend(
+        find_in_progress(cutoff)
+            .await?
+            .into_iter()
+            .filter(|task| task.last_heartbeat_at.is_none())
+            .map(|task| (task, RequeueReason::StuckInProgress)),
+    );
+
+    for (mut task, reason) in stuck_tasks {
+        let note = match reason {
+            RequeueReason::HeartbeatLost => {
+                "requeued after its worker stopped sending heartbeats".to_string()
+            }
+            _ => "requeued after being stuck in progress".to_string(),
+        };
+        // Reset the state so whichever worker picks it up can start it again
+        task.state = TaskState::NotStarted;
+        task.push_event(TaskEvent::new(
+            TaskState::NotStarted,
+            "admin".to_string(),
+            Some(note.clone()),
+        ));
+        let task_global_id = task.get_global_id();
+        let result = requeue(task_repo.get_ref(), task_queue.get_ref(), task).await;
+        if result.is_ok() {
+            record_history(
+                task_repo.get_ref(),
+                TaskHistoryEntry::new(
+                    task_global_id.to_string(),
+                    Some(TaskState::InProgress),
+                    TaskState::NotStarted,
+                    "admin".to_string(),
+                    Some(note),
+                ),
+            )
+            .await;
+        }
+        report.record(task_global_id, reason, result);
+    }
+
+    let waiting_tasks = task_repo
+        .find_stale_tasks(TaskState::NotStarted, cutoff)
+        .await
+        .map_err(|e| {
+            error!("Failed to find unstarted tasks: {}", e);
+            TaskError::TaskQueryFailure
+        })?;
+
+    if !waiting_tasks.is_empty() {
+        let queued = task_queue.queued_task_ids().await.map_err(|e| {
+            error!("Failed to read queue contents: {}", e);
+            TaskError::QueueFailure
+        })?;
+
+        match queued {
+            Some(queued) => {
+                for task in waiting_tasks {
+                    let task_global_id = task.get_global_id();
+                    if queued.contains(&task_global_id) {
+                        continue;
+                    }
+                    let result = requeue(task_repo.get_ref(), task_queue.get_ref(), task).await;
+                    report.record(task_global_id, RequeueReason::MissingFromQueue, result);
+                }
+            }
+            // SQS can't be listed, but it redelivers whatever isn't acked on its own
+            None => info!("Queue contents can't be listed, not looking for tasks missing from it"),
+        }
+    }
+
+    info!(
+        "Requeue finished: {} requeued, {} failed",
+        report.requeued.len(),
+        report.failed.len()
+    );
+
+    Ok(Json(report))
+}
+
+fn ago(secs: u64) -> DateTime<Utc> {
+    Utc::now() - Duration::seconds(secs.min(i64::MAX as u64) as i64)
+}
+
+// Saves the task (which also refreshes updated_at so it isn't picked up again straight away) and
+// sends it back to the queue
+async fn requeue(
+    task_repo: &dyn TaskRepository,
+    task_queue: &dyn TaskQueue,
+    task: Task,
+) -> Result<(), String> {
+    task_repo
+        .put_task(task.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    task_queue.send_task(&task).await.map_err(|e| e.to_string())
+}
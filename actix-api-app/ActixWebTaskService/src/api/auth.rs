@@ -0,0 +1,79 @@
+use crate::{api::task::TaskError, config::AuthConfig, model::task::Task};
+use actix_web::{dev::Payload, http::header, web::Data, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+
+// Who is calling, resolved from the `Authorization: Bearer <api key>` header against the keys in
+// the auth config. With auth disabled every caller is Anonymous and no checks are applied.
+#[derive(Clone, Debug)]
+pub enum Caller {
+    Anonymous,
+    // End user, may only see and act on their own tasks
+    User(String),
+    // Trusted service such as the worker or operator tooling, may act on any task
+    Service(String),
+}
+
+impl Caller {
+    pub fn can_access(&self, task: &Task) -> bool {
+        match self {
+            Caller::Anonymous | Caller::Service(_) => true,
+            Caller::User(user_id) => task.user_uuid == *user_id,
+        }
+    }
+
+    // User whose tasks a listing should be restricted to, if any
+    pub fn scoped_user(&self) -> Option<&str> {
+        match self {
+            Caller::User(user_id) => Some(user_id),
+            Caller::Anonymous | Caller::Service(_) => None,
+        }
+    }
+
+    // Name of the authenticated key, if the caller authenticated at all
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Caller::User(name) | Caller::Service(name) => Some(name),
+            Caller::Anonymous => None,
+        }
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        matches!(self, Caller::Anonymous | Caller::Service(_))
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = TaskError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(resolve_caller(req))
+    }
+}
+
+pub fn resolve_caller(req: &HttpRequest) -> Result<Caller, TaskError> {
+    let auth_config = match req.app_data::<Data<AuthConfig>>() {
+        Some(auth_config) if auth_config.enabled => auth_config,
+        _ => return Ok(Caller::Anonymous),
+    };
+
+    let key = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or(TaskError::Unauthorized)?;
+
+    let api_key = auth_config
+        .api_keys
+        .iter()
+        .find(|api_key| api_key.key == key)
+        .ok_or(TaskError::Unauthorized)?;
+
+    if api_key.service {
+        Ok(Caller::Service(api_key.name.clone()))
+    } else {
+        Ok(Caller::User(api_key.name.clone()))
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    api::{auth::Caller, task::TaskError},
+    config::AutoscaleConfig,
+    metrics::Metrics,
+    model::worker::WorkerStatus,
+    queue::TaskQueue,
+    repository::TaskRepository,
+};
+use actix_web::{
+    get, put,
+    web::{Data, Json},
+};
+use chrono::{Duration, Utc};
+use log::error;
+use serde::Serialize;
+use task_types::requests::LoadReport;
+
+// What an autoscaler (a KEDA metrics-api scaler, or an HPA on external metrics) scales the
+// workers by. desired_replicas is the figure to act on, the rest shows how it came about.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AutoscaleHint {
+    // Waiting tasks, the most of what the API sees on the queue and what any live worker reports
+    pub queue_depth: u64,
+    pub live_workers: usize,
+    // Means over the live workers that reported them
+    pub avg_processing_secs: Option<f64>,
+    pub utilization: Option<f64>,
+    pub desired_replicas: u32,
+}
+
+// Sent by every worker each autoscale interval. Also counts as the worker checking in.
+#[put("/autoscale/hint")]
+pub async fn report_load(
+    task_repo: Data<dyn TaskRepository>,
+    report: Json<LoadReport>,
+    caller: Caller,
+) -> Result<Json<WorkerStatus>, TaskError> {
+    if !caller.is_privileged() {
+        return Err(TaskError::Forbidden);
+    }
+
+    let report = report.into_inner();
+    if !(0.0..=1.0).contains(&report.load.utilization)
+        || report
+            .load
+            .avg_processing_secs
+            .is_some_and(|secs| !secs.is_finite() || secs < 0.0)
+    {
+        return Err(TaskError::BadTaskRequest);
+    }
+    // Same as a status report, a worker the API doesn't know about should register again
+    let mut worker = task_repo
+        .get_worker(&report.worker_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to look up worker {}: {}", report.worker_id, e);
+            TaskError::StorageUnavailable
+        })?
+        .ok_or(TaskError::TaskNotFound)?;
+    worker.load = Some(report.load);
+    worker.last_seen_at = Utc::now();
+    task_repo.save_worker(worker.clone()).await.map_err(|e| {
+        error!("Failed to save worker load: {}", e);
+        TaskError::TaskUpdateFailure
+    })?;
+
+    Ok(Json(worker))
+}
+
+#[get("/autoscale/hint")]
+pub async fn get_autoscale_hint(
+    task_repo: Data<dyn TaskRepository>,
+    task_queue: Data<dyn TaskQueue>,
+    config: Data<AutoscaleConfig>,
+    metrics: Data<Metrics>,
+    caller: Caller,
+) -> Result<Json<AutoscaleHint>, TaskError> {
+    if !caller.is_privileged() {
+        return Err(TaskError::Forbidden);
+    }
+
+    let hint = work_out_hint(task_repo.get_ref(), task_queue.get_ref(), &config).await?;
+    metrics.record_desired_replicas(hint.desired_replicas);
+    Ok(Json(hint))
+}
+
+pub async fn work_out_hint(
+    task_repo: &dyn TaskRepository,
+    task_queue: &dyn TaskQueue,
+    config: &AutoscaleConfig,
+) -> Result<AutoscaleHint, TaskError> {
+    let workers = task_repo.list_workers().await.map_err(|e| {
+        error!("Failed to list workers: {}", e);
+        TaskError::TaskQueryFailure
+    })?;
+    // The API's own count works with no workers running at all, which is what lets the workers
+    // be scaled up from zero
+    let queued: u64 = task_queue
+        .depth()
+        .await
+        .map_err(|e| {
+            error!("Failed to read queue depth: {}", e);
+            TaskError::QueueFailure
+        })?
+        .iter()
+        .map(|depth| depth.waiting)
+        .sum();
+
+    let cutoff = Utc::now() - Duration::seconds(config.worker_timeout_secs as i64);
+    let live: Vec<_> = workers
+        .iter()
+        .filter(|worker| worker.last_seen_at >= cutoff)
+        .collect();
+    let loads: Vec<_> = live
+        .iter()
+        .filter_map(|worker| Some((worker.concurrency, worker.load.as_ref()?)))
+        .collect();
+
+    let queue_depth = loads
+        .iter()
+        .map(|(_, load)| load.queue_depth)
+        .fold(queued, u64::max);
+    let avg_processing_secs = mean(
+        loads
+            .iter()
+            .filter_map(|(_, load)| load.avg_processing_secs),
+    );
+    let utilization = mean(loads.iter().map(|(_, load)| load.utilization));
+
+    // In task slots: the busy ones, plus those the waiting tasks need to be done in time. Without
+    // a processing time to go on each waiting task gets a slot of its own.
+    let busy: f64 = loads
+        .iter()
+        .map(|(concurrency, load)| load.utilization * f64::from(*concurrency))
+        .sum();
+    let backlog = match avg_processing_secs {
+        Some(secs) => queue_depth as f64 * secs / config.target_drain_secs.max(1) as f64,
+        None => queue_depth as f64,
+    };
+    let per_worker = mean(live.iter().map(|worker| f64::from(worker.concurrency)))
+        .unwrap_or(f64::from(config.default_concurrency))
+        .max(1.0);
+    let desired = ((busy + backlog) / per_worker).ceil() as u32;
+
+    Ok(AutoscaleHint {
+        queue_depth,
+        live_workers: live.len(),
+        avg_processing_secs,
+        utilization,
+        desired_replicas: desired.max(config.min_replicas).min(config.max_replicas),
+    })
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
@@ -0,0 +1,78 @@
+use crate::{
+    api::auth::Caller,
+    events::{TaskEvent, TaskEvents},
+};
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective, ContentEncoding},
+    web::{Bytes, Data},
+    HttpResponse,
+};
+use futures::stream;
+use log::{error, warn};
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::time::{self, Interval};
+
+// Comment lines sent while things are quiet, so proxies don't close an idle connection and
+// disconnected clients are noticed
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// Live task changes as server-sent events: "created" or "updated" with the task as JSON, and
+// "lagged" with how many events were dropped if the client couldn't keep up (re-fetch to catch
+// up). Users only get events for their own tasks.
+#[get("/tasks/events")]
+pub async fn task_events(events: Data<TaskEvents>, caller: Caller) -> HttpResponse {
+    let mut keepalive = time::interval(KEEPALIVE_INTERVAL);
+    keepalive.reset();
+    let state = (events.subscribe(), keepalive, caller);
+
+    let frames = stream::unfold(state, |(mut receiver, mut keepalive, caller)| async move {
+        let frame = next_frame(&mut receiver, &mut keepalive, &caller).await?;
+        Some((Ok::<_, Infallible>(frame), (receiver, keepalive, caller)))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        // Keeps the compression middleware from buffering events
+        .insert_header(ContentEncoding::Identity)
+        .streaming(frames)
+}
+
+// Waits for the next thing to send, None once the channel has shut down
+async fn next_frame(
+    receiver: &mut Receiver<TaskEvent>,
+    keepalive: &mut Interval,
+    caller: &Caller,
+) -> Option<Bytes> {
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) if caller.can_access(&event.task) => {
+                    match serde_json::to_string(&event.task) {
+                        Ok(data) => {
+                            return Some(Bytes::from(format!(
+                                "event: {}\ndata: {}\n\n",
+                                event.kind.as_str(),
+                                data
+                            )))
+                        }
+                        Err(e) => error!("Failed to serialize task event: {}", e),
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Event stream client fell behind, dropped {} events", missed);
+                    return Some(Bytes::from(format!(
+                        "event: lagged\ndata: {{\"missed\":{}}}\n\n",
+                        missed
+                    )));
+                }
+                Err(RecvError::Closed) => return None,
+            },
+            _ = keepalive.tick() => return Some(Bytes::from_static(b": keepalive\n\n")),
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use crate::api::autoscale::work_out_hint;
+use crate::config::AutoscaleConfig;
+use crate::metrics::Metrics;
+use crate::queue::TaskQueue;
+use crate::repository::TaskRepository;
+use actix_web::{get, web::Data, HttpResponse};
+use log::warn;
+use prometheus::TEXT_FORMAT;
+
+// Scraped by Prometheus, so it's left outside API key authentication. The queue gauges and the
+// autoscaling hint are worked out fresh each time, if that fails they keep their last values.
+#[get("/metrics")]
+pub async fn serve_metrics(
+    metrics: Data<Metrics>,
+    task_queue: Data<dyn TaskQueue>,
+    task_repo: Data<dyn TaskRepository>,
+    autoscale: Data<AutoscaleConfig>,
+) -> HttpResponse {
+    match task_queue.depth().await {
+        Ok(depths) => metrics.record_queue_depth(&depths),
+        Err(e) => warn!("Failed to read queue depth for metrics: {}", e),
+    }
+    // Already logged if it fails
+    if let Ok(hint) = work_out_hint(task_repo.get_ref(), task_queue.get_ref(), &autoscale).await {
+        metrics.record_desired_replicas(hint.desired_replicas);
+    }
+
+    HttpResponse::Ok()
+        .content_type(TEXT_FORMAT)
+        .body(metrics.render())
+}
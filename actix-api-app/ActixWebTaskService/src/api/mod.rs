@@ -1 +1,14 @@
+pub mod actor;
+pub mod admin;
+pub mod auth;
+pub mod autoscale;
+pub mod events;
+pub mod metrics;
+pub mod pagination;
+pub mod search;
+pub mod stats;
 pub mod task;
+pub mod workers;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,30 @@
+use crate::repository::Page;
+use serde::Deserialize;
+
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+
+// Query parameters shared by the endpoints that return lists of tasks. Leave cursor off for the
+// first page, then pass back the next_cursor from the previous response.
+#[derive(Deserialize)]
+pub struct PageParams {
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
+
+impl PageParams {
+    pub fn cursor(&self) -> Option<&str> {
+        self.cursor.as_deref().filter(|cursor| !cursor.is_empty())
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    pub fn to_page(&self) -> Page {
+        Page {
+            cursor: self.cursor().map(str::to_string),
+            limit: self.limit(),
+        }
+    }
+}
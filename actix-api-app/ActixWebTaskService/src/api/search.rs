@@ -0,0 +1,76 @@
+use crate::{
+    api::{auth::Caller, pagination::PageParams, task::TaskError},
+    model::task::Task,
+    repository::{PageOf, RepositoryError, TaskFilter, TaskRepository},
+};
+use actix_web::{
+    get,
+    web::{Data, Json, Query},
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: String,
+    #[serde(default)]
+    include_archived: bool,
+}
+
+#[derive(Serialize)]
+pub struct SearchMatch {
+    #[serde(flatten)]
+    task: Task,
+    // Mongo text score, higher is a better match
+    score: f64,
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    matches: Vec<SearchMatch>,
+    // Absent on the last page
+    next_cursor: Option<String>,
+}
+
+// Full text search over task_type, source_file and any other string fields on the task,
+// best matches first. Users only get matches from their own tasks.
+#[get("/tasks/search")]
+pub async fn search_tasks(
+    task_repo: Data<dyn TaskRepository>,
+    params: Query<SearchParams>,
+    page: Query<PageParams>,
+    caller: Caller,
+) -> Result<Json<SearchResults>, TaskError> {
+    let query = params.q.trim();
+    if query.is_empty() {
+        return Err(TaskError::BadTaskRequest);
+    }
+
+    let filter = TaskFilter {
+        user_uuid: caller.scoped_user().map(str::to_string),
+        include_archived: params.include_archived,
+        ..TaskFilter::default()
+    };
+
+    let PageOf {
+        items: matches,
+        next_cursor,
+    } = task_repo
+        .search_tasks(query, filter, page.to_page())
+        .await
+        .map_err(|e| match e {
+            RepositoryError::InvalidCursor => TaskError::BadTaskRequest,
+            e => {
+                error!("Failed to search tasks: {}", e);
+                TaskError::TaskQueryFailure
+            }
+        })?;
+
+    Ok(Json(SearchResults {
+        matches: matches
+            .into_iter()
+            .map(|(task, score)| SearchMatch { task, score })
+            .collect(),
+        next_cursor,
+    }))
+}
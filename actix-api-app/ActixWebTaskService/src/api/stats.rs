@@ -0,0 +1,65 @@
+use crate::{
+    api::{auth::Caller, task::TaskError},
+    model::task::TaskState,
+    repository::TaskRepository,
+};
+use actix_web::{
+    get,
+    web::{Data, Json, Query},
+};
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Throughput is counted over the last day unless asked otherwise
+const DEFAULT_THROUGHPUT_WINDOW_HOURS: i64 = 24;
+
+#[derive(Deserialize)]
+pub struct StatsParams {
+    since: Option<DateTime<Utc>>,
+    // Narrows the average completion time to one task type
+    task_type: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    tasks_by_state: HashMap<TaskState, u64>,
+    completed_since: DateTime<Utc>,
+    completed: u64,
+    avg_completion_secs: Option<f64>,
+}
+
+// Figures across every user's tasks, worked out by the database rather than by loading the tasks
+#[get("/stats")]
+pub async fn get_stats(
+    task_repo: Data<dyn TaskRepository>,
+    params: Query<StatsParams>,
+    caller: Caller,
+) -> Result<Json<Stats>, TaskError> {
+    if !caller.is_privileged() {
+        return Err(TaskError::Forbidden);
+    }
+
+    let params = params.into_inner();
+    let since = params
+        .since
+        .unwrap_or_else(|| Utc::now() - Duration::hours(DEFAULT_THROUGHPUT_WINDOW_HOURS));
+
+    let (tasks_by_state, completed, avg_completion_secs) = futures::try_join!(
+        task_repo.count_by_state(),
+        task_repo.throughput_since(since),
+        task_repo.avg_completion_time(params.task_type.as_deref()),
+    )
+    .map_err(|e| {
+        error!("Failed to compute task stats: {}", e);
+        TaskError::TaskQueryFailure
+    })?;
+
+    Ok(Json(Stats {
+        tasks_by_state,
+        completed_since: since,
+        completed,
+        avg_completion_secs,
+    }))
+}
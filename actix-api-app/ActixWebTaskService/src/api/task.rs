@@ -1,40 +1,166 @@
 use crate::{
-    model::task::{Task, TaskState},
-    queue::redis::RedisQueue,
-    repository::mongodb::MongoRepository,
+    api::{actor::Actor, auth::Caller, pagination::PageParams},
+    config::RetentionConfig,
+    middleware::request_id::RequestId,
+    model::{
+        history::TaskHistoryEntry,
+        task::{
+            Task, TaskEvent, TaskGlobalId, TaskPriority, TaskProgress, TaskState, TaskType,
+            TransitionError, TransitionReason,
+        },
+    },
+    queue::TaskQueue,
+    repository::{PageOf, RepositoryError, TaskFilter, TaskRepository, TransitionFields},
 };
 use actix_web::{
+    delete,
     error::ResponseError,
     get,
-    http::{header::ContentType, StatusCode},
+    http::{
+        header::{ContentType, ETag, EntityTag, IfNoneMatch},
+        StatusCode,
+    },
     post, put,
     web::Data,
+    web::Header,
     web::Json,
     web::Path,
+    web::Query,
     HttpResponse,
 };
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
 use derive_more::Display;
-use log::error;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use task_types::requests::{CheckpointRequest, TaskCompletionRequest};
+use task_types::state_machine;
 
 // Field name has to match that of the path parameter
 #[derive(Serialize, Deserialize)]
 pub struct TaskIdentifier {
-    task_global_id: String,
-}
-
-#[derive(Deserialize)]
-pub struct TaskCompletionRequest {
-    result_file: String,
+    task_global_id: TaskGlobalId,
 }
 
 #[derive(Deserialize)]
 pub struct SubmitTaskRequest {
     user_id: String,
-    task_type: String,
+    task_type: TaskType,
     source_file: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    priority: TaskPriority,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    // Holds the task back from the workers until then, it's stored as NotStarted straight away
+    #[serde(default)]
+    run_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct ListTasksParams {
+    user_id: Option<String>,
+    state: Option<TaskState>,
+    task_type: Option<String>,
+    tag: Option<String>,
+    // RFC 3339 bounds on when the task was last written
+    updated_after: Option<DateTime<Utc>>,
+    updated_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    include_archived: bool,
+}
+
+// Optional explanation recorded in the task history, e.g. ?reason=operator+cleanup
+#[derive(Deserialize)]
+pub struct TransitionParams {
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TaskList {
+    tasks: Vec<Task>,
+    // Absent on the last page
+    next_cursor: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkAction {
+    Start,
+    Pause,
+    Fail,
+    Cancel,
+}
+
+impl BulkAction {
+    fn target_state(self) -> TaskState {
+        match self {
+            BulkAction::Start => TaskState::InProgress,
+            BulkAction::Pause => TaskState::Paused,
+            BulkAction::Fail => TaskState::Failed,
+            BulkAction::Cancel => TaskState::Cancelled,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BulkTransitionRequest {
+    task_ids: Vec<String>,
+    action: BulkAction,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BatchSubmitRequest {
+    tasks: Vec<SubmitTaskRequest>,
+}
+
+// Outcome for one task, status mirrors what the single-task endpoint would have returned
+#[derive(Serialize)]
+pub struct BulkResult {
+    task_global_id: String,
+    status: u16,
+    error: Option<String>,
 }
 
+impl BulkResult {
+    fn new(task_global_id: String, result: Result<(), TaskError>) -> Self {
+        match result {
+            Ok(()) => Self {
+                task_global_id,
+                status: StatusCode::OK.as_u16(),
+                error: None,
+            },
+            Err(e) => Self {
+                task_global_id,
+                status: e.status_code().as_u16(),
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BulkResponse {
+    results: Vec<BulkResult>,
+}
+
+const MAX_BULK_TASKS: usize = 1000;
+
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LEN: usize = 64;
+
+const MAX_METADATA_ENTRIES: usize = 32;
+const MAX_METADATA_KEY_LEN: usize = 64;
+const MAX_METADATA_VALUE_LEN: usize = 1024;
+
+// Encoded, it's stored on the task so has to stay well clear of DynamoDB's 400 KB item limit
+const MAX_CHECKPOINT_LEN: usize = 16 * 1024;
+
 // As noted in the Handler function notes below. Handler function can return a Result for which the
 // error value implements ResponseError
 #[derive(Debug, Display)]
@@ -42,7 +168,19 @@ pub enum TaskError {
     TaskNotFound,
     TaskUpdateFailure,
     TaskCreationFailure,
+    TaskQueryFailure,
+    QueueFailure,
     BadTaskRequest,
+    Unauthorized,
+    Forbidden,
+    // Someone else changed the task between it being read and written, the client can retry
+    Conflict,
+    // The task couldn't be looked up at all, which says nothing about whether it exists
+    StorageUnavailable,
+    // No registered worker has a processor for the task's type
+    UnknownTaskType,
+    // The task's state doesn't allow the transition, the body says why
+    IllegalTransition(TransitionError),
 }
 
 impl ResponseError for TaskError {
@@ -57,7 +195,19 @@ impl ResponseError for TaskError {
             TaskError::TaskNotFound => StatusCode::NOT_FOUND,
             TaskError::TaskUpdateFailure => StatusCode::FAILED_DEPENDENCY,
             TaskError::TaskCreationFailure => StatusCode::FAILED_DEPENDENCY,
+            TaskError::TaskQueryFailure => StatusCode::FAILED_DEPENDENCY,
+            TaskError::QueueFailure => StatusCode::FAILED_DEPENDENCY,
             TaskError::BadTaskRequest => StatusCode::BAD_REQUEST,
+            TaskError::Unauthorized => StatusCode::UNAUTHORIZED,
+            TaskError::Forbidden => StatusCode::FORBIDDEN,
+            TaskError::Conflict => StatusCode::CONFLICT,
+            TaskError::StorageUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            TaskError::UnknownTaskType => StatusCode::BAD_REQUEST,
+            // A finished task is never going to allow it, whatever the request looks like
+            TaskError::IllegalTransition(e) if e.reason == TransitionReason::Finished => {
+                StatusCode::CONFLICT
+            }
+            TaskError::IllegalTransition(_) => StatusCode::BAD_REQUEST,
         }
     }
 }
@@ -74,38 +224,145 @@ impl ResponseError for TaskError {
 #[get("/task/{task_global_id}")]
 pub async fn get_task(
     task_identifier: Path<TaskIdentifier>,
-    mongo_repo: Data<MongoRepository>,
-) -> Result<Json<Task>, TaskError> {
-    let task = mongo_repo
-        .get_task(task_identifier.into_inner().task_global_id)
-        .await;
+    task_repo: Data<dyn TaskRepository>,
+    caller: Caller,
+    if_none_match: Option<Header<IfNoneMatch>>,
+) -> Result<HttpResponse, TaskError> {
+    let task = load_task(
+        task_repo.get_ref(),
+        task_identifier.into_inner().task_global_id,
+    )
+    .await?;
+
+    if !caller.can_access(&task) {
+        return Err(TaskError::Forbidden);
+    }
 
-    match task {
-        Some(task) => Ok(Json(task)),
-        None => Err(TaskError::TaskNotFound),
+    // Clients poll this endpoint, so let them skip the body when nothing has changed.
+    // The tag is weak because the compression middleware may re-encode the body.
+    let body = serde_json::to_vec(&task).map_err(|_| TaskError::TaskQueryFailure)?;
+    let etag = EntityTag::new_weak(format!("{:x}", Sha256::digest(&body)));
+
+    let unchanged = match if_none_match.map(Header::into_inner) {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+
+    if unchanged {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .finish());
     }
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .insert_header(ETag(etag))
+        .body(body))
+}
+
+#[get("/tasks")]
+pub async fn list_tasks(
+    task_repo: Data<dyn TaskRepository>,
+    params: Query<ListTasksParams>,
+    page: Query<PageParams>,
+    caller: Caller,
+) -> Result<Json<TaskList>, TaskError> {
+    // Users only ever see their own tasks, whatever user_id they ask for
+    let user_id = match caller.scoped_user() {
+        Some(own_id) => Some(own_id),
+        None => params.user_id.as_deref(),
+    };
+
+    query_tasks(task_repo.get_ref(), user_id, &params, &page).await
+}
+
+// Lists the caller's own tasks without them having to know their user id
+#[get("/me/tasks")]
+pub async fn list_my_tasks(
+    task_repo: Data<dyn TaskRepository>,
+    params: Query<ListTasksParams>,
+    page: Query<PageParams>,
+    caller: Caller,
+) -> Result<Json<TaskList>, TaskError> {
+    let user_id = match &caller {
+        Caller::User(user_id) => user_id,
+        // Without an authenticated user there's no "me" to list for
+        Caller::Anonymous => return Err(TaskError::Unauthorized),
+        Caller::Service(_) => return Err(TaskError::Forbidden),
+    };
+
+    query_tasks(task_repo.get_ref(), Some(user_id), &params, &page).await
+}
+
+async fn query_tasks(
+    task_repo: &dyn TaskRepository,
+    user_id: Option<&str>,
+    params: &ListTasksParams,
+    page: &PageParams,
+) -> Result<Json<TaskList>, TaskError> {
+    let filter = TaskFilter {
+        user_uuid: user_id.map(str::to_string),
+        state: params.state,
+        task_type: params.task_type.clone(),
+        tag: params.tag.clone(),
+        updated_after: params.updated_after,
+        updated_before: params.updated_before,
+        include_archived: params.include_archived,
+    };
+
+    let PageOf {
+        items: tasks,
+        next_cursor,
+    } = task_repo
+        .list_tasks(filter, page.to_page())
+        .await
+        .map_err(|e| match e {
+            RepositoryError::InvalidCursor => TaskError::BadTaskRequest,
+            e => {
+                error!("Failed to list tasks: {}", e);
+                TaskError::TaskQueryFailure
+            }
+        })?;
+
+    Ok(Json(TaskList { tasks, next_cursor }))
 }
 
 // Update the submit_task handler
 #[post("/task")]
 pub async fn submit_task(
-    mongo_repo: Data<MongoRepository>,
-    redis_queue: Data<RedisQueue>,
+    task_repo: Data<dyn TaskRepository>,
+    task_queue: Data<dyn TaskQueue>,
     request: Json<SubmitTaskRequest>,
+    request_id: RequestId,
+    actor: Actor,
+    caller: Caller,
 ) -> Result<Json<TaskIdentifier>, TaskError> {
-    let task = Task::new(
-        request.user_id.clone(),
-        request.task_type.clone(),
-        request.source_file.clone(),
-    );
+    // Users can only submit work on their own behalf
+    if caller
+        .scoped_user()
+        .is_some_and(|own_id| own_id != request.user_id)
+    {
+        return Err(TaskError::Forbidden);
+    }
 
+    let mut task = new_task(&request, request_id.into_inner())?;
+    check_processors(task_repo.get_ref(), std::slice::from_ref(&task)).await?;
     let task_identifier = task.get_global_id();
+    let submitted = submitted_entry(&mut task, actor.into_inner());
 
-    // First store task in MongoDB
-    match mongo_repo.put_task(task).await {
+    // First store the task along with its history, so neither exists without the other
+    match task_repo
+        .create_tasks(vec![task.clone()], vec![submitted])
+        .await
+    {
         Ok(()) => {
-            // Then send to Redis queue for processing
-            match redis_queue.send_task(task_identifier.clone()).await {
+            // Then send to the queue for processing
+            let queued = match delay_until(request.run_at) {
+                Some(delay) => task_queue.send_task_delayed(&task, delay).await,
+                None => task_queue.send_task(&task).await,
+            };
+            match queued {
                 Ok(()) => Ok(Json(TaskIdentifier {
                     task_global_id: task_identifier,
                 })),
@@ -118,92 +375,822 @@ pub async fn submit_task(
                 }
             }
         }
-        Err(_) => Err(TaskError::TaskCreationFailure),
+        Err(e) => {
+            error!("Failed to create task {}: {}", task_identifier, e);
+            Err(TaskError::TaskCreationFailure)
+        }
     }
 }
 
+// The first history entry of a newly submitted task, also kept as its first event
+fn submitted_entry(task: &mut Task, actor: String) -> TaskHistoryEntry {
+    task.push_event(TaskEvent::new(
+        task.state,
+        actor.clone(),
+        Some("submitted".to_string()),
+    ));
+    TaskHistoryEntry::new(
+        task.get_global_id().into(),
+        None,
+        task.state,
+        actor,
+        Some("submitted".to_string()),
+    )
+}
+
+// What completing a task records alongside the state change
+struct Completion {
+    result_file: String,
+    result_checksum: Option<String>,
+}
+
+// What completing or failing a task records alongside the state change
+struct Outcome<'a> {
+    completion: Option<Completion>,
+    // Says when the task expires
+    retention: &'a RetentionConfig,
+}
+
 // Update the state_transition function
 async fn state_transition(
-    mongo_repo: Data<MongoRepository>,
-    task_global_id: String,
+    task_repo: Data<dyn TaskRepository>,
+    task_global_id: TaskGlobalId,
     new_state: TaskState,
-    result_file: Option<String>,
+    outcome: Option<Outcome<'_>>,
+    actor: Actor,
+    reason: Option<String>,
+    caller: Caller,
 ) -> Result<Json<TaskIdentifier>, TaskError> {
-    let mut task = match mongo_repo.get_task(task_global_id).await {
-        Some(task) => task,
-        None => return Err(TaskError::TaskNotFound),
+    let task = load_task(task_repo.get_ref(), task_global_id.clone()).await?;
+
+    if !caller.can_access(&task) {
+        return Err(TaskError::Forbidden);
+    }
+
+    state_machine::check(task.state, new_state).map_err(TaskError::IllegalTransition)?;
+
+    let actor = actor.into_inner();
+    let mut fields = TransitionFields {
+        // A failed task keeps why until it moves on
+        last_error: reason.clone().filter(|_| new_state == TaskState::Failed),
+        event: Some(TaskEvent::new(new_state, actor.clone(), reason.clone())),
+        ..TransitionFields::default()
     };
+    if let Some(outcome) = outcome {
+        if let Some(completion) = outcome.completion {
+            fields.result_file = Some(completion.result_file);
+            fields.result_checksum = completion.result_checksum;
+        }
+        fields.expires_at = outcome
+            .retention
+            .expires_at(new_state, task.task_type.as_str());
+    }
 
-    if !task.can_transition_to(&new_state) {
-        return Err(TaskError::BadTaskRequest);
+    // The state check above is only for a helpful error, the write itself re-checks atomically
+    // in case someone else moved the task in the meantime
+    let previous = task_repo
+        .transition_task(
+            task_global_id.as_str(),
+            &TaskState::sources_for(new_state),
+            new_state,
+            fields,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to transition task {}: {}", task_global_id, e);
+            TaskError::TaskUpdateFailure
+        })?
+        .ok_or(TaskError::Conflict)?;
+
+    record_history(
+        task_repo.get_ref(),
+        TaskHistoryEntry::new(
+            task_global_id.to_string(),
+            Some(previous.state),
+            new_state,
+            actor,
+            reason,
+        ),
+    )
+    .await;
+
+    Ok(Json(TaskIdentifier { task_global_id }))
+}
+
+// A missing task is a 404, but a failed lookup mustn't be reported as one
+async fn load_task(
+    task_repo: &dyn TaskRepository,
+    task_global_id: TaskGlobalId,
+) -> Result<Task, TaskError> {
+    match task_repo.get_task(task_global_id.to_string()).await {
+        Ok(Some(task)) => Ok(task),
+        Ok(None) => Err(TaskError::TaskNotFound),
+        Err(e) => {
+            error!("Failed to look up task {}: {}", task_global_id, e);
+            Err(TaskError::StorageUnavailable)
+        }
     }
+}
 
-    task.state = new_state;
-    task.result_file = result_file;
+// History is best effort, a failed write is logged rather than failing the transition itself
+pub async fn record_history(task_repo: &dyn TaskRepository, entry: TaskHistoryEntry) {
+    let task_global_id = entry.task_global_id.clone();
+    if let Err(e) = task_repo.record_history(entry).await {
+        error!(
+            "Failed to record history for task {}: {}",
+            task_global_id, e
+        );
+    }
+}
 
-    let task_identifier = task.get_global_id();
-    match mongo_repo.put_task(task).await {
-        Ok(()) => Ok(Json(TaskIdentifier {
-            task_global_id: task_identifier,
-        })),
-        Err(_) => Err(TaskError::TaskUpdateFailure),
+// Same as record_history, for a batch of entries
+async fn record_histories(task_repo: &dyn TaskRepository, entries: Vec<TaskHistoryEntry>) {
+    let count = entries.len();
+    if let Err(e) = task_repo.record_histories(entries).await {
+        error!("Failed to record history for {} tasks: {}", count, e);
     }
 }
 
 // Update the remaining handler functions
 #[put("/task/{task_global_id}/start")]
 pub async fn start_task(
-    mongo_repo: Data<MongoRepository>,
+    task_repo: Data<dyn TaskRepository>,
     task_identifier: Path<TaskIdentifier>,
+    actor: Actor,
+    params: Query<TransitionParams>,
+    caller: Caller,
 ) -> Result<Json<TaskIdentifier>, TaskError> {
     state_transition(
-        mongo_repo,
+        task_repo,
         task_identifier.into_inner().task_global_id,
         TaskState::InProgress,
         None,
+        actor,
+        params.into_inner().reason,
+        caller,
     )
     .await
 }
 
 #[put("/task/{task_global_id}/pause")]
 pub async fn pause_task(
-    mongo_repo: Data<MongoRepository>,
+    task_repo: Data<dyn TaskRepository>,
     task_identifier: Path<TaskIdentifier>,
+    actor: Actor,
+    params: Query<TransitionParams>,
+    caller: Caller,
 ) -> Result<Json<TaskIdentifier>, TaskError> {
     state_transition(
-        mongo_repo,
+        task_repo,
         task_identifier.into_inner().task_global_id,
         TaskState::Paused,
         None,
+        actor,
+        params.into_inner().reason,
+        caller,
     )
     .await
 }
 
 #[put("/task/{task_global_id}/fail")]
 pub async fn fail_task(
-    mongo_repo: Data<MongoRepository>,
+    task_repo: Data<dyn TaskRepository>,
+    retention: Data<RetentionConfig>,
     task_identifier: Path<TaskIdentifier>,
+    actor: Actor,
+    params: Query<TransitionParams>,
+    caller: Caller,
 ) -> Result<Json<TaskIdentifier>, TaskError> {
     state_transition(
-        mongo_repo,
+        task_repo,
         task_identifier.into_inner().task_global_id,
         TaskState::Failed,
+        Some(Outcome {
+            completion: None,
+            retention: &retention,
+        }),
+        actor,
+        params.into_inner().reason,
+        caller,
+    )
+    .await
+}
+
+// Sends a failed task back to the queue for another attempt. It keeps the error it failed with
+// until it moves on again, so it can still be seen while it waits.
+#[put("/task/{task_global_id}/retry")]
+pub async fn retry_task(
+    task_repo: Data<dyn TaskRepository>,
+    task_queue: Data<dyn TaskQueue>,
+    task_identifier: Path<TaskIdentifier>,
+    actor: Actor,
+    params: Query<TransitionParams>,
+    caller: Caller,
+) -> Result<Json<TaskIdentifier>, TaskError> {
+    let task_global_id = task_identifier.into_inner().task_global_id;
+    let task = load_task(task_repo.get_ref(), task_global_id.clone()).await?;
+
+    if !caller.can_access(&task) {
+        return Err(TaskError::Forbidden);
+    }
+
+    if task.state != TaskState::Failed {
+        return Err(TaskError::BadTaskRequest);
+    }
+
+    let actor = actor.into_inner();
+    let reason = params
+        .into_inner()
+        .reason
+        .unwrap_or_else(|| "retried".to_string());
+    let fields = TransitionFields {
+        last_error: task.last_error.clone(),
+        retried: true,
+        event: Some(TaskEvent::new(
+            TaskState::NotStarted,
+            actor.clone(),
+            Some(reason.clone()),
+        )),
+        ..TransitionFields::default()
+    };
+    let previous = task_repo
+        .transition_task(
+            task_global_id.as_str(),
+            &[TaskState::Failed],
+            TaskState::NotStarted,
+            fields,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to retry task {}: {}", task_global_id, e);
+            TaskError::TaskUpdateFailure
+        })?
+        .ok_or(TaskError::Conflict)?;
+
+    record_history(
+        task_repo.get_ref(),
+        TaskHistoryEntry::new(
+            task_global_id.to_string(),
+            Some(previous.state),
+            TaskState::NotStarted,
+            actor,
+            Some(reason),
+        ),
+    )
+    .await;
+
+    // As on submit the task is already stored, the requeue job sends it again if this fails
+    if let Err(e) = task_queue.send_task(&previous).await {
+        error!("Failed to queue retried task {}: {}", task_global_id, e);
+    }
+
+    Ok(Json(TaskIdentifier { task_global_id }))
+}
+
+// Workers call this every so often while processing a task, so the requeue reaper can tell a
+// slow task from one whose worker has died. Only tasks in progress take heartbeats.
+#[put("/task/{task_global_id}/heartbeat")]
+pub async fn heartbeat_task(
+    task_repo: Data<dyn TaskRepository>,
+    task_identifier: Path<TaskIdentifier>,
+    caller: Caller,
+) -> Result<Json<TaskIdentifier>, TaskError> {
+    record_heartbeat(
+        task_repo.get_ref(),
+        task_identifier.into_inner().task_global_id,
         None,
+        None,
+        caller,
     )
     .await
 }
 
+// How far along a task in progress is, shown on the task until the next report. Counts as a
+// heartbeat too.
+#[put("/task/{task_global_id}/progress")]
+pub async fn report_progress(
+    task_repo: Data<dyn TaskRepository>,
+    task_identifier: Path<TaskIdentifier>,
+    progress: Json<TaskProgress>,
+    caller: Caller,
+) -> Result<Json<TaskIdentifier>, TaskError> {
+    let progress = progress.into_inner();
+    if progress.percent > 100 {
+        return Err(TaskError::BadTaskRequest);
+    }
+    record_heartbeat(
+        task_repo.get_ref(),
+        task_identifier.into_inner().task_global_id,
+        Some(progress),
+        None,
+        caller,
+    )
+    .await
+}
+
+// Saves where the worker has got to with a task in progress, replacing the last checkpoint. It's
+// handed back on the task, so a worker taking over after a crash can carry on from there. Counts
+// as a heartbeat too.
+#[put("/task/{task_global_id}/checkpoint")]
+pub async fn save_checkpoint(
+    task_repo: Data<dyn TaskRepository>,
+    task_identifier: Path<TaskIdentifier>,
+    request: Json<CheckpointRequest>,
+    caller: Caller,
+) -> Result<Json<TaskIdentifier>, TaskError> {
+    let checkpoint = request.into_inner().checkpoint;
+    if checkpoint.len() > MAX_CHECKPOINT_LEN || STANDARD.decode(&checkpoint).is_err() {
+        return Err(TaskError::BadTaskRequest);
+    }
+    record_heartbeat(
+        task_repo.get_ref(),
+        task_identifier.into_inner().task_global_id,
+        None,
+        Some(checkpoint),
+        caller,
+    )
+    .await
+}
+
+async fn record_heartbeat(
+    task_repo: &dyn TaskRepository,
+    task_global_id: TaskGlobalId,
+    progress: Option<TaskProgress>,
+    checkpoint: Option<String>,
+    caller: Caller,
+) -> Result<Json<TaskIdentifier>, TaskError> {
+    let task = load_task(task_repo, task_global_id.clone()).await?;
+    if !caller.can_access(&task) {
+        return Err(TaskError::Forbidden);
+    }
+
+    let recorded = task_repo
+        .record_heartbeat(task_global_id.as_str(), Utc::now(), progress, checkpoint)
+        .await
+        .map_err(|e| {
+            error!(
+                "Failed to record heartbeat for task {}: {}",
+                task_global_id, e
+            );
+            TaskError::TaskUpdateFailure
+        })?;
+    if !recorded {
+        return Err(TaskError::Conflict);
+    }
+
+    Ok(Json(TaskIdentifier { task_global_id }))
+}
+
 #[put("/task/{task_global_id}/complete")]
 pub async fn complete_task(
-    mongo_repo: Data<MongoRepository>,
+    task_repo: Data<dyn TaskRepository>,
+    retention: Data<RetentionConfig>,
     task_identifier: Path<TaskIdentifier>,
     completion_request: Json<TaskCompletionRequest>,
+    actor: Actor,
+    params: Query<TransitionParams>,
+    caller: Caller,
 ) -> Result<Json<TaskIdentifier>, TaskError> {
+    let completion_request = completion_request.into_inner();
+    // Stored as sent, so it has to be one already: 64 lowercase hex digits
+    if let Some(checksum) = &completion_request.result_checksum {
+        if checksum.len() != 64
+            || !checksum
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        {
+            return Err(TaskError::BadTaskRequest);
+        }
+    }
     state_transition(
-        mongo_repo,
+        task_repo,
         task_identifier.into_inner().task_global_id,
         TaskState::Completed,
-        Some(completion_request.result_file.clone()),
+        Some(Outcome {
+            completion: Some(Completion {
+                result_file: completion_request.result_file,
+                result_checksum: completion_request.result_checksum,
+            }),
+            retention: &retention,
+        }),
+        actor,
+        params.into_inner().reason,
+        caller,
     )
     .await
 }
+
+// Submits many tasks at once, storing and queueing them in a single round trip each. The batch
+// is validated up front and stored all or nothing, so one bad task rejects the whole request.
+#[post("/tasks")]
+pub async fn submit_tasks(
+    task_repo: Data<dyn TaskRepository>,
+    task_queue: Data<dyn TaskQueue>,
+    request: Json<BatchSubmitRequest>,
+    request_id: RequestId,
+    actor: Actor,
+    caller: Caller,
+) -> Result<Json<BulkResponse>, TaskError> {
+    let request = request.into_inner();
+    if request.tasks.is_empty() || request.tasks.len() > MAX_BULK_TASKS {
+        return Err(TaskError::BadTaskRequest);
+    }
+
+    let request_id = request_id.into_inner();
+    let mut tasks = Vec::with_capacity(request.tasks.len());
+    for submission in &request.tasks {
+        if caller
+            .scoped_user()
+            .is_some_and(|own_id| own_id != submission.user_id)
+        {
+            return Err(TaskError::Forbidden);
+        }
+        tasks.push(new_task(submission, request_id.clone())?);
+    }
+    check_processors(task_repo.get_ref(), &tasks).await?;
+
+    let actor = actor.into_inner();
+    let task_ids: Vec<String> = tasks
+        .iter()
+        .map(|task| task.get_global_id().into())
+        .collect();
+    let delays: Vec<Option<Duration>> = request
+        .tasks
+        .iter()
+        .map(|submission| delay_until(submission.run_at))
+        .collect();
+    let history = tasks
+        .iter_mut()
+        .map(|task| submitted_entry(task, actor.clone()))
+        .collect();
+
+    // The batch is stored as a whole or not at all
+    task_repo
+        .create_tasks(tasks.clone(), history)
+        .await
+        .map_err(|e| {
+            error!("Failed to create batch of tasks: {}", e);
+            TaskError::TaskCreationFailure
+        })?;
+
+    // As with a single submit, the tasks are stored whether or not queueing works
+    let mut immediate = Vec::with_capacity(tasks.len());
+    let mut delayed = Vec::new();
+    for (task, delay) in tasks.into_iter().zip(delays) {
+        match delay {
+            Some(delay) => delayed.push((task, delay)),
+            None => immediate.push(task),
+        }
+    }
+    if let Err(e) = task_queue.send_tasks(&immediate).await {
+        error!("Failed to queue batch of tasks: {}", e);
+    }
+    if let Err(e) = task_queue.send_tasks_delayed(&delayed).await {
+        error!("Failed to schedule batch of tasks: {}", e);
+    }
+
+    let results = task_ids
+        .into_iter()
+        .map(|task_global_id| BulkResult::new(task_global_id, Ok(())))
+        .collect();
+    Ok(Json(BulkResponse { results }))
+}
+
+// Applies the same action to many tasks, e.g. failing everything left behind by an outage.
+// Each id goes through the normal state machine and gets its own result, one bad id doesn't stop
+// the rest. The tasks are read and written in one round trip each rather than one per task.
+#[put("/tasks/transition")]
+pub async fn bulk_transition(
+    task_repo: Data<dyn TaskRepository>,
+    request: Json<BulkTransitionRequest>,
+    actor: Actor,
+    caller: Caller,
+) -> Result<Json<BulkResponse>, TaskError> {
+    let request = request.into_inner();
+    if request.task_ids.is_empty() || request.task_ids.len() > MAX_BULK_TASKS {
+        return Err(TaskError::BadTaskRequest);
+    }
+
+    // A repeated id would race itself, so each one is only transitioned once
+    let mut task_ids = request.task_ids;
+    let mut seen = HashSet::new();
+    task_ids.retain(|task_id| seen.insert(task_id.clone()));
+
+    // A malformed id can't belong to any task, so it isn't looked up
+    let well_formed: Vec<String> = task_ids
+        .iter()
+        .filter(|task_id| task_id.parse::<TaskGlobalId>().is_ok())
+        .cloned()
+        .collect();
+
+    let new_state = request.action.target_state();
+    let mut found: HashMap<String, Task> = task_repo
+        .get_tasks(&well_formed)
+        .await
+        .map_err(|e| {
+            error!("Failed to read tasks for bulk transition: {}", e);
+            TaskError::TaskQueryFailure
+        })?
+        .into_iter()
+        .map(|task| (task.get_global_id().into(), task))
+        .collect();
+
+    // Same checks as the single-task endpoints, in the same order
+    let mut outcomes: Vec<(String, Result<(), TaskError>)> = Vec::with_capacity(task_ids.len());
+    let mut eligible = Vec::new();
+    for task_global_id in task_ids {
+        let outcome = match found.remove(&task_global_id) {
+            None => Err(TaskError::TaskNotFound),
+            Some(task) if !caller.can_access(&task) => Err(TaskError::Forbidden),
+            Some(task) => state_machine::check(task.state, new_state)
+                .map(|()| eligible.push(task))
+                .map_err(TaskError::IllegalTransition),
+        };
+        outcomes.push((task_global_id, outcome));
+    }
+
+    let actor = actor.into_inner();
+    let event = TaskEvent::new(new_state, actor.clone(), request.reason.clone());
+
+    // The version check makes each write conditional on the task not having changed since the
+    // read above
+    let mut transitioned: HashMap<String, Result<TaskState, TaskError>> = match task_repo
+        .transition_many(&eligible, new_state, Some(event))
+        .await
+    {
+        Ok(moved) => eligible
+            .iter()
+            .zip(moved)
+            .map(|(task, moved)| {
+                let outcome = if moved {
+                    Ok(task.state)
+                } else {
+                    Err(TaskError::Conflict)
+                };
+                (task.get_global_id().into(), outcome)
+            })
+            .collect(),
+        Err(e) => {
+            error!("Failed to apply bulk transition: {}", e);
+            eligible
+                .iter()
+                .map(|task| {
+                    (
+                        task.get_global_id().into(),
+                        Err(TaskError::TaskUpdateFailure),
+                    )
+                })
+                .collect()
+        }
+    };
+
+    let mut history = Vec::new();
+    let results = outcomes
+        .into_iter()
+        .map(|(task_global_id, outcome)| {
+            let outcome = outcome.and_then(|()| {
+                let previous_state = transitioned
+                    .remove(&task_global_id)
+                    .unwrap_or(Err(TaskError::TaskUpdateFailure))?;
+                history.push(TaskHistoryEntry::new(
+                    task_global_id.clone(),
+                    Some(previous_state),
+                    new_state,
+                    actor.clone(),
+                    request.reason.clone(),
+                ));
+                Ok(())
+            });
+            BulkResult::new(task_global_id, outcome)
+        })
+        .collect();
+    record_histories(task_repo.get_ref(), history).await;
+
+    Ok(Json(BulkResponse { results }))
+}
+
+// Hides the task from default listings, it's purged for good once the retention window passes
+#[put("/task/{task_global_id}/archive")]
+pub async fn archive_task(
+    task_repo: Data<dyn TaskRepository>,
+    task_identifier: Path<TaskIdentifier>,
+    actor: Actor,
+    caller: Caller,
+) -> Result<Json<TaskIdentifier>, TaskError> {
+    let mut task = load_task(
+        task_repo.get_ref(),
+        task_identifier.into_inner().task_global_id,
+    )
+    .await?;
+
+    if !caller.can_access(&task) {
+        return Err(TaskError::Forbidden);
+    }
+
+    // A worker may still report back on a running task, so don't hide it from under it
+    if task.state == TaskState::InProgress {
+        return Err(TaskError::BadTaskRequest);
+    }
+
+    let task_identifier = task.get_global_id();
+    if task.archived_at.is_some() {
+        return Ok(Json(TaskIdentifier {
+            task_global_id: task_identifier,
+        }));
+    }
+
+    let state = task.state;
+    let actor = actor.into_inner();
+    task.archived_at = Some(Utc::now());
+    task.push_event(TaskEvent::new(
+        state,
+        actor.clone(),
+        Some("archived".to_string()),
+    ));
+    task_repo.put_task(task).await.map_err(|e| match e {
+        RepositoryError::Conflict => TaskError::Conflict,
+        _ => TaskError::TaskUpdateFailure,
+    })?;
+
+    record_history(
+        task_repo.get_ref(),
+        TaskHistoryEntry::new(
+            task_identifier.to_string(),
+            Some(state),
+            state,
+            actor,
+            Some("archived".to_string()),
+        ),
+    )
+    .await;
+
+    Ok(Json(TaskIdentifier {
+        task_global_id: task_identifier,
+    }))
+}
+
+// Soft delete: the task disappears from the API straight away but stays in storage until the
+// deletion retention window passes
+#[delete("/task/{task_global_id}")]
+pub async fn delete_task(
+    task_repo: Data<dyn TaskRepository>,
+    task_identifier: Path<TaskIdentifier>,
+    actor: Actor,
+    caller: Caller,
+) -> Result<Json<TaskIdentifier>, TaskError> {
+    let task = load_task(
+        task_repo.get_ref(),
+        task_identifier.into_inner().task_global_id,
+    )
+    .await?;
+
+    if !caller.can_access(&task) {
+        return Err(TaskError::Forbidden);
+    }
+
+    // Same as archiving, a running task's worker still needs it
+    if task.state == TaskState::InProgress {
+        return Err(TaskError::BadTaskRequest);
+    }
+
+    let task_identifier = task.get_global_id();
+    match task_repo.delete_task(&task_identifier).await {
+        Ok(true) => {}
+        // Someone else deleted it first
+        Ok(false) => return Err(TaskError::TaskNotFound),
+        Err(e) => {
+            error!("Failed to delete task {}: {}", task_identifier, e);
+            return Err(TaskError::TaskUpdateFailure);
+        }
+    }
+
+    record_history(
+        task_repo.get_ref(),
+        TaskHistoryEntry::new(
+            task_identifier.to_string(),
+            Some(task.state),
+            task.state,
+            actor.into_inner(),
+            Some("deleted".to_string()),
+        ),
+    )
+    .await;
+
+    Ok(Json(TaskIdentifier {
+        task_global_id: task_identifier,
+    }))
+}
+
+#[get("/task/{task_global_id}/history")]
+pub async fn get_task_history(
+    task_identifier: Path<TaskIdentifier>,
+    task_repo: Data<dyn TaskRepository>,
+    caller: Caller,
+) -> Result<Json<Vec<TaskHistoryEntry>>, TaskError> {
+    let task_global_id = task_identifier.into_inner().task_global_id;
+
+    // Also tells a task with no recorded history apart from one that doesn't exist
+    let task = load_task(task_repo.get_ref(), task_global_id.clone()).await?;
+    if !caller.can_access(&task) {
+        return Err(TaskError::Forbidden);
+    }
+
+    let history = task_repo.get_history(&task_global_id).await.map_err(|e| {
+        error!("Failed to read history for task {}: {}", task_global_id, e);
+        TaskError::TaskQueryFailure
+    })?;
+
+    Ok(Json(history))
+}
+
+// Builds a task from a submission, without storing it
+fn new_task(request: &SubmitTaskRequest, request_id: String) -> Result<Task, TaskError> {
+    // The user goes into the task's id, which has to parse back for the task to be found again
+    if !request.task_type.is_valid() || !TaskGlobalId::valid_user(&request.user_id) {
+        return Err(TaskError::BadTaskRequest);
+    }
+    let mut task = Task::new(
+        request.user_id.clone(),
+        request.task_type.clone(),
+        request.source_file.clone(),
+    );
+    task.request_id = Some(request_id);
+    task.tags = normalize_tags(&request.tags)?;
+    task.priority = request.priority;
+    validate_metadata(&request.metadata)?;
+    task.metadata = request.metadata.clone();
+    Ok(task)
+}
+
+// Turns down tasks of a custom type no registered worker has a processor for, which would sit in
+// the queue with nothing to pick them up. Every worker processes the built-in types, and while no
+// worker has said what it processes there's nothing to check against.
+async fn check_processors(task_repo: &dyn TaskRepository, tasks: &[Task]) -> Result<(), TaskError> {
+    if tasks.iter().all(|task| task.task_type.is_built_in()) {
+        return Ok(());
+    }
+    let workers = task_repo.list_workers().await.map_err(|e| {
+        error!("Failed to look up workers: {}", e);
+        TaskError::StorageUnavailable
+    })?;
+    let processors: HashSet<&TaskType> = workers
+        .iter()
+        .flat_map(|worker| &worker.processors)
+        .collect();
+    let unprocessed =
+        |task: &&Task| !task.task_type.is_built_in() && !processors.contains(&task.task_type);
+    match tasks.iter().find(unprocessed) {
+        Some(task) if !processors.is_empty() => {
+            info!(
+                "Turned down a task of type {}, no worker processes it",
+                task.task_type
+            );
+            Err(TaskError::UnknownTaskType)
+        }
+        _ => Ok(()),
+    }
+}
+
+// How long until a requested run_at, None if it's not in the future
+fn delay_until(run_at: Option<DateTime<Utc>>) -> Option<Duration> {
+    (run_at? - Utc::now())
+        .to_std()
+        .ok()
+        .filter(|delay| !delay.is_zero())
+}
+
+// Keeps metadata to a size that's fine to store on every task. Keys can't be empty or start with
+// a $, which Mongo would take for an operator.
+fn validate_metadata(metadata: &HashMap<String, String>) -> Result<(), TaskError> {
+    let valid = metadata.len() <= MAX_METADATA_ENTRIES
+        && metadata.iter().all(|(key, value)| {
+            !key.is_empty()
+                && !key.starts_with('$')
+                && key.len() <= MAX_METADATA_KEY_LEN
+                && value.len() <= MAX_METADATA_VALUE_LEN
+        });
+    if !valid {
+        return Err(TaskError::BadTaskRequest);
+    }
+    Ok(())
+}
+
+// Trims and de-duplicates submitted tags, rejecting empty or oversized ones
+fn normalize_tags(tags: &[String]) -> Result<Vec<String>, TaskError> {
+    if tags.len() > MAX_TAGS {
+        return Err(TaskError::BadTaskRequest);
+    }
+
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            return Err(TaskError::BadTaskRequest);
+        }
+        if !normalized.iter().any(|existing| existing == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+
+    Ok(normalized)
+}
@@ -0,0 +1,1278 @@
+// Handler tests against the in-memory repository and queue
+use crate::{
+    api::{
+        admin::requeue_tasks,
+        autoscale::{get_autoscale_hint, report_load},
+        events::task_events,
+        metrics::serve_metrics,
+        search::search_tasks,
+        stats::get_stats,
+        task::{
+            archive_task, bulk_transition, complete_task, delete_task, fail_task, get_task,
+            get_task_history, heartbeat_task, list_my_tasks, list_tasks, pause_task,
+            report_progress, retry_task, save_checkpoint, start_task, submit_task, submit_tasks,
+        },
+        workers::{list_workers, register_worker, report_worker_status},
+    },
+    config::{ApiKeyConfig, AuthConfig, AutoscaleConfig, RetentionConfig},
+    events::{TaskEvent, TaskEventKind, TaskEvents},
+    metrics::Metrics,
+    model::task::{Task, TaskPriority, TaskState, TaskType},
+    queue::{
+        memory::{Enqueued, InMemoryQueue},
+        TaskQueue,
+    },
+    repository::{memory::InMemoryRepository, TaskRepository},
+};
+use actix_web::{
+    body::MessageBody,
+    http::{header, StatusCode},
+    test,
+    web::Data,
+    App,
+};
+use futures::future::poll_fn;
+use serde_json::{json, Value};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+macro_rules! test_app {
+    ($repo:expr) => {
+        test_app!($repo, AuthConfig::default())
+    };
+    ($repo:expr, $auth:expr) => {
+        test_app!($repo, $auth, Arc::new(InMemoryQueue::new()))
+    };
+    ($repo:expr, $auth:expr, $queue:expr) => {{
+        let repo: Arc<dyn TaskRepository> = $repo.clone();
+        let queue: Arc<dyn TaskQueue> = $queue.clone();
+        test::init_service(
+            App::new()
+                .app_data(Data::from(repo))
+                .app_data(Data::from(queue))
+                .app_data(Data::new($auth))
+                .app_data(Data::new(RetentionConfig::default()))
+                .app_data(Data::new(AutoscaleConfig::default()))
+                .app_data(Data::new(Metrics::new()))
+                .service(get_task)
+                .service(get_task_history)
+                .service(submit_task)
+                .service(submit_tasks)
+                .service(start_task)
+                .service(complete_task)
+                .service(pause_task)
+                .service(fail_task)
+                .service(retry_task)
+                .service(heartbeat_task)
+                .service(report_progress)
+                .service(save_checkpoint)
+                .service(archive_task)
+                .service(delete_task)
+                .service(list_tasks)
+                .service(list_my_tasks)
+                .service(bulk_transition)
+                .service(search_tasks)
+                .service(get_stats)
+                .service(requeue_tasks)
+                .service(register_worker)
+                .service(report_worker_status)
+                .service(list_workers)
+                .service(report_load)
+                .service(get_autoscale_hint),
+        )
+        .await
+    }};
+}
+
+fn auth_config() -> AuthConfig {
+    AuthConfig {
+        enabled: true,
+        api_keys: vec![
+            ApiKeyConfig {
+                key: "alice-key".to_string(),
+                name: "alice".to_string(),
+                service: false,
+            },
+            ApiKeyConfig {
+                key: "worker-key".to_string(),
+                name: "worker".to_string(),
+                service: true,
+            },
+        ],
+    }
+}
+
+async fn seed(repo: &InMemoryRepository, user: &str, task_type: &str, state: TaskState) -> String {
+    let mut task = Task::new(user.to_string(), task_type.into(), "in.mp4".to_string());
+    task.state = state;
+    let task_id = task.get_global_id().into();
+    repo.put_task(task).await.unwrap();
+    task_id
+}
+
+#[actix_web::test]
+async fn submit_stores_task_and_history_even_if_queueing_fails() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(
+        repo,
+        AuthConfig::default(),
+        Arc::new(InMemoryQueue::failing())
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/task")
+        .set_json(json!({
+            "user_id": "alice",
+            "task_type": "render",
+            "source_file": "in.mp4",
+            "tags": [" video ", "video"],
+        }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let task_id = body["task_global_id"].as_str().unwrap().to_string();
+
+    let task = repo.get_task(task_id.clone()).await.unwrap().unwrap();
+    assert_eq!(task.state, TaskState::NotStarted);
+    assert_eq!(task.tags, vec!["video"]);
+    assert!(task.request_id.is_some());
+
+    let history = repo.get_history(&task_id).await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].reason.as_deref(), Some("submitted"));
+}
+
+#[actix_web::test]
+async fn submit_stores_the_priority_normal_by_default() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    for (priority, expected) in [
+        (json!("High"), TaskPriority::High),
+        (Value::Null, TaskPriority::Normal),
+    ] {
+        let mut request = json!({
+            "user_id": "alice",
+            "task_type": "render",
+            "source_file": "in.mp4",
+        });
+        if !priority.is_null() {
+            request["priority"] = priority;
+        }
+        let req = test::TestRequest::post()
+            .uri("/task")
+            .set_json(request)
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let task_id = body["task_global_id"].as_str().unwrap().to_string();
+        let task = repo.get_task(task_id).await.unwrap().unwrap();
+        assert_eq!(task.priority, expected);
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/task")
+        .set_json(json!({
+            "user_id": "alice",
+            "task_type": "render",
+            "source_file": "in.mp4",
+            "priority": "urgent",
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn submit_keeps_metadata_and_returns_it() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::post()
+        .uri("/task")
+        .set_json(json!({
+            "user_id": "alice",
+            "task_type": "render",
+            "source_file": "in.mp4",
+            "metadata": { "correlation_id": "abc-123", "resolution": "1080p" },
+        }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let task_id = body["task_global_id"].as_str().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{task_id}"))
+        .to_request();
+    let task: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        task["metadata"],
+        json!({ "correlation_id": "abc-123", "resolution": "1080p" })
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/task")
+        .set_json(json!({
+            "user_id": "alice",
+            "task_type": "render",
+            "source_file": "in.mp4",
+            "metadata": { "$where": "1" },
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn submit_turns_down_task_types_no_worker_processes() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    let submit = |task_type: &str| {
+        test::TestRequest::post()
+            .uri("/task")
+            .set_json(json!({
+                "user_id": "alice",
+                "task_type": task_type,
+                "source_file": "in.mp4",
+            }))
+            .to_request()
+    };
+
+    // Until a worker says what it processes, any well-formed type goes
+    let resp = test::call_service(&app, submit("transcde")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, submit("bad type!")).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::post()
+        .uri("/workers")
+        .set_json(json!({
+            "worker_id": "media-1",
+            "hostname": "host-a",
+            "concurrency": 2,
+            "processors": ["render", "transcode"]
+        }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let resp = test::call_service(&app, submit("transcde")).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = test::call_service(&app, submit("transcode")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, submit("render")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn batch_submit_stores_every_task_with_history() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::post()
+        .uri("/tasks")
+        .set_json(json!({
+            "tasks": [
+                { "user_id": "alice", "task_type": "render", "source_file": "a.mp4" },
+                { "user_id": "bob", "task_type": "transcode", "source_file": "b.mp4" },
+            ]
+        }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+
+    for result in results {
+        assert_eq!(result["status"], 200);
+        let task_id = result["task_global_id"].as_str().unwrap().to_string();
+        assert!(repo.get_task(task_id.clone()).await.unwrap().is_some());
+        assert_eq!(repo.get_history(&task_id).await.unwrap().len(), 1);
+    }
+
+    // One bad task rejects the whole batch
+    let req = test::TestRequest::post()
+        .uri("/tasks")
+        .set_json(json!({
+            "tasks": [
+                { "user_id": "alice", "task_type": "render", "source_file": "a.mp4" },
+                { "user_id": "alice", "task_type": "render", "source_file": "a.mp4", "tags": [""] },
+            ]
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn batch_submit_holds_back_tasks_with_a_run_at() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let queue = Arc::new(InMemoryQueue::new());
+    let app = test_app!(repo, AuthConfig::default(), queue);
+
+    let run_at = chrono::Utc::now() + chrono::Duration::hours(1);
+    let req = test::TestRequest::post()
+        .uri("/tasks")
+        .set_json(json!({
+            "tasks": [
+                { "user_id": "alice", "task_type": "render", "source_file": "a.mp4" },
+                { "user_id": "alice", "task_type": "render", "source_file": "b.mp4", "run_at": run_at },
+            ]
+        }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let task_ids: Vec<&str> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["task_global_id"].as_str().unwrap())
+        .collect();
+
+    let enqueued = queue.drain();
+    assert_eq!(enqueued.len(), 2);
+    assert!(enqueued.contains(&Enqueued {
+        task_global_id: task_ids[0].parse().unwrap(),
+        delay: None,
+    }));
+    let delayed = enqueued
+        .iter()
+        .find(|e| e.task_global_id.as_str() == task_ids[1])
+        .and_then(|e| e.delay)
+        .unwrap();
+    assert!(delayed > Duration::from_secs(59 * 60));
+}
+
+#[actix_web::test]
+async fn requeue_skips_tasks_still_in_the_queue() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let queue = Arc::new(InMemoryQueue::new());
+    let waiting = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let lost = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let task = repo.get_task(waiting.clone()).await.unwrap().unwrap();
+    queue.send_task(&task).await.unwrap();
+    let app = test_app!(repo, AuthConfig::default(), queue);
+
+    let req = test::TestRequest::post()
+        .uri("/admin/requeue?older_than_secs=0")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body["requeued"].as_array().unwrap().len(), 1);
+    assert_eq!(body["requeued"][0]["task_global_id"], lost);
+    let queued: Vec<String> = queue
+        .drain()
+        .into_iter()
+        .map(|e| e.task_global_id.into())
+        .collect();
+    assert_eq!(queued, vec![waiting, lost]);
+}
+
+#[actix_web::test]
+async fn submit_rejects_empty_tags() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::post()
+        .uri("/task")
+        .set_json(json!({
+            "user_id": "alice",
+            "task_type": "render",
+            "source_file": "in.mp4",
+            "tags": ["  "],
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn submit_rejects_user_ids_a_task_id_cannot_hold() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    for user_id in ["", "alice smith", "alice/bob"] {
+        let req = test::TestRequest::post()
+            .uri("/task")
+            .set_json(json!({
+                "user_id": user_id,
+                "task_type": "render",
+                "source_file": "in.mp4",
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{user_id:?}");
+    }
+}
+
+#[actix_web::test]
+async fn get_task_returns_task_with_etag_and_honours_if_none_match() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let task_id = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{task_id}"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let etag = resp.headers().get(header::ETAG).unwrap().clone();
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["task_type"], "render");
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{task_id}"))
+        .insert_header((header::IF_NONE_MATCH, etag))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    let req = test::TestRequest::get().uri("/task/missing").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn transitions_update_state_and_record_history() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let task_id = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let app = test_app!(repo);
+
+    for (action, state) in [
+        ("pause", TaskState::Paused),
+        ("fail", TaskState::Failed),
+        ("start", TaskState::InProgress),
+    ] {
+        let req = test::TestRequest::put()
+            .uri(&format!("/task/{task_id}/{action}?reason=testing"))
+            .insert_header(("x-actor", "worker"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{action}");
+        assert_eq!(
+            repo.get_task(task_id.clone()).await.unwrap().unwrap().state,
+            state
+        );
+    }
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{task_id}/complete"))
+        .set_json(json!({ "result_file": "out.mp4" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let task = repo.get_task(task_id.clone()).await.unwrap().unwrap();
+    assert_eq!(task.state, TaskState::Completed);
+    assert_eq!(task.result_file.as_deref(), Some("out.mp4"));
+    // Kept for the default retention from completion
+    assert!(task.expires_at.is_some_and(|at| at > chrono::Utc::now()));
+
+    // Transitioning to the state the task is already in is rejected
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{task_id}/complete"))
+        .set_json(json!({ "result_file": "out.mp4" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{task_id}/history"))
+        .to_request();
+    let history: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    let actors: Vec<_> = history.iter().map(|entry| entry["actor"].clone()).collect();
+    assert_eq!(actors, ["worker", "worker", "worker", "api"]);
+    assert_eq!(history[0]["reason"], "testing");
+}
+
+#[actix_web::test]
+async fn illegal_transitions_say_why() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let waiting = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let finished = seed(&repo, "alice", "render", TaskState::Completed).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{waiting}/complete"))
+        .set_json(json!({ "result_file": "out.mp4" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        "Task can't move from NotStarted to Completed: only a task in progress can complete"
+    );
+
+    // Nothing about the request would make a finished task move
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{finished}/start"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        "Task can't move from Completed to InProgress: the task has finished and can't move again"
+    );
+}
+
+#[actix_web::test]
+async fn completion_stores_the_result_checksum() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let task_id = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{task_id}/complete"))
+        .set_json(json!({ "result_file": "out.mp4", "result_checksum": "not hex" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let checksum = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{task_id}/complete"))
+        .set_json(json!({ "result_file": "out.mp4", "result_checksum": checksum }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{task_id}"))
+        .to_request();
+    let task: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(task["result_checksum"], checksum);
+}
+
+#[actix_web::test]
+async fn transitions_move_updated_at_but_not_created_at() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let task_id = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{task_id}"))
+        .to_request();
+    let task: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(task["created_at"].is_string());
+    assert!(task["updated_at"].is_string());
+    let before = repo.get_task(task_id.clone()).await.unwrap().unwrap();
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{task_id}/start"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let after = repo.get_task(task_id).await.unwrap().unwrap();
+    assert_eq!(after.created_at, before.created_at);
+    assert!(after.updated_at > before.updated_at);
+}
+
+#[actix_web::test]
+async fn transitions_are_kept_as_events_on_the_task() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::post()
+        .uri("/task")
+        .set_json(json!({
+            "user_id": "alice",
+            "task_type": "render",
+            "source_file": "in.mp4",
+        }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let task_id = body["task_global_id"].as_str().unwrap();
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{task_id}/pause?reason=maintenance"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{task_id}"))
+        .to_request();
+    let task: Value = test::call_and_read_body_json(&app, req).await;
+    let events: Vec<_> = task["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| (event["state"].as_str().unwrap(), event["note"].as_str()))
+        .collect();
+    assert_eq!(
+        events,
+        [
+            ("NotStarted", Some("submitted")),
+            ("Paused", Some("maintenance"))
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn history_of_unknown_task_is_not_found() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::get()
+        .uri("/task/missing/history")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn bulk_transition_reports_each_task() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let waiting = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let running = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::put()
+        .uri("/tasks/transition")
+        .set_json(json!({
+            "task_ids": [waiting, running, "missing", waiting],
+            "action": "start",
+        }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    let statuses: Vec<_> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["status"].as_u64().unwrap())
+        .collect();
+    // The repeated id is only transitioned once
+    assert_eq!(statuses, [200, 400, 404]);
+    assert_eq!(
+        repo.get_task(waiting.clone()).await.unwrap().unwrap().state,
+        TaskState::InProgress
+    );
+    assert_eq!(repo.get_history(&waiting).await.unwrap().len(), 1);
+
+    let req = test::TestRequest::put()
+        .uri("/tasks/transition")
+        .set_json(json!({ "task_ids": [], "action": "start" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn bulk_cancel_stops_unfinished_tasks() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let waiting = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let paused = seed(&repo, "alice", "render", TaskState::Paused).await;
+    let done = seed(&repo, "alice", "render", TaskState::Completed).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::put()
+        .uri("/tasks/transition")
+        .set_json(json!({
+            "task_ids": [waiting, paused, done],
+            "action": "cancel",
+            "reason": "no longer needed",
+        }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    let statuses: Vec<_> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["status"].as_u64().unwrap())
+        .collect();
+    // A finished task can't be cancelled
+    assert_eq!(statuses, [200, 200, 409]);
+    for task_global_id in [&waiting, &paused] {
+        assert_eq!(
+            repo.get_task(task_global_id.clone())
+                .await
+                .unwrap()
+                .unwrap()
+                .state,
+            TaskState::Cancelled
+        );
+    }
+    assert_eq!(
+        repo.get_task(done.clone()).await.unwrap().unwrap().state,
+        TaskState::Completed
+    );
+}
+
+#[actix_web::test]
+async fn archived_tasks_are_hidden_from_listings() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let kept = seed(&repo, "alice", "render", TaskState::Completed).await;
+    let archived = seed(&repo, "alice", "render", TaskState::Completed).await;
+    let running = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{archived}/archive"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{running}/archive"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::get().uri("/tasks").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let ids: Vec<_> = body["tasks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|task| {
+            format!(
+                "{}_{}",
+                task["user_uuid"].as_str().unwrap(),
+                task["task_uuid"].as_str().unwrap()
+            )
+        })
+        .collect();
+    assert_eq!(ids, [running.clone(), kept.clone()]);
+
+    let req = test::TestRequest::get()
+        .uri("/tasks?include_archived=true")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["tasks"].as_array().unwrap().len(), 3);
+}
+
+#[actix_web::test]
+async fn deleted_tasks_are_gone_from_the_api() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let deleted = seed(&repo, "alice", "render", TaskState::Failed).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/task/{deleted}"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{deleted}"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/task/{deleted}"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn list_tasks_pages_with_cursor() {
+    let repo = Arc::new(InMemoryRepository::new());
+    for _ in 0..3 {
+        seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    }
+    seed(&repo, "bob", "render", TaskState::NotStarted).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::get()
+        .uri("/tasks?user_id=alice&limit=2")
+        .to_request();
+    let first: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(first["tasks"].as_array().unwrap().len(), 2);
+    let cursor = first["next_cursor"].as_str().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/tasks?user_id=alice&limit=2&cursor={cursor}"))
+        .to_request();
+    let second: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(second["tasks"].as_array().unwrap().len(), 1);
+    assert!(second["next_cursor"].is_null());
+
+    let req = test::TestRequest::get()
+        .uri("/tasks?cursor=garbage")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn list_tasks_filters_by_state_and_type() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let wanted = seed(&repo, "alice", "render", TaskState::Completed).await;
+    seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    seed(&repo, "alice", "transcode", TaskState::Completed).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::get()
+        .uri("/tasks?state=Completed&task_type=render")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let tasks = body["tasks"].as_array().unwrap();
+    assert_eq!(tasks.len(), 1);
+    assert!(wanted.ends_with(tasks[0]["task_uuid"].as_str().unwrap()));
+
+    let req = test::TestRequest::get()
+        .uri("/tasks?updated_before=2000-01-01T00:00:00Z")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["tasks"].as_array().unwrap().is_empty());
+
+    let req = test::TestRequest::get()
+        .uri("/tasks?state=Bogus")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn users_only_see_their_own_tasks() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let own = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let other = seed(&repo, "bob", "render", TaskState::NotStarted).await;
+    let app = test_app!(repo, auth_config());
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{own}"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{other}"))
+        .insert_header((header::AUTHORIZATION, "Bearer alice-key"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Asking for someone else's tasks still only lists the caller's own
+    let req = test::TestRequest::get()
+        .uri("/tasks?user_id=bob")
+        .insert_header((header::AUTHORIZATION, "Bearer alice-key"))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["tasks"].as_array().unwrap().len(), 1);
+    assert_eq!(body["tasks"][0]["user_uuid"], "alice");
+
+    let req = test::TestRequest::get()
+        .uri("/me/tasks")
+        .insert_header((header::AUTHORIZATION, "Bearer alice-key"))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["tasks"][0]["user_uuid"], "alice");
+
+    let req = test::TestRequest::get()
+        .uri("/me/tasks")
+        .insert_header((header::AUTHORIZATION, "Bearer worker-key"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{other}/start"))
+        .insert_header((header::AUTHORIZATION, "Bearer worker-key"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn search_returns_scored_matches() {
+    let repo = Arc::new(InMemoryRepository::new());
+    seed(&repo, "alice", "render video", TaskState::NotStarted).await;
+    seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    seed(&repo, "alice", "transcode", TaskState::NotStarted).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::get()
+        .uri("/tasks/search?q=render+video")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let types: Vec<_> = body["matches"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|found| found["task_type"].as_str().unwrap())
+        .collect();
+    assert_eq!(types, ["render video", "render"]);
+
+    let req = test::TestRequest::get()
+        .uri("/tasks/search?q=+")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn requeue_is_limited_to_privileged_callers() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo, auth_config());
+
+    let req = test::TestRequest::post()
+        .uri("/admin/requeue")
+        .insert_header((header::AUTHORIZATION, "Bearer alice-key"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::post()
+        .uri("/admin/requeue")
+        .insert_header((header::AUTHORIZATION, "Bearer worker-key"))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["requeued"], json!([]));
+}
+
+#[actix_web::test]
+async fn requeue_reports_tasks_it_could_not_send() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let stuck = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let app = test_app!(
+        repo,
+        AuthConfig::default(),
+        Arc::new(InMemoryQueue::failing())
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/admin/requeue?older_than_secs=0")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body["failed"][0]["task_global_id"], stuck);
+    // The reset is saved before the send is attempted
+    assert_eq!(
+        repo.get_task(stuck).await.unwrap().unwrap().state,
+        TaskState::NotStarted
+    );
+}
+
+#[actix_web::test]
+async fn requeue_spares_tasks_still_sending_heartbeats() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let alive = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let dead = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let waiting = seed(&repo, "alice", "render", TaskState::NotStarted).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/heartbeat", alive))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    // Only tasks in progress take heartbeats
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/heartbeat", waiting))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CONFLICT
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/admin/requeue?older_than_secs=0&heartbeat_timeout_secs=600")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    let requeued: Vec<&str> = body["requeued"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| t["task_global_id"].as_str().unwrap())
+        .collect();
+    assert!(requeued.contains(&dead.as_str()));
+    assert!(!requeued.contains(&alive.as_str()));
+}
+
+#[actix_web::test]
+async fn progress_is_shown_on_the_task() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let task_id = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/progress", task_id))
+        .set_json(json!({ "percent": 101 }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/progress", task_id))
+        .set_json(json!({ "percent": 40, "message": "frame 160 of 400" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    // A plain heartbeat leaves the progress alone
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/heartbeat", task_id))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{}", task_id))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["progress"],
+        json!({ "percent": 40, "message": "frame 160 of 400" })
+    );
+    assert!(body["last_heartbeat_at"].is_string());
+}
+
+#[actix_web::test]
+async fn retrying_a_failed_task_counts_it_and_queues_it_again() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let queue = Arc::new(InMemoryQueue::new());
+    let task_id = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let app = test_app!(repo, AuthConfig::default(), queue);
+
+    // Only failed tasks can be retried
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{task_id}/retry"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{task_id}/fail?reason=out%20of%20disk"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{task_id}/retry"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{task_id}"))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["state"], "NotStarted");
+    assert_eq!(body["retry_count"], 1);
+    assert_eq!(body["last_error"], "out of disk");
+    assert_eq!(
+        queue.drain(),
+        [Enqueued {
+            task_global_id: task_id.parse().unwrap(),
+            delay: None,
+        }]
+    );
+
+    let history = repo.get_history(&task_id).await.unwrap();
+    assert_eq!(history.last().unwrap().reason.as_deref(), Some("retried"));
+}
+
+#[actix_web::test]
+async fn failing_a_task_keeps_the_error_until_it_moves_on() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let task_id = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::put()
+        .uri(&format!(
+            "/task/{}/fail?reason=codec%20not%20supported",
+            task_id
+        ))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{}", task_id))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["last_error"], "codec not supported");
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/start", task_id))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{}", task_id))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["last_error"].is_null());
+}
+
+#[actix_web::test]
+async fn checkpoint_is_kept_until_the_task_completes() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let task_id = seed(&repo, "alice", "render", TaskState::InProgress).await;
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/checkpoint", task_id))
+        .set_json(json!({ "checkpoint": "not base64!" }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/checkpoint", task_id))
+        .set_json(json!({ "checkpoint": "ZnJhbWUgMTYw" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{}", task_id))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["checkpoint"], "ZnJhbWUgMTYw");
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/complete", task_id))
+        .set_json(json!({ "result_file": "out.mp4" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::get()
+        .uri(&format!("/task/{}", task_id))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["checkpoint"].is_null());
+
+    // Only a task in progress takes checkpoints
+    let req = test::TestRequest::put()
+        .uri(&format!("/task/{}/checkpoint", task_id))
+        .set_json(json!({ "checkpoint": "ZnJhbWUgMTYw" }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CONFLICT
+    );
+}
+
+#[actix_web::test]
+async fn autoscale_hint_covers_busy_workers_and_the_backlog() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    let req = test::TestRequest::post()
+        .uri("/workers")
+        .set_json(json!({ "worker_id": "w1", "hostname": "h1", "concurrency": 4 }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::put()
+        .uri("/autoscale/hint")
+        .set_json(json!({
+            "worker_id": "w1",
+            "queue_depth": 20,
+            "avg_processing_secs": 60.0,
+            "utilization": 0.5,
+        }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get().uri("/autoscale/hint").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["queue_depth"], 20);
+    assert_eq!(body["live_workers"], 1);
+    // 2 busy slots, plus 20 tasks of a minute each to get through in 5 minutes on 4 slots each
+    assert_eq!(body["desired_replicas"], 2);
+}
+
+#[actix_web::test]
+async fn metrics_report_queue_depth() {
+    let queue = Arc::new(InMemoryQueue::new());
+    let task = Task::new("alice".to_string(), TaskType::Render, "in.mp4".to_string());
+    queue.send_task(&task).await.unwrap();
+    queue
+        .send_task_delayed(&task, Duration::from_secs(60))
+        .await
+        .unwrap();
+    let task_queue: Arc<dyn TaskQueue> = queue.clone();
+    let task_repo: Arc<dyn TaskRepository> = Arc::new(InMemoryRepository::new());
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(Metrics::new()))
+            .app_data(Data::from(task_queue))
+            .app_data(Data::from(task_repo))
+            .app_data(Data::new(AutoscaleConfig::default()))
+            .service(serve_metrics),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        body.contains(r#"task_queue_messages{queue="memory",status="waiting"} 1"#),
+        "{body}"
+    );
+    assert!(
+        body.contains(r#"task_queue_messages{queue="memory",status="delayed"} 1"#),
+        "{body}"
+    );
+}
+
+#[actix_web::test]
+async fn event_stream_only_carries_the_callers_tasks() {
+    let events = TaskEvents::new();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(events.clone()))
+            .app_data(Data::new(auth_config()))
+            .service(task_events),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/tasks/events")
+        .insert_header((header::AUTHORIZATION, "Bearer alice-key"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    for user in ["bob", "alice"] {
+        events.publish(TaskEvent {
+            kind: TaskEventKind::Created,
+            task: Task::new(user.to_string(), TaskType::Render, "in.mp4".to_string()),
+        });
+    }
+
+    let mut body = resp.into_body();
+    let frame = poll_fn(|cx| Pin::new(&mut body).poll_next(cx))
+        .await
+        .unwrap()
+        .unwrap();
+    let frame = String::from_utf8(frame.to_vec()).unwrap();
+    assert!(frame.starts_with("event: created\ndata: "), "{frame}");
+    assert!(frame.contains(r#""user_uuid":"alice""#), "{frame}");
+}
+
+#[actix_web::test]
+async fn workers_register_and_report_status() {
+    let repo = Arc::new(InMemoryRepository::new());
+    let app = test_app!(repo);
+
+    // Unknown workers are told to register
+    let req = test::TestRequest::put()
+        .uri("/workers/render-1/status")
+        .set_json(json!({ "current_tasks": [] }))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/workers")
+        .set_json(json!({
+            "worker_id": "render-1",
+            "hostname": "host-a",
+            "task_types": ["render"],
+            "concurrency": 2
+        }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::put()
+        .uri("/workers/render-1/status")
+        .set_json(json!({ "current_tasks": ["alice_1"] }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get().uri("/workers").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body[0]["worker_id"], "render-1");
+    assert_eq!(body[0]["concurrency"], 2);
+    assert_eq!(body[0]["current_tasks"], json!(["alice_1"]));
+}
@@ -0,0 +1,110 @@
+use crate::{
+    api::{auth::Caller, task::TaskError},
+    model::worker::WorkerStatus,
+    repository::TaskRepository,
+};
+use actix_web::{
+    get, post, put,
+    web::{Data, Json, Path},
+};
+use chrono::Utc;
+use log::{error, info};
+use serde::Deserialize;
+use task_types::requests::{RegisterWorkerRequest, WorkerStatusRequest};
+
+// Field name has to match that of the path parameter
+#[derive(Deserialize)]
+pub struct WorkerIdentifier {
+    worker_id: String,
+}
+
+// Called by a worker as it starts. Registering again under the same id, as a restarted worker
+// does, starts its record afresh.
+#[post("/workers")]
+pub async fn register_worker(
+    task_repo: Data<dyn TaskRepository>,
+    request: Json<RegisterWorkerRequest>,
+    caller: Caller,
+) -> Result<Json<WorkerStatus>, TaskError> {
+    if !caller.is_privileged() {
+        return Err(TaskError::Forbidden);
+    }
+
+    let request = request.into_inner();
+    if request.worker_id.trim().is_empty() {
+        return Err(TaskError::BadTaskRequest);
+    }
+    let now = Utc::now();
+    let worker = WorkerStatus {
+        worker_id: request.worker_id,
+        hostname: request.hostname,
+        task_types: request.task_types,
+        processors: request.processors,
+        concurrency: request.concurrency,
+        current_tasks: Vec::new(),
+        registered_at: now,
+        last_seen_at: now,
+        load: None,
+    };
+    save(task_repo.get_ref(), worker.clone()).await?;
+    info!(
+        "Worker {} registered from {}",
+        worker.worker_id, worker.hostname
+    );
+
+    Ok(Json(worker))
+}
+
+// Periodic check-in with what the worker is processing. A worker the API doesn't know about gets
+// a 404 and should register again.
+#[put("/workers/{worker_id}/status")]
+pub async fn report_worker_status(
+    task_repo: Data<dyn TaskRepository>,
+    worker_identifier: Path<WorkerIdentifier>,
+    request: Json<WorkerStatusRequest>,
+    caller: Caller,
+) -> Result<Json<WorkerStatus>, TaskError> {
+    if !caller.is_privileged() {
+        return Err(TaskError::Forbidden);
+    }
+
+    let worker_id = worker_identifier.into_inner().worker_id;
+    let mut worker = task_repo
+        .get_worker(&worker_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to look up worker {}: {}", worker_id, e);
+            TaskError::StorageUnavailable
+        })?
+        .ok_or(TaskError::TaskNotFound)?;
+    worker.current_tasks = request.into_inner().current_tasks;
+    worker.last_seen_at = Utc::now();
+    save(task_repo.get_ref(), worker.clone()).await?;
+
+    Ok(Json(worker))
+}
+
+// Every worker that has registered, with what it was last processing and when it last checked in
+#[get("/workers")]
+pub async fn list_workers(
+    task_repo: Data<dyn TaskRepository>,
+    caller: Caller,
+) -> Result<Json<Vec<WorkerStatus>>, TaskError> {
+    if !caller.is_privileged() {
+        return Err(TaskError::Forbidden);
+    }
+
+    let workers = task_repo.list_workers().await.map_err(|e| {
+        error!("Failed to list workers: {}", e);
+        TaskError::TaskQueryFailure
+    })?;
+
+    Ok(Json(workers))
+}
+
+async fn save(task_repo: &dyn TaskRepository, worker: WorkerStatus) -> Result<(), TaskError> {
+    task_repo.save_worker(worker).await.map_err(|e| {
+        error!("Failed to save worker: {}", e);
+        TaskError::TaskUpdateFailure
+    })
+}
@@ -0,0 +1,62 @@
+pub mod redis;
+
+use crate::model::task::Task;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+// How far a subscriber can fall behind before it starts missing events
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskEventKind {
+    Created,
+    Updated,
+}
+
+impl TaskEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TaskEventKind::Created => "created",
+            TaskEventKind::Updated => "updated",
+        }
+    }
+}
+
+// A task as it was straight after a change. Serialized as {"kind": "updated", "task": {...}}
+// where it leaves the process (see events::redis).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskEvent {
+    pub kind: TaskEventKind,
+    pub task: Task,
+}
+
+// In-process fan-out of task changes to whatever wants to push them on (the SSE endpoint and
+// the Redis event bus). Publishing never waits on subscribers, one that falls too far behind
+// skips ahead and is told how many events it missed.
+#[derive(Clone)]
+pub struct TaskEvents {
+    sender: broadcast::Sender<TaskEvent>,
+}
+
+impl TaskEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: TaskEvent) {
+        // Only fails when nobody is subscribed, which is fine
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TaskEvent> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for TaskEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
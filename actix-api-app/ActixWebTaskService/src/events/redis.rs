@@ -0,0 +1,155 @@
+use crate::events::{TaskEvent, TaskEvents};
+use futures::StreamExt;
+use log::{error, info, warn};
+use redis::aio::ConnectionManager;
+use redis::{Client, RedisError, Script};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+// How long a published change is remembered, so other instances don't publish it again
+const DEDUP_TTL_SECS: u64 = 60;
+
+// Pause before subscribing again after the subscription drops
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+// Publishes the event unless this version of the task has already been published. Returns the
+// number of subscribers it reached, or -1 if it was a repeat.
+const PUBLISH_ONCE_SCRIPT: &str = r#"
+if redis.call('SET', KEYS[1], '1', 'NX', 'EX', ARGV[3]) then
+    return redis.call('PUBLISH', ARGV[1], ARGV[2])
+end
+return -1
+"#;
+
+// Carries task events over a Redis pub/sub channel, so they reach every API instance (and anything
+// else subscribed to the channel) apart from the work queue. Each message is a TaskEvent as JSON.
+// Pub/sub doesn't keep messages, a subscriber that's disconnected misses what was published in
+// the meantime.
+pub struct RedisEventBus {
+    client: Client,
+    conn: ConnectionManager,
+    channel: String,
+}
+
+impl RedisEventBus {
+    pub async fn init(uri: &str, channel: &str) -> Result<Self, RedisError> {
+        let client = Client::open(uri)?;
+        let conn = ConnectionManager::new(client.clone()).await?;
+        info!("Publishing task events on Redis channel {}", channel);
+        Ok(Self {
+            client,
+            conn,
+            channel: channel.to_string(),
+        })
+    }
+
+    // Publishes every event from source on the channel. Every instance follows the same change
+    // stream, so each change is seen several times over. Tasks are versioned, the first instance
+    // to publish a version wins.
+    pub fn spawn_publisher(&self, source: &TaskEvents) {
+        let mut receiver = source.subscribe();
+        let mut conn = self.conn.clone();
+        let channel = self.channel.clone();
+        let script = Script::new(PUBLISH_ONCE_SCRIPT);
+
+        tokio::spawn(async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Event publisher fell behind, dropped {} events", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let payload = match serde_json::to_string(&event) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        error!("Failed to serialize task event: {}", e);
+                        continue;
+                    }
+                };
+                let key = format!(
+                    "{}:published:{}:{}",
+                    channel,
+                    event.task.get_global_id(),
+                    event.task.version
+                );
+
+                let published: Result<i64, RedisError> = script
+                    .key(key)
+                    .arg(&channel)
+                    .arg(payload)
+                    .arg(DEDUP_TTL_SECS)
+                    .invoke_async(&mut conn)
+                    .await;
+                if let Err(e) = published {
+                    warn!("Failed to publish task event: {}", e);
+                }
+            }
+        });
+    }
+
+    // Hands everything published on the channel to sink, subscribing again whenever the
+    // connection drops
+    pub fn spawn_subscriber(&self, sink: TaskEvents) {
+        let client = self.client.clone();
+        let channel = self.channel.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match client.get_async_connection().await {
+                    Ok(conn) => {
+                        let mut pubsub = conn.into_pubsub();
+                        match pubsub.subscribe(&channel).await {
+                            Ok(()) => {
+                                info!("Subscribed to task events on Redis channel {}", channel);
+                                let mut messages = pubsub.on_message();
+                                while let Some(message) = messages.next().await {
+                                    match decode(message.get_payload_bytes()) {
+                                        Ok(event) => sink.publish(event),
+                                        Err(e) => warn!("Skipping bad task event: {}", e),
+                                    }
+                                }
+                                warn!("Lost the subscription to Redis channel {}", channel);
+                            }
+                            Err(e) => warn!("Failed to subscribe to task events: {}", e),
+                        }
+                    }
+                    Err(e) => warn!("Failed to connect for task events: {}", e),
+                }
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        });
+    }
+}
+
+fn decode(payload: &[u8]) -> Result<TaskEvent, serde_json::Error> {
+    serde_json::from_slice(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::TaskEventKind;
+    use crate::model::task::{Task, TaskState};
+
+    #[test]
+    fn events_go_over_the_wire_as_kind_and_task() {
+        let mut task = Task::new("alice".into(), "render".into(), "in.mp4".into());
+        task.state = TaskState::InProgress;
+        let event = TaskEvent {
+            kind: TaskEventKind::Updated,
+            task,
+        };
+
+        let payload = serde_json::to_vec(&event).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["kind"], "updated");
+        assert_eq!(json["task"]["state"], "InProgress");
+
+        let decoded = decode(&payload).unwrap();
+        assert_eq!(decoded.kind, TaskEventKind::Updated);
+        assert_eq!(decoded.task.get_global_id(), event.task.get_global_id());
+    }
+}
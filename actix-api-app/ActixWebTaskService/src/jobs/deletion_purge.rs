@@ -0,0 +1,25 @@
+use crate::{config::DeletionConfig, repository::TaskRepository};
+use chrono::{Duration as ChronoDuration, Utc};
+use log::{error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+// Background loop that permanently removes tasks deleted longer ago than the retention window
+pub fn spawn(task_repo: Arc<dyn TaskRepository>, config: DeletionConfig) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(config.purge_interval_secs.max(1)));
+
+        loop {
+            interval.tick().await;
+
+            let cutoff = Utc::now() - ChronoDuration::days(config.retention_days as i64);
+
+            match task_repo.purge_deleted(cutoff).await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} deleted tasks", purged),
+                Err(e) => error!("Failed to purge deleted tasks: {}", e),
+            }
+        }
+    });
+}
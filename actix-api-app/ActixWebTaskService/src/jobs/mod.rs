@@ -0,0 +1,4 @@
+pub mod archive_purge;
+pub mod deletion_purge;
+pub mod queue_promoter;
+pub mod retention_purge;
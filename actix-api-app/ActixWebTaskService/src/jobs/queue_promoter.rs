@@ -0,0 +1,21 @@
+use crate::queue::redis::RedisQueue;
+use log::{error, info};
+use std::time::Duration;
+use tokio::time;
+
+// Background loop that moves tasks submitted with a run_at onto the queue once they're due
+pub fn spawn(redis_queue: RedisQueue, interval_ms: u64) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_millis(interval_ms.max(1)));
+
+        loop {
+            interval.tick().await;
+
+            match redis_queue.promote_due().await {
+                Ok(0) => {}
+                Ok(promoted) => info!("Queued {} scheduled tasks", promoted),
+                Err(e) => error!("Failed to queue scheduled tasks: {}", e),
+            }
+        }
+    });
+}
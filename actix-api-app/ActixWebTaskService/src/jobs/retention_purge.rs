@@ -0,0 +1,24 @@
+use crate::repository::TaskRepository;
+use chrono::Utc;
+use log::{error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+// Background loop that deletes completed and failed tasks once their retention (expires_at) has
+// passed
+pub fn spawn(task_repo: Arc<dyn TaskRepository>, purge_interval_secs: u64) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(purge_interval_secs.max(1)));
+
+        loop {
+            interval.tick().await;
+
+            match task_repo.purge_expired(Utc::now()).await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} expired tasks", purged),
+                Err(e) => error!("Failed to purge expired tasks: {}", e),
+            }
+        }
+    });
+}
@@ -1,64 +1,326 @@
 mod api;
+mod config;
+mod events;
+mod jobs;
+mod metrics;
+mod middleware;
 mod model;
 mod queue;
 mod repository;
+mod tls;
 
-use actix_web::{middleware::Logger, web::Data, App, HttpServer};
-use api::task::{complete_task, fail_task, get_task, pause_task, start_task, submit_task};
-use log::info;
-use queue::redis::RedisQueue;
-use repository::mongodb::MongoRepository;
+use actix_web::{
+    middleware::{Compress, Condition, Logger},
+    web::Data,
+    App, HttpServer,
+};
+use api::admin::requeue_tasks;
+use api::autoscale::{get_autoscale_hint, report_load};
+use api::events::task_events;
+use api::metrics::serve_metrics;
+use api::search::search_tasks;
+use api::stats::get_stats;
+use api::task::{
+    archive_task, bulk_transition, complete_task, delete_task, fail_task, get_task,
+    get_task_history, heartbeat_task, list_my_tasks, list_tasks, pause_task, report_progress,
+    retry_task, save_checkpoint, start_task, submit_task, submit_tasks,
+};
+use api::workers::{list_workers, register_worker, report_worker_status};
+use clap::Parser;
+use config::{Cli, Settings};
+use config::{QueueBackend, RepositoryBackend};
+use events::{redis::RedisEventBus, TaskEvents};
+use log::{info, warn};
+use metrics::Metrics;
+use middleware::{
+    cors::build_cors, https_redirect::HttpsRedirect, request_id::RequestIdMiddleware,
+};
+use queue::{redis::RedisQueue, sqs::SqsQueue, TaskQueue};
+use repository::{
+    cached::CachedRepository, dynamodb::DynamoRepository, embedded::EmbeddedHistoryRepository,
+    metered::MeteredRepository, mongodb::MongoRepository, sqlite::SqliteRepository, TaskRepository,
+};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::EnvFilter;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // Initialize logging, can use log macros after this
-    std::env::set_var("RUST_LOG", "debug");
-    std::env::set_var("RUST_BACKTRACE", "1");
-    env_logger::init();
-
-    // Initialize MongoDB Repository
-    let mongo_repo = match MongoRepository::init().await {
-        Ok(repo) => {
-            info!("MongoDB repository initialized");
-            repo
-        }
+    let cli = Cli::parse();
+    let settings = match Settings::load(&cli) {
+        Ok(settings) => settings,
         Err(e) => {
-            panic!("Failed to initialize MongoDB repository: {:?}", e);
+            panic!("Failed to load configuration: {}", e);
         }
     };
 
-    // Initialize Redis Queue
-    let redis_queue = match RedisQueue::init() {
-        Ok(queue) => {
-            info!("Redis queue initialized");
-            queue
+    // Initialize logging, can use log macros after this
+    // The subscriber also picks up the log macros, so every line logged while handling a request
+    // carries that request's span fields (including its request id)
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&settings.log_level));
+    tracing_subscriber::fmt().with_env_filter(env_filter).init();
+
+    let server_config = settings.server.clone();
+    let drain_timeout = server_config.shutdown_timeout_secs;
+
+    // TLS is enabled when both a certificate chain and a private key are configured
+    let tls_config = match &server_config.tls {
+        Some(tls) => Some(tls::load_rustls_config(&tls.cert_path, &tls.key_path)?),
+        None => None,
+    };
+    let https_redirect = server_config
+        .tls
+        .as_ref()
+        .is_some_and(|tls| tls.redirect_http);
+
+    // Task changes are published here for the live event stream
+    let events = TaskEvents::new();
+    // Where the change feed publishes. With a Redis channel configured they go by way of Redis,
+    // so the live event stream carries what every instance sees.
+    let changes = match &settings.events.redis_channel {
+        Some(channel) => match RedisEventBus::init(&settings.redis.uri, channel).await {
+            Ok(bus) => {
+                let changes = TaskEvents::new();
+                bus.spawn_publisher(&changes);
+                bus.spawn_subscriber(events.clone());
+                changes
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to connect the task event channel, events stay local: {}",
+                    e
+                );
+                events.clone()
+            }
+        },
+        None => events.clone(),
+    };
+
+    // Initialize the configured repository
+    let task_repo: Arc<dyn TaskRepository> = match settings.repository.backend {
+        RepositoryBackend::Mongo => match MongoRepository::init(&settings.mongo).await {
+            Ok(repo) => {
+                info!("MongoDB repository initialized");
+                repo.spawn_change_feed(changes);
+                Arc::new(repo)
+            }
+            Err(e) => {
+                panic!("Failed to initialize MongoDB repository: {:?}", e);
+            }
+        },
+        RepositoryBackend::Sqlite => match SqliteRepository::init(&settings.sqlite) {
+            Ok(repo) => {
+                info!("SQLite repository initialized");
+                info!("Live task events need MongoDB or DynamoDB, /tasks/events will stay quiet");
+                Arc::new(repo)
+            }
+            Err(e) => {
+                panic!("Failed to initialize SQLite repository: {:?}", e);
+            }
+        },
+        RepositoryBackend::Dynamo => match DynamoRepository::init(&settings.dynamo).await {
+            Ok(repo) => {
+                info!("DynamoDB repository initialized");
+                repo.spawn_change_feed(changes);
+                Arc::new(repo)
+            }
+            Err(e) => {
+                panic!("Failed to initialize DynamoDB repository: {:?}", e);
+            }
+        },
+    };
+
+    // Times the backend's own calls, under the cache so hits aren't counted as storage operations
+    let metrics = Metrics::new();
+    let task_repo: Arc<dyn TaskRepository> = Arc::new(MeteredRepository::new(
+        task_repo,
+        metrics.clone(),
+        settings.repository.backend.as_str(),
+    ));
+
+    // Above the metering, history calls it answers itself aren't storage operations
+    let task_repo: Arc<dyn TaskRepository> = if settings.repository.embedded_history {
+        info!("Keeping task history on the tasks, the history collection won't be written");
+        Arc::new(EmbeddedHistoryRepository::new(task_repo))
+    } else {
+        task_repo
+    };
+
+    // The cache is an optimisation, the service runs fine (if busier) without it
+    let task_repo: Arc<dyn TaskRepository> = if settings.cache.enabled {
+        match CachedRepository::init(task_repo.clone(), &settings.redis, &settings.cache).await {
+            Ok(cached) => Arc::new(cached),
+            Err(e) => {
+                warn!(
+                    "Failed to connect the task cache, running without it: {}",
+                    e
+                );
+                task_repo
+            }
         }
-        Err(e) => {
-            panic!("Failed to initialize Redis queue: {:?}", e);
+    } else {
+        task_repo
+    };
+
+    // Initialize the task queue
+    let task_queue: Arc<dyn TaskQueue> = match settings.queue.backend {
+        QueueBackend::Redis => match RedisQueue::init(&settings.redis) {
+            Ok(queue) => {
+                info!("Redis queue initialized");
+                // SQS holds delayed messages back itself, Redis needs them moving over
+                jobs::queue_promoter::spawn(queue.clone(), settings.redis.promote_interval_ms);
+                Arc::new(queue)
+            }
+            Err(e) => {
+                panic!("Failed to initialize Redis queue: {:?}", e);
+            }
+        },
+        QueueBackend::Sqs => match SqsQueue::init(&settings.sqs).await {
+            Ok(queue) => {
+                info!("SQS queue initialized");
+                Arc::new(queue)
+            }
+            Err(e) => {
+                panic!("Failed to initialize SQS queue: {:?}", e);
+            }
+        },
+    };
+
+    jobs::archive_purge::spawn(task_repo.clone(), settings.archive.clone());
+    jobs::deletion_purge::spawn(task_repo.clone(), settings.deletion.clone());
+    jobs::retention_purge::spawn(task_repo.clone(), settings.retention.purge_interval_secs);
+
+    let cors_config = settings.server.cors.clone();
+    let auth_config = settings.auth.clone();
+    let retention_config = settings.retention.clone();
+    let autoscale_config = settings.autoscale.clone();
+    if auth_config.enabled {
+        info!(
+            "API key authentication enabled with {} keys",
+            auth_config.api_keys.len()
+        );
+    }
+    let https_port = server_config.https_port;
+
+    // Kept outside the server closure so pending sends can be flushed after the server stops
+    let shutdown_queue = task_queue.clone();
+
+    // Draining handlers and flushing queue sends share one shutdown_timeout, counted from the
+    // signal, so the flush only gets what the drain left
+    let shutdown_deadline = Arc::new(OnceLock::new());
+    let mut terminate = signal(SignalKind::terminate())?;
+    let shutdown_signal = {
+        let shutdown_deadline = shutdown_deadline.clone();
+        async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            shutdown_deadline.get_or_init(|| Instant::now() + Duration::from_secs(drain_timeout));
         }
     };
 
     // Pass in closure that sets up everything for the web application
     // Closure is ran everytime actix starts a new thread
-    HttpServer::new(move || {
-        let logger = Logger::default();
+    // On SIGTERM or Ctrl-C actix stops accepting new connections and waits up to shutdown_timeout
+    // for in-flight handlers to finish before the workers are stopped.
+    let server = HttpServer::new(move || {
+        // Default format plus the request id so access logs can be matched to handler logs
+        let logger = Logger::new(
+            r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{x-request-id}o"#,
+        );
 
         // Create shared app data for this thread
-        let mongo_data = Data::new(mongo_repo.clone());
-        let redis_data = Data::new(redis_queue.clone());
+        let repo_data = Data::from(task_repo.clone());
+        let queue_data = Data::from(task_queue.clone());
+        let auth_data = Data::new(auth_config.clone());
+        let retention_data = Data::new(retention_config.clone());
+        let autoscale_data = Data::new(autoscale_config.clone());
+        let events_data = Data::new(events.clone());
+        let metrics_data = Data::new(metrics.clone());
 
         App::new()
+            // gzip/br/zstd, whichever the client accepts
+            .wrap(Compress::default())
+            .wrap(build_cors(&cors_config))
+            .wrap(Condition::new(
+                https_redirect,
+                HttpsRedirect::new(https_port),
+            ))
             .wrap(logger)
-            .app_data(mongo_data) // Shared MongoDB repository
-            .app_data(redis_data) // Shared Redis queue
+            .wrap(RequestIdMiddleware)
+            .app_data(repo_data) // Shared task repository
+            .app_data(queue_data) // Shared task queue
+            .app_data(auth_data) // API keys used to identify callers
+            .app_data(retention_data) // How long completed tasks are kept
+            .app_data(autoscale_data) // How the autoscaling hint is worked out
+            .app_data(events_data) // Live task changes
+            .app_data(metrics_data) // Served on /metrics
             .service(get_task)
+            .service(get_task_history)
             .service(submit_task)
+            .service(submit_tasks)
             .service(start_task)
             .service(complete_task)
             .service(pause_task)
             .service(fail_task)
+            .service(retry_task)
+            .service(heartbeat_task)
+            .service(report_progress)
+            .service(save_checkpoint)
+            .service(archive_task)
+            .service(delete_task)
+            .service(list_tasks)
+            .service(list_my_tasks)
+            .service(bulk_transition)
+            .service(search_tasks)
+            .service(get_stats)
+            .service(task_events)
+            .service(requeue_tasks)
+            .service(register_worker)
+            .service(report_worker_status)
+            .service(list_workers)
+            .service(report_load)
+            .service(get_autoscale_hint)
+            .service(serve_metrics)
     })
-    .bind(("0.0.0.0", 80))? // Bind to all interfaces to work in Docker
-    .run()
-    .await
+    .shutdown_timeout(drain_timeout)
+    .shutdown_signal(shutdown_signal);
+
+    let server = match server_config.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+
+    let bind_address = server_config.bind_address.as_str();
+    let http_port = server_config.http_port;
+    let server = match tls_config {
+        Some(tls_config) => {
+            info!("TLS enabled, listening for HTTPS on port {}", https_port);
+            let server = server.bind_rustls_0_23((bind_address, https_port), tls_config)?;
+            if https_redirect {
+                info!("Redirecting HTTP on port {} to HTTPS", http_port);
+                server.bind((bind_address, http_port))?
+            } else {
+                server
+            }
+        }
+        None => server.bind((bind_address, http_port))?,
+    };
+
+    server.run().await?;
+
+    info!("HTTP server stopped, flushing pending queue sends");
+    let remaining = match shutdown_deadline.get() {
+        Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+        None => Duration::from_secs(drain_timeout),
+    };
+    let pending = shutdown_queue.flush(remaining).await;
+    if pending > 0 {
+        warn!("Shutting down with {} queue sends still pending", pending);
+    }
+
+    Ok(())
 }
@@ -0,0 +1,152 @@
+use crate::queue::QueueDepth;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::time::Duration;
+
+// Upper bounds in seconds. Most storage calls take milliseconds, the top buckets catch the ones
+// running into the operation timeout.
+const REPOSITORY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+// Everything served on /metrics. Cheap to clone, the clones share the same counters.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    repository_duration: HistogramVec,
+    repository_operations: IntCounterVec,
+    // Read from the queue on every scrape, labelled by stream (or SQS queue)
+    queue_messages: IntGaugeVec,
+    queue_oldest_age: GaugeVec,
+    // Worked out fresh on every scrape too, see api::autoscale
+    desired_replicas: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let repository_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "task_repository_operation_duration_seconds",
+                "Time taken by storage operations",
+            )
+            .buckets(REPOSITORY_BUCKETS.to_vec()),
+            &["operation", "backend"],
+        )
+        .expect("valid histogram");
+        let repository_operations = IntCounterVec::new(
+            Opts::new(
+                "task_repository_operations_total",
+                "Storage operations by how they turned out",
+            ),
+            &["operation", "backend", "outcome"],
+        )
+        .expect("valid counter");
+
+        let queue_messages = IntGaugeVec::new(
+            Opts::new(
+                "task_queue_messages",
+                "Messages in the task queue, by whether they're waiting, pending on a worker, \
+                 delayed or dead-lettered",
+            ),
+            &["queue", "status"],
+        )
+        .expect("valid gauge");
+        let queue_oldest_age = GaugeVec::new(
+            Opts::new(
+                "task_queue_oldest_message_age_seconds",
+                "How long the oldest unacked message has been queued, 0 when there are none",
+            ),
+            &["queue"],
+        )
+        .expect("valid gauge");
+
+        let desired_replicas = IntGauge::new(
+            "task_autoscale_desired_replicas",
+            "How many workers there should be for the current load",
+        )
+        .expect("valid gauge");
+
+        registry
+            .register(Box::new(repository_duration.clone()))
+            .expect("registered once");
+        registry
+            .register(Box::new(repository_operations.clone()))
+            .expect("registered once");
+        registry
+            .register(Box::new(queue_messages.clone()))
+            .expect("registered once");
+        registry
+            .register(Box::new(queue_oldest_age.clone()))
+            .expect("registered once");
+        registry
+            .register(Box::new(desired_replicas.clone()))
+            .expect("registered once");
+
+        Self {
+            registry,
+            repository_duration,
+            repository_operations,
+            queue_messages,
+            queue_oldest_age,
+            desired_replicas,
+        }
+    }
+
+    pub fn observe_repository(
+        &self,
+        operation: &str,
+        backend: &str,
+        outcome: &str,
+        elapsed: Duration,
+    ) {
+        self.repository_duration
+            .with_label_values(&[operation, backend])
+            .observe(elapsed.as_secs_f64());
+        self.repository_operations
+            .with_label_values(&[operation, backend, outcome])
+            .inc();
+    }
+
+    pub fn record_queue_depth(&self, depths: &[QueueDepth]) {
+        for depth in depths {
+            for (status, count) in [
+                ("waiting", depth.waiting),
+                ("pending", depth.pending),
+                ("delayed", depth.delayed),
+                ("dead_lettered", depth.dead_lettered),
+            ] {
+                self.queue_messages
+                    .with_label_values(&[&depth.queue, status])
+                    .set(count.min(i64::MAX as u64) as i64);
+            }
+            // Left out rather than reported as 0 when the backend can't tell
+            if let Some(age) = depth.oldest_age {
+                self.queue_oldest_age
+                    .with_label_values(&[&depth.queue])
+                    .set(age.as_secs_f64());
+            }
+        }
+    }
+
+    pub fn record_desired_replicas(&self, replicas: u32) {
+        self.desired_replicas.set(i64::from(replicas));
+    }
+
+    // The Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        // Only fails on a metric with an invalid name, which would have failed registering
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
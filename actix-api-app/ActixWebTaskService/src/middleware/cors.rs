@@ -0,0 +1,54 @@
+use crate::config::CorsConfig;
+use actix_cors::Cors;
+use actix_web::http::{header::HeaderName, Method};
+use log::warn;
+use std::str::FromStr;
+
+// Builds the CORS middleware from config, called once per worker thread from the HttpServer closure
+pub fn build_cors(config: &CorsConfig) -> Cors {
+    let mut cors = Cors::default();
+
+    if config.allowed_origins.iter().any(|origin| origin == "*") {
+        cors = cors.allow_any_origin();
+    } else {
+        for origin in &config.allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+
+    if config.allowed_methods.iter().any(|method| method == "*") {
+        cors = cors.allow_any_method();
+    } else {
+        let methods = config
+            .allowed_methods
+            .iter()
+            .filter_map(|method| match Method::from_str(&method.to_uppercase()) {
+                Ok(method) => Some(method),
+                Err(_) => {
+                    warn!("Ignoring invalid CORS method: {}", method);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        cors = cors.allowed_methods(methods);
+    }
+
+    if config.allowed_headers.iter().any(|header| header == "*") {
+        cors = cors.allow_any_header();
+    } else {
+        let headers = config
+            .allowed_headers
+            .iter()
+            .filter_map(|header| match HeaderName::from_str(header) {
+                Ok(header) => Some(header),
+                Err(_) => {
+                    warn!("Ignoring invalid CORS header: {}", header);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        cors = cors.allowed_headers(headers);
+    }
+
+    cors.max_age(config.max_age_secs)
+}
@@ -0,0 +1,92 @@
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    Error, HttpResponse,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+// Middleware that answers plain HTTP requests with a permanent redirect to the HTTPS listener.
+// Requests that already arrived over TLS are passed through untouched.
+pub struct HttpsRedirect {
+    https_port: u16,
+}
+
+impl HttpsRedirect {
+    pub fn new(https_port: u16) -> Self {
+        Self { https_port }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for HttpsRedirect
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = HttpsRedirectMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HttpsRedirectMiddleware {
+            service,
+            https_port: self.https_port,
+        }))
+    }
+}
+
+pub struct HttpsRedirectMiddleware<S> {
+    service: S,
+    https_port: u16,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpsRedirectMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if req.connection_info().scheme() == "https" {
+            let fut = self.service.call(req);
+            return Box::pin(async move { fut.await.map(|res| res.map_into_left_body()) });
+        }
+
+        let location = redirect_location(
+            req.connection_info().host(),
+            self.https_port,
+            req.uri().path_and_query().map_or("/", |pq| pq.as_str()),
+        );
+
+        let response = HttpResponse::PermanentRedirect()
+            .insert_header((header::LOCATION, location))
+            .finish()
+            .map_into_right_body();
+
+        Box::pin(ready(Ok(req.into_response(response))))
+    }
+}
+
+// Swap whatever port the client used for the HTTPS one, leaving it off when it's the default
+fn redirect_location(host: &str, https_port: u16, path_and_query: &str) -> String {
+    let hostname = match host.rsplit_once(':') {
+        // Leave bracketed IPv6 literals without a port alone
+        Some((name, port)) if !port.ends_with(']') => name,
+        _ => host,
+    };
+
+    if https_port == 443 {
+        format!("https://{}{}", hostname, path_and_query)
+    } else {
+        format!("https://{}:{}{}", hostname, https_port, path_and_query)
+    }
+}
@@ -0,0 +1,3 @@
+pub mod cors;
+pub mod https_redirect;
+pub mod request_id;
@@ -0,0 +1,137 @@
+use actix_web::{
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use tracing::{field, info_span, Instrument};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// Sent by the worker on its calls about a task, recorded on the span so the API's lines for a
+// task can be matched up with the worker's
+const WORKER_ID_HEADER: &str = "x-worker-id";
+const ATTEMPT_HEADER: &str = "x-task-attempt";
+
+// Longest caller-supplied id we'll propagate, anything bigger gets replaced with a fresh one
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Id of the request being handled. Handlers can take this as an extractor to store it alongside
+// whatever they create, so later log lines can be tied back to the original submission.
+#[derive(Clone, Debug)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // Fall back to a new id if the middleware isn't wrapping this route
+        let request_id = req
+            .extensions()
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId(Uuid::new_v4().to_string()));
+        ready(Ok(request_id))
+    }
+}
+
+// Middleware that reuses the caller's X-Request-Id (or generates one), runs the rest of the
+// request inside a tracing span carrying it, and echoes it back in the response headers.
+pub struct RequestIdMiddleware;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdService { service }))
+    }
+}
+
+pub struct RequestIdService<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| is_valid_request_id(value))
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+
+        let span = info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.path(),
+            worker_id = field::Empty,
+            attempt = field::Empty
+        );
+        for (header, field) in [(WORKER_ID_HEADER, "worker_id"), (ATTEMPT_HEADER, "attempt")] {
+            if let Some(value) = req
+                .headers()
+                .get(header)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| is_valid_request_id(value))
+            {
+                span.record(field, value);
+            }
+        }
+        let fut = {
+            let _entered = span.enter();
+            self.service.call(req)
+        };
+
+        Box::pin(
+            async move {
+                let mut res = fut.await?;
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    res.headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(res)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+// Also used for the worker's headers, which are held to the same rules before being logged
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
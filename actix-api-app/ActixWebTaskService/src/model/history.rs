@@ -0,0 +1,37 @@
+use crate::model::task::TaskState;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// One state change of a task, kept so failures and stuck tasks can be diagnosed after the fact
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskHistoryEntry {
+    pub task_global_id: String,
+    // None for the entry recorded when the task is first submitted
+    pub old_state: Option<TaskState>,
+    pub new_state: TaskState,
+    // Who made the change, e.g. "api", "worker" or "admin"
+    pub actor: String,
+    #[serde(with = "task_types::datetime")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl TaskHistoryEntry {
+    pub fn new(
+        task_global_id: String,
+        old_state: Option<TaskState>,
+        new_state: TaskState,
+        actor: String,
+        reason: Option<String>,
+    ) -> TaskHistoryEntry {
+        TaskHistoryEntry {
+            task_global_id,
+            old_state,
+            new_state,
+            actor,
+            timestamp: Utc::now(),
+            reason,
+        }
+    }
+}
@@ -1 +1,3 @@
+pub mod history;
 pub mod task;
+pub mod worker;
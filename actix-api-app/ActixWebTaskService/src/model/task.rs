@@ -1,43 +1,78 @@
-use serde::Serialize;
-use strum_macros::{Display, EnumString};
-use uuid::Uuid;
-
-#[derive(Serialize, EnumString, Display, Debug, Eq, PartialEq)]
-pub enum TaskState {
-    NotStarted,
-    InProgress,
-    Completed,
-    Paused,
-    Failed,
-}
+// The model itself is shared with the worker
+pub use task_types::state_machine::{TransitionError, TransitionReason};
+pub use task_types::task::{
+    Task, TaskEvent, TaskGlobalId, TaskPriority, TaskProgress, TaskState, TaskType,
+};
 
-#[derive(Serialize, Debug)]
-pub struct Task {
-    pub user_uuid: String,
-    pub task_uuid: String,
-    pub task_type: String,
-    pub state: TaskState,
-    pub source_file: String,
-    pub result_file: Option<String>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::Bson;
+    use chrono::Utc;
+
+    #[test]
+    fn timestamps_are_bson_dates_when_stored_and_strings_in_json() {
+        let mut task = Task::new("alice".into(), "render".into(), "in.mp4".into());
+        task.archived_at = Some(Utc::now());
+
+        let stored = bson::to_raw_document_buf(&task).unwrap();
+        let doc = stored.to_document().unwrap();
+        assert!(matches!(doc.get("archived_at"), Some(Bson::DateTime(_))));
+        assert_eq!(doc.get_str("state").unwrap(), "NotStarted");
+
+        let loaded: Task = bson::from_slice(stored.as_bytes()).unwrap();
+        // BSON dates only keep millisecond precision
+        assert_eq!(
+            loaded.archived_at.map(|at| at.timestamp_millis()),
+            task.archived_at.map(|at| at.timestamp_millis())
+        );
 
-impl Task {
-    pub fn new(user_uuid: String, task_type: String, source_file: String) -> Task {
-        Task {
-            user_uuid,
-            task_uuid: Uuid::new_v4().to_string(),
-            task_type,
-            state: TaskState::NotStarted,
-            source_file,
-            result_file: None,
-        }
+        let json = serde_json::to_value(&task).unwrap();
+        assert!(json["archived_at"].is_string());
     }
 
-    pub fn get_global_id(&self) -> String {
-        format!("{}_{}", self.user_uuid, self.task_uuid)
+    #[test]
+    fn documents_written_before_newer_fields_still_load() {
+        let doc = bson::doc! {
+            "user_uuid": "alice",
+            "task_uuid": "1",
+            "task_type": "render",
+            "state": "Completed",
+            "source_file": "in.mp4",
+            "result_file": "out.mp4",
+        };
+        let bytes = bson::to_vec(&doc).unwrap();
+
+        let task: Task = bson::from_slice(&bytes).unwrap();
+
+        assert_eq!(task.state, TaskState::Completed);
+        assert!(task.tags.is_empty());
+        assert!(task.archived_at.is_none());
     }
 
-    pub fn can_transition_to(&self, state: &TaskState) -> bool {
-        self.state != *state
+    #[test]
+    fn unversioned_documents_are_upgraded_as_they_load() {
+        let created_at = Utc::now();
+        let doc = bson::doc! {
+            "user_uuid": "alice",
+            "task_uuid": "1",
+            "task_type": "render",
+            "state": "NotStarted",
+            "source_file": "in.mp4",
+            "created_at": bson::DateTime::from_chrono(created_at),
+        };
+
+        let task: Task = bson::from_slice(&bson::to_vec(&doc).unwrap()).unwrap();
+
+        assert_eq!(task.schema_version, Task::SCHEMA_VERSION);
+        assert_eq!(task.priority, TaskPriority::Normal);
+        assert!(task.metadata.is_empty());
+        assert_eq!(task.updated_at, task.created_at);
+
+        let stored = bson::to_document(&task).unwrap();
+        assert_eq!(
+            stored.get_i64("schema_version").unwrap(),
+            i64::from(Task::SCHEMA_VERSION)
+        );
     }
 }
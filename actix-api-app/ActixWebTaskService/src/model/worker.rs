@@ -0,0 +1,29 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use task_types::requests::WorkerLoad;
+use task_types::task::TaskType;
+
+// A worker as it last reported itself. Workers register when they start and send their status
+// periodically after that, so one whose last_seen_at falls behind has most likely gone away.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerStatus {
+    // The worker's consumer name, stable across restarts
+    pub worker_id: String,
+    pub hostname: String,
+    // Task types it takes, empty if it takes every type off the main queue
+    pub task_types: Vec<String>,
+    // Task types it can process, what submissions are checked against. Empty if it didn't say.
+    #[serde(default)]
+    pub processors: Vec<TaskType>,
+    pub concurrency: u32,
+    // Global ids of the tasks it's processing right now
+    #[serde(default)]
+    pub current_tasks: Vec<String>,
+    #[serde(with = "task_types::datetime")]
+    pub registered_at: DateTime<Utc>,
+    #[serde(with = "task_types::datetime")]
+    pub last_seen_at: DateTime<Utc>,
+    // As of the worker's last load report, None until it sends one
+    #[serde(default)]
+    pub load: Option<WorkerLoad>,
+}
@@ -0,0 +1,116 @@
+use crate::model::task::{Task, TaskGlobalId};
+use crate::queue::{QueueDepth, QueueError, TaskQueue};
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+// A send as the queue saw it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Enqueued {
+    pub task_global_id: TaskGlobalId,
+    // None for tasks queued straight away
+    pub delay: Option<Duration>,
+}
+
+// Queue for handler tests, so they can check what was queued without a Redis to hand. Messages
+// wait on a channel until the test takes them off with drain().
+pub struct InMemoryQueue {
+    sender: UnboundedSender<Enqueued>,
+    receiver: Mutex<UnboundedReceiver<Enqueued>>,
+    // Every send fails, as if the queue were down
+    failing: bool,
+}
+
+impl InMemoryQueue {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+            failing: false,
+        }
+    }
+
+    pub fn failing() -> Self {
+        Self {
+            failing: true,
+            ..Self::new()
+        }
+    }
+
+    // Takes everything queued so far, oldest first
+    pub fn drain(&self) -> Vec<Enqueued> {
+        let mut receiver = self.receiver.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut drained = Vec::new();
+        while let Ok(enqueued) = receiver.try_recv() {
+            drained.push(enqueued);
+        }
+        drained
+    }
+
+    // Reads the channel and puts everything back in the same order
+    fn peek(&self) -> Result<Vec<Enqueued>, QueueError> {
+        let queued = self.drain();
+        for enqueued in &queued {
+            self.sender
+                .send(enqueued.clone())
+                .map_err(QueueError::backend)?;
+        }
+        Ok(queued)
+    }
+
+    fn send(&self, task: &Task, delay: Option<Duration>) -> Result<(), QueueError> {
+        if self.failing {
+            return Err(QueueError::backend(std::io::Error::other("queue is down")));
+        }
+        self.sender
+            .send(Enqueued {
+                task_global_id: task.get_global_id(),
+                delay,
+            })
+            .map_err(QueueError::backend)
+    }
+}
+
+#[async_trait]
+impl TaskQueue for InMemoryQueue {
+    async fn send_task(&self, task: &Task) -> Result<(), QueueError> {
+        self.send(task, None)
+    }
+
+    async fn send_tasks(&self, tasks: &[Task]) -> Result<(), QueueError> {
+        tasks.iter().try_for_each(|task| self.send(task, None))
+    }
+
+    async fn send_task_delayed(&self, task: &Task, delay: Duration) -> Result<(), QueueError> {
+        self.send(task, Some(delay))
+    }
+
+    async fn send_tasks_delayed(&self, tasks: &[(Task, Duration)]) -> Result<(), QueueError> {
+        tasks
+            .iter()
+            .try_for_each(|(task, delay)| self.send(task, Some(*delay)))
+    }
+
+    async fn queued_task_ids(&self) -> Result<Option<HashSet<TaskGlobalId>>, QueueError> {
+        let queued = self.peek()?;
+        Ok(Some(queued.into_iter().map(|e| e.task_global_id).collect()))
+    }
+
+    async fn depth(&self) -> Result<Vec<QueueDepth>, QueueError> {
+        let queued = self.peek()?;
+        let delayed = queued.iter().filter(|e| e.delay.is_some()).count() as u64;
+        Ok(vec![QueueDepth {
+            queue: "memory".to_string(),
+            waiting: queued.len() as u64 - delayed,
+            delayed,
+            ..QueueDepth::default()
+        }])
+    }
+
+    async fn flush(&self, _timeout: Duration) -> usize {
+        0
+    }
+}
@@ -1 +1,126 @@
+#[cfg(test)]
+pub mod memory;
 pub mod redis;
+pub mod sqs;
+
+use crate::model::task::{Task, TaskGlobalId};
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// What the workers are sent for each task
+pub use task_types::message::TaskMessage;
+
+// How much work one stream or queue holds
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueueDepth {
+    pub queue: String,
+    // Waiting for a worker to pick them up
+    pub waiting: u64,
+    // Handed to a worker and not yet acked
+    pub pending: u64,
+    // Held back until they're due, retries included
+    pub delayed: u64,
+    pub dead_lettered: u64,
+    // How long the oldest message that hasn't been acked has been queued, zero if there are none.
+    // None if the backend can't tell.
+    pub oldest_age: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    Backend(Box<dyn Error + Send + Sync>),
+    Serialization(serde_json::Error),
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Backend(e) => write!(f, "{}", e),
+            Self::Serialization(e) => write!(f, "Failed to serialize task message: {}", e),
+        }
+    }
+}
+
+impl Error for QueueError {}
+
+impl QueueError {
+    fn backend(e: impl Error + Send + Sync + 'static) -> Self {
+        Self::Backend(Box::new(e))
+    }
+}
+
+impl From<::redis::RedisError> for QueueError {
+    fn from(e: ::redis::RedisError) -> Self {
+        Self::Backend(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for QueueError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Serialization(e)
+    }
+}
+
+// Hands tasks to the workers. Handlers only see this trait (as Data<dyn TaskQueue>), so the
+// queue can be Redis or SQS without them knowing.
+#[async_trait]
+pub trait TaskQueue: Send + Sync {
+    async fn send_task(&self, task: &Task) -> Result<(), QueueError>;
+
+    // Queues a batch of tasks in as few round trips as the backend allows
+    async fn send_tasks(&self, tasks: &[Task]) -> Result<(), QueueError>;
+
+    // Queues the task once the delay has passed
+    async fn send_task_delayed(&self, task: &Task, delay: Duration) -> Result<(), QueueError>;
+
+    // The batch version of send_task_delayed, each task with its own delay
+    async fn send_tasks_delayed(&self, tasks: &[(Task, Duration)]) -> Result<(), QueueError>;
+
+    // Ids of every task in the queue, whether it's waiting (or scheduled for later) or a worker
+    // has claimed it. None if the backend can't list what it holds.
+    async fn queued_task_ids(&self) -> Result<Option<HashSet<TaskGlobalId>>, QueueError>;
+
+    // How much is queued, per stream or queue
+    async fn depth(&self) -> Result<Vec<QueueDepth>, QueueError>;
+
+    // Waits for sends that are still running to finish, giving up once the timeout elapses.
+    // Returns the number of sends that were still outstanding when we stopped waiting.
+    async fn flush(&self, timeout: Duration) -> usize;
+}
+
+// Number of sends that have started but not yet finished, shared by every clone of a queue
+#[derive(Clone, Default)]
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn start(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(Arc::clone(&self.0))
+    }
+
+    async fn wait(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let pending = self.0.load(Ordering::SeqCst);
+            if pending == 0 || Instant::now() >= deadline {
+                return pending;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+// Decrements the in-flight counter when a send finishes, whichever way it exits
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
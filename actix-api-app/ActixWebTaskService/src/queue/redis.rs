@@ -1,121 +1,400 @@
-// Fixed queue/redis.rs with corrected receive_task method
+use crate::config::RedisConfig;
+use crate::model::task::{Task, TaskGlobalId, TaskPriority};
+use crate::queue::{InFlight, QueueDepth, QueueError, TaskMessage, TaskQueue};
+use async_trait::async_trait;
+use chrono::Utc;
+use deadpool_redis::{Config, Connection, Pool, PoolConfig, PoolError, Runtime, Timeouts};
 use log::{error, info};
-use redis::{AsyncCommands, Client, RedisError};
-use serde::{Deserialize, Serialize};
-use std::env;
+use redis::streams::{StreamPendingReply, StreamRangeReply};
+use redis::{AsyncCommands, RedisError, Script};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize)]
-pub struct TaskMessage {
-    pub task_global_id: String,
-}
+// The queue is a Redis stream read by the workers through a consumer group. Each entry carries
+// the JSON TaskMessage in this field, and workers delete entries once they've acked them, so
+// whatever is left in the stream is either waiting or being worked on.
+//
+// Task types listed in redis.routed_task_types get a stream of their own, {queue_name}:{type},
+// so a pool of workers can be kept to just the work it's set up for. Everything else goes to
+// the queue_name stream. With redis.priority_streams on, High and Low priority tasks go to
+// {stream}:high and {stream}:low beside the stream they'd otherwise be on, which workers read
+// before and after it. Every stream has a delayed set beside it, {stream}:delayed, which workers
+// also put tasks to retry in, and a {stream}:dead stream they give up on tasks to.
+pub const MESSAGE_FIELD: &str = "message";
+const DELAYED_SUFFIX: &str = "delayed";
+const DEAD_SUFFIX: &str = "dead";
+const HIGH_SUFFIX: &str = "high";
+const LOW_SUFFIX: &str = "low";
+
+// Due messages are moved from the delayed set onto the stream in one go, so two promoters can't
+// both move the same one. KEYS: delayed set, stream. ARGV: now, batch size, message field.
+const PROMOTE_SCRIPT: &str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, message in ipairs(due) do
+    redis.call('XADD', KEYS[2], '*', ARGV[3], message)
+    redis.call('ZREM', KEYS[1], message)
+end
+return #due
+";
+
+// Messages promoted per script call
+const PROMOTE_BATCH: usize = 500;
+
+// How long a send waits for a pooled connection before giving up, when they're all in use
+const POOL_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct RedisQueue {
-    client: Client,
+    // Shared by every clone. Connections are only opened as they're needed and are checked with
+    // a PING before being handed out again, so one Redis dropped is replaced with a fresh one.
+    pool: Pool,
     queue_name: String,
+    // The workers' group, only read for the number of entries they have pending
+    consumer_group: String,
+    routed_task_types: Arc<HashSet<String>>,
+    priority_streams: bool,
+    in_flight: InFlight,
 }
 
 impl RedisQueue {
-    pub fn init() -> Result<Self, RedisError> {
-        // Get Redis connection string from environment
-        let redis_uri =
-            env::var("REDIS_URI").unwrap_or_else(|_| "redis://localhost:6379".to_string());
-        let queue_name = env::var("REDIS_QUEUE").unwrap_or_else(|_| "task_queue".to_string());
-
-        // Create Redis client
-        let client = match Client::open(redis_uri.clone()) {
-            Ok(client) => {
-                info!("Connected to Redis: {}", redis_uri);
-                client
+    pub fn init(config: &RedisConfig) -> Result<Self, RedisError> {
+        let redis_uri = config.uri.clone();
+        let queue_name = config.queue_name.clone();
+        // Its stream would be the default stream's delayed set or dead stream, or one of its
+        // priority streams
+        if let Some(task_type) = config.routed_task_types.iter().find(|t| {
+            *t == DELAYED_SUFFIX
+                || *t == DEAD_SUFFIX
+                || (config.priority_streams && (*t == HIGH_SUFFIX || *t == LOW_SUFFIX))
+        }) {
+            return Err(RedisError::from(std::io::Error::other(format!(
+                "task type {:?} can't have a queue of its own",
+                task_type
+            ))));
+        }
+
+        // Create the connection pool
+        let pool_size = config.pool_size.max(1);
+        let pool_config = Config {
+            pool: Some(PoolConfig {
+                max_size: pool_size,
+                timeouts: Timeouts {
+                    wait: Some(POOL_WAIT_TIMEOUT),
+                    ..Timeouts::default()
+                },
+            }),
+            ..Config::from_url(redis_uri.clone())
+        };
+        let pool = match pool_config.create_pool(Some(Runtime::Tokio1)) {
+            Ok(pool) => {
+                info!(
+                    "Redis pool for {} set up with up to {} connections",
+                    redis_uri, pool_size
+                );
+                pool
             }
             Err(e) => {
-                error!("Failed to connect to Redis: {}", e);
-                return Err(e);
+                error!("Failed to set up Redis pool: {}", e);
+                return Err(RedisError::from(std::io::Error::other(e.to_string())));
             }
         };
 
-        Ok(Self { client, queue_name })
+        Ok(Self {
+            pool,
+            queue_name,
+            consumer_group: config.consumer_group.clone(),
+            routed_task_types: Arc::new(config.routed_task_types.iter().cloned().collect()),
+            priority_streams: config.priority_streams,
+            in_flight: InFlight::default(),
+        })
+    }
+
+    async fn connection(&self) -> Result<Connection, RedisError> {
+        self.pool.get().await.map_err(|e| match e {
+            PoolError::Backend(e) => e,
+            e => RedisError::from(std::io::Error::other(e.to_string())),
+        })
+    }
+
+    // The stream a task is queued on, by its type and then its priority
+    fn stream_for(&self, task: &Task) -> String {
+        let stream = if self.routed_task_types.contains(task.task_type.as_str()) {
+            format!("{}:{}", self.queue_name, task.task_type)
+        } else {
+            self.queue_name.clone()
+        };
+        match task.priority {
+            TaskPriority::High if self.priority_streams => format!("{stream}:{HIGH_SUFFIX}"),
+            TaskPriority::Low if self.priority_streams => format!("{stream}:{LOW_SUFFIX}"),
+            _ => stream,
+        }
+    }
+
+    fn streams(&self) -> Vec<String> {
+        let mut streams = vec![self.queue_name.clone()];
+        streams.extend(
+            self.routed_task_types
+                .iter()
+                .map(|task_type| format!("{}:{}", self.queue_name, task_type)),
+        );
+        if self.priority_streams {
+            streams = streams
+                .into_iter()
+                .flat_map(|stream| {
+                    [
+                        format!("{stream}:{HIGH_SUFFIX}"),
+                        format!("{stream}:{LOW_SUFFIX}"),
+                        stream,
+                    ]
+                })
+                .collect();
+        }
+        streams
+    }
+
+    // Moves every delayed message that's come due onto its stream, returning how many went
+    pub async fn promote_due(&self) -> Result<usize, RedisError> {
+        let mut conn = self.connection().await?;
+        let script = Script::new(PROMOTE_SCRIPT);
+        let mut promoted = 0;
+        for stream in self.streams() {
+            loop {
+                let moved: usize = script
+                    .key(delayed_set(&stream))
+                    .key(&stream)
+                    .arg(Utc::now().timestamp_millis())
+                    .arg(PROMOTE_BATCH)
+                    .arg(MESSAGE_FIELD)
+                    .invoke_async(&mut conn)
+                    .await?;
+                promoted += moved;
+                if moved < PROMOTE_BATCH {
+                    break;
+                }
+            }
+        }
+        Ok(promoted)
     }
+}
+
+#[async_trait]
+impl TaskQueue for RedisQueue {
+    async fn send_task(&self, task: &Task) -> Result<(), QueueError> {
+        let _guard = self.in_flight.start();
 
-    pub async fn send_task(&self, task_global_id: String) -> Result<(), RedisError> {
         // Serialize task message
-        let task_message = TaskMessage { task_global_id };
+        let task_message = TaskMessage::new(task);
         let message = match serde_json::to_string(&task_message) {
             Ok(msg) => msg,
             Err(e) => {
                 error!("Failed to serialize task message: {}", e);
-                return Err(RedisError::from(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Serialization error",
-                )));
+                return Err(e.into());
             }
         };
 
-        // Get Redis connection from the client
-        let mut conn = match self.client.get_async_connection().await {
+        // Get a Redis connection from the pool
+        let mut conn = match self.connection().await {
             Ok(conn) => conn,
             Err(e) => {
                 error!("Failed to get Redis connection: {}", e);
-                return Err(e);
+                return Err(e.into());
             }
         };
 
-        // Push the task message to the Redis list
-        match conn.rpush(&self.queue_name, message).await {
+        // Append the task message to the stream
+        match conn
+            .xadd::<_, _, _, _, ()>(self.stream_for(task), "*", &[(MESSAGE_FIELD, message)])
+            .await
+        {
             Ok(_) => {
-                info!("Task sent to Redis queue: {}", task_global_id);
+                info!("Task sent to Redis queue: {}", task_message.task_global_id);
                 Ok(())
             }
             Err(e) => {
                 error!("Failed to send task to Redis queue: {}", e);
-                Err(e)
+                Err(e.into())
             }
         }
     }
 
-    pub async fn receive_task(
-        &self,
-        timeout_seconds: u64,
-    ) -> Result<Option<TaskMessage>, RedisError> {
-        // Get Redis connection from the client
-        let mut conn = match self.client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => {
-                error!("Failed to get Redis connection: {}", e);
-                return Err(e);
-            }
-        };
+    // One round trip, all or nothing
+    async fn send_tasks(&self, tasks: &[Task]) -> Result<(), QueueError> {
+        if tasks.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.in_flight.start();
 
-        // BLPOP blocks until a message is available or timeout is reached
-        let result: Option<(String, String)> = conn
-            .blpop(&self.queue_name, timeout_seconds as usize)
-            .await?;
-
-        // Process the result
-        match result {
-            Some((_, message)) => {
-                // Deserialize the message
-                match serde_json::from_str::<TaskMessage>(&message) {
-                    Ok(task_message) => {
-                        info!(
-                            "Received task from Redis queue: {}",
-                            task_message.task_global_id
-                        );
-                        Ok(Some(task_message))
-                    }
-                    Err(e) => {
-                        error!("Failed to deserialize task message: {}", e);
-                        Err(RedisError::from(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!("Deserialization error: {}", e),
-                        )))
-                    }
-                }
-            }
-            None => {
-                // Timeout reached, no message available
-                Ok(None)
-            }
+        let messages = tasks
+            .iter()
+            .map(|task| {
+                serde_json::to_string(&TaskMessage::new(task)).map(|message| (task, message))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                error!("Failed to serialize task message: {}", e);
+                e
+            })?;
+        let count = messages.len();
+
+        let mut conn = self.connection().await.map_err(|e| {
+            error!("Failed to get Redis connection: {}", e);
+            e
+        })?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (task, message) in messages {
+            pipe.xadd(self.stream_for(task), "*", &[(MESSAGE_FIELD, message)])
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+            error!("Failed to send tasks to Redis queue: {}", e);
+            e
+        })?;
+
+        info!("{} tasks sent to Redis queue", count);
+        Ok(())
+    }
+
+    // Until the delay has passed the message sits in the delayed set, from which promote_due
+    // moves it onto the stream
+    async fn send_task_delayed(&self, task: &Task, delay: Duration) -> Result<(), QueueError> {
+        let _guard = self.in_flight.start();
+
+        let task_global_id = task.get_global_id();
+        let message = serde_json::to_string(&TaskMessage::new(task)).map_err(|e| {
+            error!("Failed to serialize task message: {}", e);
+            e
+        })?;
+        let due = Utc::now().timestamp_millis() + delay.as_millis().min(i64::MAX as u128) as i64;
+
+        let mut conn = self.connection().await.map_err(|e| {
+            error!("Failed to get Redis connection: {}", e);
+            e
+        })?;
+        let delayed = delayed_set(&self.stream_for(task));
+        conn.zadd::<_, _, _, ()>(delayed, message, due)
+            .await
+            .map_err(|e| {
+                error!("Failed to schedule task in Redis: {}", e);
+                e
+            })?;
+
+        info!("Task scheduled in {:?}: {}", delay, task_global_id);
+        Ok(())
+    }
+
+    // Every task goes into its stream's delayed set in one atomic pipeline
+    async fn send_tasks_delayed(&self, tasks: &[(Task, Duration)]) -> Result<(), QueueError> {
+        if tasks.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.in_flight.start();
+
+        let now = Utc::now().timestamp_millis();
+        let messages = tasks
+            .iter()
+            .map(|(task, delay)| {
+                let due = now + delay.as_millis().min(i64::MAX as u128) as i64;
+                serde_json::to_string(&TaskMessage::new(task)).map(|message| (task, message, due))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                error!("Failed to serialize task message: {}", e);
+                e
+            })?;
+        let count = messages.len();
+
+        let mut conn = self.connection().await.map_err(|e| {
+            error!("Failed to get Redis connection: {}", e);
+            e
+        })?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (task, message, due) in messages {
+            pipe.zadd(delayed_set(&self.stream_for(task)), message, due)
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+            error!("Failed to schedule tasks in Redis: {}", e);
+            e
+        })?;
+
+        info!("{} tasks scheduled in Redis", count);
+        Ok(())
+    }
+
+    async fn queued_task_ids(&self) -> Result<Option<HashSet<TaskGlobalId>>, QueueError> {
+        let mut conn = self.connection().await?;
+        let mut messages = Vec::new();
+        for stream in self.streams() {
+            let entries: StreamRangeReply = conn.xrange_all(&stream).await?;
+            messages.extend(
+                entries
+                    .ids
+                    .iter()
+                    .filter_map(|entry| entry.get::<String>(MESSAGE_FIELD)),
+            );
+            let delayed: Vec<String> = conn.zrange(delayed_set(&stream), 0, -1).await?;
+            messages.extend(delayed);
         }
+
+        Ok(Some(
+            messages
+                .iter()
+                .filter_map(|message| serde_json::from_str::<TaskMessage>(message).ok())
+                .map(|task_message| task_message.task_global_id)
+                .collect(),
+        ))
     }
+
+    async fn depth(&self) -> Result<Vec<QueueDepth>, QueueError> {
+        let mut conn = self.connection().await?;
+        let mut depths = Vec::new();
+        for stream in self.streams() {
+            let (length, delayed, dead_lettered): (u64, u64, u64) = redis::pipe()
+                .xlen(&stream)
+                .zcard(delayed_set(&stream))
+                .xlen(format!("{}:{}", stream, DEAD_SUFFIX))
+                .query_async(&mut conn)
+                .await?;
+            // No group yet means no worker has read the stream
+            let pending = match conn
+                .xpending::<_, _, StreamPendingReply>(&stream, &self.consumer_group)
+                .await
+            {
+                Ok(reply) => reply.count() as u64,
+                Err(e) if e.code() == Some("NOGROUP") => 0,
+                Err(e) => return Err(e.into()),
+            };
+            // Entry ids start with the time they were added in millis
+            let oldest: StreamRangeReply = conn.xrange_count(&stream, "-", "+", 1).await?;
+            let oldest_age = oldest
+                .ids
+                .first()
+                .and_then(|entry| entry.id.split('-').next()?.parse::<i64>().ok())
+                .map_or(Duration::ZERO, |added| {
+                    Duration::from_millis((Utc::now().timestamp_millis() - added).max(0) as u64)
+                });
+
+            // Workers delete what they ack, so the stream only holds waiting and pending entries
+            depths.push(QueueDepth {
+                queue: stream,
+                waiting: length.saturating_sub(pending),
+                pending,
+                delayed,
+                dead_lettered,
+                oldest_age: Some(oldest_age),
+            });
+        }
+        Ok(depths)
+    }
+
+    async fn flush(&self, timeout: Duration) -> usize {
+        self.in_flight.wait(timeout).await
+    }
+}
+
+fn delayed_set(stream: &str) -> String {
+    format!("{}:{}", stream, DELAYED_SUFFIX)
 }
@@ -0,0 +1,294 @@
+use crate::config::SqsConfig;
+use crate::model::task::{Task, TaskGlobalId};
+use crate::queue::{InFlight, QueueDepth, QueueError, TaskMessage, TaskQueue};
+use async_trait::async_trait;
+use aws_config::{timeout::TimeoutConfig, BehaviorVersion, Region};
+use aws_sdk_sqs::{
+    types::{QueueAttributeName, SendMessageBatchRequestEntry},
+    Client,
+};
+use chrono::Utc;
+use log::{error, info};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+// SQS takes at most 10 messages per batch call
+const SEND_BATCH: usize = 10;
+
+// The longest SQS will hold a message back for. Anything delayed for longer is sent with this
+// delay and a due_at, and the worker that receives it early sends it on again until it's due.
+const MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+
+// Queues tasks on a single SQS queue. Routing by task type or priority isn't supported, every task
+// goes to queue_url. Retries are left to SQS: a message the worker doesn't delete comes back once
+// its visibility timeout runs out, and after max_receive_count tries it goes to the dead letter
+// queue.
+#[derive(Clone)]
+pub struct SqsQueue {
+    client: Client,
+    queue_url: String,
+    // Only used to report its size
+    dead_letter_queue_url: Option<String>,
+    in_flight: InFlight,
+}
+
+impl SqsQueue {
+    pub async fn init(config: &SqsConfig) -> Result<Self, QueueError> {
+        let timeouts = TimeoutConfig::builder()
+            .operation_timeout(Duration::from_secs(config.operation_timeout_secs))
+            .build();
+        let mut loader = aws_config::defaults(BehaviorVersion::latest()).timeout_config(timeouts);
+        if let Some(region) = &config.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(endpoint_url) = &config.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+
+        let mut queue = Self {
+            client: Client::new(&loader.load().await),
+            queue_url: config.queue_url.clone(),
+            dead_letter_queue_url: None,
+            in_flight: InFlight::default(),
+        };
+        queue.configure(config).await?;
+        if let Some(dead_letter_queue_arn) = &config.dead_letter_queue_arn {
+            queue.dead_letter_queue_url = queue.queue_url_of(dead_letter_queue_arn).await;
+        }
+        info!("Using SQS queue {}", queue.queue_url);
+        Ok(queue)
+    }
+
+    // Applies the visibility timeout and redrive policy to the queue, so they hold even for
+    // receives that don't set them
+    async fn configure(&self, config: &SqsConfig) -> Result<(), QueueError> {
+        let mut request = self
+            .client
+            .set_queue_attributes()
+            .queue_url(&self.queue_url)
+            .attributes(
+                QueueAttributeName::VisibilityTimeout,
+                config.visibility_timeout_secs.to_string(),
+            );
+        if let Some(dead_letter_queue_arn) = &config.dead_letter_queue_arn {
+            let redrive = json!({
+                "deadLetterTargetArn": dead_letter_queue_arn,
+                "maxReceiveCount": config.max_receive_count.max(1),
+            });
+            request = request.attributes(QueueAttributeName::RedrivePolicy, redrive.to_string());
+        }
+        request.send().await.map_err(|e| {
+            error!("Failed to configure SQS queue {}: {}", self.queue_url, e);
+            QueueError::backend(e)
+        })?;
+        Ok(())
+    }
+
+    // The URL of the queue with this ARN (arn:aws:sqs:region:account:name)
+    async fn queue_url_of(&self, arn: &str) -> Option<String> {
+        let mut parts = arn.rsplit(':');
+        let (name, account) = (parts.next()?, parts.next()?);
+        match self
+            .client
+            .get_queue_url()
+            .queue_name(name)
+            .queue_owner_aws_account_id(account)
+            .send()
+            .await
+        {
+            Ok(output) => output.queue_url().map(str::to_string),
+            Err(e) => {
+                error!("Failed to look up SQS queue {}: {}", arn, e);
+                None
+            }
+        }
+    }
+
+    async fn attributes(
+        &self,
+        queue_url: &str,
+        names: &[QueueAttributeName],
+    ) -> Result<HashMap<QueueAttributeName, u64>, QueueError> {
+        let output = self
+            .client
+            .get_queue_attributes()
+            .queue_url(queue_url)
+            .set_attribute_names(Some(names.to_vec()))
+            .send()
+            .await
+            .map_err(QueueError::backend)?;
+        Ok(output
+            .attributes()
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, value)| Some((name.clone(), value.parse().ok()?)))
+            .collect())
+    }
+
+    async fn send(&self, message: &TaskMessage, delay: Duration) -> Result<(), QueueError> {
+        let body = serde_json::to_string(message).map_err(|e| {
+            error!("Failed to serialize task message: {}", e);
+            e
+        })?;
+        self.client
+            .send_message()
+            .queue_url(&self.queue_url)
+            .message_body(body)
+            .delay_seconds(delay.min(MAX_DELAY).as_secs() as i32)
+            .send()
+            .await
+            .map_err(|e| {
+                error!("Failed to send task to SQS queue: {}", e);
+                QueueError::backend(e)
+            })?;
+        Ok(())
+    }
+
+    // Ten to a call. Unlike Redis a batch isn't all or nothing, if any message fails the
+    // others stay queued and the whole send reports an error.
+    async fn send_batch(&self, messages: &[(TaskMessage, Duration)]) -> Result<(), QueueError> {
+        for chunk in messages.chunks(SEND_BATCH) {
+            let entries = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, (message, delay))| {
+                    let body = serde_json::to_string(message)?;
+                    SendMessageBatchRequestEntry::builder()
+                        .id(i.to_string())
+                        .message_body(body)
+                        .delay_seconds(delay.min(&MAX_DELAY).as_secs() as i32)
+                        .build()
+                        .map_err(QueueError::backend)
+                })
+                .collect::<Result<Vec<_>, QueueError>>()?;
+
+            let output = self
+                .client
+                .send_message_batch()
+                .queue_url(&self.queue_url)
+                .set_entries(Some(entries))
+                .send()
+                .await
+                .map_err(|e| {
+                    error!("Failed to send tasks to SQS queue: {}", e);
+                    QueueError::backend(e)
+                })?;
+            if let Some(failed) = output.failed().first() {
+                error!(
+                    "{} of {} tasks weren't accepted by SQS, first: {}",
+                    output.failed().len(),
+                    chunk.len(),
+                    failed.code()
+                );
+                return Err(QueueError::backend(std::io::Error::other(format!(
+                    "SQS rejected a message: {}",
+                    failed.message().unwrap_or(failed.code())
+                ))));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Delays past what SQS can hold a message for are carried on as a due_at
+fn delayed_message(task: &Task, delay: Duration) -> TaskMessage {
+    let mut message = TaskMessage::new(task);
+    if delay > MAX_DELAY {
+        let delay_ms = delay.as_millis().min(i64::MAX as u128) as i64;
+        message.due_at = Some(Utc::now().timestamp_millis().saturating_add(delay_ms));
+    }
+    message
+}
+
+#[async_trait]
+impl TaskQueue for SqsQueue {
+    async fn send_task(&self, task: &Task) -> Result<(), QueueError> {
+        let _guard = self.in_flight.start();
+
+        self.send(&TaskMessage::new(task), Duration::ZERO).await?;
+        info!("Task sent to SQS queue: {}", task.get_global_id());
+        Ok(())
+    }
+
+    async fn send_tasks(&self, tasks: &[Task]) -> Result<(), QueueError> {
+        let _guard = self.in_flight.start();
+
+        let messages = tasks
+            .iter()
+            .map(|task| (TaskMessage::new(task), Duration::ZERO))
+            .collect::<Vec<_>>();
+        self.send_batch(&messages).await?;
+        if !tasks.is_empty() {
+            info!("{} tasks sent to SQS queue", tasks.len());
+        }
+        Ok(())
+    }
+
+    async fn send_task_delayed(&self, task: &Task, delay: Duration) -> Result<(), QueueError> {
+        let _guard = self.in_flight.start();
+
+        let message = delayed_message(task, delay);
+        self.send(&message, delay).await?;
+
+        info!("Task scheduled in {:?}: {}", delay, message.task_global_id);
+        Ok(())
+    }
+
+    async fn send_tasks_delayed(&self, tasks: &[(Task, Duration)]) -> Result<(), QueueError> {
+        let _guard = self.in_flight.start();
+
+        let messages = tasks
+            .iter()
+            .map(|(task, delay)| (delayed_message(task, *delay), *delay))
+            .collect::<Vec<_>>();
+        self.send_batch(&messages).await?;
+        if !tasks.is_empty() {
+            info!("{} tasks scheduled in SQS queue", tasks.len());
+        }
+        Ok(())
+    }
+
+    // SQS has no way to look through the messages it holds
+    async fn queued_task_ids(&self) -> Result<Option<HashSet<TaskGlobalId>>, QueueError> {
+        Ok(None)
+    }
+
+    // SQS only gives approximate counts, and the age of the oldest message only through
+    // CloudWatch
+    async fn depth(&self) -> Result<Vec<QueueDepth>, QueueError> {
+        let counts = self
+            .attributes(
+                &self.queue_url,
+                &[
+                    QueueAttributeName::ApproximateNumberOfMessages,
+                    QueueAttributeName::ApproximateNumberOfMessagesNotVisible,
+                    QueueAttributeName::ApproximateNumberOfMessagesDelayed,
+                ],
+            )
+            .await?;
+        let dead_lettered = match &self.dead_letter_queue_url {
+            Some(url) => self
+                .attributes(url, &[QueueAttributeName::ApproximateNumberOfMessages])
+                .await?
+                .get(&QueueAttributeName::ApproximateNumberOfMessages)
+                .copied()
+                .unwrap_or(0),
+            None => 0,
+        };
+        let count = |name| counts.get(&name).copied().unwrap_or(0);
+
+        Ok(vec![QueueDepth {
+            queue: self.queue_url.clone(),
+            waiting: count(QueueAttributeName::ApproximateNumberOfMessages),
+            pending: count(QueueAttributeName::ApproximateNumberOfMessagesNotVisible),
+            delayed: count(QueueAttributeName::ApproximateNumberOfMessagesDelayed),
+            dead_lettered,
+            oldest_age: None,
+        }])
+    }
+
+    async fn flush(&self, timeout: Duration) -> usize {
+        self.in_flight.wait(timeout).await
+    }
+}
@@ -0,0 +1,285 @@
+use crate::config::{CacheConfig, RedisConfig};
+use crate::model::history::TaskHistoryEntry;
+use crate::model::task::{Task, TaskEvent, TaskProgress, TaskState};
+use crate::model::worker::WorkerStatus;
+use crate::repository::{
+    Page, PageOf, RepositoryError, TaskFilter, TaskRepository, TransitionFields,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, Client, RedisError};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+// A slow cache mustn't make lookups slower than going straight to the database
+const REDIS_TIMEOUT: Duration = Duration::from_millis(250);
+
+// Wraps another repository, serving get_task from Redis when it can. Every write that goes
+// through here drops the cached copy of the tasks it touched. A lookup racing a write can still
+// put the old copy back, which then lives until the TTL runs out. Redis failing only means
+// falling back to the wrapped repository.
+pub struct CachedRepository {
+    inner: Arc<dyn TaskRepository>,
+    conn: ConnectionManager,
+    ttl_secs: u64,
+    key_prefix: String,
+}
+
+impl CachedRepository {
+    pub async fn init(
+        inner: Arc<dyn TaskRepository>,
+        redis: &RedisConfig,
+        config: &CacheConfig,
+    ) -> Result<Self, RedisError> {
+        let client = Client::open(redis.uri.as_str())?;
+        let conn = ConnectionManager::new(client).await?;
+
+        info!("Caching tasks in Redis for {}s", config.ttl_secs);
+
+        Ok(Self {
+            inner,
+            conn,
+            ttl_secs: config.ttl_secs.max(1),
+            key_prefix: config.key_prefix.clone(),
+        })
+    }
+
+    fn key(&self, task_id: &str) -> String {
+        format!("{}{}", self.key_prefix, task_id)
+    }
+
+    async fn redis<T>(
+        &self,
+        command: impl Future<Output = Result<T, RedisError>>,
+    ) -> Result<T, RedisError> {
+        tokio::time::timeout(REDIS_TIMEOUT, command)
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()))
+    }
+
+    async fn cached(&self, task_id: &str) -> Option<Task> {
+        let mut conn = self.conn.clone();
+        match self
+            .redis(conn.get::<_, Option<String>>(self.key(task_id)))
+            .await
+        {
+            Ok(cached) => cached.and_then(|json| serde_json::from_str(&json).ok()),
+            Err(e) => {
+                warn!("Failed to read task {} from cache: {}", task_id, e);
+                None
+            }
+        }
+    }
+
+    async fn store(&self, task: &Task) {
+        let Ok(json) = serde_json::to_string(task) else {
+            return;
+        };
+        let task_id = task.get_global_id();
+        let mut conn = self.conn.clone();
+        if let Err(e) = self
+            .redis(conn.set_ex::<_, _, ()>(
+                self.key(task_id.as_str()),
+                json,
+                self.ttl_secs as usize,
+            ))
+            .await
+        {
+            warn!("Failed to cache task {}: {}", task_id, e);
+        }
+    }
+
+    async fn invalidate(&self, task_ids: impl IntoIterator<Item = impl AsRef<str>>) {
+        let keys: Vec<String> = task_ids
+            .into_iter()
+            .map(|id| self.key(id.as_ref()))
+            .collect();
+        if keys.is_empty() {
+            return;
+        }
+        let mut conn = self.conn.clone();
+        if let Err(e) = self.redis(conn.del::<_, ()>(&keys)).await {
+            warn!("Failed to invalidate {} cached tasks: {}", keys.len(), e);
+        }
+    }
+}
+
+#[async_trait]
+impl TaskRepository for CachedRepository {
+    async fn put_task(&self, task: Task) -> Result<(), RepositoryError> {
+        let task_id = task.get_global_id();
+        let result = self.inner.put_task(task).await;
+        // Even a failed write may have landed, so always drop the cached copy
+        self.invalidate([task_id]).await;
+        result
+    }
+
+    async fn put_tasks(&self, tasks: Vec<Task>) -> Result<Vec<bool>, RepositoryError> {
+        let task_ids: Vec<_> = tasks.iter().map(Task::get_global_id).collect();
+        let result = self.inner.put_tasks(tasks).await;
+        self.invalidate(task_ids).await;
+        result
+    }
+
+    // New tasks have nothing cached to invalidate
+    async fn create_tasks(
+        &self,
+        tasks: Vec<Task>,
+        history: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        self.inner.create_tasks(tasks, history).await
+    }
+
+    async fn get_task(&self, task_id: String) -> Result<Option<Task>, RepositoryError> {
+        if let Some(task) = self.cached(&task_id).await {
+            return Ok(Some(task));
+        }
+
+        let Some(task) = self.inner.get_task(task_id).await? else {
+            return Ok(None);
+        };
+        self.store(&task).await;
+        Ok(Some(task))
+    }
+
+    async fn get_tasks(&self, task_ids: &[String]) -> Result<Vec<Task>, RepositoryError> {
+        self.inner.get_tasks(task_ids).await
+    }
+
+    async fn transition_task(
+        &self,
+        task_id: &str,
+        from_states: &[TaskState],
+        new_state: TaskState,
+        fields: TransitionFields,
+    ) -> Result<Option<Task>, RepositoryError> {
+        let result = self
+            .inner
+            .transition_task(task_id, from_states, new_state, fields)
+            .await;
+        self.invalidate([task_id.to_string()]).await;
+        result
+    }
+
+    async fn transition_many(
+        &self,
+        tasks: &[Task],
+        new_state: TaskState,
+        event: Option<TaskEvent>,
+    ) -> Result<Vec<bool>, RepositoryError> {
+        let result = self.inner.transition_many(tasks, new_state, event).await;
+        self.invalidate(tasks.iter().map(Task::get_global_id)).await;
+        result
+    }
+
+    // Heartbeats come too often to evict the cached copy for each, it shows the new time once it
+    // expires
+    async fn record_heartbeat(
+        &self,
+        task_id: &str,
+        at: DateTime<Utc>,
+        progress: Option<TaskProgress>,
+        checkpoint: Option<String>,
+    ) -> Result<bool, RepositoryError> {
+        // A stale checkpoint would send a redelivered task back further than it needs to go
+        let invalidate = checkpoint.is_some();
+        let recorded = self
+            .inner
+            .record_heartbeat(task_id, at, progress, checkpoint)
+            .await?;
+        if invalidate {
+            self.invalidate([task_id.to_string()]).await;
+        }
+        Ok(recorded)
+    }
+
+    async fn find_stale_tasks(
+        &self,
+        state: TaskState,
+        updated_before: DateTime<Utc>,
+    ) -> Result<Vec<Task>, RepositoryError> {
+        self.inner.find_stale_tasks(state, updated_before).await
+    }
+
+    async fn list_tasks(
+        &self,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<Task>, RepositoryError> {
+        self.inner.list_tasks(filter, page).await
+    }
+
+    async fn search_tasks(
+        &self,
+        query: &str,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<(Task, f64)>, RepositoryError> {
+        self.inner.search_tasks(query, filter, page).await
+    }
+
+    async fn count_by_state(&self) -> Result<HashMap<TaskState, u64>, RepositoryError> {
+        self.inner.count_by_state().await
+    }
+
+    async fn throughput_since(&self, since: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.inner.throughput_since(since).await
+    }
+
+    async fn avg_completion_time(
+        &self,
+        task_type: Option<&str>,
+    ) -> Result<Option<f64>, RepositoryError> {
+        self.inner.avg_completion_time(task_type).await
+    }
+
+    async fn delete_task(&self, task_id: &str) -> Result<bool, RepositoryError> {
+        let result = self.inner.delete_task(task_id).await;
+        self.invalidate([task_id.to_string()]).await;
+        result
+    }
+
+    // Purged tasks aren't invalidated, their cached copies just run out
+    async fn purge_archived(&self, archived_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.inner.purge_archived(archived_before).await
+    }
+
+    async fn purge_deleted(&self, deleted_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.inner.purge_deleted(deleted_before).await
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.inner.purge_expired(now).await
+    }
+
+    async fn record_history(&self, entry: TaskHistoryEntry) -> Result<(), RepositoryError> {
+        self.inner.record_history(entry).await
+    }
+
+    async fn record_histories(
+        &self,
+        entries: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        self.inner.record_histories(entries).await
+    }
+
+    async fn get_history(&self, task_id: &str) -> Result<Vec<TaskHistoryEntry>, RepositoryError> {
+        self.inner.get_history(task_id).await
+    }
+
+    async fn save_worker(&self, worker: WorkerStatus) -> Result<(), RepositoryError> {
+        self.inner.save_worker(worker).await
+    }
+
+    async fn get_worker(&self, worker_id: &str) -> Result<Option<WorkerStatus>, RepositoryError> {
+        self.inner.get_worker(worker_id).await
+    }
+
+    async fn list_workers(&self) -> Result<Vec<WorkerStatus>, RepositoryError> {
+        self.inner.list_workers().await
+    }
+}
@@ -0,0 +1,2071 @@
+use crate::config::DynamoConfig;
+use crate::events::{TaskEventKind, TaskEvents};
+use crate::model::history::TaskHistoryEntry;
+use crate::model::task::{Task, TaskEvent, TaskProgress, TaskState};
+use crate::model::worker::WorkerStatus;
+use crate::repository::{
+    completion_time, decode_cursor, encode_cursor, mean_secs, Page, PageOf, RepositoryError,
+    TaskFilter, TaskRepository, TransitionFields,
+};
+use async_trait::async_trait;
+use aws_config::{timeout::TimeoutConfig, BehaviorVersion, Region};
+use aws_sdk_dynamodb::{
+    client::Waiters,
+    error::SdkError,
+    operation::{transact_write_items::TransactWriteItemsError, update_item::UpdateItemError},
+    types::{
+        AttributeDefinition, AttributeValue, BillingMode, CreateGlobalSecondaryIndexAction,
+        DeleteRequest, GlobalSecondaryIndex, GlobalSecondaryIndexUpdate, IndexStatus,
+        KeySchemaElement, KeyType, KeysAndAttributes, Projection, ProjectionType, Put, PutRequest,
+        ReturnValue, ScalarAttributeType, StreamSpecification, StreamViewType, TableDescription,
+        TimeToLiveSpecification, TimeToLiveStatus, TransactWriteItem, WriteRequest,
+    },
+    Client,
+};
+use aws_sdk_dynamodbstreams::types::{
+    AttributeValue as StreamValue, OperationType, Record, ShardIteratorType,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+type Item = HashMap<String, AttributeValue>;
+
+// Single table design. A task is the item at (pk = task_global_id, sk = "TASK") and its history
+// entries share its partition under sk = "HISTORY#<timestamp>#<id>", so they read back oldest
+// first and a task can be purged along with its history in one go.
+const TASK_SK: &str = "TASK";
+const HISTORY_PREFIX: &str = "HISTORY#";
+
+// Workers share a partition of their own under sk = worker_id, which no global id can collide
+// with, so they're listed with a single query
+const WORKERS_PK: &str = "#WORKERS";
+
+// Indexes for newest-first listing, all sorted on created_at. Every task item carries
+// entity = "TASK" so the second one can list all tasks.
+const USER_INDEX: &str = "user_created";
+const ALL_INDEX: &str = "entity_created";
+const STATE_INDEX: &str = "state_created";
+const ENTITY: &str = "TASK";
+
+// Table TTL deletes a task item some time after the epoch seconds in this attribute. It's set a
+// grace period past expires_at so the retention purge normally gets there first and takes the
+// task's history with it, TTL only catches what the purge doesn't.
+const TTL_ATTRIBUTE: &str = "ttl";
+const TTL_GRACE_DAYS: i64 = 1;
+
+// How long the change feed waits between reads of the stream when there was nothing new, and
+// how often it looks for shards that have split off since
+const STREAM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SHARD_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+// Per-request limits set by DynamoDB
+const MAX_TRANSACT_ITEMS: usize = 100;
+const MAX_BATCH_WRITE: usize = 25;
+const MAX_BATCH_GET: usize = 100;
+
+// DynamoDB has no conditional batch write, so batches go out as this many writes at a time
+const CONCURRENT_WRITES: usize = 16;
+
+// Batch writes hand back whatever was throttled, which is resent this many times with backoff
+const MAX_UNPROCESSED_RETRIES: u32 = 5;
+
+fn backend(error: impl Error + Send + Sync + 'static) -> RepositoryError {
+    RepositoryError::Backend(Box::new(error))
+}
+
+fn is_conditional_check_failed<R>(error: &SdkError<UpdateItemError, R>) -> bool {
+    error
+        .as_service_error()
+        .is_some_and(UpdateItemError::is_conditional_check_failed_exception)
+}
+
+// Whether a transaction was cancelled because one of its conditions didn't hold
+fn is_transaction_conflict<R>(error: &SdkError<TransactWriteItemsError, R>) -> bool {
+    match error.as_service_error() {
+        Some(TransactWriteItemsError::TransactionCanceledException(e)) => e
+            .cancellation_reasons()
+            .iter()
+            .any(|reason| reason.code() == Some("ConditionalCheckFailed")),
+        _ => false,
+    }
+}
+
+fn string(value: impl Into<String>) -> AttributeValue {
+    AttributeValue::S(value.into())
+}
+
+// Fixed width, so comparing the stored strings compares the times
+fn timestamp_string(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn timestamp(time: DateTime<Utc>) -> AttributeValue {
+    string(timestamp_string(time))
+}
+
+fn optional(value: Option<AttributeValue>) -> AttributeValue {
+    value.unwrap_or(AttributeValue::Null(true))
+}
+
+// The TTL attribute for a task expiring at expires_at. TTL skips items where it's null.
+fn ttl(expires_at: Option<DateTime<Utc>>) -> AttributeValue {
+    optional(expires_at.map(|at| {
+        AttributeValue::N(
+            (at + chrono::Duration::days(TTL_GRACE_DAYS))
+                .timestamp()
+                .to_string(),
+        )
+    }))
+}
+
+fn task_key(task_id: &str) -> Item {
+    HashMap::from([
+        ("pk".to_string(), string(task_id)),
+        ("sk".to_string(), string(TASK_SK)),
+    ])
+}
+
+// Lowercased text that search matches against, there's no text index to lean on
+fn search_text(task: &Task) -> String {
+    let mut text = vec![task.task_type.as_str(), task.source_file.as_str()];
+    text.extend(task.result_file.as_deref());
+    text.extend(task.tags.iter().map(String::as_str));
+    text.join(" ").to_lowercase()
+}
+
+// The stored form of a task at the given version, apart from created_at which only the first
+// write sets
+fn task_item(task: &Task, version: u64, now: DateTime<Utc>) -> Result<Item, RepositoryError> {
+    let mut item: Item = serde_dynamo::to_item(task).map_err(backend)?;
+    // Serde writes however many fractional digits a timestamp needs
+    for (name, time) in [
+        ("archived_at", task.archived_at),
+        ("expires_at", task.expires_at),
+        ("deleted_at", task.deleted_at),
+        ("last_heartbeat_at", task.last_heartbeat_at),
+    ] {
+        if let Some(time) = time {
+            item.insert(name.to_string(), timestamp(time));
+        }
+    }
+    item.extend(task_key(&task.get_global_id()));
+    item.insert("entity".to_string(), string(ENTITY));
+    item.insert(
+        "version".to_string(),
+        AttributeValue::N(version.to_string()),
+    );
+    item.remove("created_at");
+    item.insert("updated_at".to_string(), timestamp(now));
+    item.insert("search_text".to_string(), string(search_text(task)));
+    item.insert(TTL_ATTRIBUTE.to_string(), ttl(task.expires_at));
+    Ok(item)
+}
+
+fn task_from_item(item: Item) -> Result<Task, RepositoryError> {
+    serde_dynamo::from_item(item).map_err(backend)
+}
+
+fn history_item(entry: &TaskHistoryEntry) -> Result<Item, RepositoryError> {
+    let mut item: Item = serde_dynamo::to_item(entry).map_err(backend)?;
+    let time = timestamp_string(entry.timestamp);
+    // The id keeps entries recorded in the same microsecond apart
+    item.insert("pk".to_string(), string(&entry.task_global_id));
+    item.insert(
+        "sk".to_string(),
+        string(format!("{HISTORY_PREFIX}{time}#{}", Uuid::new_v4())),
+    );
+    item.insert("timestamp".to_string(), string(time));
+    Ok(item)
+}
+
+fn worker_key(worker_id: &str) -> Item {
+    HashMap::from([
+        ("pk".to_string(), string(WORKERS_PK)),
+        ("sk".to_string(), string(worker_id)),
+    ])
+}
+
+fn attribute_definition(name: &str) -> Result<AttributeDefinition, RepositoryError> {
+    AttributeDefinition::builder()
+        .attribute_name(name)
+        .attribute_type(ScalarAttributeType::S)
+        .build()
+        .map_err(backend)
+}
+
+fn key_schema_element(name: &str, key_type: KeyType) -> Result<KeySchemaElement, RepositoryError> {
+    KeySchemaElement::builder()
+        .attribute_name(name)
+        .key_type(key_type)
+        .build()
+        .map_err(backend)
+}
+
+fn all_attributes() -> Projection {
+    Projection::builder()
+        .projection_type(ProjectionType::All)
+        .build()
+}
+
+fn attribute<'a>(item: &'a Item, name: &str) -> Option<&'a str> {
+    item.get(name)
+        .and_then(|value| value.as_s().ok())
+        .map(String::as_str)
+}
+
+// The placeholders of an expression. DynamoDB wants attribute names and values passed alongside
+// it rather than inline, and names have to be for anything that's a reserved word (like state).
+#[derive(Default)]
+struct Placeholders {
+    names: HashMap<String, String>,
+    values: Item,
+}
+
+impl Placeholders {
+    fn name(&mut self, name: &str) -> String {
+        let placeholder = format!("#{}", name);
+        self.names.insert(placeholder.clone(), name.to_string());
+        placeholder
+    }
+
+    fn value(&mut self, value: AttributeValue) -> String {
+        let placeholder = format!(":v{}", self.values.len());
+        self.values.insert(placeholder.clone(), value);
+        placeholder
+    }
+
+    // Empty maps are rejected, so they're left off instead
+    fn names(&self) -> Option<HashMap<String, String>> {
+        (!self.names.is_empty()).then(|| self.names.clone())
+    }
+
+    fn values(&self) -> Option<Item> {
+        (!self.values.is_empty()).then(|| self.values.clone())
+    }
+}
+
+// The parts of a TaskFilter the index key doesn't cover, as filter expression conditions
+// Matches items where the attribute is missing or null
+fn unset(attribute: &str, placeholders: &mut Placeholders) -> String {
+    let name = placeholders.name(attribute);
+    format!(
+        "(attribute_not_exists({name}) OR attribute_type({name}, {}))",
+        placeholders.value(string("NULL"))
+    )
+}
+
+fn filter_conditions(filter: &TaskFilter, placeholders: &mut Placeholders) -> Vec<String> {
+    let mut conditions = Vec::new();
+    if let Some(state) = filter.state {
+        let name = placeholders.name("state");
+        conditions.push(format!(
+            "{} = {}",
+            name,
+            placeholders.value(string(state.to_string()))
+        ));
+    }
+    if let Some(task_type) = &filter.task_type {
+        let name = placeholders.name("task_type");
+        conditions.push(format!(
+            "{} = {}",
+            name,
+            placeholders.value(string(task_type))
+        ));
+    }
+    if let Some(tag) = &filter.tag {
+        let name = placeholders.name("tags");
+        conditions.push(format!(
+            "contains({}, {})",
+            name,
+            placeholders.value(string(tag))
+        ));
+    }
+    if let Some(after) = filter.updated_after {
+        let name = placeholders.name("updated_at");
+        conditions.push(format!(
+            "{} >= {}",
+            name,
+            placeholders.value(timestamp(after))
+        ));
+    }
+    if let Some(before) = filter.updated_before {
+        let name = placeholders.name("updated_at");
+        conditions.push(format!(
+            "{} < {}",
+            name,
+            placeholders.value(timestamp(before))
+        ));
+    }
+    if !filter.include_archived {
+        conditions.push(unset("archived_at", placeholders));
+    }
+    conditions.push(unset("deleted_at", placeholders));
+    conditions
+}
+
+// Where the change feed has got to in one shard of the table's stream
+struct ShardReader {
+    // Where to start reading the shard before anything's been read from it
+    start: ShardIteratorType,
+    // For the next read, None when one has to be fetched again
+    iterator: Option<String>,
+    // The last record read, to carry on after when the iterator is fetched again
+    last_sequence: Option<String>,
+    // The shard was closed and has been read to its end
+    finished: bool,
+}
+
+impl ShardReader {
+    fn new(start: ShardIteratorType) -> Self {
+        Self {
+            start,
+            iterator: None,
+            last_sequence: None,
+            finished: false,
+        }
+    }
+}
+
+// The task event for a record of the table's stream, None for anything that isn't a task being
+// written (a removal, or a history entry or worker sharing the table)
+fn stream_event(record: &Record) -> Option<Result<crate::events::TaskEvent, RepositoryError>> {
+    let kind = match record.event_name()? {
+        OperationType::Insert => TaskEventKind::Created,
+        OperationType::Modify => TaskEventKind::Updated,
+        _ => return None,
+    };
+    let image = record.dynamodb()?.new_image()?;
+    if !matches!(image.get("sk"), Some(StreamValue::S(sk)) if sk == TASK_SK) {
+        return None;
+    }
+    Some(
+        serde_dynamo::from_item(image.clone())
+            .map(|task| crate::events::TaskEvent { kind, task })
+            .map_err(backend),
+    )
+}
+
+// Tasks (and their history) in one DynamoDB table, see the constants above for the layout.
+// Listings read from secondary indexes, so a task written a moment ago may not show up yet.
+// Live events come from the table's stream, see spawn_change_feed.
+#[derive(Clone)]
+pub struct DynamoRepository {
+    client: Client,
+    streams: aws_sdk_dynamodbstreams::Client,
+    table: String,
+    // Whether the state index is there to query, it's built in the background on tables that
+    // were created before it
+    state_index: bool,
+}
+
+impl DynamoRepository {
+    pub async fn init(config: &DynamoConfig) -> Result<Self, RepositoryError> {
+        let timeouts = TimeoutConfig::builder()
+            .operation_timeout(Duration::from_secs(config.operation_timeout_secs))
+            .build();
+        let mut loader = aws_config::defaults(BehaviorVersion::latest()).timeout_config(timeouts);
+        if let Some(region) = &config.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(endpoint_url) = &config.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+
+        let sdk_config = loader.load().await;
+        let mut repo = Self {
+            client: Client::new(&sdk_config),
+            streams: aws_sdk_dynamodbstreams::Client::new(&sdk_config),
+            table: config.table.clone(),
+            state_index: false,
+        };
+        repo.state_index = repo.ensure_table().await?;
+        // Expired tasks are still purged by the retention job without it
+        if let Err(e) = repo.ensure_ttl().await {
+            error!("Failed to enable TTL on {}: {}", repo.table, e);
+        }
+
+        info!("Connected to DynamoDB table {}", repo.table);
+        Ok(repo)
+    }
+
+    // Creates the table with its indexes if it doesn't exist yet, mostly for DynamoDB Local and
+    // fresh environments. Billed on demand, change it in AWS if that doesn't suit. Returns whether
+    // the state index can be queried yet.
+    async fn ensure_table(&self) -> Result<bool, RepositoryError> {
+        match self
+            .client
+            .describe_table()
+            .table_name(&self.table)
+            .send()
+            .await
+        {
+            Ok(output) => return self.ensure_state_index(output.table).await,
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_resource_not_found_exception()) => {}
+            Err(e) => return Err(backend(e)),
+        }
+
+        info!("Creating DynamoDB table {}", self.table);
+        let index = |name: &str, partition: &str| {
+            GlobalSecondaryIndex::builder()
+                .index_name(name)
+                .key_schema(key_schema_element(partition, KeyType::Hash)?)
+                .key_schema(key_schema_element("created_at", KeyType::Range)?)
+                .projection(all_attributes())
+                .build()
+                .map_err(backend)
+        };
+
+        let created = self
+            .client
+            .create_table()
+            .table_name(&self.table)
+            .billing_mode(BillingMode::PayPerRequest)
+            .attribute_definitions(attribute_definition("pk")?)
+            .attribute_definitions(attribute_definition("sk")?)
+            .attribute_definitions(attribute_definition("user_uuid")?)
+            .attribute_definitions(attribute_definition("entity")?)
+            .attribute_definitions(attribute_definition("state")?)
+            .attribute_definitions(attribute_definition("created_at")?)
+            .key_schema(key_schema_element("pk", KeyType::Hash)?)
+            .key_schema(key_schema_element("sk", KeyType::Range)?)
+            .global_secondary_indexes(index(USER_INDEX, "user_uuid")?)
+            .global_secondary_indexes(index(ALL_INDEX, "entity")?)
+            .global_secondary_indexes(index(STATE_INDEX, "state")?)
+            .send()
+            .await;
+        match created {
+            Ok(_) => {}
+            // Another instance starting up at the same time got there first
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_resource_in_use_exception()) => {}
+            Err(e) => return Err(backend(e)),
+        }
+
+        self.client
+            .wait_until_table_exists()
+            .table_name(&self.table)
+            .wait(Duration::from_secs(60))
+            .await
+            .map_err(backend)?;
+        Ok(true)
+    }
+
+    // Follows the table's stream in the background, publishing every task written as a task
+    // event, as the Mongo change stream does. Turns the stream on if it's off. Shards that are
+    // there when it starts are read from their latest record, ones split off later from their
+    // start, so nothing written after startup is missed. Events from a shard and the one split off
+    // it may interleave, each carries the whole task and its version to tell them apart.
+    pub fn spawn_change_feed(&self, events: TaskEvents) {
+        let repo = self.clone();
+
+        tokio::spawn(async move {
+            let stream_arn = loop {
+                match repo.table_stream().await {
+                    Ok(Some(stream_arn)) => break stream_arn,
+                    Ok(None) => {}
+                    Err(e) => warn!("Failed to look up the DynamoDB stream: {}", e),
+                }
+                tokio::time::sleep(SHARD_REFRESH_INTERVAL).await;
+            };
+            info!("Following DynamoDB stream {} for task events", stream_arn);
+
+            let mut shards: HashMap<String, ShardReader> = HashMap::new();
+            let mut refreshed: Option<Instant> = None;
+            loop {
+                if refreshed.is_none_or(|at| at.elapsed() >= SHARD_REFRESH_INTERVAL) {
+                    match repo.list_shards(&stream_arn).await {
+                        Ok(shard_ids) => {
+                            let start = if refreshed.is_none() {
+                                ShardIteratorType::Latest
+                            } else {
+                                ShardIteratorType::TrimHorizon
+                            };
+                            // Closed shards drop out of the listing once their records expire
+                            shards.retain(|shard_id, _| shard_ids.contains(shard_id));
+                            for shard_id in shard_ids {
+                                shards
+                                    .entry(shard_id)
+                                    .or_insert_with(|| ShardReader::new(start.clone()));
+                            }
+                            refreshed = Some(Instant::now());
+                        }
+                        Err(e) => warn!("Failed to list the DynamoDB stream's shards: {}", e),
+                    }
+                }
+
+                let mut published = false;
+                for (shard_id, reader) in shards.iter_mut().filter(|(_, r)| !r.finished) {
+                    match repo.read_shard(&stream_arn, shard_id, reader).await {
+                        Ok(records) => {
+                            for record in records {
+                                match stream_event(&record) {
+                                    Some(Ok(event)) => {
+                                        events.publish(event);
+                                        published = true;
+                                    }
+                                    Some(Err(e)) => {
+                                        warn!("Skipping stream record for bad task: {}", e)
+                                    }
+                                    None => {}
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Failed to read DynamoDB stream shard {}: {}", shard_id, e);
+                            // Fetched again next time round, after the last record read
+                            reader.iterator = None;
+                        }
+                    }
+                }
+                if !published {
+                    tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+                }
+            }
+        });
+    }
+
+    // The ARN of the table's stream, or None while it's still being turned on. Only the new
+    // image of each item is needed.
+    async fn table_stream(&self) -> Result<Option<String>, RepositoryError> {
+        let output = self
+            .client
+            .describe_table()
+            .table_name(&self.table)
+            .send()
+            .await
+            .map_err(backend)?;
+        let table = output
+            .table
+            .ok_or_else(|| RepositoryError::Backend("table without a description".into()))?;
+
+        if let Some(spec) = table
+            .stream_specification()
+            .filter(|spec| spec.stream_enabled())
+        {
+            return match spec.stream_view_type() {
+                Some(StreamViewType::NewImage | StreamViewType::NewAndOldImages) => {
+                    Ok(table.latest_stream_arn().map(str::to_string))
+                }
+                _ => Err(RepositoryError::Backend(
+                    "the table's stream doesn't carry new images".into(),
+                )),
+            };
+        }
+
+        info!("Enabling the stream on DynamoDB table {}", self.table);
+        let specification = StreamSpecification::builder()
+            .stream_enabled(true)
+            .stream_view_type(StreamViewType::NewImage)
+            .build()
+            .map_err(backend)?;
+        self.client
+            .update_table()
+            .table_name(&self.table)
+            .stream_specification(specification)
+            .send()
+            .await
+            .map_err(backend)?;
+        Ok(None)
+    }
+
+    async fn list_shards(&self, stream_arn: &str) -> Result<HashSet<String>, RepositoryError> {
+        let mut shard_ids = HashSet::new();
+        let mut start_shard_id = None;
+        loop {
+            let output = self
+                .streams
+                .describe_stream()
+                .stream_arn(stream_arn)
+                .set_exclusive_start_shard_id(start_shard_id)
+                .send()
+                .await
+                .map_err(backend)?;
+            let Some(description) = output.stream_description else {
+                return Ok(shard_ids);
+            };
+            shard_ids.extend(
+                description
+                    .shards()
+                    .iter()
+                    .filter_map(|shard| shard.shard_id().map(str::to_string)),
+            );
+            start_shard_id = description.last_evaluated_shard_id;
+            if start_shard_id.is_none() {
+                return Ok(shard_ids);
+            }
+        }
+    }
+
+    // The next records in the shard, moving the reader on past them
+    async fn read_shard(
+        &self,
+        stream_arn: &str,
+        shard_id: &str,
+        reader: &mut ShardReader,
+    ) -> Result<Vec<Record>, RepositoryError> {
+        let iterator = match reader.iterator.take() {
+            Some(iterator) => iterator,
+            None => {
+                let request = self
+                    .streams
+                    .get_shard_iterator()
+                    .stream_arn(stream_arn)
+                    .shard_id(shard_id);
+                let request = match &reader.last_sequence {
+                    Some(sequence) => request
+                        .shard_iterator_type(ShardIteratorType::AfterSequenceNumber)
+                        .sequence_number(sequence),
+                    None => request.shard_iterator_type(reader.start.clone()),
+                };
+                let output = request.send().await.map_err(backend)?;
+                match output.shard_iterator {
+                    Some(iterator) => iterator,
+                    None => {
+                        reader.finished = true;
+                        return Ok(Vec::new());
+                    }
+                }
+            }
+        };
+
+        let output = self
+            .streams
+            .get_records()
+            .shard_iterator(iterator)
+            .send()
+            .await
+            .map_err(backend)?;
+        let records = output.records.unwrap_or_default();
+        if let Some(sequence) = records
+            .last()
+            .and_then(|record| record.dynamodb())
+            .and_then(|record| record.sequence_number())
+        {
+            reader.last_sequence = Some(sequence.to_string());
+        }
+        // No next iterator once a closed shard has been read to its end
+        reader.finished = output.next_shard_iterator.is_none();
+        reader.iterator = output.next_shard_iterator;
+        Ok(records)
+    }
+
+    // Turns on table TTL for expired tasks, if it isn't already
+    async fn ensure_ttl(&self) -> Result<(), RepositoryError> {
+        let status = self
+            .client
+            .describe_time_to_live()
+            .table_name(&self.table)
+            .send()
+            .await
+            .map_err(backend)?
+            .time_to_live_description
+            .and_then(|ttl| ttl.time_to_live_status);
+        if matches!(
+            status,
+            Some(TimeToLiveStatus::Enabled | TimeToLiveStatus::Enabling)
+        ) {
+            return Ok(());
+        }
+
+        info!("Enabling TTL on DynamoDB table {}", self.table);
+        let specification = TimeToLiveSpecification::builder()
+            .enabled(true)
+            .attribute_name(TTL_ATTRIBUTE)
+            .build()
+            .map_err(backend)?;
+        self.client
+            .update_time_to_live()
+            .table_name(&self.table)
+            .time_to_live_specification(specification)
+            .send()
+            .await
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    // Adds the state index to a table created before it. DynamoDB builds it in the background,
+    // listings by state read from the entity index meanwhile and pick it up after a restart.
+    // Failing to add it isn't worth refusing to start over.
+    async fn ensure_state_index(
+        &self,
+        table: Option<TableDescription>,
+    ) -> Result<bool, RepositoryError> {
+        let status = table.as_ref().and_then(|table| {
+            table
+                .global_secondary_indexes()
+                .iter()
+                .find(|index| index.index_name() == Some(STATE_INDEX))
+                .map(|index| index.index_status().cloned())
+        });
+        if let Some(status) = status {
+            return Ok(status == Some(IndexStatus::Active));
+        }
+
+        info!(
+            "Adding index {} to DynamoDB table {}",
+            STATE_INDEX, self.table
+        );
+        let index = CreateGlobalSecondaryIndexAction::builder()
+            .index_name(STATE_INDEX)
+            .key_schema(key_schema_element("state", KeyType::Hash)?)
+            .key_schema(key_schema_element("created_at", KeyType::Range)?)
+            .projection(all_attributes())
+            .build()
+            .map_err(backend)?;
+        let result = self
+            .client
+            .update_table()
+            .table_name(&self.table)
+            .attribute_definitions(attribute_definition("state")?)
+            .attribute_definitions(attribute_definition("created_at")?)
+            .global_secondary_index_updates(
+                GlobalSecondaryIndexUpdate::builder().create(index).build(),
+            )
+            .send()
+            .await;
+        if let Err(e) = result {
+            error!("Failed to add index {}: {:?}", STATE_INDEX, e);
+        }
+        Ok(false)
+    }
+
+    // put_task's compare-and-swap, returning whether the task was stored. Every attribute is
+    // SET rather than the item replaced, so that created_at survives updates.
+    async fn store_task(&self, task: &Task) -> Result<bool, RepositoryError> {
+        let now = Utc::now();
+        let expected = task.version;
+        let task_id = task.get_global_id();
+
+        let mut placeholders = Placeholders::default();
+        let mut sets = Vec::new();
+        for (name, value) in task_item(task, expected + 1, now)? {
+            if name != "pk" && name != "sk" {
+                sets.push(format!(
+                    "{} = {}",
+                    placeholders.name(&name),
+                    placeholders.value(value)
+                ));
+            }
+        }
+        let created_at = placeholders.name("created_at");
+        sets.push(format!(
+            "{created_at} = if_not_exists({created_at}, {})",
+            placeholders.value(timestamp(task.created_at.unwrap_or(now)))
+        ));
+
+        // Only a task that's never been stored may be inserted
+        let condition = if expected == 0 {
+            format!("attribute_not_exists({})", placeholders.name("pk"))
+        } else {
+            format!(
+                "{} = {} AND {}",
+                placeholders.name("version"),
+                placeholders.value(AttributeValue::N(expected.to_string())),
+                unset("deleted_at", &mut placeholders)
+            )
+        };
+
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .set_key(Some(task_key(&task_id)))
+            .update_expression(format!("SET {}", sets.join(", ")))
+            .condition_expression(condition)
+            .set_expression_attribute_names(placeholders.names())
+            .set_expression_attribute_values(placeholders.values())
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if is_conditional_check_failed(&e) => Ok(false),
+            Err(e) => Err(backend(e)),
+        }
+    }
+
+    // Moves a task to new_state provided the condition holds, setting the fields and bumping the
+    // counters alongside. Returns the task as it was before, or None if the condition didn't hold.
+    async fn update_state(
+        &self,
+        task_id: &str,
+        condition: impl FnOnce(&mut Placeholders) -> String,
+        new_state: TaskState,
+        fields: Vec<(&str, AttributeValue)>,
+        counters: &[&str],
+        event: Option<&TaskEvent>,
+    ) -> Result<Option<Task>, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let condition = format!(
+            "attribute_exists({}) AND {} AND {}",
+            placeholders.name("pk"),
+            unset("deleted_at", &mut placeholders),
+            condition(&mut placeholders)
+        );
+        let mut update = format!(
+            "SET {} = {}, {} = {}, {version} = {version} + {}",
+            placeholders.name("state"),
+            placeholders.value(string(new_state.to_string())),
+            placeholders.name("updated_at"),
+            placeholders.value(timestamp(Utc::now())),
+            placeholders.value(AttributeValue::N("1".to_string())),
+            version = placeholders.name("version"),
+        );
+        for (name, value) in fields {
+            update.push_str(&format!(
+                ", {} = {}",
+                placeholders.name(name),
+                placeholders.value(value)
+            ));
+        }
+        // Bumped by one, starting from zero on items written before the counter existed
+        for name in counters {
+            update.push_str(&format!(
+                ", {counter} = if_not_exists({counter}, {}) + {}",
+                placeholders.value(AttributeValue::N("0".to_string())),
+                placeholders.value(AttributeValue::N("1".to_string())),
+                counter = placeholders.name(name),
+            ));
+        }
+        if let Some(event) = event {
+            let event = serde_dynamo::to_attribute_value(event).map_err(backend)?;
+            update.push_str(&format!(
+                ", {events} = list_append(if_not_exists({events}, {}), {})",
+                placeholders.value(AttributeValue::L(Vec::new())),
+                placeholders.value(AttributeValue::L(vec![event])),
+                events = placeholders.name("events"),
+            ));
+        }
+
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .set_key(Some(task_key(task_id)))
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(placeholders.names())
+            .set_expression_attribute_values(placeholders.values())
+            .return_values(ReturnValue::AllOld)
+            .send()
+            .await;
+
+        let previous = match result {
+            Ok(output) => output.attributes.map(task_from_item).transpose()?,
+            Err(e) if is_conditional_check_failed(&e) => return Ok(None),
+            Err(e) => return Err(backend(e)),
+        };
+        if event.is_some()
+            && previous
+                .as_ref()
+                .is_some_and(|task| task.events.len() >= Task::MAX_EVENTS)
+        {
+            self.trim_events(task_id).await?;
+        }
+        Ok(previous)
+    }
+
+    // list_append can't cap the list, so the oldest event is dropped after the fact. Every append
+    // adds one, so one removal brings it back to Task::MAX_EVENTS, and the condition stops racing
+    // trims from taking it below that.
+    async fn trim_events(&self, task_id: &str) -> Result<(), RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let events = placeholders.name("events");
+        let condition = format!(
+            "size({events}) > {}",
+            placeholders.value(AttributeValue::N(Task::MAX_EVENTS.to_string()))
+        );
+
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .set_key(Some(task_key(task_id)))
+            .update_expression(format!("REMOVE {events}[0]"))
+            .condition_expression(condition)
+            .set_expression_attribute_names(placeholders.names())
+            .set_expression_attribute_values(placeholders.values())
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) if is_conditional_check_failed(&e) => Ok(()),
+            Err(e) => Err(backend(e)),
+        }
+    }
+
+    // Every task item matching the filter expression. Only the background jobs and the stats use
+    // this, a full scan is fine at their pace.
+    async fn scan_tasks(
+        &self,
+        filter: String,
+        mut placeholders: Placeholders,
+    ) -> Result<Vec<Item>, RepositoryError> {
+        let filter = format!(
+            "{} = {} AND ({})",
+            placeholders.name("sk"),
+            placeholders.value(string(TASK_SK)),
+            filter
+        );
+        self.scan(filter, placeholders).await
+    }
+
+    // Same for history entries
+    async fn scan_history(
+        &self,
+        filter: String,
+        mut placeholders: Placeholders,
+    ) -> Result<Vec<TaskHistoryEntry>, RepositoryError> {
+        let filter = format!(
+            "begins_with({}, {}) AND ({})",
+            placeholders.name("sk"),
+            placeholders.value(string(HISTORY_PREFIX)),
+            filter
+        );
+        self.scan(filter, placeholders)
+            .await?
+            .into_iter()
+            .map(|item| serde_dynamo::from_item(item).map_err(backend))
+            .collect()
+    }
+
+    async fn scan(
+        &self,
+        filter: String,
+        placeholders: Placeholders,
+    ) -> Result<Vec<Item>, RepositoryError> {
+        let mut items = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = self
+                .client
+                .scan()
+                .table_name(&self.table)
+                .filter_expression(&filter)
+                .set_expression_attribute_names(placeholders.names())
+                .set_expression_attribute_values(placeholders.values())
+                .consistent_read(true)
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(backend)?;
+            items.extend(output.items.unwrap_or_default());
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+
+    // Every item in one partition of an index that matches the filter expression, following
+    // LastEvaluatedKey through however many pages that takes
+    async fn query_index(
+        &self,
+        index: &str,
+        key_condition: String,
+        filter: String,
+        placeholders: Placeholders,
+    ) -> Result<Vec<Item>, RepositoryError> {
+        let mut items = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = self
+                .client
+                .query()
+                .table_name(&self.table)
+                .index_name(index)
+                .key_condition_expression(&key_condition)
+                .filter_expression(&filter)
+                .set_expression_attribute_names(placeholders.names())
+                .set_expression_attribute_values(placeholders.values())
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(backend)?;
+            items.extend(output.items.unwrap_or_default());
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+
+    // A page of the tasks matching the filter, newest first, that contain every search term
+    async fn page_tasks(
+        &self,
+        filter: &TaskFilter,
+        terms: &[String],
+        page: Page,
+    ) -> Result<PageOf<Item>, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        // A user's tasks are the narrower partition when the filter has both
+        let state = filter.state.map(|state| state.to_string());
+        let (index, partition, partition_value) = match (&filter.user_uuid, &state) {
+            (Some(user_uuid), _) => (USER_INDEX, "user_uuid", user_uuid.as_str()),
+            (None, Some(state)) if self.state_index => (STATE_INDEX, "state", state.as_str()),
+            _ => (ALL_INDEX, "entity", ENTITY),
+        };
+        let key_condition = format!(
+            "{} = {}",
+            placeholders.name(partition),
+            placeholders.value(string(partition_value))
+        );
+
+        let mut conditions = filter_conditions(filter, &mut placeholders);
+        for term in terms {
+            let name = placeholders.name("search_text");
+            conditions.push(format!(
+                "contains({}, {})",
+                name,
+                placeholders.value(string(term))
+            ));
+        }
+        let filter_expression = (!conditions.is_empty()).then(|| conditions.join(" AND "));
+
+        // The cursor is where the previous page left off in the index
+        let mut start_key = match page.cursor.as_deref() {
+            Some(cursor) => {
+                let cursor = decode_cursor(cursor)?;
+                let (created_at, task_id) = cursor
+                    .split_once('|')
+                    .ok_or(RepositoryError::InvalidCursor)?;
+                let mut key = task_key(task_id);
+                key.insert("created_at".to_string(), string(created_at));
+                key.insert(partition.to_string(), string(partition_value));
+                Some(key)
+            }
+            None => None,
+        };
+
+        // Filters apply after a query reads its items, so keep reading until there's one more
+        // than a page, which also tells whether there's another page
+        let limit = page.limit as usize;
+        let mut items: Vec<Item> = Vec::new();
+        loop {
+            let output = self
+                .client
+                .query()
+                .table_name(&self.table)
+                .index_name(index)
+                .key_condition_expression(&key_condition)
+                .set_filter_expression(filter_expression.clone())
+                .set_expression_attribute_names(placeholders.names())
+                .set_expression_attribute_values(placeholders.values())
+                .scan_index_forward(false)
+                .limit(i32::try_from(limit + 1).unwrap_or(i32::MAX))
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(backend)?;
+            items.extend(output.items.unwrap_or_default());
+            start_key = output.last_evaluated_key;
+            if items.len() > limit || start_key.is_none() {
+                break;
+            }
+        }
+
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().and_then(|item| {
+                let created_at = attribute(item, "created_at")?;
+                let task_id = attribute(item, "pk")?;
+                Some(encode_cursor(&format!("{}|{}", created_at, task_id)))
+            })
+        } else {
+            None
+        };
+
+        Ok(PageOf { items, next_cursor })
+    }
+
+    // Writes in batches, resending whatever DynamoDB hands back as unprocessed
+    async fn batch_write(&self, requests: Vec<WriteRequest>) -> Result<(), RepositoryError> {
+        for chunk in requests.chunks(MAX_BATCH_WRITE) {
+            let mut pending = chunk.to_vec();
+            let mut attempt = 0;
+            while !pending.is_empty() {
+                if attempt > MAX_UNPROCESSED_RETRIES {
+                    return Err(RepositoryError::Backend(
+                        format!("{} writes left unprocessed by DynamoDB", pending.len()).into(),
+                    ));
+                }
+                if attempt > 0 {
+                    tokio::time::sleep(Duration::from_millis(50 << attempt)).await;
+                }
+
+                let output = self
+                    .client
+                    .batch_write_item()
+                    .request_items(&self.table, pending)
+                    .send()
+                    .await
+                    .map_err(backend)?;
+                pending = output
+                    .unprocessed_items
+                    .and_then(|mut unprocessed| unprocessed.remove(&self.table))
+                    .unwrap_or_default();
+                attempt += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Deletes the tasks along with their history, returning how many tasks went
+    async fn delete_tasks(&self, tasks: Vec<Item>) -> Result<u64, RepositoryError> {
+        let mut requests = Vec::new();
+        for task in &tasks {
+            let Some(task_id) = attribute(task, "pk") else {
+                continue;
+            };
+            for key in self.partition_keys(task_id).await? {
+                requests.push(
+                    WriteRequest::builder()
+                        .delete_request(
+                            DeleteRequest::builder()
+                                .set_key(Some(key))
+                                .build()
+                                .map_err(backend)?,
+                        )
+                        .build(),
+                );
+            }
+        }
+        self.batch_write(requests).await?;
+        Ok(tasks.len() as u64)
+    }
+
+    // Keys of the task and all its history entries
+    async fn partition_keys(&self, task_id: &str) -> Result<Vec<Item>, RepositoryError> {
+        let mut keys = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = self
+                .client
+                .query()
+                .table_name(&self.table)
+                .key_condition_expression("pk = :pk")
+                .expression_attribute_values(":pk", string(task_id))
+                .projection_expression("pk, sk")
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(backend)?;
+            keys.extend(output.items.unwrap_or_default());
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TaskRepository for DynamoRepository {
+    async fn put_task(&self, task: Task) -> Result<(), RepositoryError> {
+        let task_id = task.get_global_id();
+        match self.store_task(&task).await {
+            Ok(true) => {
+                info!("Task saved to DynamoDB: {}", task_id);
+                Ok(())
+            }
+            Ok(false) => {
+                info!("Task {} changed since version {}", task_id, task.version);
+                Err(RepositoryError::Conflict)
+            }
+            Err(e) => {
+                error!("Failed to save task to DynamoDB: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn put_tasks(&self, tasks: Vec<Task>) -> Result<Vec<bool>, RepositoryError> {
+        let writes: Vec<_> = tasks.iter().map(|task| self.store_task(task)).collect();
+        let stored: Vec<bool> = stream::iter(writes)
+            .buffered(CONCURRENT_WRITES)
+            .try_collect()
+            .await?;
+        info!(
+            "Saved {} of {} tasks to DynamoDB",
+            stored.iter().filter(|stored| **stored).count(),
+            stored.len()
+        );
+        Ok(stored)
+    }
+
+    // A transaction only takes 100 writes, so bigger batches are split and each part is all or
+    // nothing on its own. A task always goes in the same transaction as its history.
+    async fn create_tasks(
+        &self,
+        tasks: Vec<Task>,
+        history: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        if tasks.iter().any(|task| task.version != 0) {
+            return Err(RepositoryError::Conflict);
+        }
+
+        let now = Utc::now();
+        let mut history_by_task: HashMap<String, Vec<Item>> = HashMap::new();
+        for entry in &history {
+            history_by_task
+                .entry(entry.task_global_id.clone())
+                .or_default()
+                .push(history_item(entry)?);
+        }
+
+        let put = |item: Item, condition: Option<&str>| {
+            Put::builder()
+                .table_name(&self.table)
+                .set_item(Some(item))
+                .set_condition_expression(condition.map(str::to_string))
+                .build()
+                .map(|put| TransactWriteItem::builder().put(put).build())
+                .map_err(backend)
+        };
+        let mut transactions: Vec<Vec<TransactWriteItem>> = vec![Vec::new()];
+        for task in &tasks {
+            let mut item = task_item(task, 1, now)?;
+            item.insert(
+                "created_at".to_string(),
+                timestamp(task.created_at.unwrap_or(now)),
+            );
+            let mut writes = vec![put(item, Some("attribute_not_exists(pk)"))?];
+            for entry in history_by_task
+                .remove(task.get_global_id().as_str())
+                .unwrap_or_default()
+            {
+                writes.push(put(entry, None)?);
+            }
+
+            let current = transactions.last_mut().expect("always at least one");
+            if !current.is_empty() && current.len() + writes.len() > MAX_TRANSACT_ITEMS {
+                transactions.push(writes);
+            } else {
+                current.extend(writes);
+            }
+        }
+        // History for tasks outside the batch, which there shouldn't be
+        let mut requests = Vec::new();
+        for item in history_by_task.into_values().flatten() {
+            requests.push(
+                WriteRequest::builder()
+                    .put_request(
+                        PutRequest::builder()
+                            .set_item(Some(item))
+                            .build()
+                            .map_err(backend)?,
+                    )
+                    .build(),
+            );
+        }
+
+        for writes in transactions.into_iter().filter(|writes| !writes.is_empty()) {
+            // The token lets the SDK retry a transaction without applying it twice
+            let result = self
+                .client
+                .transact_write_items()
+                .set_transact_items(Some(writes))
+                .client_request_token(Uuid::new_v4().to_string())
+                .send()
+                .await;
+            match result {
+                Ok(_) => {}
+                Err(e) if is_transaction_conflict(&e) => {
+                    info!("Some of {} new tasks were already stored", tasks.len());
+                    return Err(RepositoryError::Conflict);
+                }
+                Err(e) => {
+                    error!("Failed to create tasks in DynamoDB: {}", e);
+                    return Err(backend(e));
+                }
+            }
+        }
+        self.batch_write(requests).await?;
+
+        info!("Created {} tasks in DynamoDB", tasks.len());
+        Ok(())
+    }
+
+    async fn get_task(&self, task_id: String) -> Result<Option<Task>, RepositoryError> {
+        let result = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .set_key(Some(task_key(&task_id)))
+            .consistent_read(true)
+            .send()
+            .await;
+
+        match result {
+            Ok(output) => {
+                let task = output
+                    .item
+                    .map(task_from_item)
+                    .transpose()?
+                    .filter(|task| task.deleted_at.is_none());
+                match &task {
+                    Some(_) => info!("Retrieved task from DynamoDB: {}", task_id),
+                    None => info!("Task not found: {}", task_id),
+                }
+                Ok(task)
+            }
+            Err(e) => {
+                error!("Error finding task {}: {}", task_id, e);
+                Err(backend(e))
+            }
+        }
+    }
+
+    async fn get_tasks(&self, task_ids: &[String]) -> Result<Vec<Task>, RepositoryError> {
+        // A batch may not ask for the same key twice
+        let task_ids: Vec<&String> = task_ids
+            .iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        let mut tasks = Vec::with_capacity(task_ids.len());
+        for chunk in task_ids.chunks(MAX_BATCH_GET) {
+            let mut pending = Some(
+                KeysAndAttributes::builder()
+                    .set_keys(Some(
+                        chunk.iter().map(|task_id| task_key(task_id)).collect(),
+                    ))
+                    .consistent_read(true)
+                    .build()
+                    .map_err(backend)?,
+            );
+            let mut attempt = 0;
+            while let Some(keys) = pending {
+                if attempt > MAX_UNPROCESSED_RETRIES {
+                    return Err(RepositoryError::Backend(
+                        "reads left unprocessed by DynamoDB".into(),
+                    ));
+                }
+                if attempt > 0 {
+                    tokio::time::sleep(Duration::from_millis(50 << attempt)).await;
+                }
+
+                let output = self
+                    .client
+                    .batch_get_item()
+                    .request_items(&self.table, keys)
+                    .send()
+                    .await
+                    .map_err(backend)?;
+                for item in output
+                    .responses
+                    .and_then(|mut responses| responses.remove(&self.table))
+                    .unwrap_or_default()
+                {
+                    let task = task_from_item(item)?;
+                    if task.deleted_at.is_none() {
+                        tasks.push(task);
+                    }
+                }
+                pending = output
+                    .unprocessed_keys
+                    .and_then(|mut unprocessed| unprocessed.remove(&self.table));
+                attempt += 1;
+            }
+        }
+        Ok(tasks)
+    }
+
+    async fn transition_task(
+        &self,
+        task_id: &str,
+        from_states: &[TaskState],
+        new_state: TaskState,
+        fields: TransitionFields,
+    ) -> Result<Option<Task>, RepositoryError> {
+        let TransitionFields {
+            result_file,
+            result_checksum,
+            expires_at,
+            last_error,
+            retried,
+            event,
+        } = fields;
+        if from_states.is_empty() {
+            return Ok(None);
+        }
+        let condition = |placeholders: &mut Placeholders| {
+            let state = placeholders.name("state");
+            let allowed: Vec<String> = from_states
+                .iter()
+                .map(|state| placeholders.value(string(state.to_string())))
+                .collect();
+            format!("{} IN ({})", state, allowed.join(", "))
+        };
+        let mut fields = vec![
+            ("result_file", optional(result_file.map(string))),
+            ("result_checksum", optional(result_checksum.map(string))),
+            ("expires_at", optional(expires_at.map(timestamp))),
+            (TTL_ATTRIBUTE, ttl(expires_at)),
+            ("last_error", optional(last_error.map(string))),
+        ];
+        if new_state == TaskState::Completed {
+            fields.push(("checkpoint", AttributeValue::Null(true)));
+        }
+        let counters: &[&str] = if retried { &["retry_count"] } else { &[] };
+        self.update_state(
+            task_id,
+            condition,
+            new_state,
+            fields,
+            counters,
+            event.as_ref(),
+        )
+        .await
+    }
+
+    // Conditional updates sent concurrently, as with put_tasks
+    async fn transition_many(
+        &self,
+        tasks: &[Task],
+        new_state: TaskState,
+        event: Option<TaskEvent>,
+    ) -> Result<Vec<bool>, RepositoryError> {
+        let event = event.as_ref();
+        let writes: Vec<_> = tasks
+            .iter()
+            .map(|task| async move {
+                let condition = |placeholders: &mut Placeholders| {
+                    format!(
+                        "{} = {}",
+                        placeholders.name("version"),
+                        placeholders.value(AttributeValue::N(task.version.to_string()))
+                    )
+                };
+                let fields = [
+                    "result_file",
+                    "result_checksum",
+                    "expires_at",
+                    TTL_ATTRIBUTE,
+                    "last_error",
+                ]
+                .into_iter()
+                .map(|name| (name, AttributeValue::Null(true)))
+                .collect();
+                let previous = self
+                    .update_state(
+                        &task.get_global_id(),
+                        condition,
+                        new_state,
+                        fields,
+                        &[],
+                        event,
+                    )
+                    .await?;
+                Ok::<_, RepositoryError>(previous.is_some())
+            })
+            .collect();
+        stream::iter(writes)
+            .buffered(CONCURRENT_WRITES)
+            .try_collect()
+            .await
+    }
+
+    async fn record_heartbeat(
+        &self,
+        task_id: &str,
+        at: DateTime<Utc>,
+        progress: Option<TaskProgress>,
+        checkpoint: Option<String>,
+    ) -> Result<bool, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let condition = format!(
+            "attribute_exists({}) AND {} = {} AND {}",
+            placeholders.name("pk"),
+            placeholders.name("state"),
+            placeholders.value(string(TaskState::InProgress.to_string())),
+            unset("deleted_at", &mut placeholders)
+        );
+        let mut update = format!(
+            "SET {} = {}",
+            placeholders.name("last_heartbeat_at"),
+            placeholders.value(timestamp(at))
+        );
+        if let Some(progress) = &progress {
+            let progress = serde_dynamo::to_attribute_value(progress).map_err(backend)?;
+            update.push_str(&format!(
+                ", {} = {}",
+                placeholders.name("progress"),
+                placeholders.value(progress)
+            ));
+        }
+        if let Some(checkpoint) = checkpoint {
+            update.push_str(&format!(
+                ", {} = {}",
+                placeholders.name("checkpoint"),
+                placeholders.value(string(checkpoint))
+            ));
+        }
+
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .set_key(Some(task_key(task_id)))
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(placeholders.names())
+            .set_expression_attribute_values(placeholders.values())
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if is_conditional_check_failed(&e) => Ok(false),
+            Err(e) => Err(backend(e)),
+        }
+    }
+
+    async fn find_stale_tasks(
+        &self,
+        state: TaskState,
+        updated_before: DateTime<Utc>,
+    ) -> Result<Vec<Task>, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let in_state = format!(
+            "{} = {}",
+            placeholders.name("state"),
+            placeholders.value(string(state.to_string()))
+        );
+        let cutoff = placeholders.value(timestamp(updated_before));
+        let heartbeat = placeholders.name("last_heartbeat_at");
+        let filter = format!(
+            "{} < {cutoff} AND ({} OR {heartbeat} < {cutoff}) AND {} AND {}",
+            placeholders.name("updated_at"),
+            unset("last_heartbeat_at", &mut placeholders),
+            unset("archived_at", &mut placeholders),
+            unset("deleted_at", &mut placeholders)
+        );
+        // The index only reads the tasks in that state, the scan reads them all
+        let items = if self.state_index {
+            self.query_index(STATE_INDEX, in_state, filter, placeholders)
+                .await?
+        } else {
+            self.scan_tasks(format!("{in_state} AND {filter}"), placeholders)
+                .await?
+        };
+        items.into_iter().map(task_from_item).collect()
+    }
+
+    async fn list_tasks(
+        &self,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<Task>, RepositoryError> {
+        let PageOf { items, next_cursor } = self.page_tasks(&filter, &[], page).await?;
+        Ok(PageOf {
+            items: items
+                .into_iter()
+                .map(task_from_item)
+                .collect::<Result<_, _>>()?,
+            next_cursor,
+        })
+    }
+
+    // DynamoDB has no text index, so this matches tasks containing every term in their text
+    // fields. Matches come newest first, scored by how often the terms occur.
+    async fn search_tasks(
+        &self,
+        query: &str,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<(Task, f64)>, RepositoryError> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        let PageOf { items, next_cursor } = self.page_tasks(&filter, &terms, page).await?;
+
+        let mut matches = Vec::with_capacity(items.len());
+        for item in items {
+            let text = attribute(&item, "search_text").unwrap_or_default();
+            let score = terms
+                .iter()
+                .map(|term| text.matches(term.as_str()).count())
+                .sum::<usize>() as f64;
+            matches.push((task_from_item(item)?, score));
+        }
+        Ok(PageOf {
+            items: matches,
+            next_cursor,
+        })
+    }
+
+    // DynamoDB can't aggregate, so the stats scan the table and add up here
+    async fn count_by_state(&self) -> Result<HashMap<TaskState, u64>, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let filter = unset("deleted_at", &mut placeholders);
+        let mut counts = HashMap::new();
+        for item in self.scan_tasks(filter, placeholders).await? {
+            let state = attribute(&item, "state")
+                .and_then(|state| TaskState::from_str(state).ok())
+                .ok_or_else(|| RepositoryError::Backend("task item without a state".into()))?;
+            *counts.entry(state).or_default() += 1;
+        }
+        Ok(counts)
+    }
+
+    async fn throughput_since(&self, since: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let filter = format!(
+            "{} = {} AND {} >= {}",
+            placeholders.name("new_state"),
+            placeholders.value(string(TaskState::Completed.to_string())),
+            placeholders.name("timestamp"),
+            placeholders.value(timestamp(since))
+        );
+        Ok(self.scan_history(filter, placeholders).await?.len() as u64)
+    }
+
+    async fn avg_completion_time(
+        &self,
+        task_type: Option<&str>,
+    ) -> Result<Option<f64>, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let mut conditions = vec![
+            format!(
+                "{} = {}",
+                placeholders.name("state"),
+                placeholders.value(string(TaskState::Completed.to_string()))
+            ),
+            unset("deleted_at", &mut placeholders),
+        ];
+        if let Some(task_type) = task_type {
+            conditions.push(format!(
+                "{} = {}",
+                placeholders.name("task_type"),
+                placeholders.value(string(task_type))
+            ));
+        }
+        let completed: HashSet<String> = self
+            .scan_tasks(conditions.join(" AND "), placeholders)
+            .await?
+            .iter()
+            .filter_map(|item| attribute(item, "pk").map(str::to_string))
+            .collect();
+        if completed.is_empty() {
+            return Ok(None);
+        }
+
+        let mut placeholders = Placeholders::default();
+        let filter = format!("attribute_exists({})", placeholders.name("new_state"));
+        let mut history: HashMap<String, Vec<TaskHistoryEntry>> = HashMap::new();
+        for entry in self.scan_history(filter, placeholders).await? {
+            if completed.contains(&entry.task_global_id) {
+                history
+                    .entry(entry.task_global_id.clone())
+                    .or_default()
+                    .push(entry);
+            }
+        }
+        Ok(mean_secs(history.values().filter_map(completion_time)))
+    }
+
+    async fn purge_archived(&self, archived_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let filter = format!(
+            "{} < {}",
+            placeholders.name("archived_at"),
+            placeholders.value(timestamp(archived_before))
+        );
+        let tasks = self.scan_tasks(filter, placeholders).await?;
+        self.delete_tasks(tasks).await
+    }
+
+    async fn delete_task(&self, task_id: &str) -> Result<bool, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let condition = format!(
+            "attribute_exists({}) AND {}",
+            placeholders.name("pk"),
+            unset("deleted_at", &mut placeholders)
+        );
+        let now = placeholders.value(timestamp(Utc::now()));
+        let update = format!(
+            "SET {} = {now}, {} = {now}, {version} = {version} + {}",
+            placeholders.name("deleted_at"),
+            placeholders.name("updated_at"),
+            placeholders.value(AttributeValue::N("1".to_string())),
+            version = placeholders.name("version"),
+        );
+
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .set_key(Some(task_key(task_id)))
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(placeholders.names())
+            .set_expression_attribute_values(placeholders.values())
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if is_conditional_check_failed(&e) => Ok(false),
+            Err(e) => Err(backend(e)),
+        }
+    }
+
+    async fn purge_deleted(&self, deleted_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let filter = format!(
+            "{} < {}",
+            placeholders.name("deleted_at"),
+            placeholders.value(timestamp(deleted_before))
+        );
+        let tasks = self.scan_tasks(filter, placeholders).await?;
+        self.delete_tasks(tasks).await
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        let mut placeholders = Placeholders::default();
+        let filter = format!(
+            "{} < {}",
+            placeholders.name("expires_at"),
+            placeholders.value(timestamp(now))
+        );
+        let tasks = self.scan_tasks(filter, placeholders).await?;
+        self.delete_tasks(tasks).await
+    }
+
+    async fn record_history(&self, entry: TaskHistoryEntry) -> Result<(), RepositoryError> {
+        self.client
+            .put_item()
+            .table_name(&self.table)
+            .set_item(Some(history_item(&entry)?))
+            .send()
+            .await
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    async fn record_histories(
+        &self,
+        entries: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        let requests = entries
+            .iter()
+            .map(|entry| {
+                let put = PutRequest::builder()
+                    .set_item(Some(history_item(entry)?))
+                    .build()
+                    .map_err(backend)?;
+                Ok(WriteRequest::builder().put_request(put).build())
+            })
+            .collect::<Result<Vec<_>, RepositoryError>>()?;
+        self.batch_write(requests).await
+    }
+
+    async fn get_history(&self, task_id: &str) -> Result<Vec<TaskHistoryEntry>, RepositoryError> {
+        let mut entries = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = self
+                .client
+                .query()
+                .table_name(&self.table)
+                .key_condition_expression("pk = :pk AND begins_with(sk, :prefix)")
+                .expression_attribute_values(":pk", string(task_id))
+                .expression_attribute_values(":prefix", string(HISTORY_PREFIX))
+                .consistent_read(true)
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(backend)?;
+            for item in output.items.unwrap_or_default() {
+                entries.push(serde_dynamo::from_item(item).map_err(backend)?);
+            }
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(entries);
+            }
+        }
+    }
+
+    async fn save_worker(&self, worker: WorkerStatus) -> Result<(), RepositoryError> {
+        let mut item: Item = serde_dynamo::to_item(&worker).map_err(backend)?;
+        item.extend(worker_key(&worker.worker_id));
+        self.client
+            .put_item()
+            .table_name(&self.table)
+            .set_item(Some(item))
+            .send()
+            .await
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    async fn get_worker(&self, worker_id: &str) -> Result<Option<WorkerStatus>, RepositoryError> {
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .set_key(Some(worker_key(worker_id)))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(backend)?;
+        output
+            .item
+            .map(|item| serde_dynamo::from_item(item).map_err(backend))
+            .transpose()
+    }
+
+    // Comes back ordered by sk, which is the worker_id
+    async fn list_workers(&self) -> Result<Vec<WorkerStatus>, RepositoryError> {
+        let mut workers = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = self
+                .client
+                .query()
+                .table_name(&self.table)
+                .key_condition_expression("pk = :pk")
+                .expression_attribute_values(":pk", string(WORKERS_PK))
+                .consistent_read(true)
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(backend)?;
+            for item in output.items.unwrap_or_default() {
+                workers.push(serde_dynamo::from_item(item).map_err(backend)?);
+            }
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(workers);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::task::TaskType;
+
+    // The tests below talk to DynamoDB Local (or LocalStack), at DYNAMODB_ENDPOINT or
+    // http://localhost:8000, and are left out of a plain cargo test. Start one with
+    // `docker run -p 8000:8000 amazon/dynamodb-local`, export any AWS credentials (Local doesn't
+    // check them) and run `cargo test -- --ignored dynamodb`. Each test gets a table of its own.
+    async fn local_repo() -> DynamoRepository {
+        let config = DynamoConfig {
+            table: format!("tasks-test-{}", Uuid::new_v4()),
+            region: Some("us-east-1".to_string()),
+            endpoint_url: Some(
+                std::env::var("DYNAMODB_ENDPOINT")
+                    .unwrap_or_else(|_| "http://localhost:8000".to_string()),
+            ),
+            ..DynamoConfig::default()
+        };
+        DynamoRepository::init(&config).await.unwrap()
+    }
+
+    async fn drop_table(repo: DynamoRepository) {
+        repo.client
+            .delete_table()
+            .table_name(&repo.table)
+            .send()
+            .await
+            .unwrap();
+    }
+
+    fn event(state: TaskState) -> TaskEvent {
+        TaskEvent::new(state, "api".to_string(), None)
+    }
+
+    #[test]
+    fn task_items_round_trip_with_comparable_timestamps() {
+        let mut task = Task::new("alice".into(), "Render".into(), "in.mp4".into());
+        task.tags = vec!["Customer-X".to_string()];
+        // A whole second, which serde would write without any fractional digits
+        task.archived_at = DateTime::from_timestamp(1_700_000_000, 0);
+        task.expires_at = task.archived_at;
+
+        let item = task_item(&task, 3, Utc::now()).unwrap();
+        assert_eq!(
+            attribute(&item, "archived_at"),
+            Some("2023-11-14T22:13:20.000000Z")
+        );
+        // Epoch seconds, a day past expires_at
+        assert_eq!(
+            item[TTL_ATTRIBUTE],
+            AttributeValue::N("1700086400".to_string())
+        );
+        assert_eq!(attribute(&item, "pk"), Some(task.get_global_id().as_str()));
+        assert_eq!(attribute(&item, "state"), Some("NotStarted"));
+        assert_eq!(
+            attribute(&item, "search_text"),
+            Some("render in.mp4 customer-x")
+        );
+
+        let stored = task_from_item(item).unwrap();
+        assert_eq!(stored.version, 3);
+        assert_eq!(stored.archived_at, task.archived_at);
+        assert_eq!(stored.result_file, None);
+        assert_eq!(stored.tags, task.tags);
+    }
+
+    #[test]
+    fn only_tasks_written_become_events() {
+        use aws_sdk_dynamodbstreams::types::StreamRecord;
+
+        let task = Task::new("alice".into(), TaskType::Render, "in.mp4".into());
+        let record = |event_name, sk: &str| {
+            let mut image: HashMap<String, StreamValue> = serde_dynamo::to_item(&task).unwrap();
+            image.insert("sk".to_string(), StreamValue::S(sk.to_string()));
+            Record::builder()
+                .event_name(event_name)
+                .dynamodb(StreamRecord::builder().set_new_image(Some(image)).build())
+                .build()
+        };
+
+        let event = stream_event(&record(OperationType::Insert, TASK_SK))
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.kind, TaskEventKind::Created);
+        assert_eq!(event.task.get_global_id(), task.get_global_id());
+        let event = stream_event(&record(OperationType::Modify, TASK_SK))
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.kind, TaskEventKind::Updated);
+
+        assert!(stream_event(&record(OperationType::Remove, TASK_SK)).is_none());
+        assert!(stream_event(&record(OperationType::Insert, "HISTORY#1")).is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DynamoDB Local"]
+    async fn tasks_are_stored_and_read_back_locally() {
+        let repo = local_repo().await;
+        let task = Task::new("alice".into(), TaskType::Render, "in.mp4".into());
+        let task_id = task.get_global_id().to_string();
+
+        repo.put_task(task.clone()).await.unwrap();
+        let stored = repo.get_task(task_id.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.version, 1);
+        assert_eq!(stored.source_file, "in.mp4");
+        assert_eq!(
+            stored.created_at.map(|at| at.timestamp_millis()),
+            task.created_at.map(|at| at.timestamp_millis())
+        );
+
+        // Written at a version that's gone
+        assert!(matches!(
+            repo.put_task(task).await,
+            Err(RepositoryError::Conflict)
+        ));
+        let mut updated = stored;
+        updated.tags = vec!["customer-x".to_string()];
+        repo.put_task(updated).await.unwrap();
+        let stored = repo.get_task(task_id).await.unwrap().unwrap();
+        assert_eq!(stored.version, 2);
+        assert_eq!(stored.tags, ["customer-x"]);
+
+        drop_table(repo).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DynamoDB Local"]
+    async fn transitions_only_go_through_from_allowed_states_locally() {
+        let repo = local_repo().await;
+        let task = Task::new("alice".into(), TaskType::Render, "in.mp4".into());
+        let task_id = task.get_global_id().to_string();
+        repo.put_task(task).await.unwrap();
+
+        let fields = TransitionFields {
+            event: Some(event(TaskState::InProgress)),
+            ..TransitionFields::default()
+        };
+        let previous = repo
+            .transition_task(
+                &task_id,
+                &[TaskState::NotStarted],
+                TaskState::InProgress,
+                fields,
+            )
+            .await
+            .unwrap();
+        assert_eq!(previous.map(|task| task.state), Some(TaskState::NotStarted));
+
+        let again = repo
+            .transition_task(
+                &task_id,
+                &[TaskState::NotStarted],
+                TaskState::InProgress,
+                TransitionFields::default(),
+            )
+            .await
+            .unwrap();
+        assert!(again.is_none());
+
+        let stored = repo.get_task(task_id).await.unwrap().unwrap();
+        assert_eq!(stored.state, TaskState::InProgress);
+        assert_eq!(stored.version, 2);
+        let states: Vec<TaskState> = stored.events.iter().map(|event| event.state).collect();
+        assert_eq!(states, [TaskState::InProgress]);
+
+        drop_table(repo).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DynamoDB Local"]
+    async fn state_listings_read_from_the_state_index_locally() {
+        let repo = local_repo().await;
+        assert!(repo.state_index);
+        let waiting = Task::new("alice".into(), TaskType::Render, "a.mp4".into());
+        let started = Task::new("bob".into(), TaskType::Render, "b.mp4".into());
+        let started_id = started.get_global_id().to_string();
+        repo.put_tasks(vec![waiting, started]).await.unwrap();
+        repo.transition_task(
+            &started_id,
+            &[TaskState::NotStarted],
+            TaskState::InProgress,
+            TransitionFields::default(),
+        )
+        .await
+        .unwrap();
+
+        let filter = TaskFilter {
+            state: Some(TaskState::InProgress),
+            ..TaskFilter::default()
+        };
+        let page = repo
+            .list_tasks(
+                filter,
+                Page {
+                    cursor: None,
+                    limit: 10,
+                },
+            )
+            .await
+            .unwrap();
+        let ids: Vec<String> = page
+            .items
+            .iter()
+            .map(|task| task.get_global_id().into())
+            .collect();
+        assert_eq!(ids, [started_id]);
+
+        let stale = repo
+            .find_stale_tasks(TaskState::InProgress, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(stale.len(), 1);
+
+        drop_table(repo).await;
+    }
+}
@@ -0,0 +1,248 @@
+use crate::model::history::TaskHistoryEntry;
+use crate::model::task::{Task, TaskEvent, TaskProgress, TaskState};
+use crate::model::worker::WorkerStatus;
+use crate::repository::{
+    Page, PageOf, RepositoryError, TaskFilter, TaskRepository, TransitionFields,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Wraps a storage backend, serving task history from the events kept on each task instead of
+// the history collection, which is then never written. Meant for small deployments that would
+// rather not keep the collection around: only the last Task::MAX_EVENTS changes of a task are
+// kept, and the stats that go by the history (throughput and completion times) have nothing to
+// go on.
+pub struct EmbeddedHistoryRepository {
+    inner: Arc<dyn TaskRepository>,
+}
+
+impl EmbeddedHistoryRepository {
+    pub fn new(inner: Arc<dyn TaskRepository>) -> Self {
+        Self { inner }
+    }
+}
+
+// Each event as a history entry, the state it moved from being the one before it. The oldest
+// kept event has no state before it, like the entry recorded on submission.
+fn history_from_events(task_id: &str, events: &[TaskEvent]) -> Vec<TaskHistoryEntry> {
+    let mut old_state = None;
+    events
+        .iter()
+        .map(|event| {
+            let entry = TaskHistoryEntry {
+                task_global_id: task_id.to_string(),
+                old_state,
+                new_state: event.state,
+                actor: event.actor.clone(),
+                timestamp: event.at,
+                reason: event.note.clone(),
+            };
+            old_state = Some(event.state);
+            entry
+        })
+        .collect()
+}
+
+#[async_trait]
+impl TaskRepository for EmbeddedHistoryRepository {
+    async fn put_task(&self, task: Task) -> Result<(), RepositoryError> {
+        self.inner.put_task(task).await
+    }
+
+    async fn put_tasks(&self, tasks: Vec<Task>) -> Result<Vec<bool>, RepositoryError> {
+        self.inner.put_tasks(tasks).await
+    }
+
+    async fn create_tasks(
+        &self,
+        tasks: Vec<Task>,
+        _history: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        self.inner.create_tasks(tasks, Vec::new()).await
+    }
+
+    async fn get_task(&self, task_id: String) -> Result<Option<Task>, RepositoryError> {
+        self.inner.get_task(task_id).await
+    }
+
+    async fn get_tasks(&self, task_ids: &[String]) -> Result<Vec<Task>, RepositoryError> {
+        self.inner.get_tasks(task_ids).await
+    }
+
+    async fn transition_task(
+        &self,
+        task_id: &str,
+        from_states: &[TaskState],
+        new_state: TaskState,
+        fields: TransitionFields,
+    ) -> Result<Option<Task>, RepositoryError> {
+        self.inner
+            .transition_task(task_id, from_states, new_state, fields)
+            .await
+    }
+
+    async fn transition_many(
+        &self,
+        tasks: &[Task],
+        new_state: TaskState,
+        event: Option<TaskEvent>,
+    ) -> Result<Vec<bool>, RepositoryError> {
+        self.inner.transition_many(tasks, new_state, event).await
+    }
+
+    async fn record_heartbeat(
+        &self,
+        task_id: &str,
+        at: DateTime<Utc>,
+        progress: Option<TaskProgress>,
+        checkpoint: Option<String>,
+    ) -> Result<bool, RepositoryError> {
+        self.inner
+            .record_heartbeat(task_id, at, progress, checkpoint)
+            .await
+    }
+
+    async fn find_stale_tasks(
+        &self,
+        state: TaskState,
+        updated_before: DateTime<Utc>,
+    ) -> Result<Vec<Task>, RepositoryError> {
+        self.inner.find_stale_tasks(state, updated_before).await
+    }
+
+    async fn list_tasks(
+        &self,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<Task>, RepositoryError> {
+        self.inner.list_tasks(filter, page).await
+    }
+
+    async fn search_tasks(
+        &self,
+        query: &str,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<(Task, f64)>, RepositoryError> {
+        self.inner.search_tasks(query, filter, page).await
+    }
+
+    async fn count_by_state(&self) -> Result<HashMap<TaskState, u64>, RepositoryError> {
+        self.inner.count_by_state().await
+    }
+
+    async fn throughput_since(&self, since: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.inner.throughput_since(since).await
+    }
+
+    async fn avg_completion_time(
+        &self,
+        task_type: Option<&str>,
+    ) -> Result<Option<f64>, RepositoryError> {
+        self.inner.avg_completion_time(task_type).await
+    }
+
+    async fn purge_archived(&self, archived_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.inner.purge_archived(archived_before).await
+    }
+
+    async fn delete_task(&self, task_id: &str) -> Result<bool, RepositoryError> {
+        self.inner.delete_task(task_id).await
+    }
+
+    async fn purge_deleted(&self, deleted_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.inner.purge_deleted(deleted_before).await
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.inner.purge_expired(now).await
+    }
+
+    // Whatever the entry says is already on the task as an event
+    async fn record_history(&self, _entry: TaskHistoryEntry) -> Result<(), RepositoryError> {
+        Ok(())
+    }
+
+    async fn record_histories(
+        &self,
+        _entries: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        Ok(())
+    }
+
+    async fn get_history(&self, task_id: &str) -> Result<Vec<TaskHistoryEntry>, RepositoryError> {
+        let task = self.inner.get_task(task_id.to_string()).await?;
+        Ok(task.map_or_else(Vec::new, |task| history_from_events(task_id, &task.events)))
+    }
+
+    async fn save_worker(&self, worker: WorkerStatus) -> Result<(), RepositoryError> {
+        self.inner.save_worker(worker).await
+    }
+
+    async fn get_worker(&self, worker_id: &str) -> Result<Option<WorkerStatus>, RepositoryError> {
+        self.inner.get_worker(worker_id).await
+    }
+
+    async fn list_workers(&self) -> Result<Vec<WorkerStatus>, RepositoryError> {
+        self.inner.list_workers().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::memory::InMemoryRepository;
+
+    #[tokio::test]
+    async fn history_comes_from_the_events_on_the_task() {
+        let repo = EmbeddedHistoryRepository::new(Arc::new(InMemoryRepository::new()));
+
+        let mut task = Task::new("alice".into(), "render".into(), "in.mp4".into());
+        task.push_event(TaskEvent::new(task.state, "api".into(), None));
+        let task_id = task.get_global_id().to_string();
+        repo.put_task(task).await.unwrap();
+        let fields = TransitionFields {
+            event: Some(TaskEvent::new(
+                TaskState::InProgress,
+                "worker".into(),
+                Some("picked up".into()),
+            )),
+            ..TransitionFields::default()
+        };
+        repo.transition_task(
+            &task_id,
+            &[TaskState::NotStarted],
+            TaskState::InProgress,
+            fields,
+        )
+        .await
+        .unwrap();
+        // Not kept anywhere, the event above already says it
+        repo.record_history(TaskHistoryEntry::new(
+            task_id.clone(),
+            Some(TaskState::NotStarted),
+            TaskState::InProgress,
+            "worker".into(),
+            None,
+        ))
+        .await
+        .unwrap();
+
+        let history = repo.get_history(&task_id).await.unwrap();
+        let states: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.old_state, entry.new_state))
+            .collect();
+        assert_eq!(
+            states,
+            [
+                (None, TaskState::NotStarted),
+                (Some(TaskState::NotStarted), TaskState::InProgress),
+            ]
+        );
+        assert_eq!(history[1].actor, "worker");
+        assert_eq!(history[1].reason.as_deref(), Some("picked up"));
+    }
+}
@@ -0,0 +1,520 @@
+use crate::model::history::TaskHistoryEntry;
+use crate::model::task::{Task, TaskEvent, TaskProgress, TaskState};
+use crate::model::worker::WorkerStatus;
+use crate::repository::{
+    completion_time, decode_cursor, encode_cursor, mean_secs, Page, PageOf, RepositoryError,
+    TaskFilter, TaskRepository, TransitionFields,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+struct StoredTask {
+    // Insertion order, plays the part of Mongo's _id for newest-first listing
+    seq: u64,
+    task: Task,
+}
+
+impl StoredTask {
+    fn matches(&self, filter: &TaskFilter) -> bool {
+        let task = &self.task;
+        filter
+            .user_uuid
+            .as_ref()
+            .is_none_or(|user_uuid| &task.user_uuid == user_uuid)
+            && filter.state.is_none_or(|state| task.state == state)
+            && filter
+                .task_type
+                .as_ref()
+                .is_none_or(|task_type| task.task_type.as_str() == task_type)
+            && filter
+                .tag
+                .as_ref()
+                .is_none_or(|tag| task.tags.contains(tag))
+            && filter
+                .updated_after
+                .is_none_or(|after| task.updated_at.is_some_and(|at| at >= after))
+            && filter
+                .updated_before
+                .is_none_or(|before| task.updated_at.is_some_and(|at| at < before))
+            && (filter.include_archived || task.archived_at.is_none())
+            && task.deleted_at.is_none()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    tasks: HashMap<String, StoredTask>,
+    history: Vec<TaskHistoryEntry>,
+    // Kept ordered by worker_id, as list_workers returns them
+    workers: BTreeMap<String, WorkerStatus>,
+    next_seq: u64,
+}
+
+impl State {
+    // The task unless it's been deleted
+    fn live(&self, task_id: &str) -> Option<&StoredTask> {
+        self.tasks
+            .get(task_id)
+            .filter(|stored| stored.task.deleted_at.is_none())
+    }
+
+    // put_task's compare-and-swap, returning whether the task was stored
+    fn store(&mut self, mut task: Task) -> bool {
+        let task_id = String::from(task.get_global_id());
+        // Version 0 means the task has never been stored
+        let stored_version = self.tasks.get(&task_id).map(|stored| stored.task.version);
+        if stored_version != (task.version > 0).then_some(task.version) {
+            return false;
+        }
+        // Deleted tasks stay deleted until they're purged
+        if self
+            .tasks
+            .get(&task_id)
+            .is_some_and(|stored| stored.task.deleted_at.is_some())
+        {
+            return false;
+        }
+
+        let now = Utc::now();
+        let (seq, created_at) = match self.tasks.get(&task_id) {
+            Some(stored) => (stored.seq, stored.task.created_at),
+            None => {
+                self.next_seq += 1;
+                (self.next_seq, Some(task.created_at.unwrap_or(now)))
+            }
+        };
+        task.version += 1;
+        task.created_at = created_at;
+        task.updated_at = Some(now);
+        self.tasks.insert(task_id, StoredTask { seq, task });
+        true
+    }
+
+    // Removes the matching tasks and their history, returning how many tasks went
+    fn purge(&mut self, matches: impl Fn(&Task) -> bool) -> u64 {
+        let purged: Vec<String> = self
+            .tasks
+            .iter()
+            .filter(|(_, stored)| matches(&stored.task))
+            .map(|(task_id, _)| task_id.clone())
+            .collect();
+
+        for task_id in &purged {
+            self.tasks.remove(task_id);
+        }
+        self.history
+            .retain(|entry| !purged.contains(&entry.task_global_id));
+
+        purged.len() as u64
+    }
+}
+
+// Keeps everything in a HashMap so handlers can be tested without a database
+#[derive(Default)]
+pub struct InMemoryRepository {
+    state: RwLock<State>,
+}
+
+impl InMemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[async_trait]
+impl TaskRepository for InMemoryRepository {
+    async fn put_task(&self, task: Task) -> Result<(), RepositoryError> {
+        if self.write().store(task) {
+            Ok(())
+        } else {
+            Err(RepositoryError::Conflict)
+        }
+    }
+
+    async fn put_tasks(&self, tasks: Vec<Task>) -> Result<Vec<bool>, RepositoryError> {
+        let mut state = self.write();
+        Ok(tasks.into_iter().map(|task| state.store(task)).collect())
+    }
+
+    async fn create_tasks(
+        &self,
+        tasks: Vec<Task>,
+        history: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        let mut state = self.write();
+        // Checked up front so a conflict leaves nothing behind
+        if tasks.iter().any(|task| {
+            task.version != 0 || state.tasks.contains_key(task.get_global_id().as_str())
+        }) {
+            return Err(RepositoryError::Conflict);
+        }
+        for task in tasks {
+            state.store(task);
+        }
+        state.history.extend(history);
+        Ok(())
+    }
+
+    async fn get_task(&self, task_id: String) -> Result<Option<Task>, RepositoryError> {
+        Ok(self.read().live(&task_id).map(|stored| stored.task.clone()))
+    }
+
+    async fn get_tasks(&self, task_ids: &[String]) -> Result<Vec<Task>, RepositoryError> {
+        let state = self.read();
+        Ok(task_ids
+            .iter()
+            .filter_map(|task_id| state.live(task_id))
+            .map(|stored| stored.task.clone())
+            .collect())
+    }
+
+    async fn transition_task(
+        &self,
+        task_id: &str,
+        from_states: &[TaskState],
+        new_state: TaskState,
+        fields: TransitionFields,
+    ) -> Result<Option<Task>, RepositoryError> {
+        let TransitionFields {
+            result_file,
+            result_checksum,
+            expires_at,
+            last_error,
+            retried,
+            event,
+        } = fields;
+        let mut state = self.write();
+        let Some(stored) = state.tasks.get_mut(task_id) else {
+            return Ok(None);
+        };
+        if stored.task.deleted_at.is_some() || !from_states.contains(&stored.task.state) {
+            return Ok(None);
+        }
+
+        let previous = stored.task.clone();
+        stored.task.state = new_state;
+        stored.task.result_file = result_file;
+        stored.task.result_checksum = result_checksum;
+        stored.task.expires_at = expires_at;
+        stored.task.last_error = last_error;
+        if retried {
+            stored.task.retry_count += 1;
+        }
+        if new_state == TaskState::Completed {
+            stored.task.checkpoint = None;
+        }
+        if let Some(event) = event {
+            stored.task.push_event(event);
+        }
+        stored.task.version += 1;
+        stored.task.updated_at = Some(Utc::now());
+        Ok(Some(previous))
+    }
+
+    async fn transition_many(
+        &self,
+        tasks: &[Task],
+        new_state: TaskState,
+        event: Option<TaskEvent>,
+    ) -> Result<Vec<bool>, RepositoryError> {
+        let mut state = self.write();
+        Ok(tasks
+            .iter()
+            .map(|task| {
+                let Some(stored) = state.tasks.get_mut(task.get_global_id().as_str()) else {
+                    return false;
+                };
+                if stored.task.version != task.version {
+                    return false;
+                }
+                stored.task.state = new_state;
+                stored.task.result_file = None;
+                stored.task.result_checksum = None;
+                stored.task.expires_at = None;
+                stored.task.last_error = None;
+                if let Some(event) = &event {
+                    stored.task.push_event(event.clone());
+                }
+                stored.task.version += 1;
+                stored.task.updated_at = Some(Utc::now());
+                true
+            })
+            .collect())
+    }
+
+    async fn record_heartbeat(
+        &self,
+        task_id: &str,
+        at: DateTime<Utc>,
+        progress: Option<TaskProgress>,
+        checkpoint: Option<String>,
+    ) -> Result<bool, RepositoryError> {
+        let mut state = self.write();
+        match state.tasks.get_mut(task_id) {
+            Some(stored)
+                if stored.task.deleted_at.is_none()
+                    && stored.task.state == TaskState::InProgress =>
+            {
+                stored.task.last_heartbeat_at = Some(at);
+                if progress.is_some() {
+                    stored.task.progress = progress;
+                }
+                if checkpoint.is_some() {
+                    stored.task.checkpoint = checkpoint;
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn find_stale_tasks(
+        &self,
+        state: TaskState,
+        updated_before: DateTime<Utc>,
+    ) -> Result<Vec<Task>, RepositoryError> {
+        Ok(self
+            .read()
+            .tasks
+            .values()
+            .filter(|stored| {
+                stored.task.state == state
+                    && stored.task.updated_at.is_some_and(|at| at < updated_before)
+                    && stored
+                        .task
+                        .last_heartbeat_at
+                        .is_none_or(|at| at < updated_before)
+                    && stored.task.archived_at.is_none()
+                    && stored.task.deleted_at.is_none()
+            })
+            .map(|stored| stored.task.clone())
+            .collect())
+    }
+
+    async fn list_tasks(
+        &self,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<Task>, RepositoryError> {
+        let last_seq = page
+            .cursor
+            .as_deref()
+            .map(|cursor| {
+                decode_cursor(cursor)?
+                    .parse::<u64>()
+                    .map_err(|_| RepositoryError::InvalidCursor)
+            })
+            .transpose()?;
+
+        let state = self.read();
+        let mut matches: Vec<&StoredTask> = state
+            .tasks
+            .values()
+            .filter(|stored| {
+                stored.matches(&filter) && last_seq.is_none_or(|last_seq| stored.seq < last_seq)
+            })
+            .collect();
+        matches.sort_by_key(|stored| Reverse(stored.seq));
+
+        let next_cursor = if matches.len() > page.limit as usize {
+            matches.truncate(page.limit as usize);
+            matches
+                .last()
+                .map(|stored| encode_cursor(&stored.seq.to_string()))
+        } else {
+            None
+        };
+
+        Ok(PageOf {
+            items: matches
+                .into_iter()
+                .map(|stored| stored.task.clone())
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    // Scores each task by how many of the query's words appear in its descriptive fields. The
+    // cursor is simply an offset into the ranked results.
+    async fn search_tasks(
+        &self,
+        query: &str,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<(Task, f64)>, RepositoryError> {
+        let offset = page
+            .cursor
+            .as_deref()
+            .map(|cursor| {
+                decode_cursor(cursor)?
+                    .parse::<usize>()
+                    .map_err(|_| RepositoryError::InvalidCursor)
+            })
+            .transpose()?
+            .unwrap_or(0);
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+
+        let state = self.read();
+        let mut matches: Vec<(&StoredTask, f64)> = state
+            .tasks
+            .values()
+            .filter(|stored| stored.matches(&filter))
+            .filter_map(|stored| {
+                let task = &stored.task;
+                let words: Vec<String> = [task.task_type.as_str(), &task.source_file]
+                    .into_iter()
+                    .chain(task.result_file.as_deref())
+                    .chain(task.tags.iter().map(String::as_str))
+                    .flat_map(|field| field.split_whitespace())
+                    .map(str::to_lowercase)
+                    .collect();
+                let score = terms.iter().filter(|term| words.contains(term)).count();
+                (score > 0).then_some((stored, score as f64))
+            })
+            .collect();
+        matches.sort_by(|(a, a_score), (b, b_score)| {
+            b_score.total_cmp(a_score).then(a.seq.cmp(&b.seq))
+        });
+
+        let end = offset.saturating_add(page.limit as usize);
+        let next_cursor = (matches.len() > end).then(|| encode_cursor(&end.to_string()));
+
+        Ok(PageOf {
+            items: matches
+                .into_iter()
+                .skip(offset)
+                .take(page.limit as usize)
+                .map(|(stored, score)| (stored.task.clone(), score))
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    async fn count_by_state(&self) -> Result<HashMap<TaskState, u64>, RepositoryError> {
+        let mut counts = HashMap::new();
+        for stored in self.read().tasks.values() {
+            if stored.task.deleted_at.is_none() {
+                *counts.entry(stored.task.state).or_default() += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn throughput_since(&self, since: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        Ok(self
+            .read()
+            .history
+            .iter()
+            .filter(|entry| entry.new_state == TaskState::Completed && entry.timestamp >= since)
+            .count() as u64)
+    }
+
+    async fn avg_completion_time(
+        &self,
+        task_type: Option<&str>,
+    ) -> Result<Option<f64>, RepositoryError> {
+        let state = self.read();
+        let durations = state
+            .tasks
+            .iter()
+            .filter(|(_, stored)| {
+                stored.task.state == TaskState::Completed
+                    && stored.task.deleted_at.is_none()
+                    && task_type.is_none_or(|task_type| stored.task.task_type.as_str() == task_type)
+            })
+            .filter_map(|(task_id, _)| {
+                completion_time(
+                    state
+                        .history
+                        .iter()
+                        .filter(|entry| entry.task_global_id == *task_id),
+                )
+            });
+        Ok(mean_secs(durations))
+    }
+
+    async fn purge_archived(&self, archived_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        Ok(self.write().purge(|task| {
+            task.archived_at
+                .is_some_and(|archived_at| archived_at < archived_before)
+        }))
+    }
+
+    async fn delete_task(&self, task_id: &str) -> Result<bool, RepositoryError> {
+        let mut state = self.write();
+        let Some(stored) = state.tasks.get_mut(task_id) else {
+            return Ok(false);
+        };
+        if stored.task.deleted_at.is_some() {
+            return Ok(false);
+        }
+        let now = Utc::now();
+        stored.task.deleted_at = Some(now);
+        stored.task.version += 1;
+        stored.task.updated_at = Some(now);
+        Ok(true)
+    }
+
+    async fn purge_deleted(&self, deleted_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        Ok(self.write().purge(|task| {
+            task.deleted_at
+                .is_some_and(|deleted_at| deleted_at < deleted_before)
+        }))
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        Ok(self
+            .write()
+            .purge(|task| task.expires_at.is_some_and(|expires_at| expires_at <= now)))
+    }
+
+    async fn record_history(&self, entry: TaskHistoryEntry) -> Result<(), RepositoryError> {
+        self.write().history.push(entry);
+        Ok(())
+    }
+
+    async fn record_histories(
+        &self,
+        entries: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        self.write().history.extend(entries);
+        Ok(())
+    }
+
+    async fn get_history(&self, task_id: &str) -> Result<Vec<TaskHistoryEntry>, RepositoryError> {
+        // Entries are appended as they happen, so they're already oldest first
+        Ok(self
+            .read()
+            .history
+            .iter()
+            .filter(|entry| entry.task_global_id == task_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn save_worker(&self, worker: WorkerStatus) -> Result<(), RepositoryError> {
+        self.write()
+            .workers
+            .insert(worker.worker_id.clone(), worker);
+        Ok(())
+    }
+
+    async fn get_worker(&self, worker_id: &str) -> Result<Option<WorkerStatus>, RepositoryError> {
+        Ok(self.read().workers.get(worker_id).cloned())
+    }
+
+    async fn list_workers(&self) -> Result<Vec<WorkerStatus>, RepositoryError> {
+        Ok(self.read().workers.values().cloned().collect())
+    }
+}
@@ -0,0 +1,267 @@
+use crate::metrics::Metrics;
+use crate::model::history::TaskHistoryEntry;
+use crate::model::task::{Task, TaskEvent, TaskProgress, TaskState};
+use crate::model::worker::WorkerStatus;
+use crate::repository::{
+    Page, PageOf, RepositoryError, TaskFilter, TaskRepository, TransitionFields,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+// Wraps a storage backend, timing every call and counting how it turned out. Sits directly on
+// the backend, so cache hits don't count as storage operations.
+pub struct MeteredRepository {
+    inner: Arc<dyn TaskRepository>,
+    metrics: Metrics,
+    backend: &'static str,
+}
+
+impl MeteredRepository {
+    pub fn new(inner: Arc<dyn TaskRepository>, metrics: Metrics, backend: &'static str) -> Self {
+        Self {
+            inner,
+            metrics,
+            backend,
+        }
+    }
+
+    async fn observe<T>(
+        &self,
+        operation: &str,
+        call: impl Future<Output = Result<T, RepositoryError>>,
+    ) -> Result<T, RepositoryError> {
+        let started = Instant::now();
+        let result = call.await;
+        self.metrics.observe_repository(
+            operation,
+            self.backend,
+            outcome(&result),
+            started.elapsed(),
+        );
+        result
+    }
+}
+
+// Conflicts and bad cursors are the caller's doing rather than the storage failing, so they're
+// kept apart from errors
+fn outcome<T>(result: &Result<T, RepositoryError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(RepositoryError::Conflict) => "conflict",
+        Err(RepositoryError::InvalidCursor) => "invalid_cursor",
+        Err(RepositoryError::Backend(_)) => "error",
+    }
+}
+
+#[async_trait]
+impl TaskRepository for MeteredRepository {
+    async fn put_task(&self, task: Task) -> Result<(), RepositoryError> {
+        self.observe("put_task", self.inner.put_task(task)).await
+    }
+
+    async fn put_tasks(&self, tasks: Vec<Task>) -> Result<Vec<bool>, RepositoryError> {
+        self.observe("put_tasks", self.inner.put_tasks(tasks)).await
+    }
+
+    async fn create_tasks(
+        &self,
+        tasks: Vec<Task>,
+        history: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        self.observe("create_tasks", self.inner.create_tasks(tasks, history))
+            .await
+    }
+
+    async fn get_task(&self, task_id: String) -> Result<Option<Task>, RepositoryError> {
+        self.observe("get_task", self.inner.get_task(task_id)).await
+    }
+
+    async fn get_tasks(&self, task_ids: &[String]) -> Result<Vec<Task>, RepositoryError> {
+        self.observe("get_tasks", self.inner.get_tasks(task_ids))
+            .await
+    }
+
+    async fn transition_task(
+        &self,
+        task_id: &str,
+        from_states: &[TaskState],
+        new_state: TaskState,
+        fields: TransitionFields,
+    ) -> Result<Option<Task>, RepositoryError> {
+        self.observe(
+            "transition_task",
+            self.inner
+                .transition_task(task_id, from_states, new_state, fields),
+        )
+        .await
+    }
+
+    async fn transition_many(
+        &self,
+        tasks: &[Task],
+        new_state: TaskState,
+        event: Option<TaskEvent>,
+    ) -> Result<Vec<bool>, RepositoryError> {
+        self.observe(
+            "transition_many",
+            self.inner.transition_many(tasks, new_state, event),
+        )
+        .await
+    }
+
+    async fn record_heartbeat(
+        &self,
+        task_id: &str,
+        at: DateTime<Utc>,
+        progress: Option<TaskProgress>,
+        checkpoint: Option<String>,
+    ) -> Result<bool, RepositoryError> {
+        self.observe(
+            "record_heartbeat",
+            self.inner
+                .record_heartbeat(task_id, at, progress, checkpoint),
+        )
+        .await
+    }
+
+    async fn find_stale_tasks(
+        &self,
+        state: TaskState,
+        updated_before: DateTime<Utc>,
+    ) -> Result<Vec<Task>, RepositoryError> {
+        self.observe(
+            "find_stale_tasks",
+            self.inner.find_stale_tasks(state, updated_before),
+        )
+        .await
+    }
+
+    async fn list_tasks(
+        &self,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<Task>, RepositoryError> {
+        self.observe("list_tasks", self.inner.list_tasks(filter, page))
+            .await
+    }
+
+    async fn search_tasks(
+        &self,
+        query: &str,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<(Task, f64)>, RepositoryError> {
+        self.observe("search_tasks", self.inner.search_tasks(query, filter, page))
+            .await
+    }
+
+    async fn count_by_state(&self) -> Result<HashMap<TaskState, u64>, RepositoryError> {
+        self.observe("count_by_state", self.inner.count_by_state())
+            .await
+    }
+
+    async fn throughput_since(&self, since: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.observe("throughput_since", self.inner.throughput_since(since))
+            .await
+    }
+
+    async fn avg_completion_time(
+        &self,
+        task_type: Option<&str>,
+    ) -> Result<Option<f64>, RepositoryError> {
+        self.observe(
+            "avg_completion_time",
+            self.inner.avg_completion_time(task_type),
+        )
+        .await
+    }
+
+    async fn purge_archived(&self, archived_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.observe("purge_archived", self.inner.purge_archived(archived_before))
+            .await
+    }
+
+    async fn delete_task(&self, task_id: &str) -> Result<bool, RepositoryError> {
+        self.observe("delete_task", self.inner.delete_task(task_id))
+            .await
+    }
+
+    async fn purge_deleted(&self, deleted_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.observe("purge_deleted", self.inner.purge_deleted(deleted_before))
+            .await
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.observe("purge_expired", self.inner.purge_expired(now))
+            .await
+    }
+
+    async fn record_history(&self, entry: TaskHistoryEntry) -> Result<(), RepositoryError> {
+        self.observe("record_history", self.inner.record_history(entry))
+            .await
+    }
+
+    async fn record_histories(
+        &self,
+        entries: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        self.observe("record_histories", self.inner.record_histories(entries))
+            .await
+    }
+
+    async fn get_history(&self, task_id: &str) -> Result<Vec<TaskHistoryEntry>, RepositoryError> {
+        self.observe("get_history", self.inner.get_history(task_id))
+            .await
+    }
+
+    async fn save_worker(&self, worker: WorkerStatus) -> Result<(), RepositoryError> {
+        self.observe("save_worker", self.inner.save_worker(worker))
+            .await
+    }
+
+    async fn get_worker(&self, worker_id: &str) -> Result<Option<WorkerStatus>, RepositoryError> {
+        self.observe("get_worker", self.inner.get_worker(worker_id))
+            .await
+    }
+
+    async fn list_workers(&self) -> Result<Vec<WorkerStatus>, RepositoryError> {
+        self.observe("list_workers", self.inner.list_workers())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::memory::InMemoryRepository;
+
+    #[tokio::test]
+    async fn operations_are_counted_by_outcome() {
+        let metrics = Metrics::new();
+        let repo = MeteredRepository::new(
+            Arc::new(InMemoryRepository::new()),
+            metrics.clone(),
+            "memory",
+        );
+
+        let task = Task::new("alice".into(), "render".into(), "in.mp4".into());
+        repo.put_task(task.clone()).await.unwrap();
+        // Still at version 0, so this one conflicts
+        assert!(repo.put_task(task).await.is_err());
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            r#"task_repository_operations_total{backend="memory",operation="put_task",outcome="ok"} 1"#
+        ));
+        assert!(rendered.contains(
+            r#"task_repository_operations_total{backend="memory",operation="put_task",outcome="conflict"} 1"#
+        ));
+        assert!(rendered.contains(
+            r#"task_repository_operation_duration_seconds_count{backend="memory",operation="put_task"} 2"#
+        ));
+    }
+}
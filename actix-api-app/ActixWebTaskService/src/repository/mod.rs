@@ -1 +1,255 @@
+pub mod cached;
+pub mod dynamodb;
+pub mod embedded;
+#[cfg(test)]
+pub mod memory;
+pub mod metered;
 pub mod mongodb;
+pub mod sqlite;
+
+use crate::model::history::TaskHistoryEntry;
+use crate::model::task::{Task, TaskEvent, TaskProgress, TaskState};
+use crate::model::worker::WorkerStatus;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+// Backend-neutral errors the handlers can react to. Anything specific to a storage backend is
+// boxed up in Backend and only ever logged.
+#[derive(Debug)]
+pub enum RepositoryError {
+    Backend(Box<dyn Error + Send + Sync>),
+    InvalidCursor,
+    // The task was changed by someone else since it was read
+    Conflict,
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Backend(e) => write!(f, "{}", e),
+            Self::InvalidCursor => write!(f, "Invalid pagination cursor"),
+            Self::Conflict => write!(f, "Task was modified concurrently"),
+        }
+    }
+}
+
+impl Error for RepositoryError {}
+
+// Narrows down which tasks list_tasks and search_tasks return. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct TaskFilter {
+    pub user_uuid: Option<String>,
+    pub state: Option<TaskState>,
+    pub task_type: Option<String>,
+    // Tasks carrying this tag among others
+    pub tag: Option<String>,
+    // Last written at or after / strictly before these times
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+    pub include_archived: bool,
+}
+
+// Which page of results to fetch. Leave cursor off for the first page, then pass back the
+// next_cursor of the previous one.
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub cursor: Option<String>,
+    pub limit: u32,
+}
+
+// What a transition sets alongside the new state, each cleared where it's None
+#[derive(Debug, Clone, Default)]
+pub struct TransitionFields {
+    pub result_file: Option<String>,
+    pub result_checksum: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    // Counts the move as another attempt, bumping retry_count
+    pub retried: bool,
+    // Appended to the task's events, dropping the oldest beyond Task::MAX_EVENTS
+    pub event: Option<TaskEvent>,
+}
+
+#[derive(Debug)]
+pub struct PageOf<T> {
+    pub items: Vec<T>,
+    // Absent on the last page
+    pub next_cursor: Option<String>,
+}
+
+// Storage for tasks and their history. Handlers only ever see this trait (as
+// Data<dyn TaskRepository>), so backends are interchangeable and handlers can be run against a
+// fake in tests.
+#[async_trait]
+pub trait TaskRepository: Send + Sync {
+    // Inserts the task or replaces the stored copy with the same global id. The write only goes
+    // through if the stored copy is still at task.version (0 for a task that's never been
+    // stored) and bumps the version, otherwise it fails with Conflict.
+    async fn put_task(&self, task: Task) -> Result<(), RepositoryError>;
+
+    // Stores a batch of tasks under the same rule as put_task, in as few round trips as the
+    // backend allows. Returns whether each task was stored, in order, false being a conflict.
+    async fn put_tasks(&self, tasks: Vec<Task>) -> Result<Vec<bool>, RepositoryError>;
+
+    // Stores brand new tasks along with their first history entries, all or nothing: if any of
+    // it can't be written none of it is kept. Fails with Conflict if one of the tasks already
+    // exists.
+    async fn create_tasks(
+        &self,
+        tasks: Vec<Task>,
+        history: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError>;
+
+    // None only if there's no such task, failing to look it up is an error
+    async fn get_task(&self, task_id: String) -> Result<Option<Task>, RepositoryError>;
+
+    // Every task with one of the ids, in no particular order. Ids with no task are skipped.
+    async fn get_tasks(&self, task_ids: &[String]) -> Result<Vec<Task>, RepositoryError>;
+
+    // Atomically moves the task to new_state (setting the fields alongside, and dropping the
+    // checkpoint if it's now Completed), but only if it's currently in one of from_states.
+    // Returns the task as it was before the change, or None if no task with that id was in an
+    // allowed state.
+    async fn transition_task(
+        &self,
+        task_id: &str,
+        from_states: &[TaskState],
+        new_state: TaskState,
+        fields: TransitionFields,
+    ) -> Result<Option<Task>, RepositoryError>;
+
+    // Moves each of the tasks to new_state (clearing result_file, result_checksum, expires_at and
+    // last_error, and appending the event as transition_task does), provided it's still at the
+    // version it was read at. Returns whether each transition went through, in order.
+    async fn transition_many(
+        &self,
+        tasks: &[Task],
+        new_state: TaskState,
+        event: Option<TaskEvent>,
+    ) -> Result<Vec<bool>, RepositoryError>;
+
+    // Marks the task as still being worked on, if it's in progress. Only sets last_heartbeat_at
+    // and, if given, progress and checkpoint, the version and the time it was last written stay
+    // as they are. Returns whether the task was found in progress.
+    async fn record_heartbeat(
+        &self,
+        task_id: &str,
+        at: DateTime<Utc>,
+        progress: Option<TaskProgress>,
+        checkpoint: Option<String>,
+    ) -> Result<bool, RepositoryError>;
+
+    // Tasks in the given state that haven't been written, nor heartbeated, since the cutoff
+    async fn find_stale_tasks(
+        &self,
+        state: TaskState,
+        updated_before: DateTime<Utc>,
+    ) -> Result<Vec<Task>, RepositoryError>;
+
+    // Tasks matching the filter, newest first
+    async fn list_tasks(
+        &self,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<Task>, RepositoryError>;
+
+    // Full text search over the tasks matching the filter, best matches first, returning each
+    // task with its relevance score
+    async fn search_tasks(
+        &self,
+        query: &str,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<(Task, f64)>, RepositoryError>;
+
+    // How many tasks are in each state, archived ones included but not deleted ones. States
+    // with no tasks are left out.
+    async fn count_by_state(&self) -> Result<HashMap<TaskState, u64>, RepositoryError>;
+
+    // How many times a task was completed since the given time, going by the history
+    async fn throughput_since(&self, since: DateTime<Utc>) -> Result<u64, RepositoryError>;
+
+    // Mean seconds from submission to completion over the completed tasks, optionally only those
+    // of one type. None if there are none to go on.
+    async fn avg_completion_time(
+        &self,
+        task_type: Option<&str>,
+    ) -> Result<Option<f64>, RepositoryError>;
+
+    // Permanently removes tasks archived before the cutoff along with their history, returning
+    // how many tasks were removed
+    async fn purge_archived(&self, archived_before: DateTime<Utc>) -> Result<u64, RepositoryError>;
+
+    // Marks the task deleted instead of removing it. From then on it's left out of every query,
+    // get_task included, and can't be transitioned. Returns false if there was no task to delete.
+    async fn delete_task(&self, task_id: &str) -> Result<bool, RepositoryError>;
+
+    // Same as purge_archived, for tasks deleted before the cutoff
+    async fn purge_deleted(&self, deleted_before: DateTime<Utc>) -> Result<u64, RepositoryError>;
+
+    // Same as purge_archived, for tasks whose expires_at has passed
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64, RepositoryError>;
+
+    async fn record_history(&self, entry: TaskHistoryEntry) -> Result<(), RepositoryError>;
+
+    async fn record_histories(&self, entries: Vec<TaskHistoryEntry>)
+        -> Result<(), RepositoryError>;
+
+    // Every recorded transition for the task, oldest first
+    async fn get_history(&self, task_id: &str) -> Result<Vec<TaskHistoryEntry>, RepositoryError>;
+
+    // Inserts the worker or replaces the stored copy with the same worker_id. Workers only ever
+    // write their own status, so there's no version check.
+    async fn save_worker(&self, worker: WorkerStatus) -> Result<(), RepositoryError>;
+
+    async fn get_worker(&self, worker_id: &str) -> Result<Option<WorkerStatus>, RepositoryError>;
+
+    // Every worker that has registered, ordered by worker_id
+    async fn list_workers(&self) -> Result<Vec<WorkerStatus>, RepositoryError>;
+}
+
+// Cursors are opaque to clients, base64 keeps them URL safe and discourages hand-editing
+fn encode_cursor(payload: &str) -> String {
+    URL_SAFE_NO_PAD.encode(payload)
+}
+
+fn decode_cursor(cursor: &str) -> Result<String, RepositoryError> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or(RepositoryError::InvalidCursor)
+}
+
+// Time from a task's first history entry to its last completion, for backends that work out
+// avg_completion_time themselves rather than in the database
+fn completion_time<'a>(
+    history: impl IntoIterator<Item = &'a TaskHistoryEntry>,
+) -> Option<Duration> {
+    let mut started: Option<DateTime<Utc>> = None;
+    let mut completed: Option<DateTime<Utc>> = None;
+    for entry in history {
+        started = Some(started.map_or(entry.timestamp, |at| at.min(entry.timestamp)));
+        if entry.new_state == TaskState::Completed {
+            completed = Some(completed.map_or(entry.timestamp, |at| at.max(entry.timestamp)));
+        }
+    }
+    Some(completed? - started?)
+}
+
+// The mean of the durations in seconds, None for none
+fn mean_secs(durations: impl IntoIterator<Item = Duration>) -> Option<f64> {
+    let (total, count) = durations
+        .into_iter()
+        .fold((0.0, 0u32), |(total, count), duration| {
+            (
+                total + duration.num_milliseconds() as f64 / 1000.0,
+                count + 1,
+            )
+        });
+    (count > 0).then(|| total / count as f64)
+}
@@ -0,0 +1,1545 @@
+use crate::config::SqliteConfig;
+use crate::model::history::TaskHistoryEntry;
+use crate::model::task::{Task, TaskEvent, TaskPriority, TaskProgress, TaskState};
+use crate::model::worker::WorkerStatus;
+use crate::repository::{
+    decode_cursor, encode_cursor, Page, PageOf, RepositoryError, TaskFilter, TaskRepository,
+    TransitionFields,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use rusqlite::{
+    params, params_from_iter, types::Type, Connection, OptionalExtension, Row, ToSql, Transaction,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS tasks (
+        -- AUTOINCREMENT so ids are never reused and list cursors stay valid after purges
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        task_global_id TEXT NOT NULL UNIQUE,
+        user_uuid TEXT NOT NULL,
+        task_uuid TEXT NOT NULL,
+        task_type TEXT NOT NULL,
+        state TEXT NOT NULL,
+        priority TEXT NOT NULL DEFAULT 'Normal',
+        source_file TEXT NOT NULL,
+        result_file TEXT,
+        -- Hex SHA-256 of the result, only ever written by transitions like last_error
+        result_checksum TEXT,
+        request_id TEXT,
+        -- JSON array of strings
+        tags TEXT NOT NULL DEFAULT '[]',
+        -- JSON object of strings
+        metadata TEXT NOT NULL DEFAULT '{}',
+        -- Timestamps are unix milliseconds
+        archived_at INTEGER,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        version INTEGER NOT NULL DEFAULT 0,
+        expires_at INTEGER,
+        deleted_at INTEGER,
+        last_heartbeat_at INTEGER,
+        -- Both NULL until the worker first reports progress
+        progress_percent INTEGER,
+        progress_message TEXT,
+        last_error TEXT,
+        -- Only ever bumped by transitions
+        retry_count INTEGER NOT NULL DEFAULT 0,
+        -- Only ever written by record_heartbeat, cleared on completion
+        checkpoint TEXT,
+        -- JSON array of the latest events, oldest first
+        events TEXT NOT NULL DEFAULT '[]'
+    );
+    CREATE INDEX IF NOT EXISTS tasks_user ON tasks (user_uuid, id);
+    CREATE INDEX IF NOT EXISTS tasks_state_updated ON tasks (state, updated_at);
+    CREATE INDEX IF NOT EXISTS tasks_expires ON tasks (expires_at) WHERE expires_at IS NOT NULL;
+    CREATE INDEX IF NOT EXISTS tasks_deleted ON tasks (deleted_at) WHERE deleted_at IS NOT NULL;
+
+    -- Full text index over the descriptive fields, rowid matches tasks.id
+    CREATE VIRTUAL TABLE IF NOT EXISTS tasks_fts
+        USING fts5 (task_type, source_file, result_file, tags);
+
+    CREATE TABLE IF NOT EXISTS task_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        task_global_id TEXT NOT NULL,
+        old_state TEXT,
+        new_state TEXT NOT NULL,
+        actor TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        reason TEXT
+    );
+    CREATE INDEX IF NOT EXISTS task_history_task ON task_history (task_global_id, timestamp);
+
+    CREATE TABLE IF NOT EXISTS workers (
+        worker_id TEXT PRIMARY KEY,
+        hostname TEXT NOT NULL,
+        -- JSON arrays of strings
+        task_types TEXT NOT NULL,
+        concurrency INTEGER NOT NULL,
+        current_tasks TEXT NOT NULL,
+        registered_at INTEGER NOT NULL,
+        last_seen_at INTEGER NOT NULL,
+        -- JSON object, NULL until the worker reports its load
+        load TEXT,
+        -- JSON array of strings
+        processors TEXT NOT NULL DEFAULT '[]'
+    );
+";
+
+const TASK_COLUMNS: &str = "id, user_uuid, task_uuid, task_type, state, priority, source_file, \
+                            result_file, result_checksum, request_id, tags, metadata, \
+                            archived_at, version, expires_at, deleted_at, last_heartbeat_at, \
+                            progress_percent, progress_message, last_error, retry_count, \
+                            checkpoint, created_at, updated_at, events";
+
+// The TaskFilter as a WHERE condition, with its values bound by FilterParams. Columns are
+// qualified because the search query also has tasks_fts columns of the same names in scope.
+const FILTER_CONDITION: &str = "
+    (:user_uuid IS NULL OR tasks.user_uuid = :user_uuid)
+    AND (:state IS NULL OR tasks.state = :state)
+    AND (:task_type IS NULL OR tasks.task_type = :task_type)
+    AND (:tag IS NULL OR EXISTS
+         (SELECT 1 FROM json_each(tasks.tags) WHERE json_each.value = :tag))
+    AND (:updated_after IS NULL OR tasks.updated_at >= :updated_after)
+    AND (:updated_before IS NULL OR tasks.updated_at < :updated_before)
+    AND (:include_archived OR tasks.archived_at IS NULL)
+    AND tasks.deleted_at IS NULL";
+
+// Owned copy of a TaskFilter's values so they can be moved onto the blocking pool
+struct FilterParams {
+    user_uuid: Option<String>,
+    state: Option<String>,
+    task_type: Option<String>,
+    tag: Option<String>,
+    updated_after: Option<i64>,
+    updated_before: Option<i64>,
+    include_archived: bool,
+}
+
+impl FilterParams {
+    fn new(filter: TaskFilter) -> Self {
+        Self {
+            user_uuid: filter.user_uuid,
+            state: filter.state.map(|state| state.to_string()),
+            task_type: filter.task_type,
+            tag: filter.tag,
+            updated_after: filter.updated_after.map(|at| at.timestamp_millis()),
+            updated_before: filter.updated_before.map(|at| at.timestamp_millis()),
+            include_archived: filter.include_archived,
+        }
+    }
+
+    // Named parameters for FILTER_CONDITION followed by the query's own
+    fn with<'a>(&'a self, extra: &[(&'a str, &'a dyn ToSql)]) -> Vec<(&'a str, &'a dyn ToSql)> {
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![
+            (":user_uuid", &self.user_uuid),
+            (":state", &self.state),
+            (":task_type", &self.task_type),
+            (":tag", &self.tag),
+            (":updated_after", &self.updated_after),
+            (":updated_before", &self.updated_before),
+            (":include_archived", &self.include_archived),
+        ];
+        params.extend_from_slice(extra);
+        params
+    }
+}
+
+impl From<rusqlite::Error> for RepositoryError {
+    fn from(error: rusqlite::Error) -> Self {
+        RepositoryError::Backend(Box::new(error))
+    }
+}
+
+// SQLite only allows one writer at a time anyway, so a single connection behind a mutex is
+// enough. Queries are blocking and run on tokio's blocking pool to keep them off the actix
+// workers.
+#[derive(Clone)]
+pub struct SqliteRepository {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteRepository {
+    pub fn init(config: &SqliteConfig) -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open(&config.path)?;
+        // WAL lets readers carry on while a write is in progress (ignored for in-memory databases)
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+
+        info!("Opened SQLite database: {}", config.path);
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    async fn call<T, F>(&self, f: F) -> Result<T, RepositoryError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            // A panic mid-query can't leave the connection itself in a bad state
+            let mut conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| RepositoryError::Backend(Box::new(e)))?;
+        Ok(result?)
+    }
+
+    // Deletes the tasks matching the condition along with their history and search entries,
+    // returning how many tasks went. The condition takes the cutoff as ?1.
+    async fn purge(&self, condition: &'static str, cutoff: i64) -> Result<u64, RepositoryError> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                &format!(
+                    "DELETE FROM task_history WHERE task_global_id IN
+                         (SELECT task_global_id FROM tasks WHERE {condition})"
+                ),
+                [cutoff],
+            )?;
+            tx.execute(
+                &format!(
+                    "DELETE FROM tasks_fts WHERE rowid IN (SELECT id FROM tasks WHERE {condition})"
+                ),
+                [cutoff],
+            )?;
+            let removed = tx.execute(&format!("DELETE FROM tasks WHERE {condition}"), [cutoff])?;
+            tx.commit()?;
+            Ok(removed as u64)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl TaskRepository for SqliteRepository {
+    async fn put_task(&self, task: Task) -> Result<(), RepositoryError> {
+        let task_id = task.get_global_id();
+        let expected = task.version;
+
+        let result = self
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let stored = store_task(&tx, &task)?;
+                tx.commit()?;
+                Ok(stored)
+            })
+            .await;
+
+        match result {
+            Ok(true) => {
+                info!("Task saved to SQLite: {}", task_id);
+                Ok(())
+            }
+            Ok(false) => {
+                info!("Task {} changed since version {}", task_id, expected);
+                Err(RepositoryError::Conflict)
+            }
+            Err(e) => {
+                error!("Failed to save task to SQLite: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    // One transaction for the whole batch, which also saves syncing the journal per task
+    async fn put_tasks(&self, tasks: Vec<Task>) -> Result<Vec<bool>, RepositoryError> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let stored = tasks
+                .iter()
+                .map(|task| store_task(&tx, task))
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            tx.commit()?;
+            Ok(stored)
+        })
+        .await
+    }
+
+    async fn create_tasks(
+        &self,
+        tasks: Vec<Task>,
+        history: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        let count = tasks.len();
+        let created = self
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for task in &tasks {
+                    // Dropping the transaction rolls back whatever was already written
+                    if task.version != 0 || !store_task(&tx, task)? {
+                        return Ok(false);
+                    }
+                }
+                for entry in &history {
+                    insert_history(&tx, entry)?;
+                }
+                tx.commit()?;
+                Ok(true)
+            })
+            .await;
+
+        match created {
+            Ok(true) => {
+                info!("Created {} tasks in SQLite", count);
+                Ok(())
+            }
+            Ok(false) => Err(RepositoryError::Conflict),
+            Err(e) => {
+                error!("Failed to create tasks in SQLite: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn get_task(&self, task_id: String) -> Result<Option<Task>, RepositoryError> {
+        let lookup_id = task_id.clone();
+        let result = self
+            .call(move |conn| {
+                conn.query_row(
+                    &format!(
+                        "SELECT {TASK_COLUMNS} FROM tasks
+                     WHERE task_global_id = ?1 AND deleted_at IS NULL"
+                    ),
+                    [lookup_id],
+                    row_to_task,
+                )
+                .optional()
+            })
+            .await;
+
+        match result {
+            Ok(Some(task)) => {
+                info!("Retrieved task from SQLite: {}", task_id);
+                Ok(Some(task))
+            }
+            Ok(None) => {
+                info!("Task not found: {}", task_id);
+                Ok(None)
+            }
+            Err(e) => {
+                error!("Error finding task {}: {}", task_id, e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn get_tasks(&self, task_ids: &[String]) -> Result<Vec<Task>, RepositoryError> {
+        // Bound as one JSON array rather than a parameter per id
+        let task_ids =
+            serde_json::to_string(task_ids).map_err(|e| RepositoryError::Backend(Box::new(e)))?;
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {TASK_COLUMNS} FROM tasks
+                 WHERE task_global_id IN (SELECT value FROM json_each(?1))
+                   AND deleted_at IS NULL"
+            ))?;
+            let tasks = stmt
+                .query_map([task_ids], row_to_task)?
+                .collect::<rusqlite::Result<Vec<_>>>();
+            tasks
+        })
+        .await
+    }
+
+    // The read and the write happen in one transaction on the only connection, so nothing can
+    // change the task in between
+    async fn transition_task(
+        &self,
+        task_id: &str,
+        from_states: &[TaskState],
+        new_state: TaskState,
+        fields: TransitionFields,
+    ) -> Result<Option<Task>, RepositoryError> {
+        let TransitionFields {
+            result_file,
+            result_checksum,
+            expires_at,
+            last_error,
+            retried,
+            event,
+        } = fields;
+        let task_id = task_id.to_string();
+        let from_states = from_states.to_vec();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let current = tx
+                .query_row(
+                    &format!(
+                        "SELECT {TASK_COLUMNS} FROM tasks
+                     WHERE task_global_id = ?1 AND deleted_at IS NULL"
+                    ),
+                    [&task_id],
+                    |row| Ok((row.get::<_, i64>("id")?, row_to_task(row)?)),
+                )
+                .optional()?;
+            let Some((id, previous)) = current else {
+                return Ok(None);
+            };
+            if !from_states.contains(&previous.state) {
+                return Ok(None);
+            }
+
+            tx.execute(
+                &format!(
+                    "UPDATE tasks SET state = ?1, result_file = ?2, updated_at = ?3,
+                         expires_at = ?4, last_error = ?5, version = version + 1,
+                         checkpoint = CASE WHEN ?7 THEN NULL ELSE checkpoint END,
+                         result_checksum = ?8, retry_count = retry_count + ?9,
+                         events = {}
+                     WHERE id = ?6",
+                    append_event("?10")
+                ),
+                params![
+                    new_state.to_string(),
+                    result_file,
+                    Utc::now().timestamp_millis(),
+                    expires_at.map(|at| at.timestamp_millis()),
+                    last_error,
+                    id,
+                    new_state == TaskState::Completed,
+                    result_checksum,
+                    retried as i64,
+                    event.as_ref().map(to_json).transpose()?,
+                ],
+            )?;
+            tx.execute(
+                "UPDATE tasks_fts SET result_file = ?1 WHERE rowid = ?2",
+                params![result_file, id],
+            )?;
+            tx.commit()?;
+            Ok(Some(previous))
+        })
+        .await
+    }
+
+    async fn transition_many(
+        &self,
+        tasks: &[Task],
+        new_state: TaskState,
+        event: Option<TaskEvent>,
+    ) -> Result<Vec<bool>, RepositoryError> {
+        let versions: Vec<(String, i64)> = tasks
+            .iter()
+            .map(|task| (task.get_global_id().into(), task.version as i64))
+            .collect();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let now = Utc::now().timestamp_millis();
+            let event = event.as_ref().map(to_json).transpose()?;
+            let mut moved = Vec::with_capacity(versions.len());
+            for (task_id, version) in versions {
+                let id: Option<i64> = tx
+                    .query_row(
+                        &format!(
+                            "UPDATE tasks SET state = ?1, result_file = NULL,
+                                 result_checksum = NULL, expires_at = NULL, last_error = NULL,
+                                 updated_at = ?2, version = version + 1, events = {}
+                             WHERE task_global_id = ?3 AND version = ?4 AND deleted_at IS NULL
+                             RETURNING id",
+                            append_event("?5")
+                        ),
+                        params![new_state.to_string(), now, task_id, version, event],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if let Some(id) = id {
+                    tx.execute(
+                        "UPDATE tasks_fts SET result_file = NULL WHERE rowid = ?1",
+                        [id],
+                    )?;
+                }
+                moved.push(id.is_some());
+            }
+            tx.commit()?;
+            Ok(moved)
+        })
+        .await
+    }
+
+    async fn record_heartbeat(
+        &self,
+        task_id: &str,
+        at: DateTime<Utc>,
+        progress: Option<TaskProgress>,
+        checkpoint: Option<String>,
+    ) -> Result<bool, RepositoryError> {
+        let task_id = task_id.to_string();
+        self.call(move |conn| {
+            let (percent, message) = match progress {
+                Some(progress) => (Some(progress.percent), progress.message),
+                None => (None, None),
+            };
+            // Progress is only replaced when there's new progress to replace it with
+            let updated = conn.execute(
+                "UPDATE tasks SET last_heartbeat_at = ?1,
+                     progress_message = CASE WHEN ?4 IS NULL THEN progress_message ELSE ?5 END,
+                     progress_percent = COALESCE(?4, progress_percent),
+                     checkpoint = COALESCE(?6, checkpoint)
+                 WHERE task_global_id = ?2 AND state = ?3 AND deleted_at IS NULL",
+                params![
+                    at.timestamp_millis(),
+                    task_id,
+                    TaskState::InProgress.to_string(),
+                    percent,
+                    message,
+                    checkpoint,
+                ],
+            )?;
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    async fn find_stale_tasks(
+        &self,
+        state: TaskState,
+        updated_before: DateTime<Utc>,
+    ) -> Result<Vec<Task>, RepositoryError> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {TASK_COLUMNS} FROM tasks
+                 WHERE state = ?1 AND updated_at < ?2
+                   AND (last_heartbeat_at IS NULL OR last_heartbeat_at < ?2)
+                   AND archived_at IS NULL AND deleted_at IS NULL"
+            ))?;
+            let tasks = stmt
+                .query_map(
+                    params![state.to_string(), updated_before.timestamp_millis()],
+                    row_to_task,
+                )?
+                .collect();
+            tasks
+        })
+        .await
+    }
+
+    // Newest first, paged on the row id the same way the Mongo backend pages on _id
+    async fn list_tasks(
+        &self,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<Task>, RepositoryError> {
+        let last_id = page
+            .cursor
+            .as_deref()
+            .map(|cursor| {
+                decode_cursor(cursor)?
+                    .parse::<i64>()
+                    .map_err(|_| RepositoryError::InvalidCursor)
+            })
+            .transpose()?;
+        let limit = page.limit;
+        let filter = FilterParams::new(filter);
+
+        let mut rows = self
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {TASK_COLUMNS} FROM tasks
+                     WHERE {FILTER_CONDITION}
+                       AND (:last_id IS NULL OR id < :last_id)
+                     ORDER BY id DESC
+                     LIMIT :limit"
+                ))?;
+                // Fetch one extra to find out whether there's another page
+                let fetch = limit + 1;
+                let rows = stmt
+                    .query_map(
+                        filter
+                            .with(&[(":last_id", &last_id), (":limit", &fetch)])
+                            .as_slice(),
+                        |row| Ok((row.get::<_, i64>("id")?, row_to_task(row)?)),
+                    )?
+                    .collect::<rusqlite::Result<Vec<_>>>();
+                rows
+            })
+            .await?;
+
+        let next_cursor = if rows.len() > limit as usize {
+            rows.truncate(limit as usize);
+            rows.last().map(|(id, _)| encode_cursor(&id.to_string()))
+        } else {
+            None
+        };
+
+        Ok(PageOf {
+            items: rows.into_iter().map(|(_, task)| task).collect(),
+            next_cursor,
+        })
+    }
+
+    // Ranked with FTS5's bm25, negated so that higher scores are better as with Mongo. Results
+    // are ordered by (score desc, id asc) and the cursor carries both.
+    async fn search_tasks(
+        &self,
+        query: &str,
+        filter: TaskFilter,
+        page: Page,
+    ) -> Result<PageOf<(Task, f64)>, RepositoryError> {
+        let last = page
+            .cursor
+            .as_deref()
+            .map(|cursor| {
+                decode_cursor(cursor)?
+                    .split_once(':')
+                    .and_then(|(score, id)| {
+                        Some((score.parse::<f64>().ok()?, id.parse::<i64>().ok()?))
+                    })
+                    .ok_or(RepositoryError::InvalidCursor)
+            })
+            .transpose()?;
+        let (last_score, last_id) = last.unzip();
+
+        let Some(match_expr) = fts_query(query) else {
+            return Ok(PageOf {
+                items: Vec::new(),
+                next_cursor: None,
+            });
+        };
+        let limit = page.limit;
+        let filter = FilterParams::new(filter);
+
+        let mut rows = self
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT * FROM (
+                         SELECT tasks.*, -bm25(tasks_fts) AS score
+                         FROM tasks_fts JOIN tasks ON tasks.id = tasks_fts.rowid
+                         WHERE tasks_fts MATCH :query
+                           AND {FILTER_CONDITION}
+                     )
+                     WHERE :last_score IS NULL
+                        OR score < :last_score
+                        OR (score = :last_score AND id > :last_id)
+                     ORDER BY score DESC, id ASC
+                     LIMIT :limit"
+                ))?;
+                let fetch = limit + 1;
+                let rows = stmt
+                    .query_map(
+                        filter
+                            .with(&[
+                                (":query", &match_expr),
+                                (":last_score", &last_score),
+                                (":last_id", &last_id),
+                                (":limit", &fetch),
+                            ])
+                            .as_slice(),
+                        |row| {
+                            Ok((
+                                row.get::<_, i64>("id")?,
+                                row_to_task(row)?,
+                                row.get::<_, f64>("score")?,
+                            ))
+                        },
+                    )?
+                    .collect::<rusqlite::Result<Vec<_>>>();
+                rows
+            })
+            .await?;
+
+        let next_cursor = if rows.len() > limit as usize {
+            rows.truncate(limit as usize);
+            rows.last()
+                .map(|(id, _, score)| encode_cursor(&format!("{}:{}", score, id)))
+        } else {
+            None
+        };
+
+        Ok(PageOf {
+            items: rows
+                .into_iter()
+                .map(|(_, task, score)| (task, score))
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    async fn count_by_state(&self) -> Result<HashMap<TaskState, u64>, RepositoryError> {
+        self.call(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT state, COUNT(*) FROM tasks WHERE deleted_at IS NULL GROUP BY state",
+            )?;
+            let counts = stmt
+                .query_map([], |row| {
+                    Ok((parse_state(row.get(0)?)?, row.get::<_, i64>(1)? as u64))
+                })?
+                .collect();
+            counts
+        })
+        .await
+    }
+
+    async fn throughput_since(&self, since: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT COUNT(*) FROM task_history WHERE new_state = ?1 AND timestamp >= ?2",
+                params![TaskState::Completed.to_string(), since.timestamp_millis()],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|count| count as u64)
+        })
+        .await
+    }
+
+    // Submission is the task's first history entry, completion its last Completed one
+    async fn avg_completion_time(
+        &self,
+        task_type: Option<&str>,
+    ) -> Result<Option<f64>, RepositoryError> {
+        let task_type = task_type.map(str::to_string);
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT AVG(completed - started) FROM (
+                     SELECT MIN(task_history.timestamp) AS started,
+                         MAX(CASE WHEN task_history.new_state = ?1
+                             THEN task_history.timestamp END) AS completed
+                     FROM tasks
+                     JOIN task_history ON task_history.task_global_id = tasks.task_global_id
+                     WHERE tasks.state = ?1 AND tasks.deleted_at IS NULL
+                       AND (?2 IS NULL OR tasks.task_type = ?2)
+                     GROUP BY tasks.id
+                 )
+                 WHERE completed IS NOT NULL",
+                params![TaskState::Completed.to_string(), task_type],
+                |row| row.get::<_, Option<f64>>(0),
+            )
+            .map(|millis| millis.map(|millis| millis / 1000.0))
+        })
+        .await
+    }
+
+    async fn purge_archived(&self, archived_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.purge("archived_at < ?1", archived_before.timestamp_millis())
+            .await
+    }
+
+    async fn delete_task(&self, task_id: &str) -> Result<bool, RepositoryError> {
+        let task_id = task_id.to_string();
+        self.call(move |conn| {
+            let now = Utc::now().timestamp_millis();
+            let deleted = conn.execute(
+                "UPDATE tasks SET deleted_at = ?1, updated_at = ?1, version = version + 1
+                 WHERE task_global_id = ?2 AND deleted_at IS NULL",
+                params![now, task_id],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    async fn purge_deleted(&self, deleted_before: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.purge("deleted_at < ?1", deleted_before.timestamp_millis())
+            .await
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64, RepositoryError> {
+        self.purge("expires_at <= ?1", now.timestamp_millis()).await
+    }
+
+    async fn record_history(&self, entry: TaskHistoryEntry) -> Result<(), RepositoryError> {
+        self.call(move |conn| insert_history(conn, &entry)).await
+    }
+
+    async fn record_histories(
+        &self,
+        entries: Vec<TaskHistoryEntry>,
+    ) -> Result<(), RepositoryError> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            for entry in &entries {
+                insert_history(&tx, entry)?;
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn get_history(&self, task_id: &str) -> Result<Vec<TaskHistoryEntry>, RepositoryError> {
+        let task_id = task_id.to_string();
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT task_global_id, old_state, new_state, actor, timestamp, reason
+                 FROM task_history WHERE task_global_id = ?1
+                 ORDER BY timestamp, id",
+            )?;
+            let entries = stmt.query_map([task_id], row_to_history_entry)?.collect();
+            entries
+        })
+        .await
+    }
+
+    async fn save_worker(&self, worker: WorkerStatus) -> Result<(), RepositoryError> {
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO workers (worker_id, hostname, task_types, concurrency,
+                     current_tasks, registered_at, last_seen_at, load, processors)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    worker.worker_id,
+                    worker.hostname,
+                    to_json(&worker.task_types)?,
+                    worker.concurrency,
+                    to_json(&worker.current_tasks)?,
+                    worker.registered_at.timestamp_millis(),
+                    worker.last_seen_at.timestamp_millis(),
+                    worker.load.as_ref().map(to_json).transpose()?,
+                    to_json(&worker.processors)?,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_worker(&self, worker_id: &str) -> Result<Option<WorkerStatus>, RepositoryError> {
+        let worker_id = worker_id.to_string();
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT * FROM workers WHERE worker_id = ?1",
+                [worker_id],
+                row_to_worker,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn list_workers(&self) -> Result<Vec<WorkerStatus>, RepositoryError> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare("SELECT * FROM workers ORDER BY worker_id")?;
+            let workers = stmt.query_map([], row_to_worker)?.collect();
+            workers
+        })
+        .await
+    }
+}
+
+// Compare-and-swap on the version the caller read, returning whether the task was stored. A task
+// that's never been stored (version 0) is inserted, unless someone else stored it first. Updating
+// in place keeps the row id, so the task doesn't move between list pages.
+fn store_task(tx: &Transaction, task: &Task) -> rusqlite::Result<bool> {
+    let tags = to_json(&task.tags)?;
+    let metadata = to_json(&task.metadata)?;
+    let events = to_json(&task.events)?;
+    let expected = task.version as i64;
+    let now = Utc::now();
+    let params = params![
+        String::from(task.get_global_id()),
+        task.user_uuid,
+        task.task_uuid,
+        task.task_type.as_str(),
+        task.state.to_string(),
+        task.source_file,
+        task.result_file,
+        task.request_id,
+        tags,
+        task.archived_at.map(|at| at.timestamp_millis()),
+        now.timestamp_millis(),
+        expected,
+        task.expires_at.map(|at| at.timestamp_millis()),
+        task.priority.to_string(),
+        metadata,
+        events,
+    ];
+    // Only the insert sets created_at, an update keeps the stored one
+    let created_at = task.created_at.unwrap_or(now).timestamp_millis();
+    let id: Option<i64> = if expected == 0 {
+        tx.query_row(
+            "INSERT INTO tasks (task_global_id, user_uuid, task_uuid, task_type, state,
+                 source_file, result_file, request_id, tags, archived_at, updated_at, version,
+                 expires_at, priority, metadata, events, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12 + 1, ?13, ?14, ?15, ?16,
+                 ?17)
+             ON CONFLICT (task_global_id) DO NOTHING
+             RETURNING id",
+            params_from_iter(params.iter().copied().chain([&created_at as &dyn ToSql])),
+            |row| row.get(0),
+        )
+        .optional()?
+    } else {
+        tx.query_row(
+            "UPDATE tasks SET task_type = ?4, state = ?5, source_file = ?6,
+                 result_file = ?7, request_id = ?8, tags = ?9, archived_at = ?10,
+                 updated_at = ?11, version = ?12 + 1, expires_at = ?13, priority = ?14,
+                 metadata = ?15, events = ?16
+             WHERE task_global_id = ?1 AND user_uuid = ?2 AND task_uuid = ?3
+               AND version = ?12 AND deleted_at IS NULL
+             RETURNING id",
+            params,
+            |row| row.get(0),
+        )
+        .optional()?
+    };
+    let Some(id) = id else {
+        return Ok(false);
+    };
+
+    tx.execute("DELETE FROM tasks_fts WHERE rowid = ?1", [id])?;
+    tx.execute(
+        "INSERT INTO tasks_fts (rowid, task_type, source_file, result_file, tags)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            id,
+            task.task_type.as_str(),
+            task.source_file,
+            task.result_file,
+            task.tags.join(" "),
+        ],
+    )?;
+    Ok(true)
+}
+
+fn insert_history(conn: &Connection, entry: &TaskHistoryEntry) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO task_history
+             (task_global_id, old_state, new_state, actor, timestamp, reason)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            entry.task_global_id,
+            entry.old_state.map(|state| state.to_string()),
+            entry.new_state.to_string(),
+            entry.actor,
+            entry.timestamp.timestamp_millis(),
+            entry.reason,
+        ],
+    )?;
+    Ok(())
+}
+
+fn row_to_task(row: &Row) -> rusqlite::Result<Task> {
+    let tags = from_json(row.get("tags")?)?;
+
+    Ok(Task {
+        // The table's columns are the layout, so a row always reads as the current version
+        schema_version: Task::SCHEMA_VERSION,
+        user_uuid: row.get("user_uuid")?,
+        task_uuid: row.get("task_uuid")?,
+        task_type: row.get::<_, String>("task_type")?.into(),
+        state: parse_state(row.get("state")?)?,
+        priority: parse_priority(row.get("priority")?)?,
+        source_file: row.get("source_file")?,
+        result_file: row.get("result_file")?,
+        result_checksum: row.get("result_checksum")?,
+        request_id: row.get("request_id")?,
+        tags,
+        metadata: from_json(row.get("metadata")?)?,
+        archived_at: row
+            .get::<_, Option<i64>>("archived_at")?
+            .map(from_millis)
+            .transpose()?,
+        version: row.get("version")?,
+        expires_at: row
+            .get::<_, Option<i64>>("expires_at")?
+            .map(from_millis)
+            .transpose()?,
+        deleted_at: row
+            .get::<_, Option<i64>>("deleted_at")?
+            .map(from_millis)
+            .transpose()?,
+        last_heartbeat_at: row
+            .get::<_, Option<i64>>("last_heartbeat_at")?
+            .map(from_millis)
+            .transpose()?,
+        progress: row
+            .get::<_, Option<u8>>("progress_percent")?
+            .map(|percent| -> rusqlite::Result<TaskProgress> {
+                Ok(TaskProgress {
+                    percent,
+                    message: row.get("progress_message")?,
+                })
+            })
+            .transpose()?,
+        last_error: row.get("last_error")?,
+        retry_count: row.get("retry_count")?,
+        checkpoint: row.get("checkpoint")?,
+        created_at: Some(from_millis(row.get("created_at")?)?),
+        updated_at: Some(from_millis(row.get("updated_at")?)?),
+        events: from_json(row.get("events")?)?,
+    })
+}
+
+fn row_to_history_entry(row: &Row) -> rusqlite::Result<TaskHistoryEntry> {
+    Ok(TaskHistoryEntry {
+        task_global_id: row.get("task_global_id")?,
+        old_state: row
+            .get::<_, Option<String>>("old_state")?
+            .map(parse_state)
+            .transpose()?,
+        new_state: parse_state(row.get("new_state")?)?,
+        actor: row.get("actor")?,
+        timestamp: from_millis(row.get("timestamp")?)?,
+        reason: row.get("reason")?,
+    })
+}
+
+fn row_to_worker(row: &Row) -> rusqlite::Result<WorkerStatus> {
+    Ok(WorkerStatus {
+        worker_id: row.get("worker_id")?,
+        hostname: row.get("hostname")?,
+        task_types: from_json(row.get("task_types")?)?,
+        processors: from_json(row.get("processors")?)?,
+        concurrency: row.get("concurrency")?,
+        current_tasks: from_json(row.get("current_tasks")?)?,
+        registered_at: from_millis(row.get("registered_at")?)?,
+        last_seen_at: from_millis(row.get("last_seen_at")?)?,
+        load: row
+            .get::<_, Option<String>>("load")?
+            .map(from_json)
+            .transpose()?,
+    })
+}
+
+// The events column with the JSON event bound at the given parameter appended, dropping the oldest
+// once there are Task::MAX_EVENTS. A NULL event leaves it as it is.
+fn append_event(event: &str) -> String {
+    format!(
+        "CASE WHEN {event} IS NULL THEN events
+              WHEN json_array_length(events) >= {max}
+                  THEN json_insert(json_remove(events, '$[0]'), '$[#]', json({event}))
+              ELSE json_insert(events, '$[#]', json({event})) END",
+        max = Task::MAX_EVENTS
+    )
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+fn from_json<T: DeserializeOwned>(json: String) -> rusqlite::Result<T> {
+    serde_json::from_str(&json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))
+}
+
+fn parse_state(state: String) -> rusqlite::Result<TaskState> {
+    TaskState::from_str(&state)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))
+}
+
+fn parse_priority(priority: String) -> rusqlite::Result<TaskPriority> {
+    TaskPriority::from_str(&priority)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))
+}
+
+fn from_millis(millis: i64) -> rusqlite::Result<DateTime<Utc>> {
+    DateTime::from_timestamp_millis(millis)
+        .ok_or(rusqlite::Error::IntegralValueOutOfRange(0, millis))
+}
+
+// Turns free text into an FTS5 query matching any of the words, like Mongo's $search. Each word
+// is quoted so characters that mean something to FTS5 are searched for literally.
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" OR "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::task::TaskType;
+    use chrono::Duration;
+
+    fn repo() -> SqliteRepository {
+        SqliteRepository::init(&SqliteConfig {
+            path: ":memory:".to_string(),
+        })
+        .unwrap()
+    }
+
+    fn task(user: &str, task_type: &str, tags: &[&str]) -> Task {
+        let mut task = Task::new(user.to_string(), task_type.into(), "in.mp4".to_string());
+        task.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        task
+    }
+
+    fn page(cursor: Option<String>, limit: u32) -> Page {
+        Page { cursor, limit }
+    }
+
+    #[tokio::test]
+    async fn put_task_round_trips_and_upserts() {
+        let repo = repo();
+        let task = task("alice", "render", &["customer-x"]);
+        let id = task.get_global_id().to_string();
+        repo.put_task(task).await.unwrap();
+
+        let mut task = repo.get_task(id.clone()).await.unwrap().unwrap();
+        task.state = TaskState::Completed;
+        task.result_file = Some("out.mp4".to_string());
+        task.priority = TaskPriority::High;
+        task.metadata
+            .insert("customer".to_string(), "x".to_string());
+        repo.put_task(task).await.unwrap();
+
+        let stored = repo.get_task(id).await.unwrap().unwrap();
+        assert_eq!(stored.priority, TaskPriority::High);
+        assert_eq!(stored.metadata["customer"], "x");
+        assert_eq!(stored.state, TaskState::Completed);
+        assert_eq!(stored.result_file.as_deref(), Some("out.mp4"));
+        assert_eq!(stored.tags, vec!["customer-x"]);
+        assert!(repo
+            .get_task("missing".to_string())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_writes_are_rejected() {
+        let repo = repo();
+        let task = task("alice", "render", &[]);
+        let id = task.get_global_id().to_string();
+        repo.put_task(task.clone()).await.unwrap();
+        // Storing the same new task twice
+        assert!(matches!(
+            repo.put_task(task).await,
+            Err(RepositoryError::Conflict)
+        ));
+
+        let mut first = repo.get_task(id.clone()).await.unwrap().unwrap();
+        let mut second = first.clone();
+        first.state = TaskState::InProgress;
+        repo.put_task(first).await.unwrap();
+        second.state = TaskState::Failed;
+        assert!(matches!(
+            repo.put_task(second).await,
+            Err(RepositoryError::Conflict)
+        ));
+
+        let stored = repo.get_task(id).await.unwrap().unwrap();
+        assert_eq!(stored.state, TaskState::InProgress);
+        assert_eq!(stored.version, 2);
+    }
+
+    #[tokio::test]
+    async fn transition_only_applies_from_allowed_states() {
+        let repo = repo();
+        let task = task("alice", "render", &[]);
+        let id = task.get_global_id().to_string();
+        repo.put_task(task).await.unwrap();
+
+        let sources = TaskState::sources_for(TaskState::InProgress);
+        let previous = repo
+            .transition_task(&id, &sources, TaskState::InProgress, Default::default())
+            .await
+            .unwrap();
+        assert_eq!(previous.unwrap().state, TaskState::NotStarted);
+
+        // A second caller that also saw NotStarted loses
+        let lost = repo
+            .transition_task(&id, &sources, TaskState::InProgress, Default::default())
+            .await
+            .unwrap();
+        assert!(lost.is_none());
+
+        let stored = repo.get_task(id).await.unwrap().unwrap();
+        assert_eq!(stored.state, TaskState::InProgress);
+        assert_eq!(stored.version, 2);
+    }
+
+    #[tokio::test]
+    async fn transitions_keep_only_the_latest_events() {
+        let repo = repo();
+        let task = task("alice", "render", &[]);
+        let id = task.get_global_id().to_string();
+        repo.put_task(task).await.unwrap();
+
+        let (paused, waiting) = (TaskState::Paused, TaskState::NotStarted);
+        for n in 0..Task::MAX_EVENTS + 2 {
+            let (from, to) = if n % 2 == 0 {
+                (waiting, paused)
+            } else {
+                (paused, waiting)
+            };
+            let fields = TransitionFields {
+                event: Some(TaskEvent::new(to, "api".into(), Some(n.to_string()))),
+                ..Default::default()
+            };
+            repo.transition_task(&id, &[from], to, fields)
+                .await
+                .unwrap();
+        }
+
+        let events = repo.get_task(id).await.unwrap().unwrap().events;
+        assert_eq!(events.len(), Task::MAX_EVENTS);
+        assert_eq!(events[0].note.as_deref(), Some("2"));
+        assert_eq!(
+            events.last().unwrap().note,
+            Some((Task::MAX_EVENTS + 1).to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn retried_transitions_bump_the_retry_count() {
+        let repo = repo();
+        let task = task("alice", "render", &[]);
+        let id = task.get_global_id().to_string();
+        repo.put_task(task).await.unwrap();
+
+        let failed = TransitionFields {
+            last_error: Some("out of disk".to_string()),
+            ..Default::default()
+        };
+        repo.transition_task(&id, &[TaskState::NotStarted], TaskState::Failed, failed)
+            .await
+            .unwrap();
+        let retried = TransitionFields {
+            last_error: Some("out of disk".to_string()),
+            retried: true,
+            ..Default::default()
+        };
+        repo.transition_task(&id, &[TaskState::Failed], TaskState::NotStarted, retried)
+            .await
+            .unwrap();
+
+        let stored = repo.get_task(id.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.retry_count, 1);
+        assert_eq!(stored.last_error.as_deref(), Some("out of disk"));
+
+        // Writing the whole task leaves the count alone
+        repo.put_task(stored).await.unwrap();
+        assert_eq!(repo.get_task(id).await.unwrap().unwrap().retry_count, 1);
+    }
+
+    #[tokio::test]
+    async fn batch_writes_apply_per_task() {
+        let repo = repo();
+        let first = task("alice", "render", &["a"]);
+        let second = task("bob", "render", &[]);
+        let ids = vec![first.get_global_id().into(), second.get_global_id().into()];
+        let stored = repo
+            .put_tasks(vec![first.clone(), second, first])
+            .await
+            .unwrap();
+        // The repeat is a second insert of the same new task
+        assert_eq!(stored, [true, true, false]);
+
+        let mut tasks = repo.get_tasks(&ids).await.unwrap();
+        assert_eq!(tasks.len(), 2);
+        tasks.sort_by(|a, b| a.user_uuid.cmp(&b.user_uuid));
+
+        // Alice's task changes after being read, so only Bob's moves
+        let mut changed = tasks[0].clone();
+        changed.tags.clear();
+        repo.put_task(changed).await.unwrap();
+        let event = TaskEvent::new(TaskState::InProgress, "admin".into(), None);
+        let moved = repo
+            .transition_many(&tasks, TaskState::InProgress, Some(event.clone()))
+            .await
+            .unwrap();
+        assert_eq!(moved, [false, true]);
+
+        let bob = repo.get_task(ids[1].clone()).await.unwrap().unwrap();
+        assert_eq!(bob.state, TaskState::InProgress);
+        assert_eq!(bob.version, 2);
+        assert_eq!(bob.events, [event]);
+    }
+
+    #[tokio::test]
+    async fn create_tasks_keeps_nothing_on_conflict() {
+        let repo = repo();
+        let existing = task("alice", "render", &[]);
+        repo.put_task(existing.clone()).await.unwrap();
+
+        let fresh = task("bob", "render", &[]);
+        let fresh_id = fresh.get_global_id().to_string();
+        let history = vec![TaskHistoryEntry::new(
+            fresh_id.clone(),
+            None,
+            TaskState::NotStarted,
+            "api".to_string(),
+            None,
+        )];
+        let result = repo
+            .create_tasks(vec![fresh.clone(), existing], history.clone())
+            .await;
+        assert!(matches!(result, Err(RepositoryError::Conflict)));
+        assert!(repo.get_task(fresh_id.clone()).await.unwrap().is_none());
+        assert!(repo.get_history(&fresh_id).await.unwrap().is_empty());
+
+        repo.create_tasks(vec![fresh], history).await.unwrap();
+        let stored = repo.get_task(fresh_id.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.version, 1);
+        assert_eq!(repo.get_history(&fresh_id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn list_tasks_filters_and_pages_newest_first() {
+        let repo = repo();
+        for i in 0..5 {
+            let tags: &[&str] = if i % 2 == 0 { &["even"] } else { &[] };
+            repo.put_task(task("alice", &format!("type-{i}"), tags))
+                .await
+                .unwrap();
+        }
+        repo.put_task(task("bob", "other", &["even"]))
+            .await
+            .unwrap();
+
+        let alice = TaskFilter {
+            user_uuid: Some("alice".to_string()),
+            ..TaskFilter::default()
+        };
+        let first = repo.list_tasks(alice.clone(), page(None, 3)).await.unwrap();
+        let types: Vec<_> = first
+            .items
+            .iter()
+            .map(|task| task.task_type.as_str())
+            .collect();
+        assert_eq!(types, ["type-4", "type-3", "type-2"]);
+
+        let second = repo
+            .list_tasks(alice, page(first.next_cursor, 3))
+            .await
+            .unwrap();
+        let types: Vec<_> = second
+            .items
+            .iter()
+            .map(|task| task.task_type.as_str())
+            .collect();
+        assert_eq!(types, ["type-1", "type-0"]);
+        assert!(second.next_cursor.is_none());
+
+        let tagged = TaskFilter {
+            tag: Some("even".to_string()),
+            ..TaskFilter::default()
+        };
+        let tagged = repo.list_tasks(tagged, page(None, 10)).await.unwrap();
+        assert_eq!(tagged.items.len(), 4);
+
+        assert!(matches!(
+            repo.list_tasks(
+                TaskFilter::default(),
+                page(Some("not a cursor".to_string()), 10)
+            )
+            .await,
+            Err(RepositoryError::InvalidCursor)
+        ));
+    }
+
+    #[tokio::test]
+    async fn list_tasks_filters_on_state_type_and_time() {
+        let repo = repo();
+        let before = Utc::now() - Duration::seconds(1);
+        let mut completed = task("alice", "render", &[]);
+        completed.state = TaskState::Completed;
+        repo.put_task(completed).await.unwrap();
+        repo.put_task(task("alice", "render", &[])).await.unwrap();
+        repo.put_task(task("alice", "transcode", &[]))
+            .await
+            .unwrap();
+
+        let filter = TaskFilter {
+            state: Some(TaskState::NotStarted),
+            task_type: Some("render".to_string()),
+            ..TaskFilter::default()
+        };
+        let found = repo.list_tasks(filter, page(None, 10)).await.unwrap();
+        assert_eq!(found.items.len(), 1);
+        assert_eq!(found.items[0].state, TaskState::NotStarted);
+
+        let recent = TaskFilter {
+            updated_after: Some(before),
+            ..TaskFilter::default()
+        };
+        assert_eq!(
+            repo.list_tasks(recent, page(None, 10))
+                .await
+                .unwrap()
+                .items
+                .len(),
+            3
+        );
+        let old = TaskFilter {
+            updated_before: Some(before),
+            ..TaskFilter::default()
+        };
+        assert!(repo
+            .list_tasks(old, page(None, 10))
+            .await
+            .unwrap()
+            .items
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_ranks_and_pages_matches() {
+        let repo = repo();
+        repo.put_task(task("alice", "render video", &["video"]))
+            .await
+            .unwrap();
+        repo.put_task(task("alice", "render", &[])).await.unwrap();
+        repo.put_task(task("bob", "render", &[])).await.unwrap();
+        repo.put_task(task("alice", "transcode", &[]))
+            .await
+            .unwrap();
+
+        let alice = TaskFilter {
+            user_uuid: Some("alice".to_string()),
+            ..TaskFilter::default()
+        };
+        let first = repo
+            .search_tasks("video render", alice.clone(), page(None, 1))
+            .await
+            .unwrap();
+        assert_eq!(first.items[0].0.task_type.as_str(), "render video");
+
+        let rest = repo
+            .search_tasks("video render", alice, page(first.next_cursor, 1))
+            .await
+            .unwrap();
+        assert_eq!(rest.items[0].0.task_type, TaskType::Render);
+        assert_eq!(rest.items[0].0.user_uuid, "alice");
+        assert!(rest.items[0].1 <= first.items[0].1);
+        assert!(rest.next_cursor.is_none());
+    }
+
+    #[tokio::test]
+    async fn purge_expired_removes_tasks_past_retention() {
+        let repo = repo();
+        let mut expired = task("alice", "render", &[]);
+        expired.expires_at = Some(Utc::now() - Duration::hours(1));
+        let expired_id = expired.get_global_id().to_string();
+        let mut kept = task("alice", "render", &[]);
+        kept.expires_at = Some(Utc::now() + Duration::days(1));
+        let kept_id = kept.get_global_id().to_string();
+        repo.put_tasks(vec![expired, kept, task("alice", "render", &[])])
+            .await
+            .unwrap();
+
+        assert_eq!(repo.purge_expired(Utc::now()).await.unwrap(), 1);
+        assert!(repo.get_task(expired_id).await.unwrap().is_none());
+        let kept = repo.get_task(kept_id).await.unwrap().unwrap();
+        assert!(kept.expires_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn purge_removes_old_archived_tasks_and_history() {
+        let repo = repo();
+        let mut archived = task("alice", "render", &[]);
+        archived.archived_at = Some(Utc::now() - Duration::days(40));
+        let archived_id = archived.get_global_id().to_string();
+        repo.record_history(TaskHistoryEntry::new(
+            archived_id.clone(),
+            None,
+            TaskState::NotStarted,
+            "api".to_string(),
+            None,
+        ))
+        .await
+        .unwrap();
+        repo.put_task(archived).await.unwrap();
+        repo.put_task(task("alice", "render", &[])).await.unwrap();
+
+        let removed = repo
+            .purge_archived(Utc::now() - Duration::days(30))
+            .await
+            .unwrap();
+
+        assert_eq!(removed, 1);
+        assert!(repo.get_task(archived_id.clone()).await.unwrap().is_none());
+        assert!(repo.get_history(&archived_id).await.unwrap().is_empty());
+        let everything = TaskFilter {
+            include_archived: true,
+            ..TaskFilter::default()
+        };
+        let remaining = repo.list_tasks(everything, page(None, 10)).await.unwrap();
+        assert_eq!(remaining.items.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stats_count_states_and_time_completions() {
+        let repo = repo();
+        let started = Utc::now() - Duration::hours(2);
+        let mut entries = Vec::new();
+        for (task_type, minutes) in [("render", 10), ("render", 30), ("encode", 60)] {
+            let mut done = task("alice", task_type, &[]);
+            done.state = TaskState::Completed;
+            let task_id = done.get_global_id().to_string();
+            repo.put_task(done).await.unwrap();
+            let mut submitted = TaskHistoryEntry::new(
+                task_id.clone(),
+                None,
+                TaskState::NotStarted,
+                "api".to_string(),
+                None,
+            );
+            submitted.timestamp = started;
+            let mut completed = TaskHistoryEntry::new(
+                task_id,
+                Some(TaskState::InProgress),
+                TaskState::Completed,
+                "worker".to_string(),
+                None,
+            );
+            completed.timestamp = started + Duration::minutes(minutes);
+            entries.extend([submitted, completed]);
+        }
+        repo.record_histories(entries).await.unwrap();
+        repo.put_task(task("bob", "render", &[])).await.unwrap();
+
+        let counts = repo.count_by_state().await.unwrap();
+        assert_eq!(counts.get(&TaskState::Completed), Some(&3));
+        assert_eq!(counts.get(&TaskState::NotStarted), Some(&1));
+        assert_eq!(counts.get(&TaskState::Failed), None);
+
+        let since = started + Duration::minutes(20);
+        assert_eq!(repo.throughput_since(since).await.unwrap(), 2);
+
+        let render = repo.avg_completion_time(Some("render")).await.unwrap();
+        assert_eq!(render, Some(20.0 * 60.0));
+        let all = repo.avg_completion_time(None).await.unwrap();
+        assert!((all.unwrap() - 100.0 / 3.0 * 60.0).abs() < 0.01);
+        assert_eq!(repo.avg_completion_time(Some("scan")).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn deleted_tasks_are_hidden_until_purged() {
+        let repo = repo();
+        let deleted = task("alice", "render", &[]);
+        let deleted_id = deleted.get_global_id().to_string();
+        repo.put_task(deleted.clone()).await.unwrap();
+        repo.put_task(task("alice", "render", &[])).await.unwrap();
+
+        assert!(repo.delete_task(&deleted_id).await.unwrap());
+        assert!(!repo.delete_task(&deleted_id).await.unwrap());
+        assert!(repo.get_task(deleted_id.clone()).await.unwrap().is_none());
+        let listed = repo
+            .list_tasks(TaskFilter::default(), page(None, 10))
+            .await
+            .unwrap();
+        assert_eq!(listed.items.len(), 1);
+        // Writes against the deleted task are refused rather than bringing it back
+        let mut stale = deleted;
+        stale.version = 1;
+        assert!(repo.put_task(stale).await.is_err());
+
+        // Not old enough yet
+        let cutoff = Utc::now() - Duration::days(30);
+        assert_eq!(repo.purge_deleted(cutoff).await.unwrap(), 0);
+        let cutoff = Utc::now() + Duration::seconds(1);
+        assert_eq!(repo.purge_deleted(cutoff).await.unwrap(), 1);
+        let everything = TaskFilter {
+            include_archived: true,
+            ..TaskFilter::default()
+        };
+        let remaining = repo.list_tasks(everything, page(None, 10)).await.unwrap();
+        assert_eq!(remaining.items.len(), 1);
+    }
+}
@@ -0,0 +1,28 @@
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::{self, BufReader};
+
+// Builds the rustls server config from a PEM certificate chain and private key
+pub fn load_rustls_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig> {
+    let mut cert_reader = BufReader::new(File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", cert_path),
+        ));
+    }
+
+    let mut key_reader = BufReader::new(File::open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut key_reader)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No private key found in {}", key_path),
+        )
+    })?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)
+}
@@ -0,0 +1,52 @@
+// Serde helpers for timestamps. They go out as RFC 3339 strings in JSON but are stored as native
+// BSON dates so Mongo can compare and index them. The BSON (de)serializers used by the driver
+// aren't human readable, which is how the two are told apart. Without the bson feature they're
+// always strings.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub fn serialize<S: Serializer>(value: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    #[cfg(feature = "bson")]
+    if !serializer.is_human_readable() {
+        return bson::DateTime::from_chrono(*value).serialize(serializer);
+    }
+    value.serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    #[cfg(feature = "bson")]
+    if !deserializer.is_human_readable() {
+        return bson::DateTime::deserialize(deserializer).map(bson::DateTime::to_chrono);
+    }
+    DateTime::deserialize(deserializer)
+}
+
+pub mod optional {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            #[cfg(feature = "bson")]
+            Some(value) if !serializer.is_human_readable() => {
+                serializer.serialize_some(&bson::DateTime::from_chrono(*value))
+            }
+            value => value.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        #[cfg(feature = "bson")]
+        if !deserializer.is_human_readable() {
+            return Ok(
+                Option::<bson::DateTime>::deserialize(deserializer)?.map(bson::DateTime::to_chrono)
+            );
+        }
+        Option::deserialize(deserializer)
+    }
+}
@@ -0,0 +1,8 @@
+// What the API and the worker say to each other: the task model, the messages queued for each
+// task and the bodies of the calls the worker makes. Both build against these, so the two sides
+// can't drift apart.
+pub mod datetime;
+pub mod message;
+pub mod requests;
+pub mod state_machine;
+pub mod task;
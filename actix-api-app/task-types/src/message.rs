@@ -0,0 +1,30 @@
+use crate::task::{Task, TaskGlobalId};
+use serde::{Deserialize, Serialize};
+
+// What the workers are sent for each task
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TaskMessage {
+    pub task_global_id: TaskGlobalId,
+    // Unix millis the task mustn't run before, only set by backends that can't hold a message
+    // back for as long as it was delayed (the API's queue::sqs). Left out otherwise, so messages
+    // for the same task always compare equal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due_at: Option<i64>,
+    // How many times processing it has already failed, counted up by the worker as it retries
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub attempts: u32,
+}
+
+impl TaskMessage {
+    pub fn new(task: &Task) -> Self {
+        Self {
+            task_global_id: task.get_global_id(),
+            due_at: None,
+            attempts: 0,
+        }
+    }
+}
+
+fn is_zero(attempts: &u32) -> bool {
+    *attempts == 0
+}
@@ -0,0 +1,56 @@
+// Bodies of the calls a worker makes to the API. Progress reports are a task::TaskProgress.
+use crate::task::TaskType;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskCompletionRequest {
+    pub result_file: String,
+    // Hex SHA-256 of the result, optional for workers that predate it
+    #[serde(default)]
+    pub result_checksum: Option<String>,
+}
+
+// Replaces the task's checkpoint, base64 encoded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CheckpointRequest {
+    pub checkpoint: String,
+}
+
+// Sent by a worker as it starts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegisterWorkerRequest {
+    pub worker_id: String,
+    pub hostname: String,
+    // Empty if it takes every type
+    #[serde(default)]
+    pub task_types: Vec<String>,
+    // Task types it has a processor for, empty from workers that predate reporting them
+    #[serde(default)]
+    pub processors: Vec<TaskType>,
+    pub concurrency: u32,
+}
+
+// Sent by a worker every status interval
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerStatusRequest {
+    pub current_tasks: Vec<String>,
+}
+
+// Sent by a worker every autoscale interval, what the API's autoscaling hint is worked out from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoadReport {
+    pub worker_id: String,
+    #[serde(flatten)]
+    pub load: WorkerLoad,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorkerLoad {
+    // Messages waiting on the queues the worker reads, not yet handed to any worker
+    pub queue_depth: u64,
+    // Mean seconds the tasks it finished since its last report took, None if it finished none
+    #[serde(default)]
+    pub avg_processing_secs: Option<f64>,
+    // Share of its task slots in use when it reported, 0 to 1
+    pub utilization: f64,
+}
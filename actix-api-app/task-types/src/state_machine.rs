@@ -0,0 +1,153 @@
+use crate::task::TaskState;
+use std::fmt;
+
+// Why a task can't move from one state to another
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TransitionReason {
+    // The task is in the target state already
+    AlreadyInState,
+    // Completed and Cancelled tasks never move again
+    Finished,
+    // Only a task being processed can complete
+    NotInProgress,
+    // A failed task can only be retried, waiting for another attempt or started again straight
+    // away
+    NotRetried,
+}
+
+impl fmt::Display for TransitionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyInState => write!(f, "the task is in that state already"),
+            Self::Finished => write!(f, "the task has finished and can't move again"),
+            Self::NotInProgress => write!(f, "only a task in progress can complete"),
+            Self::NotRetried => write!(f, "a failed task can only be retried"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TransitionError {
+    pub from: TaskState,
+    pub to: TaskState,
+    pub reason: TransitionReason,
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Task can't move from {} to {}: {}",
+            self.from, self.to, self.reason
+        )
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+// The one place that says which transitions are legal, the rules are checked in order so each
+// illegal transition is put down to the most specific reason
+pub fn check(from: TaskState, to: TaskState) -> Result<(), TransitionError> {
+    use TaskState::*;
+    let reason = match (from, to) {
+        _ if from == to => TransitionReason::AlreadyInState,
+        (Completed | Cancelled, _) => TransitionReason::Finished,
+        (_, Completed) if from != InProgress => TransitionReason::NotInProgress,
+        (Failed, Paused | Cancelled) => TransitionReason::NotRetried,
+        _ => return Ok(()),
+    };
+    Err(TransitionError { from, to, reason })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_transition_is_legal_or_illegal_as_the_table_says() {
+        use TaskState::*;
+        let legal = [
+            (NotStarted, Queued),
+            (NotStarted, InProgress),
+            (NotStarted, Paused),
+            (NotStarted, Failed),
+            (NotStarted, Cancelled),
+            (Queued, NotStarted),
+            (Queued, InProgress),
+            (Queued, Paused),
+            (Queued, Failed),
+            (Queued, Cancelled),
+            (InProgress, NotStarted),
+            (InProgress, Queued),
+            (InProgress, Completed),
+            (InProgress, Paused),
+            (InProgress, Failed),
+            (InProgress, Cancelled),
+            (Paused, NotStarted),
+            (Paused, Queued),
+            (Paused, InProgress),
+            (Paused, Failed),
+            (Paused, Cancelled),
+            (Failed, NotStarted),
+            (Failed, Queued),
+            (Failed, InProgress),
+        ];
+
+        for from in TaskState::ALL {
+            for to in TaskState::ALL {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    legal.contains(&(from, to)),
+                    "{from} -> {to}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn finished_tasks_stay_finished() {
+        for state in [TaskState::Completed, TaskState::Cancelled] {
+            assert!(TaskState::ALL
+                .into_iter()
+                .all(|target| !state.can_transition_to(target)));
+        }
+        assert_eq!(
+            TaskState::sources_for(TaskState::Completed),
+            [TaskState::InProgress]
+        );
+    }
+
+    #[test]
+    fn every_illegal_transition_says_why() {
+        use TaskState::*;
+        for from in TaskState::ALL {
+            for to in TaskState::ALL {
+                let expected = if from == to {
+                    Some(TransitionReason::AlreadyInState)
+                } else if matches!(from, Completed | Cancelled) {
+                    Some(TransitionReason::Finished)
+                } else if to == Completed && from != InProgress {
+                    Some(TransitionReason::NotInProgress)
+                } else if from == Failed && matches!(to, Paused | Cancelled) {
+                    Some(TransitionReason::NotRetried)
+                } else {
+                    None
+                };
+                let result = check(from, to);
+                assert_eq!(
+                    result.map_err(|e| e.reason).err(),
+                    expected,
+                    "{from} -> {to}"
+                );
+                assert_eq!(result.is_ok(), from.can_transition_to(to));
+            }
+        }
+
+        let error = check(Paused, Completed).unwrap_err();
+        assert_eq!((error.from, error.to), (Paused, Completed));
+        assert_eq!(
+            error.to_string(),
+            "Task can't move from Paused to Completed: only a task in progress can complete"
+        );
+    }
+}
@@ -0,0 +1,514 @@
+use crate::state_machine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use strum_macros::{Display, EnumString};
+use uuid::Uuid;
+
+#[derive(EnumString, Display, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum TaskState {
+    // Stored and sent to the queue (or held back for its run_at), waiting for a worker
+    NotStarted,
+    // Accepted onto the queue, not set yet since workers start tasks straight from NotStarted
+    Queued,
+    InProgress,
+    Completed,
+    Paused,
+    Failed,
+    // Called off before it finished, e.g. by a bulk cancel, and won't be processed again
+    Cancelled,
+}
+
+impl TaskState {
+    pub const ALL: [TaskState; 7] = [
+        TaskState::NotStarted,
+        TaskState::Queued,
+        TaskState::InProgress,
+        TaskState::Completed,
+        TaskState::Paused,
+        TaskState::Failed,
+        TaskState::Cancelled,
+    ];
+
+    // See state_machine::check for the rules and why a transition isn't allowed
+    pub fn can_transition_to(self, state: TaskState) -> bool {
+        state_machine::check(self, state).is_ok()
+    }
+
+    // Every state a task may be in for it to move to the target state
+    pub fn sources_for(target: TaskState) -> Vec<TaskState> {
+        TaskState::ALL
+            .into_iter()
+            .filter(|state| state.can_transition_to(target))
+            .collect()
+    }
+}
+
+// The strum names are the one representation of a state, in JSON, in every database and in queue
+// messages, so serde goes through them too
+impl Serialize for TaskState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = String::deserialize(deserializer)?;
+        TaskState::from_str(&state)
+            .map_err(|_| serde::de::Error::custom(format!("invalid task state: {}", state)))
+    }
+}
+
+// What processing a task is for. The worker's built-in processors have variants of their own,
+// the types its command and plugin config add are Custom. Serialized as the bare name, so a task
+// stored as "render" before this existed loads as Render.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum TaskType {
+    Render,
+    Custom(String),
+}
+
+impl TaskType {
+    // Types every worker can process without any config
+    pub const BUILT_IN: [TaskType; 1] = [TaskType::Render];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            TaskType::Render => "render",
+            TaskType::Custom(name) => name,
+        }
+    }
+
+    // The name ends up in queue stream names and config keys, so it's kept to letters, digits,
+    // dashes and underscores
+    pub fn is_valid(&self) -> bool {
+        let name = self.as_str();
+        !name.is_empty()
+            && name.len() <= 64
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    pub fn is_built_in(&self) -> bool {
+        !matches!(self, TaskType::Custom(_))
+    }
+}
+
+impl From<&str> for TaskType {
+    fn from(name: &str) -> Self {
+        match name {
+            "render" => TaskType::Render,
+            name => TaskType::Custom(name.to_string()),
+        }
+    }
+}
+
+impl From<String> for TaskType {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "render" => TaskType::Render,
+            _ => TaskType::Custom(name),
+        }
+    }
+}
+
+impl fmt::Display for TaskType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for TaskType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(TaskType::from)
+    }
+}
+
+// How urgently a task should be picked up, ordered Low < Normal < High. Serialized by name like
+// TaskState.
+#[derive(
+    EnumString, Display, Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash,
+)]
+pub enum TaskPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Serialize for TaskPriority {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskPriority {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let priority = String::deserialize(deserializer)?;
+        TaskPriority::from_str(&priority)
+            .map_err(|_| serde::de::Error::custom(format!("invalid task priority: {}", priority)))
+    }
+}
+
+// How a task is addressed everywhere outside storage, "{user_uuid}_{task_uuid}". The task uuid
+// never has an underscore in it, so the id is split at the last one and the user part may have
+// its own. Submissions are checked with `valid_user` before a task is made, so every stored
+// task's id parses back.
+const MAX_USER_LEN: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TaskGlobalId {
+    id: String,
+    // Where the underscore between the two parts is
+    split: usize,
+}
+
+impl TaskGlobalId {
+    pub fn new(user_uuid: &str, task_uuid: &str) -> TaskGlobalId {
+        TaskGlobalId {
+            id: format!("{}_{}", user_uuid, task_uuid),
+            split: user_uuid.len(),
+        }
+    }
+
+    // What a user id may be: up to 128 ASCII letters, digits, '-', '_' and '.'
+    pub fn valid_user(user: &str) -> bool {
+        (1..=MAX_USER_LEN).contains(&user.len())
+            && user
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    }
+
+    pub fn user(&self) -> &str {
+        &self.id[..self.split]
+    }
+
+    pub fn task(&self) -> &str {
+        &self.id[self.split + 1..]
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTaskGlobalId(String);
+
+impl fmt::Display for InvalidTaskGlobalId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid task id: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTaskGlobalId {}
+
+impl FromStr for TaskGlobalId {
+    type Err = InvalidTaskGlobalId;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        match id.rsplit_once('_') {
+            Some((user, task))
+                if TaskGlobalId::valid_user(user) && Uuid::parse_str(task).is_ok() =>
+            {
+                Ok(TaskGlobalId::new(user, task))
+            }
+            _ => Err(InvalidTaskGlobalId(id.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for TaskGlobalId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+impl AsRef<str> for TaskGlobalId {
+    fn as_ref(&self) -> &str {
+        &self.id
+    }
+}
+
+// Storage keys tasks by the plain string, this saves spelling out as_str() at every call
+impl std::ops::Deref for TaskGlobalId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.id
+    }
+}
+
+impl From<TaskGlobalId> for String {
+    fn from(id: TaskGlobalId) -> String {
+        id.id
+    }
+}
+
+impl Serialize for TaskGlobalId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.id)
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskGlobalId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        id.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// Also the stored form of a task. Fields added after the first release default when missing so
+// older documents still load, anything a default can't cover is fixed up by Task::upgrade as the
+// task is read. The derived (de)serializers are wrapped below to do that.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(remote = "Self")]
+pub struct Task {
+    // Which layout the task was written with, missing on documents from before it was versioned
+    #[serde(default)]
+    pub schema_version: u32,
+    pub user_uuid: String,
+    pub task_uuid: String,
+    pub task_type: TaskType,
+    pub state: TaskState,
+    // Given on submission, decides which queue the task goes on where the backend routes by it
+    #[serde(default)]
+    pub priority: TaskPriority,
+    pub source_file: String,
+    pub result_file: Option<String>,
+    // Hex SHA-256 of the result file as the worker uploaded it, to check a download against.
+    // Set along with result_file on completion.
+    #[serde(default)]
+    pub result_checksum: Option<String>,
+    // X-Request-Id of the submission, lets the worker's follow-up calls be traced back to it
+    #[serde(default)]
+    pub request_id: Option<String>,
+    // Free-form labels for grouping related tasks, e.g. ["render", "customer-x"]
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Whatever the submitter wants kept with the task, e.g. a correlation id or renderer settings.
+    // Opaque to the service.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    // Archived tasks are left out of listings by default and purged after the retention window
+    #[serde(default, with = "crate::datetime::optional")]
+    pub archived_at: Option<DateTime<Utc>>,
+    // Bumped on every write, so concurrent writers can't silently overwrite each other
+    #[serde(default)]
+    pub version: u64,
+    // Set on completion from the retention config, the task is deleted once this passes
+    #[serde(default, with = "crate::datetime::optional")]
+    pub expires_at: Option<DateTime<Utc>>,
+    // Deleted tasks are kept (hidden from everything) until the deletion retention passes
+    #[serde(default, with = "crate::datetime::optional")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    // When the worker processing the task last checked in. Heartbeats aren't writes, they leave
+    // the version alone.
+    #[serde(default, with = "crate::datetime::optional")]
+    pub last_heartbeat_at: Option<DateTime<Utc>>,
+    // How far along the worker last said it was, reported at last_heartbeat_at. Left as it was
+    // once the task moves on.
+    #[serde(default)]
+    pub progress: Option<TaskProgress>,
+    // Why the task failed, the reason given when it was moved to Failed. Kept when it's retried,
+    // cleared once it moves on to any other state.
+    #[serde(default)]
+    pub last_error: Option<String>,
+    // How many times the task has been sent back for another attempt after failing
+    #[serde(default)]
+    pub retry_count: u32,
+    // Where the worker got to, saved by long-running processing so a redelivered task picks up
+    // from there. Opaque to the API (base64 as the worker sends it), dropped once it's Completed.
+    #[serde(default)]
+    pub checkpoint: Option<String>,
+    // Set by the repository, created_at on the first write and updated_at on every one after.
+    // Task::new fills both in so a task has them before it's stored.
+    #[serde(default, with = "crate::datetime::optional")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default, with = "crate::datetime::optional")]
+    pub updated_at: Option<DateTime<Utc>>,
+    // The latest state changes, oldest first, appended by the repository along with each
+    // transition. Only the last MAX_EVENTS are kept.
+    #[serde(default)]
+    pub events: Vec<TaskEvent>,
+}
+
+// A state change as kept on the task itself, a lighter copy of the history entry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TaskEvent {
+    // The state the task moved to
+    pub state: TaskState,
+    #[serde(with = "crate::datetime")]
+    pub at: DateTime<Utc>,
+    // Who made the change, e.g. "api", "worker" or "admin"
+    pub actor: String,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+impl TaskEvent {
+    pub fn new(state: TaskState, actor: String, note: Option<String>) -> TaskEvent {
+        TaskEvent {
+            state,
+            at: Utc::now(),
+            actor,
+            note,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TaskProgress {
+    // 0 to 100
+    pub percent: u8,
+    // What it's doing, e.g. "rendering frame 120 of 400"
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+impl Serialize for Task {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Task::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Task {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut task = Task::deserialize(deserializer)?;
+        task.upgrade();
+        Ok(task)
+    }
+}
+
+impl Task {
+    // Bumped whenever a change to the stored layout needs more than a serde default to read older
+    // tasks, with a step for it added to upgrade
+    pub const SCHEMA_VERSION: u32 = 1;
+
+    // Enough for a task's usual life and a few retries, while keeping the document small
+    pub const MAX_EVENTS: usize = 50;
+
+    pub fn new(user_uuid: String, task_type: TaskType, source_file: String) -> Task {
+        let now = Utc::now();
+        Task {
+            schema_version: Task::SCHEMA_VERSION,
+            user_uuid,
+            task_uuid: Uuid::new_v4().to_string(),
+            task_type,
+            state: TaskState::NotStarted,
+            priority: TaskPriority::default(),
+            source_file,
+            result_file: None,
+            result_checksum: None,
+            request_id: None,
+            tags: Vec::new(),
+            metadata: HashMap::new(),
+            archived_at: None,
+            version: 0,
+            expires_at: None,
+            deleted_at: None,
+            last_heartbeat_at: None,
+            progress: None,
+            last_error: None,
+            retry_count: 0,
+            checkpoint: None,
+            created_at: Some(now),
+            updated_at: Some(now),
+            events: Vec::new(),
+        }
+    }
+
+    // Appends the event, dropping the oldest ones beyond MAX_EVENTS
+    pub fn push_event(&mut self, event: TaskEvent) {
+        self.events.push(event);
+        let overflow = self.events.len().saturating_sub(Task::MAX_EVENTS);
+        self.events.drain(..overflow);
+    }
+
+    // Brings a task read from storage up to the current layout, one version at a time. A task
+    // written by a newer release is left as it is, whatever it has that this one doesn't know
+    // about is dropped on reading anyway.
+    fn upgrade(&mut self) {
+        if self.schema_version < 1 {
+            // Written before created_at and updated_at were set on every write, so either may be
+            // missing. The one that's there stands in for the other.
+            self.updated_at = self.updated_at.or(self.created_at);
+            self.created_at = self.created_at.or(self.updated_at);
+        }
+        self.schema_version = self.schema_version.max(Task::SCHEMA_VERSION);
+    }
+
+    pub fn get_global_id(&self) -> TaskGlobalId {
+        TaskGlobalId::new(&self.user_uuid, &self.task_uuid)
+    }
+
+    pub fn can_transition_to(&self, state: &TaskState) -> bool {
+        self.state.can_transition_to(*state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_capped_dropping_the_oldest() {
+        let mut task = Task::new("alice".into(), "render".into(), "in.mp4".into());
+        for n in 0..Task::MAX_EVENTS + 3 {
+            task.push_event(TaskEvent::new(
+                TaskState::Paused,
+                "api".into(),
+                Some(n.to_string()),
+            ));
+        }
+        assert_eq!(task.events.len(), Task::MAX_EVENTS);
+        assert_eq!(task.events[0].note.as_deref(), Some("3"));
+
+        // Missing from tasks stored before events were kept
+        let mut json = serde_json::to_value(&task).unwrap();
+        json.as_object_mut().unwrap().remove("events");
+        assert!(serde_json::from_value::<Task>(json)
+            .unwrap()
+            .events
+            .is_empty());
+    }
+
+    #[test]
+    fn new_states_round_trip_by_name() {
+        for state in [TaskState::Queued, TaskState::Cancelled] {
+            let json = serde_json::to_value(state).unwrap();
+            assert_eq!(json, state.to_string());
+            assert_eq!(serde_json::from_value::<TaskState>(json).unwrap(), state);
+        }
+    }
+
+    #[test]
+    fn global_ids_are_checked_when_parsed() {
+        let task_uuid = uuid::Uuid::new_v4().to_string();
+        let id: TaskGlobalId = format!("some_user_{task_uuid}").parse().unwrap();
+        assert_eq!(id.user(), "some_user");
+        assert_eq!(id.task(), task_uuid);
+        assert_eq!(id, TaskGlobalId::new("some_user", &task_uuid));
+
+        for bad in [
+            "missing".to_string(),
+            format!("_{task_uuid}"),
+            "alice_notauuid".into(),
+            format!("alice smith_{task_uuid}"),
+            format!("{}_{task_uuid}", "a".repeat(129)),
+        ] {
+            assert!(bad.parse::<TaskGlobalId>().is_err(), "{bad}");
+            assert!(serde_json::from_value::<TaskGlobalId>(bad.into()).is_err());
+        }
+    }
+}
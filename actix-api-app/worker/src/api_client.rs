@@ -0,0 +1,64 @@
+use crate::config::WorkerConfig;
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Client, Response, StatusCode};
+use reqwest_middleware::ClientBuilder;
+use reqwest_retry::{
+    default_on_request_failure, default_on_request_success, policies::ExponentialBackoff,
+    RetryTransientMiddleware, Retryable, RetryableStrategy,
+};
+use std::time::Duration;
+
+// What every call to the task API goes through
+pub type HttpClient = reqwest_middleware::ClientWithMiddleware;
+
+// Identifies the worker as the actor in the task's state history
+const ACTOR_HEADER: &str = "X-Actor";
+
+// Retries what the default strategy does (connection errors, timeouts, 5xx, 408 and 429), plus
+// the 424 the API answers with when its storage or queue failed, which is just as transient
+struct ApiRetryStrategy;
+
+impl RetryableStrategy for ApiRetryStrategy {
+    fn handle(&self, res: &Result<Response, reqwest_middleware::Error>) -> Option<Retryable> {
+        match res {
+            Ok(response) if response.status() == StatusCode::FAILED_DEPENDENCY => {
+                Some(Retryable::Transient)
+            }
+            Ok(response) => default_on_request_success(response),
+            Err(err) => default_on_request_failure(err),
+        }
+    }
+}
+
+// Every call the worker makes is safe to send again: the GETs and PUTs set rather than add to
+// what's there, and re-registering overwrites the worker's entry. A state change whose response
+// was lost is rejected the second time round, which the processing loop copes with by looking at
+// the task's state when it picks it up.
+pub fn build(config: &WorkerConfig) -> Result<HttpClient> {
+    let mut default_headers = HeaderMap::new();
+    default_headers.insert(ACTOR_HEADER, HeaderValue::from_static("worker"));
+    if let Some(api_key) = &config.api_key {
+        let mut auth_value =
+            HeaderValue::from_str(&format!("Bearer {}", api_key)).context("Invalid API key")?;
+        auth_value.set_sensitive(true);
+        default_headers.insert(AUTHORIZATION, auth_value);
+    }
+    let client = Client::builder()
+        .default_headers(default_headers)
+        .build()
+        .context("Failed to build HTTP client")?;
+
+    let retry_policy = ExponentialBackoff::builder()
+        .retry_bounds(
+            Duration::from_millis(config.api_retry_base_delay_ms),
+            Duration::from_millis(config.api_retry_max_delay_ms),
+        )
+        .build_with_max_retries(config.api_retries);
+    Ok(ClientBuilder::new(client)
+        .with(RetryTransientMiddleware::new_with_policy_and_strategy(
+            retry_policy,
+            ApiRetryStrategy,
+        ))
+        .build())
+}
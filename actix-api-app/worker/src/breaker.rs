@@ -0,0 +1,95 @@
+use anyhow::Result;
+use log::{info, warn};
+use reqwest::StatusCode;
+use std::future::Future;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::time;
+
+// Stops the worker taking tasks off the queue while the API is down. Every task it took would only
+// fail to be fetched or reported on, using up one of its attempts, so after failure_threshold
+// deliveries in a row fail to reach the API the circuit opens and consumption pauses until a probe
+// gets through.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    consecutive_failures: Mutex<u32>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            failure_threshold,
+            base_delay,
+            max_delay,
+            consecutive_failures: Mutex::new(0),
+        }
+    }
+
+    fn failures(&self) -> MutexGuard<'_, u32> {
+        self.consecutive_failures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn is_open(&self) -> bool {
+        *self.failures() >= self.failure_threshold
+    }
+
+    pub fn record_success(&self) {
+        *self.failures() = 0;
+    }
+
+    pub fn record_failure(&self) {
+        let mut failures = self.failures();
+        *failures = failures.saturating_add(1);
+        if *failures == self.failure_threshold {
+            warn!(
+                "{} tasks in a row failed to reach the API, pausing consumption",
+                failures
+            );
+        }
+    }
+
+    // Returns straight away while the circuit is closed. Otherwise probes the API after a delay
+    // doubling from base_delay up to max_delay, until a probe succeeds and the circuit closes.
+    pub async fn wait_until_closed<F, Fut>(&self, probe: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut delay = self.base_delay;
+        while self.is_open() {
+            time::sleep(delay).await;
+            match probe().await {
+                Ok(()) => {
+                    info!("The API is reachable again, resuming consumption");
+                    self.record_success();
+                }
+                Err(err) => {
+                    delay = delay.saturating_mul(2).min(self.max_delay);
+                    warn!(
+                        "The API is still unreachable, probing again in {:?}: {:?}",
+                        delay, err
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Whether an error means the API itself couldn't be reached or fell over (its 424 is a storage or
+// queue failure), as opposed to it rejecting the call or the processing failing
+pub fn is_api_failure(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|err| {
+            err.is_connect()
+                || err.is_timeout()
+                || err.is_request()
+                || err.status().is_some_and(|status| {
+                    status.is_server_error() || status == StatusCode::FAILED_DEPENDENCY
+                })
+        })
+}
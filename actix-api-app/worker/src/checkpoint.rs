@@ -0,0 +1,73 @@
+use crate::api_client::HttpClient;
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{info, warn};
+use reqwest::header::HeaderMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use task_types::requests::CheckpointRequest;
+
+// Handed to the processing along with the progress reporter, so long tasks can save where they've
+// got to and pick up from there instead of starting over. Checkpoints are opaque bytes, kept by
+// the API on the task until it's completed. Clones share the last checkpoint.
+#[derive(Clone)]
+pub struct Checkpoints {
+    http_client: HttpClient,
+    url: String,
+    headers: HeaderMap,
+    last: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl Checkpoints {
+    // Starts from the checkpoint the task was fetched with, if it has one
+    pub fn new(
+        http_client: HttpClient,
+        api_base_url: &str,
+        task_id: &str,
+        headers: &HeaderMap,
+        checkpoint: Option<&str>,
+    ) -> Self {
+        let last = checkpoint.and_then(|checkpoint| match STANDARD.decode(checkpoint) {
+            Ok(checkpoint) => {
+                info!("Task has a checkpoint, resuming from it");
+                Some(checkpoint)
+            }
+            Err(err) => {
+                warn!("Ignoring the task's checkpoint, it isn't base64: {}", err);
+                None
+            }
+        });
+        Self {
+            http_client,
+            url: format!("{}/task/{}/checkpoint", api_base_url, task_id),
+            headers: headers.clone(),
+            last: Arc::new(Mutex::new(last)),
+        }
+    }
+
+    // The checkpoint to resume from, the last one saved, or the task's own if none has been yet.
+    // None means starting from the beginning.
+    pub fn last(&self) -> Option<Vec<u8>> {
+        self.last
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // Sends the checkpoint to the API, returning once it's stored. A later retry of the
+    // processing, in this worker or another, resumes from it.
+    pub async fn save(&self, checkpoint: Vec<u8>) -> Result<()> {
+        self.http_client
+            .put(&self.url)
+            .headers(self.headers.clone())
+            .json(&CheckpointRequest {
+                checkpoint: STANDARD.encode(&checkpoint),
+            })
+            .send()
+            .await
+            .context("Failed to send checkpoint")?
+            .error_for_status()
+            .context("Checkpoint rejected")?;
+        *self.last.lock().unwrap_or_else(PoisonError::into_inner) = Some(checkpoint);
+        Ok(())
+    }
+}
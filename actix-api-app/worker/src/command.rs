@@ -0,0 +1,208 @@
+use crate::config::CommandConfig;
+use crate::processing::{ProcessingError, ResourceLimits};
+use crate::progress::ProgressReporter;
+use anyhow::anyhow;
+use log::info;
+use std::collections::VecDeque;
+use std::env;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+
+// Lines kept from the end of each stream, stderr's say why the command failed
+const TAIL_LINES: usize = 20;
+
+// Output lines are cut to this many characters before they're sent as the task's progress, it's
+// stored on the task
+const MAX_PROGRESS_CHARS: usize = 200;
+
+// Runs the command over the source file, which is expected to leave its result at output. The
+// process is killed if this is dropped, which is how the task's timeout stops it. The limits are
+// set on it as rlimits.
+pub async fn run(
+    config: &CommandConfig,
+    limits: ResourceLimits,
+    progress: &ProgressReporter,
+    workspace: &Path,
+    source: &Path,
+    output: &Path,
+) -> Result<(), ProcessingError> {
+    let source = source.to_string_lossy();
+    let output_arg = output.to_string_lossy();
+    let args = config.args.iter().map(|arg| {
+        arg.replace("{source}", &source)
+            .replace("{output}", &output_arg)
+    });
+
+    let mut command = Command::new(&config.program);
+    command
+        .args(args)
+        .current_dir(workspace)
+        .env_clear()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(path) = env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+    command.envs(&config.env);
+    if limits.cpu_time.is_some() || limits.memory_bytes.is_some() {
+        // SAFETY: only calls setrlimit, which is async-signal-safe, between fork and exec
+        unsafe {
+            command.pre_exec(move || set_limits(limits));
+        }
+    }
+
+    info!("Running {}", config.program);
+    let mut child = command.spawn().map_err(|e| {
+        let err = anyhow!(e).context(format!("Failed to run {}", config.program));
+        // A missing program won't turn up by trying again
+        match err.downcast_ref::<io::Error>().map(io::Error::kind) {
+            Some(io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied) => {
+                ProcessingError::Permanent(err)
+            }
+            _ => ProcessingError::Retryable(err),
+        }
+    })?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let (status, _, stderr_tail) = tokio::join!(
+        child.wait(),
+        log_lines(stdout, "stdout", progress),
+        log_lines(stderr, "stderr", progress)
+    );
+    let status = status?;
+
+    if status.success() {
+        if !tokio::fs::try_exists(output).await? {
+            return Err(ProcessingError::Permanent(anyhow!(
+                "{} finished without writing {}",
+                config.program,
+                output.display()
+            )));
+        }
+        return Ok(());
+    }
+
+    if status.signal() == Some(libc::SIGXCPU) {
+        let limit = limits.cpu_time.unwrap_or_default();
+        return Err(ProcessingError::ResourceLimit(format!(
+            "{} ran past its CPU limit of {:?}",
+            config.program, limit
+        )));
+    }
+    if let Some(bytes) = limits
+        .memory_bytes
+        .filter(|_| out_of_memory(status, &stderr_tail))
+    {
+        return Err(ProcessingError::ResourceLimit(format!(
+            "{} ran out of its {} MB of memory",
+            config.program,
+            bytes / (1024 * 1024)
+        )));
+    }
+
+    let mut message = format!("{} {}", config.program, describe(status));
+    if !stderr_tail.is_empty() {
+        message.push_str(":\n");
+        message.push_str(&Vec::from(stderr_tail).join("\n"));
+    }
+    let err = anyhow!(message);
+    if is_retryable(config, status) {
+        Err(ProcessingError::Retryable(err))
+    } else {
+        Err(ProcessingError::Permanent(err))
+    }
+}
+
+// Logs every line of the stream as it comes, in the task's span, and returns the last few. The
+// lines also go to the API as the task's progress message, so its latest output can be seen on
+// the task (throttled like any progress, so not every line makes it).
+async fn log_lines(
+    stream: impl AsyncRead + Unpin,
+    name: &str,
+    progress: &ProgressReporter,
+) -> VecDeque<String> {
+    let mut tail = VecDeque::with_capacity(TAIL_LINES);
+    let mut lines = BufReader::new(stream).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        info!("[{}] {}", name, line);
+        let shown: String = line.chars().take(MAX_PROGRESS_CHARS).collect();
+        progress.report(10, format!("[{}] {}", name, shown));
+        if tail.len() == TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+    tail
+}
+
+// Exit codes the config lists are worth another go, as is being killed from outside (most likely
+// for memory)
+fn is_retryable(config: &CommandConfig, status: ExitStatus) -> bool {
+    match status.code() {
+        Some(code) => config.retryable_exit_codes.contains(&code),
+        None => true,
+    }
+}
+
+fn describe(status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exited with status {}", code),
+        (None, Some(signal)) => format!("was killed by signal {}", signal),
+        (None, None) => "failed".to_string(),
+    }
+}
+
+// A process over RLIMIT_AS just has its allocations fail, there's no signal saying so. Most
+// programs then abort or crash, or exit saying they couldn't allocate.
+fn out_of_memory(status: ExitStatus, stderr_tail: &VecDeque<String>) -> bool {
+    const MESSAGES: [&str; 4] = [
+        "out of memory",
+        "cannot allocate memory",
+        "memory allocation",
+        "bad_alloc",
+    ];
+    matches!(
+        status.signal(),
+        Some(libc::SIGABRT | libc::SIGSEGV | libc::SIGBUS)
+    ) || stderr_tail.iter().any(|line| {
+        let line = line.to_lowercase();
+        MESSAGES.iter().any(|message| line.contains(message))
+    })
+}
+
+// The soft CPU limit sends SIGXCPU, which ends the process. The hard limit a little above it
+// kills a process that catches that. Memory is capped as address space.
+fn set_limits(limits: ResourceLimits) -> io::Result<()> {
+    if let Some(cpu_time) = limits.cpu_time {
+        let secs = cpu_time.as_secs().max(1);
+        set_limit(
+            libc::RLIMIT_CPU as libc::c_int,
+            secs,
+            secs.saturating_add(5),
+        )?;
+    }
+    if let Some(bytes) = limits.memory_bytes {
+        set_limit(libc::RLIMIT_AS as libc::c_int, bytes, bytes)?;
+    }
+    Ok(())
+}
+
+// glibc types the resource as its own unsigned enum, other libcs as a c_int
+fn set_limit(resource: libc::c_int, soft: u64, hard: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    // SAFETY: setrlimit only reads the struct passed in
+    if unsafe { libc::setrlimit(resource as _, &limit) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -0,0 +1,634 @@
+use crate::validation::ResultFormat;
+use anyhow::{bail, Result};
+use clap::Parser;
+use figment::{
+    providers::{Env, Format, Serialized, Toml},
+    Figment,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+
+// Same file and env prefix as the task API, so both services can share one config.
+// Sections the worker doesn't know about (server, mongo) are ignored.
+const ENV_PREFIX: &str = "TASK_SERVICE_";
+const DEFAULT_CONFIG_FILE: &str = "task-service.toml";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Settings {
+    // Used as the log filter when RUST_LOG isn't set
+    pub log_level: String,
+    pub queue: QueueConfig,
+    pub redis: RedisConfig,
+    pub sqs: SqsConfig,
+    pub storage: StorageConfig,
+    pub worker: WorkerConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    pub backend: QueueBackend,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueBackend {
+    #[default]
+    Redis,
+    Sqs,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RedisConfig {
+    pub uri: String,
+    // The task stream
+    pub queue_name: String,
+    // Consumer group the workers share, each entry goes to one worker in it
+    pub consumer_group: String,
+    // Task types the API queues on streams of their own, {queue_name}:{type}
+    pub routed_task_types: Vec<String>,
+    // Whether the API queues High and Low priority tasks on {stream}:high and {stream}:low. They're
+    // read by weight, twice and half as often as the normal stream, see queue::redis.
+    pub priority_streams: bool,
+    // Most connections kept open at once. The worker needs one for the blocking read, one for the
+    // reaper and one for each task it processes at once (worker.concurrency).
+    pub pool_size: usize,
+}
+
+// Used when queue.backend is "sqs"
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SqsConfig {
+    pub queue_url: String,
+    pub region: Option<String>,
+    pub endpoint_url: Option<String>,
+    // How long a received message stays hidden from other workers, has to be longer than any
+    // task takes to process
+    pub visibility_timeout_secs: u32,
+    // Set on the queue by the API. Tasks the worker gives up on are sent here directly.
+    pub dead_letter_queue_arn: Option<String>,
+    pub operation_timeout_secs: u64,
+}
+
+// Where source files are fetched from and results uploaded to
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    // Holds the results, and the source files given as plain keys rather than s3:// URLs
+    pub bucket: String,
+    pub region: Option<String>,
+    // Points the client at something other than AWS, e.g. MinIO
+    pub endpoint_url: Option<String>,
+    // Addresses buckets as paths rather than subdomains, which MinIO needs
+    pub force_path_style: bool,
+    // Static credentials, e.g. for MinIO. Both or neither, the usual AWS sources when neither.
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    // Results are stored as {result_prefix}{user_uuid}/{task_uuid}/{file name}
+    pub result_prefix: String,
+    // Where each task gets a scratch directory, in a worker-{worker id} directory that's cleared at
+    // startup. The system temp directory when unset.
+    pub workspace_dir: Option<String>,
+    // Results larger than this are uploaded in parts of part_size_mb
+    pub multipart_threshold_mb: u64,
+    pub part_size_mb: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WorkerConfig {
+    pub api_base_url: String,
+    // Service API key sent as a bearer token when the API has authentication enabled
+    pub api_key: Option<String>,
+    // This worker's name within the consumer group, and its id in the API's list of workers.
+    // Keep it stable across restarts so the worker picks its unfinished entries back up, defaults
+    // to the hostname.
+    pub consumer_name: Option<String>,
+    // Only take tasks of these types, off their own streams. They have to be in
+    // redis.routed_task_types too. Empty takes everything else, from the main stream.
+    pub task_types: Vec<String>,
+    // Relative share of reads for each of task_types while several have tasks waiting, 1 for
+    // any not listed. The heavier streams are drained first, the lighter ones still get their
+    // share.
+    pub queue_weights: HashMap<String, u32>,
+    // Most tasks processed at once. The next message is only taken off the queue once one of them
+    // finishes.
+    pub concurrency: usize,
+    // Messages read ahead and kept waiting while every task slot is busy, none when 0. They're
+    // claimed while they wait, one kept waiting past heartbeat_interval_secs has its lease
+    // renewed before it's processed.
+    pub prefetch: usize,
+    // How long a single XREADGROUP waits for a message before looping
+    pub poll_timeout_secs: u64,
+    // A Redis entry being processed is leased to its worker for this long, renewed by a heartbeat
+    // every heartbeat_interval_secs. Entries whose lease runs out are taken over by another
+    // worker. SQS extends the message's visibility timeout instead.
+    pub lease_secs: u64,
+    pub heartbeat_interval_secs: u64,
+    // How often to look for entries with an expired lease
+    pub reclaim_interval_secs: u64,
+    // How often the worker reports what it's processing to the API
+    pub status_interval_secs: u64,
+    // How often the worker reports its load for the API's autoscaling hint
+    pub autoscale_interval_secs: u64,
+    // Where the outcomes of finished tasks are kept while the API can't be reached, and how often
+    // sending them is tried again. Defaults to outbox-{worker id}.jsonl in storage.workspace_dir.
+    pub outbox_path: Option<String>,
+    pub outbox_flush_interval_secs: u64,
+    // Least time between two progress updates sent for a task, the ones in between are dropped
+    pub progress_interval_secs: u64,
+    // Where to serve Prometheus metrics on /metrics, e.g. "0.0.0.0:9100". Not served when unset.
+    pub metrics_address: Option<String>,
+    // Pause after a failed iteration of the processing loop
+    pub error_backoff_secs: u64,
+    // Most tasks started in any minute, unlimited when unset
+    pub max_tasks_per_minute: Option<u32>,
+    // No more tasks are taken while the workspace's disk or the machine's available memory is
+    // below these, checked every resource_check_interval_secs until it's back above
+    pub min_free_disk_mb: Option<u64>,
+    pub min_free_memory_mb: Option<u64>,
+    pub resource_check_interval_secs: u64,
+    // After this many tasks in a row fail to reach the API, no more are taken off the queue until
+    // it answers again. It's probed after api_probe_base_delay_secs, doubling up to
+    // api_probe_max_delay_secs.
+    pub api_failure_threshold: u32,
+    pub api_probe_base_delay_secs: u64,
+    pub api_probe_max_delay_secs: u64,
+    // A call to the API that fails in a way worth trying again (it couldn't be reached, or
+    // answered with a 5xx, 424, 408 or 429) is sent up to api_retries more times, after a delay
+    // doubling from api_retry_base_delay_ms up to api_retry_max_delay_ms
+    pub api_retries: u32,
+    pub api_retry_base_delay_ms: u64,
+    pub api_retry_max_delay_ms: u64,
+    // A task whose processing errors out is retried after retry_base_delay_secs, doubling with
+    // each further attempt up to retry_max_delay_secs. After max_attempts the task is failed and
+    // its message dead-lettered.
+    pub max_attempts: u32,
+    pub retry_base_delay_secs: u64,
+    pub retry_max_delay_secs: u64,
+    // How many times the processing itself is tried before the task is marked failed. Only
+    // errors the processor reports as retryable are tried again, after
+    // processing_retry_delay_ms doubling with each try.
+    pub processing_attempts: u32,
+    pub processing_retry_delay_ms: u64,
+    // Longest a task may take to process, retries included, before it's cancelled. Per task type
+    // in task_timeouts, task_timeout_secs for every other type.
+    pub task_timeout_secs: u64,
+    pub task_timeouts: HashMap<String, u64>,
+    // Puts a task that timed out back on the queue (to be retried as after any other error)
+    // rather than failing it
+    pub requeue_timed_out: bool,
+    // External commands that process tasks of a type, in place of the built-in processing for
+    // render. Other types with neither a command nor a plugin fail.
+    pub commands: HashMap<String, CommandConfig>,
+    // Directory of WASM processors, {task_type}.wasm, loaded at startup. A command configured for
+    // the same type takes precedence.
+    pub plugin_dir: Option<String>,
+    // Most memory and CPU time processing a single task may use, unlimited when unset. Commands
+    // get them as rlimits, plugins are checked as they run. A task going over is failed.
+    pub task_memory_limit_mb: Option<u64>,
+    pub task_cpu_limit_secs: Option<u64>,
+    // Most a task's workspace may take up on disk, unlimited when unset. Processing is stopped and
+    // the task failed once it's over.
+    pub task_disk_limit_mb: Option<u64>,
+    // Per task type, the format its results have to be in. Any other type's only have to be
+    // non-empty.
+    pub result_formats: HashMap<String, ResultFormat>,
+}
+
+// A program run to process a task, e.g. Blender or ffmpeg. It runs in the task's workspace with
+// a cleared environment and no stdin, and its output is logged line by line with the task's.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CommandConfig {
+    pub program: String,
+    // {source} and {output} are replaced with the path of the downloaded source file and the path
+    // the result is expected at
+    pub args: Vec<String>,
+    // Set for the command on top of PATH, nothing else of the worker's environment is passed on
+    pub env: HashMap<String, String>,
+    // CPU seconds the command may use before it's killed, and the memory it may map, in place of
+    // worker.task_cpu_limit_secs and worker.task_memory_limit_mb. The task's timeout applies too.
+    pub cpu_limit_secs: Option<u64>,
+    pub memory_limit_mb: Option<u64>,
+    // Exit codes meaning the command might succeed if run again. Any other non-zero exit fails
+    // the task.
+    pub retryable_exit_codes: Vec<i32>,
+}
+
+impl StorageConfig {
+    pub fn workspace_path(&self) -> PathBuf {
+        self.workspace_dir
+            .as_ref()
+            .map_or_else(env::temp_dir, PathBuf::from)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_string(),
+            queue: QueueConfig::default(),
+            redis: RedisConfig::default(),
+            sqs: SqsConfig::default(),
+            storage: StorageConfig::default(),
+            worker: WorkerConfig::default(),
+        }
+    }
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            uri: "redis://localhost:6379".to_string(),
+            queue_name: "task_queue".to_string(),
+            consumer_group: "workers".to_string(),
+            routed_task_types: Vec::new(),
+            priority_streams: false,
+            pool_size: 16,
+        }
+    }
+}
+
+impl Default for SqsConfig {
+    fn default() -> Self {
+        Self {
+            queue_url: String::new(),
+            region: None,
+            endpoint_url: None,
+            visibility_timeout_secs: 10 * 60,
+            dead_letter_queue_arn: None,
+            operation_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            bucket: "tasks".to_string(),
+            region: None,
+            endpoint_url: None,
+            force_path_style: false,
+            access_key_id: None,
+            secret_access_key: None,
+            result_prefix: "results/".to_string(),
+            workspace_dir: None,
+            multipart_threshold_mb: 64,
+            part_size_mb: 16,
+        }
+    }
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            api_base_url: "http://localhost:80".to_string(),
+            api_key: None,
+            consumer_name: None,
+            task_types: Vec::new(),
+            queue_weights: HashMap::new(),
+            concurrency: 4,
+            prefetch: 0,
+            poll_timeout_secs: 20,
+            lease_secs: 60,
+            heartbeat_interval_secs: 20,
+            reclaim_interval_secs: 60,
+            status_interval_secs: 30,
+            autoscale_interval_secs: 30,
+            outbox_path: None,
+            outbox_flush_interval_secs: 30,
+            progress_interval_secs: 5,
+            metrics_address: None,
+            error_backoff_secs: 5,
+            max_tasks_per_minute: None,
+            min_free_disk_mb: None,
+            min_free_memory_mb: None,
+            resource_check_interval_secs: 10,
+            api_failure_threshold: 5,
+            api_probe_base_delay_secs: 5,
+            api_probe_max_delay_secs: 5 * 60,
+            api_retries: 3,
+            api_retry_base_delay_ms: 200,
+            api_retry_max_delay_ms: 5000,
+            max_attempts: 5,
+            retry_base_delay_secs: 10,
+            retry_max_delay_secs: 15 * 60,
+            processing_attempts: 3,
+            processing_retry_delay_ms: 1000,
+            task_timeout_secs: 60 * 60,
+            task_timeouts: HashMap::new(),
+            requeue_timed_out: false,
+            commands: HashMap::new(),
+            plugin_dir: None,
+            task_memory_limit_mb: None,
+            task_cpu_limit_secs: None,
+            task_disk_limit_mb: None,
+            result_formats: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Processes the tasks queued by the task API")]
+pub struct Cli {
+    /// Path to the TOML config file, missing files are ignored
+    #[arg(short, long, env = "TASK_SERVICE_CONFIG", default_value = DEFAULT_CONFIG_FILE)]
+    pub config: PathBuf,
+
+    /// Print the configuration the worker would run with, secrets redacted, and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Log filter used when RUST_LOG is not set, e.g. "info" or "worker=debug"
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Redis connection string
+    #[arg(long)]
+    pub redis_uri: Option<String>,
+
+    /// The Redis task stream
+    #[arg(long)]
+    pub queue_name: Option<String>,
+
+    /// SQS queue URL, when queue.backend is "sqs"
+    #[arg(long)]
+    pub sqs_queue_url: Option<String>,
+
+    /// Base URL of the task API
+    #[arg(long)]
+    pub api_base_url: Option<String>,
+
+    /// Most tasks processed at once
+    #[arg(long)]
+    pub concurrency: Option<usize>,
+
+    /// Longest a task may take to process before it's cancelled
+    #[arg(long)]
+    pub task_timeout_secs: Option<u64>,
+
+    /// Bucket holding source files and results
+    #[arg(long)]
+    pub storage_bucket: Option<String>,
+
+    /// Object storage endpoint, for anything other than AWS
+    #[arg(long)]
+    pub storage_endpoint_url: Option<String>,
+
+    /// Object storage access key id
+    #[arg(long)]
+    pub storage_access_key_id: Option<String>,
+
+    /// Object storage secret key
+    #[arg(
+        long,
+        env = "TASK_SERVICE_STORAGE_SECRET_ACCESS_KEY",
+        hide_env_values = true
+    )]
+    pub storage_secret_access_key: Option<String>,
+}
+
+impl Settings {
+    // Layers built-in defaults, the TOML config file, environment variables, then the command line
+    pub fn load(cli: &Cli) -> Result<Self, Box<figment::Error>> {
+        let mut figment = Figment::from(Serialized::defaults(Settings::default()))
+            .merge(Toml::file(&cli.config))
+            .merge(legacy_env())
+            .merge(Env::prefixed(ENV_PREFIX).split("__"));
+
+        // Only flags that were actually passed override the other sources
+        if let Some(log_level) = &cli.log_level {
+            figment = figment.merge(Serialized::default("log_level", log_level));
+        }
+        if let Some(redis_uri) = &cli.redis_uri {
+            figment = figment.merge(Serialized::default("redis.uri", redis_uri));
+        }
+        if let Some(queue_name) = &cli.queue_name {
+            figment = figment.merge(Serialized::default("redis.queue_name", queue_name));
+        }
+        if let Some(sqs_queue_url) = &cli.sqs_queue_url {
+            figment = figment.merge(Serialized::default("sqs.queue_url", sqs_queue_url));
+        }
+        if let Some(api_base_url) = &cli.api_base_url {
+            figment = figment.merge(Serialized::default("worker.api_base_url", api_base_url));
+        }
+        if let Some(concurrency) = cli.concurrency {
+            figment = figment.merge(Serialized::default("worker.concurrency", concurrency));
+        }
+        if let Some(task_timeout_secs) = cli.task_timeout_secs {
+            figment = figment.merge(Serialized::default(
+                "worker.task_timeout_secs",
+                task_timeout_secs,
+            ));
+        }
+        if let Some(storage_bucket) = &cli.storage_bucket {
+            figment = figment.merge(Serialized::default("storage.bucket", storage_bucket));
+        }
+        if let Some(storage_endpoint_url) = &cli.storage_endpoint_url {
+            figment = figment.merge(Serialized::default(
+                "storage.endpoint_url",
+                storage_endpoint_url,
+            ));
+        }
+        if let Some(storage_access_key_id) = &cli.storage_access_key_id {
+            figment = figment.merge(Serialized::default(
+                "storage.access_key_id",
+                storage_access_key_id,
+            ));
+        }
+        if let Some(storage_secret_access_key) = &cli.storage_secret_access_key {
+            figment = figment.merge(Serialized::default(
+                "storage.secret_access_key",
+                storage_secret_access_key,
+            ));
+        }
+
+        figment.extract().map_err(Box::new)
+    }
+
+    // Catches settings the worker can't run with before it takes anything off the queue, listing
+    // every problem at once
+    pub fn validate(&self) -> Result<()> {
+        let worker = &self.worker;
+        let mut problems = Vec::new();
+        if !worker.api_base_url.starts_with("http://")
+            && !worker.api_base_url.starts_with("https://")
+        {
+            problems.push(format!(
+                "worker.api_base_url must be an http(s) URL, got {:?}",
+                worker.api_base_url
+            ));
+        }
+        for (name, value) in [
+            ("worker.concurrency", worker.concurrency as u64),
+            ("worker.lease_secs", worker.lease_secs),
+            (
+                "worker.heartbeat_interval_secs",
+                worker.heartbeat_interval_secs,
+            ),
+            ("worker.reclaim_interval_secs", worker.reclaim_interval_secs),
+            ("worker.status_interval_secs", worker.status_interval_secs),
+            (
+                "worker.autoscale_interval_secs",
+                worker.autoscale_interval_secs,
+            ),
+            (
+                "worker.outbox_flush_interval_secs",
+                worker.outbox_flush_interval_secs,
+            ),
+            (
+                "worker.resource_check_interval_secs",
+                worker.resource_check_interval_secs,
+            ),
+            (
+                "worker.api_failure_threshold",
+                worker.api_failure_threshold as u64,
+            ),
+            (
+                "worker.api_probe_base_delay_secs",
+                worker.api_probe_base_delay_secs,
+            ),
+            ("worker.max_attempts", worker.max_attempts as u64),
+            (
+                "worker.processing_attempts",
+                worker.processing_attempts as u64,
+            ),
+            ("worker.task_timeout_secs", worker.task_timeout_secs),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be at least 1", name));
+            }
+        }
+        if worker.api_retry_base_delay_ms > worker.api_retry_max_delay_ms {
+            problems.push(
+                "worker.api_retry_base_delay_ms can't be more than worker.api_retry_max_delay_ms"
+                    .to_string(),
+            );
+        }
+        // Otherwise the lease runs out between two heartbeats and the entry is taken over
+        if worker.heartbeat_interval_secs >= worker.lease_secs {
+            problems.push(
+                "worker.heartbeat_interval_secs must be shorter than worker.lease_secs".to_string(),
+            );
+        }
+        if worker.max_tasks_per_minute == Some(0) {
+            problems.push("worker.max_tasks_per_minute must be at least 1".to_string());
+        }
+        if worker.task_memory_limit_mb == Some(0) {
+            problems.push("worker.task_memory_limit_mb must be at least 1".to_string());
+        }
+        if worker.task_cpu_limit_secs == Some(0) {
+            problems.push("worker.task_cpu_limit_secs must be at least 1".to_string());
+        }
+        if worker.task_disk_limit_mb == Some(0) {
+            problems.push("worker.task_disk_limit_mb must be at least 1".to_string());
+        }
+        for (task_type, weight) in &worker.queue_weights {
+            if !worker.task_types.contains(task_type) {
+                problems.push(format!(
+                    "worker.queue_weights.{} is for a type not in worker.task_types",
+                    task_type
+                ));
+            }
+            if *weight == 0 {
+                problems.push(format!(
+                    "worker.queue_weights.{} must be at least 1",
+                    task_type
+                ));
+            }
+        }
+        for (task_type, command) in &worker.commands {
+            if command.program.is_empty() {
+                problems.push(format!("worker.commands.{}.program is required", task_type));
+            }
+            if command.cpu_limit_secs == Some(0) {
+                problems.push(format!(
+                    "worker.commands.{}.cpu_limit_secs must be at least 1",
+                    task_type
+                ));
+            }
+            if command.memory_limit_mb == Some(0) {
+                problems.push(format!(
+                    "worker.commands.{}.memory_limit_mb must be at least 1",
+                    task_type
+                ));
+            }
+        }
+        for (task_type, secs) in &worker.task_timeouts {
+            if *secs == 0 {
+                problems.push(format!(
+                    "worker.task_timeouts.{} must be at least 1",
+                    task_type
+                ));
+            }
+        }
+        if self.queue.backend == QueueBackend::Sqs && self.sqs.queue_url.is_empty() {
+            problems.push("sqs.queue_url is required when queue.backend is \"sqs\"".to_string());
+        }
+        if self.storage.bucket.is_empty() {
+            problems.push("storage.bucket is required".to_string());
+        }
+        if self.storage.access_key_id.is_some() != self.storage.secret_access_key.is_some() {
+            problems.push(
+                "storage.access_key_id and storage.secret_access_key go together".to_string(),
+            );
+        }
+
+        if !problems.is_empty() {
+            bail!("Invalid configuration:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+
+    // The settings as a config file would give them, with the secrets left out
+    pub fn to_redacted_toml(&self) -> Result<String> {
+        let mut settings = self.clone();
+        for secret in [
+            &mut settings.worker.api_key,
+            &mut settings.storage.secret_access_key,
+        ] {
+            if secret.is_some() {
+                *secret = Some("<redacted>".to_string());
+            }
+        }
+        Ok(toml::to_string_pretty(&settings)?)
+    }
+}
+
+impl WorkerConfig {
+    // The consumer name, or failing that the hostname, or failing that something unique to the
+    // process
+    pub fn worker_id(&self) -> String {
+        self.consumer_name.clone().unwrap_or_else(|| {
+            hostname().unwrap_or_else(|| format!("worker-{}", std::process::id()))
+        })
+    }
+}
+
+// Set by Docker and most shells
+pub fn hostname() -> Option<String> {
+    env::var("HOSTNAME").ok()
+}
+
+// The unprefixed variables the Docker setup already passes in
+fn legacy_env() -> Env {
+    Env::raw()
+        .only(&[
+            "REDIS_URI",
+            "REDIS_QUEUE",
+            "API_BASE_URL",
+            "WORKER_CONCURRENCY",
+        ])
+        .map(|key| match key.as_str().to_ascii_lowercase().as_str() {
+            "redis_uri" => "redis.uri".into(),
+            "redis_queue" => "redis.queue_name".into(),
+            "api_base_url" => "worker.api_base_url".into(),
+            "worker_concurrency" => "worker.concurrency".into(),
+            other => other.to_string().into(),
+        })
+}
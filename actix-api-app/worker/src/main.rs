@@ -1,151 +1,604 @@
+mod api_client;
+mod breaker;
+mod checkpoint;
+mod command;
+mod config;
+mod metrics;
+mod outbox;
+mod plugins;
+mod processing;
+mod progress;
+mod queue;
+mod registration;
+mod storage;
+mod throttle;
+mod validation;
+mod workspace;
+
 use anyhow::{Context, Result};
-use log::{error, info};
-use redis::AsyncCommands;
-use redis::Client as RedisClient;
-use reqwest::Client as HttpClient;
-use serde::{Deserialize, Serialize};
-use std::env;
-use std::time::Duration;
+use api_client::HttpClient;
+use breaker::{is_api_failure, CircuitBreaker};
+use checkpoint::Checkpoints;
+use clap::Parser;
+use config::{Cli, QueueBackend, Settings, WorkerConfig};
+use log::{error, info, warn};
+use metrics::Metrics;
+use outbox::{Outbox, Outcome};
+use plugins::Plugins;
+use processing::{ProcessingError, Processor};
+use progress::ProgressReporter;
+use queue::{prefetch::PrefetchQueue, redis::RedisQueue, sqs::SqsQueue, Delivery, TaskQueue};
+use registration::Registration;
+use reqwest::header::{HeaderMap, HeaderValue};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use storage::ObjectStore;
+use task_types::requests::TaskCompletionRequest;
+use task_types::task::{Task, TaskState};
+use throttle::Throttle;
+use tokio::sync::Semaphore;
 use tokio::time;
+use tracing::{field, info_span, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+use workspace::Workspaces;
 
-// Import the TaskMessage from the Redis queue module
-#[derive(Serialize, Deserialize)]
-struct TaskMessage {
-    task_global_id: String,
-}
-
-#[derive(Serialize, Deserialize)]
-struct TaskCompletionRequest {
-    result_file: String,
-}
-
-#[derive(Serialize, Deserialize)]
-struct Task {
-    user_uuid: String,
-    task_uuid: String,
-    task_type: String,
-    state: String,
-    source_file: String,
-    result_file: Option<String>,
-}
-
-#[derive(Serialize, Deserialize)]
-struct TaskIdentifier {
-    task_global_id: String,
-}
+// Header the API uses to correlate a task's calls with its original submission
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+// Which worker is calling about a task and on which attempt, logged by the API alongside the
+// request so its lines can be matched up with the worker's
+const WORKER_ID_HEADER: &str = "X-Worker-Id";
+const ATTEMPT_HEADER: &str = "X-Task-Attempt";
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
-
-    // Get configuration from environment variables
-    let redis_uri = env::var("REDIS_URI").unwrap_or_else(|_| "redis://localhost:6379".to_string());
-    let queue_name = env::var("REDIS_QUEUE").unwrap_or_else(|_| "task_queue".to_string());
-    let api_base_url =
-        env::var("API_BASE_URL").unwrap_or_else(|_| "http://localhost:80".to_string());
-
-    // HTTP client for API calls
-    let http_client = HttpClient::new();
+    let cli = Cli::parse();
+    let settings = Settings::load(&cli).context("Failed to load configuration")?;
+    if cli.print_config {
+        print!("{}", settings.to_redacted_toml()?);
+        return Ok(());
+    }
+    settings.validate()?;
+
+    // Initialize logging. The subscriber also picks up the log macros, so every line logged while
+    // processing a task carries that task's span fields.
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&settings.log_level));
+    tracing_subscriber::fmt().with_env_filter(env_filter).init();
+
+    // HTTP client for API calls, retrying the ones that fail in passing
+    let http_client = api_client::build(&settings.worker)?;
+
+    let worker_id = settings.worker.worker_id();
+
+    let task_queue: Arc<dyn TaskQueue> = match settings.queue.backend {
+        QueueBackend::Redis => start_redis_queue(&settings, &worker_id).await?,
+        QueueBackend::Sqs => {
+            if !settings.worker.task_types.is_empty() {
+                warn!(
+                    "worker.task_types and worker.queue_weights are ignored, SQS has a single \
+                     queue for every type"
+                );
+            }
+            info!(
+                "Worker service started, reading SQS queue {}",
+                settings.sqs.queue_url
+            );
+            Arc::new(SqsQueue::init(&settings.sqs).await)
+        }
+    };
 
-    // Redis client for fetching tasks
-    let redis_client =
-        RedisClient::open(redis_uri.clone()).context("Failed to connect to Redis")?;
+    let task_queue: Arc<dyn TaskQueue> = if settings.worker.prefetch > 0 {
+        Arc::new(PrefetchQueue::spawn(
+            task_queue,
+            settings.worker.prefetch,
+            Duration::from_secs(settings.worker.poll_timeout_secs),
+            Duration::from_secs(settings.worker.error_backoff_secs),
+            Duration::from_secs(settings.worker.heartbeat_interval_secs),
+        ))
+    } else {
+        task_queue
+    };
 
-    info!("Worker service started");
+    let metrics = Metrics::new();
+    if let Some(address) = &settings.worker.metrics_address {
+        metrics.spawn_server(address)?;
+    }
 
-    // Main processing loop
+    let workspaces = Workspaces::init(
+        &settings.storage.workspace_path(),
+        &worker_id,
+        settings.worker.task_disk_limit_mb,
+    )?;
+    workspaces.spawn_cleanup_on_shutdown()?;
+    let processor = Arc::new(Processor::new(
+        ObjectStore::init(&settings.storage).await,
+        &settings.storage,
+        &settings.worker,
+        Plugins::load(settings.worker.plugin_dir.as_deref())?,
+        workspaces,
+    ));
+
+    // Lets operators see this worker and what it's doing in the API's GET /workers
+    let registration = Arc::new(Registration::new(
+        http_client.clone(),
+        settings.worker.api_base_url.clone(),
+        worker_id.clone(),
+        config::hostname().unwrap_or_else(|| "unknown".to_string()),
+        settings.worker.task_types.clone(),
+        processor.processors(),
+        settings.worker.concurrency,
+    ));
+    if let Err(err) = registration.register().await {
+        // Not fatal, the status reports register again until it works
+        warn!("Failed to register with the API: {:?}", err);
+    }
+    registration
+        .clone()
+        .spawn_status_reports(Duration::from_secs(settings.worker.status_interval_secs));
+    registration.clone().spawn_load_reports(
+        task_queue.clone(),
+        Duration::from_secs(settings.worker.autoscale_interval_secs),
+    );
+
+    let breaker = Arc::new(CircuitBreaker::new(
+        settings.worker.api_failure_threshold,
+        Duration::from_secs(settings.worker.api_probe_base_delay_secs),
+        Duration::from_secs(settings.worker.api_probe_max_delay_secs),
+    ));
+
+    let throttle = Throttle::new(
+        settings.worker.max_tasks_per_minute,
+        settings.storage.workspace_path(),
+        settings.worker.min_free_disk_mb,
+        settings.worker.min_free_memory_mb,
+        Duration::from_secs(settings.worker.resource_check_interval_secs),
+    );
+
+    // Finished tasks the API can't be told about wait here until it's back
+    let outbox_path = match &settings.worker.outbox_path {
+        Some(path) => path.into(),
+        None => settings
+            .storage
+            .workspace_path()
+            .join(format!("outbox-{}.jsonl", workspace::file_name(&worker_id))),
+    };
+    let outbox = Arc::new(Outbox::open(
+        http_client.clone(),
+        settings.worker.api_base_url.clone(),
+        outbox_path,
+    )?);
+    outbox.clone().spawn_flushes(Duration::from_secs(
+        settings.worker.outbox_flush_interval_secs,
+    ));
+
+    let services = Arc::new(Services {
+        processor,
+        http_client: http_client.clone(),
+        metrics: metrics.clone(),
+        outbox,
+    });
+
+    // Main processing loop. A permit is held for each task being processed, so no more than
+    // worker.concurrency messages are taken off the queue at once.
+    let settings = Arc::new(settings);
+    let permits = Arc::new(Semaphore::new(settings.worker.concurrency));
     loop {
-        let process_result =
-            process_next_task(&redis_client, &queue_name, &http_client, &api_base_url).await;
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        // No use taking tasks that can't be fetched or reported on, the status report doubles as
+        // the probe
+        breaker
+            .wait_until_closed(|| registration.report_status())
+            .await;
+        throttle.wait().await;
+
+        let polled_at = Instant::now();
+        let polled = task_queue
+            .next(Duration::from_secs(settings.worker.poll_timeout_secs))
+            .await;
+        let outcome = match &polled {
+            Ok(Some(_)) => "message",
+            Ok(None) => "empty",
+            Err(_) => "error",
+        };
+        metrics.observe_poll(outcome, polled_at.elapsed());
+        let delivery = match polled {
+            Ok(Some(delivery)) => delivery,
+            Ok(None) => continue,
+            Err(err) => {
+                error!("Error reading from the task queue: {:?}", err);
+                time::sleep(Duration::from_secs(settings.worker.error_backoff_secs)).await;
+                continue;
+            }
+        };
+        throttle.record_start();
+
+        let task_queue = task_queue.clone();
+        let settings = settings.clone();
+        let services = services.clone();
+        let worker_id = worker_id.clone();
+        let breaker = breaker.clone();
+        let tracked = registration.track(&delivery.message.task_global_id);
+        let in_flight = metrics.in_flight();
+        // task_type is filled in once the task has been fetched
+        let span = info_span!(
+            "task",
+            task_global_id = %delivery.message.task_global_id,
+            task_type = field::Empty,
+            attempt = delivery.message.attempts + 1,
+            worker_id = %worker_id
+        );
+        tokio::spawn(
+            async move {
+                let handled = handle_delivery(
+                    task_queue.as_ref(),
+                    &settings.worker,
+                    &services,
+                    &worker_id,
+                    delivery,
+                )
+                .await;
+                drop(in_flight);
+
+                match &handled {
+                    Ok(()) => breaker.record_success(),
+                    Err(err) if is_api_failure(err) => breaker.record_failure(),
+                    Err(_) => {}
+                }
+                if let Err(err) = handled {
+                    error!("Error processing task: {:?}", err);
+                    // Holds on to the permit, so a failing API isn't hit at full speed
+                    time::sleep(Duration::from_secs(settings.worker.error_backoff_secs)).await;
+                }
+                drop(tracked);
+                drop(permit);
+            }
+            .instrument(span),
+        );
+    }
+}
 
-        if let Err(err) = process_result {
-            error!("Error processing task: {:?}", err);
-            // Wait before retrying after an error
-            time::sleep(Duration::from_secs(5)).await;
+// Sets up the consumer group and the reaper. SQS needs neither, it hands out messages that aren't
+// deleted in time again by itself.
+async fn start_redis_queue(settings: &Settings, consumer_name: &str) -> Result<Arc<dyn TaskQueue>> {
+    // The API would never queue anything on the stream of a type it doesn't route
+    for task_type in &settings.worker.task_types {
+        if !settings.redis.routed_task_types.contains(task_type) {
+            warn!(
+                "Task type {} isn't in redis.routed_task_types, no tasks will arrive for it",
+                task_type
+            );
         }
     }
-}
+    let task_queue = Arc::new(RedisQueue::new(
+        &settings.redis,
+        &settings.worker.task_types,
+        &settings.worker.queue_weights,
+        consumer_name.to_string(),
+        Duration::from_secs(settings.worker.lease_secs),
+    )?);
+
+    // Redis may still be starting up alongside us
+    while let Err(err) = task_queue.ensure_group().await {
+        error!("Failed to set up the task queue: {:?}", err);
+        time::sleep(Duration::from_secs(settings.worker.error_backoff_secs)).await;
+    }
 
-// Function to process the next task from the queue
-async fn process_next_task(
-    redis_client: &RedisClient,
-    queue_name: &str,
-    http_client: &HttpClient,
-    api_base_url: &str,
-) -> Result<()> {
-    // Get Redis connection
-    let mut conn = redis_client
-        .get_async_connection()
-        .await
-        .context("Failed to get Redis connection")?;
+    spawn_reaper(
+        task_queue.clone(),
+        Duration::from_secs(settings.worker.reclaim_interval_secs),
+    );
+
+    info!(
+        "Worker service started as {}, reading {}",
+        consumer_name,
+        task_queue.streams().join(", ")
+    );
+    Ok(task_queue)
+}
 
-    // BLPOP blocks until a message is available or timeout is reached
-    let result: Option<(String, String)> = conn
-        .blpop(queue_name, 20)
-        .await
-        .context("Error executing BLPOP command")?;
+// Periodically takes over queue entries whose worker died or hung before acking them, so they're
+// processed again by this one
+fn spawn_reaper(task_queue: Arc<RedisQueue>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = task_queue.reclaim().await {
+                error!("Failed to reclaim abandoned tasks: {:?}", err);
+            }
+        }
+    });
+}
 
-    if let Some((_, message)) = result {
-        // Deserialize the message
-        let task_message: TaskMessage =
-            serde_json::from_str(&message).context("Failed to deserialize task message")?;
+// What every task is handled with, shared across the ones in flight
+struct Services {
+    processor: Arc<Processor>,
+    http_client: HttpClient,
+    metrics: Metrics,
+    outbox: Arc<Outbox>,
+}
 
-        // Process the task
-        process_task(http_client, api_base_url, &task_message.task_global_id).await?;
+// Processes a message taken off the queue, then acks it or schedules it to be retried
+async fn handle_delivery(
+    task_queue: &dyn TaskQueue,
+    config: &WorkerConfig,
+    services: &Services,
+    worker_id: &str,
+    delivery: Delivery,
+) -> Result<()> {
+    let Services {
+        http_client,
+        metrics,
+        outbox,
+        ..
+    } = services;
+    let api_base_url = config.api_base_url.as_str();
+    // Only acked once it's been processed
+    let task_id = &delivery.message.task_global_id;
+    let attempt = delivery.message.attempts + 1;
+    let headers = trace_headers(worker_id, attempt)?;
+    let processed = with_heartbeat(
+        task_queue,
+        &delivery,
+        http_client,
+        api_base_url,
+        &headers,
+        Duration::from_secs(config.heartbeat_interval_secs),
+        process_task(config, services, task_id, headers.clone()),
+    )
+    .await;
+    let Err(err) = processed else {
+        return task_queue.ack(&delivery).await;
+    };
 
-        Ok(())
+    if attempt >= config.max_attempts {
+        let last_error = format!("{:#}", err);
+        error!(
+            "Giving up on task {} after {} attempts: {}",
+            task_id, attempt, last_error
+        );
+        metrics.record_failure("dead_lettered");
+        // So the task doesn't sit in progress forever with nothing left to run it. If the API is
+        // why it failed that's buffered until it's back, the dead letter keeps the error either
+        // way.
+        let reason = format!("Gave up after {} attempts: {}", attempt, last_error);
+        if let Err(err) = report_outcome(
+            http_client,
+            api_base_url,
+            outbox,
+            task_id,
+            Outcome::Fail { reason },
+            &headers,
+        )
+        .await
+        {
+            warn!("Failed to mark task {} failed: {:?}", task_id, err);
+        }
+        task_queue.dead_letter(&delivery, &last_error).await?;
     } else {
-        // No message received within timeout, return without error
-        Ok(())
+        let delay = retry_delay(config, attempt);
+        warn!(
+            "Attempt {} at task {} failed, retrying in {:?}",
+            attempt, task_id, delay
+        );
+        metrics.record_failure("retried");
+        task_queue.retry(&delivery, delay).await?;
     }
+    Err(err)
 }
 
-async fn process_task(http_client: &HttpClient, api_base_url: &str, task_id: &str) -> Result<()> {
-    info!("Processing task: {}", task_id);
+// Runs the processing, heartbeating the delivery and the task every interval until it's done.
+// Losing the lease doesn't stop the task, it's only logged, another worker may now run it too.
+// The API's heartbeat keeps the requeue reaper off a task that's slow rather than abandoned.
+async fn with_heartbeat<T>(
+    task_queue: &dyn TaskQueue,
+    delivery: &Delivery,
+    http_client: &HttpClient,
+    api_base_url: &str,
+    headers: &HeaderMap,
+    interval: Duration,
+    processing: impl Future<Output = T>,
+) -> T {
+    tokio::pin!(processing);
+    let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            output = &mut processing => return output,
+            _ = ticks.tick() => {
+                let task_id = &delivery.message.task_global_id;
+                if let Err(err) = task_queue.heartbeat(delivery).await {
+                    warn!("Heartbeat for task {} failed: {:?}", task_id, err);
+                }
+                // Fails until the task has been started, which is fine
+                if let Err(err) = send_heartbeat(http_client, api_base_url, task_id, headers).await {
+                    warn!("Failed to report heartbeat for task {}: {:?}", task_id, err);
+                }
+            }
+        }
+    }
+}
 
-    // 1. Update task state to InProgress
-    update_task_state(http_client, api_base_url, task_id, "start")
-        .await
-        .context("Failed to update task state to InProgress")?;
+// Doubles with every attempt, from the base delay after the first
+fn retry_delay(config: &WorkerConfig, attempt: u32) -> Duration {
+    let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+    Duration::from_secs(
+        config
+            .retry_base_delay_secs
+            .saturating_mul(factor)
+            .min(config.retry_max_delay_secs),
+    )
+}
+
+async fn process_task(
+    config: &WorkerConfig,
+    services: &Services,
+    task_id: &str,
+    mut headers: HeaderMap,
+) -> Result<()> {
+    let Services {
+        processor,
+        http_client,
+        metrics,
+        outbox,
+    } = services;
+    let api_base_url = config.api_base_url.as_str();
+    info!("Processing task: {}", task_id);
 
-    // 2. Get task details
-    let task = get_task(http_client, api_base_url, task_id)
+    // 1. Get task details, including the request id to echo on every follow-up call
+    let task = get_task(http_client, api_base_url, task_id, &headers)
         .await
         .context("Failed to get task details")?;
+    Span::current().record("task_type", task.task_type.as_str());
+    let request_id = task.request_id.as_deref();
+    if let Some(value) = request_id.and_then(|id| HeaderValue::from_str(id).ok()) {
+        headers.insert(REQUEST_ID_HEADER, value);
+    }
 
-    // 3. Process the task
-    info!("Processing source file: {}", task.source_file);
+    // 2. Update task state to InProgress. A redelivered task may have got further last time: one
+    // already in progress is picked up where it is, and one that already finished (with only the
+    // ack lost) has nothing left to do.
+    match task.state {
+        TaskState::Completed | TaskState::Failed | TaskState::Cancelled => {
+            info!("Task {} already {}, skipping it", task_id, task.state);
+            return Ok(());
+        }
+        TaskState::InProgress => info!("Task {} already in progress, processing it again", task_id),
+        _ => update_task_state(http_client, api_base_url, task_id, "start", &headers, None)
+            .await
+            .context("Failed to update task state to InProgress")?,
+    }
 
-    // This is where the actual task processing/rendering would happen
-    match execute_task_processing(&task).await {
-        Ok(result_file) => {
+    // 3. Process the task
+    info!(
+        "Processing source file: {} (request_id: {})",
+        task.source_file,
+        request_id.unwrap_or("-")
+    );
+
+    // Download, process and upload. Retryable errors are tried again here, the task is only
+    // failed once that's no use.
+    let progress = ProgressReporter::new(
+        http_client.clone(),
+        api_base_url,
+        task_id,
+        &headers,
+        Duration::from_secs(config.progress_interval_secs),
+    );
+    let checkpoints = Checkpoints::new(
+        http_client.clone(),
+        api_base_url,
+        task_id,
+        &headers,
+        task.checkpoint.as_deref(),
+    );
+    let started_at = Instant::now();
+    let processed = processor
+        .process_isolated(task.clone(), progress, checkpoints)
+        .await;
+    let outcome = if processed.is_ok() {
+        "completed"
+    } else {
+        "failed"
+    };
+    metrics.observe_processing(task.task_type.as_str(), outcome, started_at.elapsed());
+    match processed {
+        Ok(completion) => {
             // 4. Complete the task
-            complete_task(http_client, api_base_url, task_id, &result_file)
-                .await
-                .context("Failed to complete task")?;
+            report_outcome(
+                http_client,
+                api_base_url,
+                outbox,
+                task_id,
+                Outcome::Complete(completion),
+                &headers,
+            )
+            .await
+            .context("Failed to complete task")?;
             info!("Task completed: {}", task_id);
         }
+        Err(ProcessingError::TimedOut(limit)) if config.requeue_timed_out => {
+            // Left in progress, the queue hands it out again
+            anyhow::bail!("Task {} timed out after {:?}", task_id, limit);
+        }
         Err(err) => {
-            error!("Task processing failed: {:?}", err);
-            // 4. Mark task as failed
-            update_task_state(http_client, api_base_url, task_id, "fail")
-                .await
-                .context("Failed to update task state to failed")?;
+            error!("Task processing failed: {}", err);
+            // 4. Mark task as failed, with why
+            let reason = err.to_string();
+            report_outcome(
+                http_client,
+                api_base_url,
+                outbox,
+                task_id,
+                Outcome::Fail { reason },
+                &headers,
+            )
+            .await
+            .context("Failed to update task state to failed")?;
         }
     }
 
     Ok(())
 }
 
-async fn get_task(http_client: &HttpClient, api_base_url: &str, task_id: &str) -> Result<Task> {
+// Tells the API how the task ended. If it can't be reached the outcome is buffered instead, to be
+// sent once it's back, and the task counts as done: processing it again would only end the same
+// way.
+async fn report_outcome(
+    http_client: &HttpClient,
+    api_base_url: &str,
+    outbox: &Outbox,
+    task_id: &str,
+    outcome: Outcome,
+    headers: &HeaderMap,
+) -> Result<()> {
+    let sent = match &outcome {
+        Outcome::Complete(completion) => {
+            complete_task(http_client, api_base_url, task_id, completion, headers).await
+        }
+        Outcome::Fail { reason } => {
+            update_task_state(
+                http_client,
+                api_base_url,
+                task_id,
+                "fail",
+                headers,
+                Some(reason),
+            )
+            .await
+        }
+    };
+    match sent {
+        Err(err) if is_api_failure(&err) => {
+            warn!(
+                "Couldn't reach the API about task {}, buffering its outcome: {:#}",
+                task_id, err
+            );
+            outbox.push(task_id, outcome, headers).await
+        }
+        sent => sent,
+    }
+}
+
+async fn get_task(
+    http_client: &HttpClient,
+    api_base_url: &str,
+    task_id: &str,
+    headers: &HeaderMap,
+) -> Result<Task> {
     let url = format!("{}/task/{}", api_base_url, task_id);
     let response = http_client
         .get(&url)
+        .headers(headers.clone())
         .send()
         .await
-        .context("Failed to send GET request")?;
+        .context("Failed to send GET request")?
+        .error_for_status()
+        .context("Task lookup rejected")?;
 
     let task = response
         .json::<Task>()
@@ -160,13 +613,40 @@ async fn update_task_state(
     api_base_url: &str,
     task_id: &str,
     action: &str,
+    headers: &HeaderMap,
+    // Recorded in the task's history
+    reason: Option<&str>,
 ) -> Result<()> {
     let url = format!("{}/task/{}/{}", api_base_url, task_id, action);
+    let mut request = http_client.put(&url).headers(headers.clone());
+    if let Some(reason) = reason {
+        request = request.query(&[("reason", reason)]);
+    }
+    request
+        .send()
+        .await
+        .context(format!("Failed to send PUT request to {}", action))?
+        .error_for_status()
+        .context(format!("Request to {} rejected", action))?;
+
+    Ok(())
+}
+
+async fn send_heartbeat(
+    http_client: &HttpClient,
+    api_base_url: &str,
+    task_id: &str,
+    headers: &HeaderMap,
+) -> Result<()> {
+    let url = format!("{}/task/{}/heartbeat", api_base_url, task_id);
     http_client
         .put(&url)
+        .headers(headers.clone())
         .send()
         .await
-        .context(format!("Failed to send PUT request to {}", action))?;
+        .context("Failed to send heartbeat")?
+        .error_for_status()
+        .context("Heartbeat rejected")?;
 
     Ok(())
 }
@@ -175,36 +655,122 @@ async fn complete_task(
     http_client: &HttpClient,
     api_base_url: &str,
     task_id: &str,
-    result_file: &str,
+    completion: &TaskCompletionRequest,
+    headers: &HeaderMap,
 ) -> Result<()> {
     let url = format!("{}/task/{}/complete", api_base_url, task_id);
-    let request = TaskCompletionRequest {
-        result_file: result_file.to_string(),
-    };
-
     http_client
         .put(&url)
-        .json(&request)
+        .headers(headers.clone())
+        .json(completion)
         .send()
         .await
-        .context("Failed to send complete task request")?;
+        .context("Failed to send complete task request")?
+        .error_for_status()
+        .context("Completion rejected")?;
 
     Ok(())
 }
 
-// This function would contain your actual task processing logic
-async fn execute_task_processing(task: &Task) -> Result<String> {
-    // Simulate processing time
-    time::sleep(Duration::from_secs(2)).await;
+// Sent on every call made about a task, the request id is added once the task has been fetched
+fn trace_headers(worker_id: &str, attempt: u32) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        WORKER_ID_HEADER,
+        HeaderValue::from_str(worker_id).context("Invalid worker id")?,
+    );
+    headers.insert(ATTEMPT_HEADER, HeaderValue::from(attempt));
+    Ok(headers)
+}
 
-    // Here we would:
-    // 1. Download the source file from S3 or other storage
-    // 2. Process it (e.g., render 3D model)
-    // 3. Upload the result to storage
-    // 4. Return the path to the result file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::StorageConfig;
+    use queue::memory::{InMemoryQueue, Settled};
+    use queue::TaskMessage;
+    use task_types::task::TaskType;
+
+    // Nothing listens on port 1, so every call to the API fails straight away
+    const UNREACHABLE_API: &str = "http://127.0.0.1:1";
+
+    async fn services(config: &WorkerConfig, dir: &std::path::Path) -> Services {
+        let storage = StorageConfig {
+            region: Some("us-east-1".to_string()),
+            endpoint_url: Some(UNREACHABLE_API.to_string()),
+            access_key_id: Some("test".to_string()),
+            secret_access_key: Some("test".to_string()),
+            ..StorageConfig::default()
+        };
+        let http_client = api_client::build(config).unwrap();
+        let processor = Processor::new(
+            ObjectStore::init(&storage).await,
+            &storage,
+            config,
+            Plugins::load(None).unwrap(),
+            Workspaces::init(dir, "test-worker", None).unwrap(),
+        );
+        let outbox = Outbox::open(
+            http_client.clone(),
+            config.api_base_url.clone(),
+            dir.join("outbox.jsonl"),
+        )
+        .unwrap();
+        Services {
+            processor: Arc::new(processor),
+            http_client,
+            metrics: Metrics::new(),
+            outbox: Arc::new(outbox),
+        }
+    }
 
-    // For this example, we'll just return a dummy result file path
-    let result_file = format!("processed_{}.result", task.task_uuid);
+    async fn handle_next(queue: &InMemoryQueue, config: &WorkerConfig, services: &Services) {
+        let delivery = queue.next(Duration::ZERO).await.unwrap().unwrap();
+        let handled = handle_delivery(queue, config, services, "test-worker", delivery).await;
+        assert!(handled.is_err());
+    }
 
-    Ok(result_file)
+    #[tokio::test]
+    async fn failed_attempts_are_retried_then_dead_lettered_on_the_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = WorkerConfig {
+            api_base_url: UNREACHABLE_API.to_string(),
+            api_retries: 0,
+            max_attempts: 3,
+            retry_base_delay_secs: 10,
+            ..WorkerConfig::default()
+        };
+        let services = services(&config, dir.path()).await;
+        let queue = InMemoryQueue::new();
+        let task = Task::new("alice".into(), TaskType::Render, "in.mp4".into());
+        let task_global_id = task.get_global_id();
+        queue.push(TaskMessage::new(&task));
+
+        for _ in 0..config.max_attempts {
+            handle_next(&queue, &config, &services).await;
+        }
+
+        let settled = queue.settled();
+        assert_eq!(
+            settled[..2],
+            [
+                Settled::Retried {
+                    task_global_id: task_global_id.clone(),
+                    delay: Duration::from_secs(10),
+                },
+                Settled::Retried {
+                    task_global_id: task_global_id.clone(),
+                    delay: Duration::from_secs(20),
+                },
+            ]
+        );
+        assert!(matches!(
+            &settled[2],
+            Settled::DeadLettered { task_global_id: id, error }
+                if *id == task_global_id && error.contains("Failed to get task details")
+        ));
+        // Dead-lettered, not put back for another go
+        assert_eq!(settled.len(), 3);
+        assert_eq!(queue.waiting().await.unwrap(), 0);
+    }
 }
@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+    TEXT_FORMAT,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// Upper bounds in seconds. Tasks run from seconds to an hour or more.
+const PROCESSING_BUCKETS: [f64; 12] = [
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0, 7200.0,
+];
+// A poll that finds nothing waits out the whole poll timeout, the top buckets are mostly those
+const POLL_BUCKETS: [f64; 10] = [0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 20.0, 30.0];
+
+// Everything served on the worker's /metrics. Cheap to clone, the clones share the same counters.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    tasks_processed: IntCounterVec,
+    task_failures: IntCounterVec,
+    processing_duration: HistogramVec,
+    queue_poll_duration: HistogramVec,
+    in_flight: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let tasks_processed = IntCounterVec::new(
+            Opts::new(
+                "worker_tasks_processed_total",
+                "Tasks processed to the end, by whether they completed or were marked failed",
+            ),
+            &["task_type", "outcome"],
+        )
+        .expect("valid counter");
+        let task_failures = IntCounterVec::new(
+            Opts::new(
+                "worker_task_failures_total",
+                "Deliveries that errored out, by whether they were retried or dead-lettered",
+            ),
+            &["action"],
+        )
+        .expect("valid counter");
+        let processing_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "worker_processing_duration_seconds",
+                "Time taken to process a task, retries included",
+            )
+            .buckets(PROCESSING_BUCKETS.to_vec()),
+            &["task_type", "outcome"],
+        )
+        .expect("valid histogram");
+        let queue_poll_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "worker_queue_poll_duration_seconds",
+                "Time taken to read the next message off the queue",
+            )
+            .buckets(POLL_BUCKETS.to_vec()),
+            &["outcome"],
+        )
+        .expect("valid histogram");
+        let in_flight = IntGauge::new("worker_tasks_in_flight", "Tasks being processed right now")
+            .expect("valid gauge");
+
+        registry
+            .register(Box::new(tasks_processed.clone()))
+            .expect("registered once");
+        registry
+            .register(Box::new(task_failures.clone()))
+            .expect("registered once");
+        registry
+            .register(Box::new(processing_duration.clone()))
+            .expect("registered once");
+        registry
+            .register(Box::new(queue_poll_duration.clone()))
+            .expect("registered once");
+        registry
+            .register(Box::new(in_flight.clone()))
+            .expect("registered once");
+
+        Self {
+            registry,
+            tasks_processed,
+            task_failures,
+            processing_duration,
+            queue_poll_duration,
+            in_flight,
+        }
+    }
+
+    // outcome is "completed" or "failed"
+    pub fn observe_processing(&self, task_type: &str, outcome: &str, elapsed: Duration) {
+        self.tasks_processed
+            .with_label_values(&[task_type, outcome])
+            .inc();
+        self.processing_duration
+            .with_label_values(&[task_type, outcome])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // action is "retried" or "dead_lettered"
+    pub fn record_failure(&self, action: &str) {
+        self.task_failures.with_label_values(&[action]).inc();
+    }
+
+    // outcome is "message", "empty" or "error"
+    pub fn observe_poll(&self, outcome: &str, elapsed: Duration) {
+        self.queue_poll_duration
+            .with_label_values(&[outcome])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // Counts a task as in flight until the guard is dropped
+    pub fn in_flight(&self) -> InFlight {
+        self.in_flight.inc();
+        InFlight(self.in_flight.clone())
+    }
+
+    // The Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        // Only fails on a metric with an invalid name, which would have failed registering
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+
+    // Serves GET /metrics on the address until the worker exits
+    pub fn spawn_server(&self, address: &str) -> Result<()> {
+        let address: SocketAddr = address
+            .parse()
+            .with_context(|| format!("Invalid metrics address {}", address))?;
+        let server = Server::try_bind(&address)
+            .with_context(|| format!("Failed to bind the metrics listener to {}", address))?;
+
+        let metrics = self.clone();
+        let make_service = make_service_fn(move |_| {
+            let metrics = metrics.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let metrics = metrics.clone();
+                    async move { Ok::<_, Infallible>(metrics.respond(req)) }
+                }))
+            }
+        });
+
+        info!("Serving metrics on http://{}/metrics", address);
+        tokio::spawn(async move {
+            if let Err(err) = server.serve(make_service).await {
+                error!("Metrics listener stopped: {}", err);
+            }
+        });
+        Ok(())
+    }
+
+    fn respond(&self, req: Request<Body>) -> Response<Body> {
+        let mut response = Response::default();
+        if req.method() != Method::GET || req.uri().path() != "/metrics" {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            TEXT_FORMAT.parse().expect("valid header value"),
+        );
+        *response.body_mut() = Body::from(self.render());
+        response
+    }
+}
+
+pub struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
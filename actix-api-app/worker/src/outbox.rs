@@ -0,0 +1,337 @@
+use crate::api_client::HttpClient;
+use crate::breaker::is_api_failure;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use task_types::requests::TaskCompletionRequest;
+use tokio::{task, time};
+
+// How a finished task ended, as it would have been sent to the API
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Complete(TaskCompletionRequest),
+    Fail { reason: String },
+}
+
+// An outcome the API couldn't be told about, with the headers the call went out with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Pending {
+    id: u64,
+    task_id: String,
+    #[serde(flatten)]
+    outcome: Outcome,
+    headers: Vec<(String, String)>,
+}
+
+// Outcomes of finished tasks the API couldn't be reached to record, kept until it can be. Without
+// it such a task would be retried from scratch, or left in progress once it ran out of attempts.
+// Each is appended to a JSON lines file as it's buffered, so a restart picks them back up, and
+// the file is rewritten as they're flushed.
+pub struct Outbox {
+    http_client: HttpClient,
+    api_base_url: String,
+    path: PathBuf,
+    // Only held to read or change the list, never across the file being written
+    pending: Mutex<Vec<Pending>>,
+    // Ids only ever go up, carrying on from the file's, so none is used twice
+    next_id: AtomicU64,
+    // Held while the file is written (off the async threads), so an append can't land in a file
+    // a rewrite is about to replace
+    file: tokio::sync::Mutex<()>,
+}
+
+impl Outbox {
+    // Loads whatever an earlier run left buffered in the file
+    pub fn open(http_client: HttpClient, api_base_url: String, path: PathBuf) -> Result<Self> {
+        let mut pending = Vec::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.context("Failed to read the outbox")?;
+                    // A line cut short by a crash mid-write is the only one that can be bad
+                    match serde_json::from_str::<Pending>(&line) {
+                        Ok(entry) => pending.push(entry),
+                        Err(err) => warn!("Skipping an unreadable outbox entry: {}", err),
+                    }
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to open {}", path.display()))
+            }
+        }
+        if !pending.is_empty() {
+            info!(
+                "{} task outcomes are waiting in the outbox from an earlier run",
+                pending.len()
+            );
+        }
+        let next_id = pending.iter().map(|entry| entry.id + 1).max().unwrap_or(0);
+        Ok(Self {
+            http_client,
+            api_base_url,
+            path,
+            pending: Mutex::new(pending),
+            next_id: AtomicU64::new(next_id),
+            file: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Vec<Pending>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Keeps the outcome to be sent later. Returns once it's on disk.
+    pub async fn push(&self, task_id: &str, outcome: Outcome, headers: &HeaderMap) -> Result<()> {
+        // Taken first, so entries go into the list and the file in the order of their ids
+        let _file = self.file.lock().await;
+        let entry = Pending {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            task_id: task_id.to_string(),
+            outcome,
+            headers: headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+        };
+        let line = serde_json::to_string(&entry)?;
+        let path = self.path.clone();
+        task::spawn_blocking(move || append(&path, &line))
+            .await
+            .context("Writing to the outbox panicked")??;
+        self.entries().push(entry);
+        Ok(())
+    }
+
+    // Sends the buffered outcomes in order, stopping at the first one the API can't be reached
+    // for. One the API rejects (the task was deleted, or moved on without us) is dropped.
+    pub async fn flush(&self) -> Result<()> {
+        let entries = self.entries().clone();
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut done = Vec::new();
+        let mut unreachable = None;
+        for entry in &entries {
+            match self.send(entry).await {
+                Ok(()) => info!("Sent the buffered outcome of task {}", entry.task_id),
+                Err(err) if is_api_failure(&err) => {
+                    unreachable = Some(err);
+                    break;
+                }
+                Err(err) => warn!(
+                    "Dropping the buffered outcome of task {}: {:?}",
+                    entry.task_id, err
+                ),
+            }
+            done.push(entry.id);
+        }
+
+        self.remove(&done).await?;
+        match unreachable {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // Drops the entries with these ids and rewrites the file without them
+    async fn remove(&self, ids: &[u64]) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let _file = self.file.lock().await;
+        let lines = {
+            let mut entries = self.entries();
+            entries.retain(|entry| !ids.contains(&entry.id));
+            entries
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<serde_json::Result<Vec<_>>>()?
+        };
+        let path = self.path.clone();
+        task::spawn_blocking(move || rewrite(&path, &lines))
+            .await
+            .context("Rewriting the outbox panicked")?
+    }
+
+    async fn send(&self, entry: &Pending) -> Result<()> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &entry.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.insert(name, value);
+            }
+        }
+        let request = match &entry.outcome {
+            Outcome::Complete(completion) => self
+                .http_client
+                .put(format!(
+                    "{}/task/{}/complete",
+                    self.api_base_url, entry.task_id
+                ))
+                .json(completion),
+            Outcome::Fail { reason } => self
+                .http_client
+                .put(format!("{}/task/{}/fail", self.api_base_url, entry.task_id))
+                .query(&[("reason", reason)]),
+        };
+        request
+            .headers(headers)
+            .send()
+            .await
+            .context("Failed to send buffered outcome")?
+            .error_for_status()
+            .context("Buffered outcome rejected")?;
+        Ok(())
+    }
+
+    // Flushes every interval for as long as the worker runs, starting straight away with what an
+    // earlier run left
+    pub fn spawn_flushes(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(err) = self.flush().await {
+                    warn!(
+                        "The API is still unreachable, outcomes stay buffered: {:?}",
+                        err
+                    );
+                }
+            }
+        });
+    }
+}
+
+fn append(path: &Path, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{}", line)?;
+    file.sync_data().context("Failed to write to the outbox")
+}
+
+// Swapped in whole, so a crash leaves either the old file or the new one
+fn rewrite(path: &Path, lines: &[String]) -> Result<()> {
+    let temp = path.with_extension("tmp");
+    let mut file =
+        File::create(&temp).with_context(|| format!("Failed to create {}", temp.display()))?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.sync_all()?;
+    fs::rename(&temp, path).context("Failed to replace the outbox")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client;
+    use crate::config::WorkerConfig;
+
+    fn open(path: &Path) -> Outbox {
+        let http_client = api_client::build(&WorkerConfig::default()).unwrap();
+        Outbox::open(http_client, "http://127.0.0.1:1".to_string(), path.into()).unwrap()
+    }
+
+    fn fail(reason: &str) -> Outcome {
+        Outcome::Fail {
+            reason: reason.to_string(),
+        }
+    }
+
+    // Task id and reason of each entry, in order
+    fn summary(outbox: &Outbox) -> Vec<(u64, String, String)> {
+        outbox
+            .entries()
+            .iter()
+            .map(|entry| match &entry.outcome {
+                Outcome::Fail { reason } => (entry.id, entry.task_id.clone(), reason.clone()),
+                Outcome::Complete(_) => (entry.id, entry.task_id.clone(), "complete".into()),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn buffered_outcomes_are_replayed_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.jsonl");
+        let mut headers = HeaderMap::new();
+        headers.insert("x-task-attempt", HeaderValue::from(2));
+
+        let outbox = open(&path);
+        outbox
+            .push("task-1", fail("disk full"), &headers)
+            .await
+            .unwrap();
+        outbox
+            .push("task-2", fail("timed out"), &headers)
+            .await
+            .unwrap();
+        drop(outbox);
+        // As a crash part way through an append would leave it
+        append(&path, "{\"id\": 2, \"task_").unwrap();
+
+        let outbox = open(&path);
+        assert_eq!(
+            summary(&outbox),
+            [
+                (0, "task-1".into(), "disk full".into()),
+                (1, "task-2".into(), "timed out".into()),
+            ]
+        );
+        assert_eq!(
+            outbox.entries()[0].headers,
+            [("x-task-attempt".to_string(), "2".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn sent_outcomes_are_rewritten_out_and_ids_are_never_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.jsonl");
+        let outbox = open(&path);
+        for task_id in ["task-1", "task-2", "task-3"] {
+            outbox
+                .push(task_id, fail("failed"), &HeaderMap::new())
+                .await
+                .unwrap();
+        }
+
+        outbox.remove(&[0, 2]).await.unwrap();
+        assert_eq!(summary(&outbox), [(1, "task-2".into(), "failed".into())]);
+        assert!(!path.with_extension("tmp").exists());
+
+        // Emptied, the next entry still gets a new id
+        outbox.remove(&[1]).await.unwrap();
+        outbox
+            .push("task-4", fail("failed"), &HeaderMap::new())
+            .await
+            .unwrap();
+        drop(outbox);
+
+        let outbox = open(&path);
+        assert_eq!(summary(&outbox), [(3, "task-4".into(), "failed".into())]);
+        outbox
+            .push("task-5", fail("failed"), &HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(outbox.entries()[1].id, 4);
+    }
+}
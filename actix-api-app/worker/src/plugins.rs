@@ -0,0 +1,348 @@
+use crate::checkpoint::Checkpoints;
+use crate::processing::{ProcessingError, ResourceLimits};
+use crate::progress::ProgressReporter;
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use wasmtime::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, ResourceLimiter, Store, UpdateDeadline,
+};
+
+// How often running plugins yield, which is what lets the task's timeout cancel them
+const EPOCH_INTERVAL: Duration = Duration::from_millis(100);
+
+// Task processors compiled to WASM, one module per task type, named {task_type}.wasm in the
+// plugin directory. A module exports its `memory` and `process() -> i32`, returning 0 when the
+// result has been written and anything else to fail the task. It gets the host functions below
+// (module "host") instead of any file or network access of its own:
+//
+//   source_len() -> i64                                 size of the source file
+//   read_source(offset: i64, ptr: i32, len: i32) -> i32 bytes read into memory, 0 at the end
+//   write_result(ptr: i32, len: i32) -> i32             appends to the result, 0 when written
+//   report_progress(percent: i32, ptr: i32, len: i32)   with a UTF-8 message
+//   log(ptr: i32, len: i32)                             a UTF-8 line for the worker's log
+//   checkpoint_len() -> i32                             size of the checkpoint to resume from,
+//                                                       -1 if there's none
+//   read_checkpoint(ptr: i32, len: i32) -> i32          bytes of it read into memory
+//   save_checkpoint(ptr: i32, len: i32) -> i32          0 once the API has stored it
+//
+// The functions returning i32 return -1 when they fail.
+pub struct Plugins {
+    engine: Engine,
+    linker: Linker<PluginState>,
+    modules: HashMap<String, Module>,
+}
+
+// What a running plugin's host functions work on
+struct PluginState {
+    source: File,
+    result: File,
+    progress: ProgressReporter,
+    checkpoints: Checkpoints,
+    limits: ResourceLimits,
+    // Epoch intervals the plugin has been running through, its CPU time give or take one
+    running_intervals: u32,
+    // Set when the plugin went over a limit, which is why it was stopped
+    limit_exceeded: Option<String>,
+}
+
+// Stops a plugin growing its memory past the limit, rather than letting the growth fail quietly
+impl ResourceLimiter for PluginState {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        if let Some(limit) = self.limits.memory_bytes {
+            if desired as u64 > limit {
+                let exceeded = format!(
+                    "plugin wanted {} MB of memory, its limit is {} MB",
+                    desired as u64 / (1024 * 1024),
+                    limit / (1024 * 1024)
+                );
+                self.limit_exceeded = Some(exceeded.clone());
+                return Err(anyhow!(exceeded));
+            }
+        }
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        _desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+impl Plugins {
+    // Compiles every module in the directory. Without one there are no plugins.
+    pub fn load(dir: Option<&str>) -> Result<Self> {
+        let mut config = Config::new();
+        config.async_support(true).epoch_interruption(true);
+        let engine = Engine::new(&config).context("Failed to set up the WASM engine")?;
+
+        let mut modules = HashMap::new();
+        if let Some(dir) = dir {
+            let entries =
+                fs::read_dir(dir).with_context(|| format!("Failed to read plugin dir {}", dir))?;
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_none_or(|extension| extension != "wasm") {
+                    continue;
+                }
+                let Some(task_type) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                let module = Module::from_file(&engine, &path)
+                    .with_context(|| format!("Failed to compile plugin {}", path.display()))?;
+                info!(
+                    "Loaded plugin {} for task type {}",
+                    path.display(),
+                    task_type
+                );
+                modules.insert(task_type.to_string(), module);
+            }
+        }
+
+        // Running plugins only yield once the epoch moves on. Ticked from a thread of its own, a
+        // runtime thread could be the one stuck running the plugin.
+        if !modules.is_empty() {
+            let engine = engine.clone();
+            thread::spawn(move || loop {
+                thread::sleep(EPOCH_INTERVAL);
+                engine.increment_epoch();
+            });
+        }
+
+        let mut linker = Linker::new(&engine);
+        define_host_functions(&mut linker)?;
+        Ok(Self {
+            engine,
+            linker,
+            modules,
+        })
+    }
+
+    pub fn handles(&self, task_type: &str) -> bool {
+        self.modules.contains_key(task_type)
+    }
+
+    pub fn task_types(&self) -> impl Iterator<Item = &str> {
+        self.modules.keys().map(String::as_str)
+    }
+
+    // Runs the task type's plugin over the source file, writing the result to output
+    pub async fn run(
+        &self,
+        task_type: &str,
+        limits: ResourceLimits,
+        source: &Path,
+        output: &Path,
+        progress: &ProgressReporter,
+        checkpoints: &Checkpoints,
+    ) -> Result<(), ProcessingError> {
+        let Some(module) = self.modules.get(task_type) else {
+            return Err(ProcessingError::Permanent(anyhow!(
+                "No plugin for task type {}",
+                task_type
+            )));
+        };
+        let state = PluginState {
+            source: File::open(source)?,
+            result: File::create(output)?,
+            progress: progress.clone(),
+            checkpoints: checkpoints.clone(),
+            limits,
+            running_intervals: 0,
+            limit_exceeded: None,
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| state);
+        // Reached every epoch the plugin runs through, where its CPU time is checked before it
+        // yields
+        store.epoch_deadline_callback(|mut context| {
+            let state = context.data_mut();
+            state.running_intervals += 1;
+            if let Some(limit) = state.limits.cpu_time {
+                if EPOCH_INTERVAL * state.running_intervals > limit {
+                    let exceeded = format!("plugin ran past its CPU limit of {:?}", limit);
+                    state.limit_exceeded = Some(exceeded.clone());
+                    return Err(anyhow!(exceeded));
+                }
+            }
+            Ok(UpdateDeadline::Yield(1))
+        });
+
+        // Anything going wrong inside the module would go the same way next time
+        let code = async {
+            let instance = self
+                .linker
+                .instantiate_async(&mut store, module)
+                .await
+                .context("Failed to instantiate plugin")?;
+            let process = instance
+                .get_typed_func::<(), i32>(&mut store, "process")
+                .context("Plugin doesn't export process")?;
+            process
+                .call_async(&mut store, ())
+                .await
+                .context("Plugin trapped")
+        }
+        .await;
+        if let Some(exceeded) = store.data_mut().limit_exceeded.take() {
+            return Err(ProcessingError::ResourceLimit(exceeded));
+        }
+        let code = code.map_err(ProcessingError::Permanent)?;
+        if code != 0 {
+            return Err(ProcessingError::Permanent(anyhow!(
+                "Plugin for {} failed with code {}",
+                task_type,
+                code
+            )));
+        }
+        store.into_data().result.sync_all()?;
+        Ok(())
+    }
+}
+
+fn define_host_functions(linker: &mut Linker<PluginState>) -> Result<()> {
+    linker.func_wrap("host", "source_len", |caller: Caller<'_, PluginState>| {
+        caller
+            .data()
+            .source
+            .metadata()
+            .map_or(-1, |metadata| metadata.len() as i64)
+    })?;
+    linker.func_wrap(
+        "host",
+        "read_source",
+        |mut caller: Caller<'_, PluginState>, offset: i64, ptr: i32, len: i32| {
+            let Some(memory) = memory(&mut caller) else {
+                return -1;
+            };
+            let (data, state) = memory.data_and_store_mut(&mut caller);
+            let Some(buffer) = guest_slice(data, ptr, len) else {
+                return -1;
+            };
+            let read = u64::try_from(offset)
+                .map_err(|_| std::io::ErrorKind::InvalidInput.into())
+                .and_then(|offset| state.source.seek(SeekFrom::Start(offset)))
+                .and_then(|_| state.source.read(buffer));
+            read.map_or(-1, |read| read as i32)
+        },
+    )?;
+    linker.func_wrap(
+        "host",
+        "write_result",
+        |mut caller: Caller<'_, PluginState>, ptr: i32, len: i32| {
+            let Some(memory) = memory(&mut caller) else {
+                return -1;
+            };
+            let (data, state) = memory.data_and_store_mut(&mut caller);
+            let Some(buffer) = guest_slice(data, ptr, len) else {
+                return -1;
+            };
+            state.result.write_all(buffer).map_or(-1, |_| 0)
+        },
+    )?;
+    linker.func_wrap(
+        "host",
+        "report_progress",
+        |mut caller: Caller<'_, PluginState>, percent: i32, ptr: i32, len: i32| {
+            let message = guest_string(&mut caller, ptr, len);
+            let percent = percent.clamp(0, 100) as u8;
+            caller.data().progress.report(percent, message);
+        },
+    )?;
+    linker.func_wrap(
+        "host",
+        "log",
+        |mut caller: Caller<'_, PluginState>, ptr: i32, len: i32| {
+            info!("[plugin] {}", guest_string(&mut caller, ptr, len));
+        },
+    )?;
+    linker.func_wrap(
+        "host",
+        "checkpoint_len",
+        |caller: Caller<'_, PluginState>| {
+            caller
+                .data()
+                .checkpoints
+                .last()
+                .map_or(-1, |checkpoint| checkpoint.len() as i32)
+        },
+    )?;
+    linker.func_wrap(
+        "host",
+        "read_checkpoint",
+        |mut caller: Caller<'_, PluginState>, ptr: i32, len: i32| {
+            let Some(checkpoint) = caller.data().checkpoints.last() else {
+                return -1;
+            };
+            let Some(memory) = memory(&mut caller) else {
+                return -1;
+            };
+            let Some(buffer) = guest_slice(memory.data_mut(&mut caller), ptr, len) else {
+                return -1;
+            };
+            let read = buffer.len().min(checkpoint.len());
+            buffer[..read].copy_from_slice(&checkpoint[..read]);
+            read as i32
+        },
+    )?;
+    linker.func_wrap_async(
+        "host",
+        "save_checkpoint",
+        |mut caller: Caller<'_, PluginState>, (ptr, len): (i32, i32)| {
+            Box::new(async move {
+                let Some(memory) = memory(&mut caller) else {
+                    return -1;
+                };
+                let Some(checkpoint) =
+                    guest_slice(memory.data_mut(&mut caller), ptr, len).map(|bytes| bytes.to_vec())
+                else {
+                    return -1;
+                };
+                let checkpoints = caller.data().checkpoints.clone();
+                match checkpoints.save(checkpoint).await {
+                    Ok(()) => 0,
+                    Err(err) => {
+                        warn!("Failed to save the plugin's checkpoint: {:#}", err);
+                        -1
+                    }
+                }
+            })
+        },
+    )?;
+    Ok(())
+}
+
+fn memory(caller: &mut Caller<'_, PluginState>) -> Option<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory)
+}
+
+// The guest's ptr..ptr+len, None if that's outside its memory
+fn guest_slice(data: &mut [u8], ptr: i32, len: i32) -> Option<&mut [u8]> {
+    let start = usize::try_from(ptr).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    data.get_mut(start..end)
+}
+
+fn guest_string(caller: &mut Caller<'_, PluginState>, ptr: i32, len: i32) -> String {
+    let Some(memory) = memory(caller) else {
+        return String::new();
+    };
+    let data = memory.data_mut(caller);
+    guest_slice(data, ptr, len)
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,349 @@
+use crate::checkpoint::Checkpoints;
+use crate::command;
+use crate::config::{CommandConfig, StorageConfig, WorkerConfig};
+use crate::plugins::Plugins;
+use crate::progress::ProgressReporter;
+use crate::storage::{self, ObjectStore};
+use crate::validation::{self, ResultFormat};
+use crate::workspace::Workspaces;
+use anyhow::anyhow;
+use log::{info, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use task_types::requests::TaskCompletionRequest;
+use task_types::task::{Task, TaskType};
+use tokio::time;
+use tracing::{Instrument, Span};
+
+// Why processing a task didn't produce a result
+#[derive(Debug)]
+pub enum ProcessingError {
+    // Might well work next time, e.g. storage that couldn't be reached
+    Retryable(anyhow::Error),
+    // Will fail the same way however often it's tried, e.g. a source file that isn't there
+    Permanent(anyhow::Error),
+    // Ran past the task type's timeout and was cancelled
+    TimedOut(Duration),
+    // The processing panicked, with the panic's message. Not retried, a bug won't go away.
+    Panicked(String),
+    // Used more memory, CPU time or disk than the task is allowed, saying which. Not retried
+    // either.
+    ResourceLimit(String),
+}
+
+impl fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Retryable(e) => write!(f, "{:#}", e),
+            Self::Permanent(e) => write!(f, "{:#} (not retryable)", e),
+            Self::TimedOut(limit) => write!(f, "Timed out after {:?}", limit),
+            Self::Panicked(message) => write!(f, "Processing panicked: {}", message),
+            Self::ResourceLimit(message) => write!(f, "Resource limit exceeded: {}", message),
+        }
+    }
+}
+
+// Trouble with the local workspace, e.g. a full disk
+impl From<std::io::Error> for ProcessingError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Retryable(anyhow!(e).context("Workspace I/O failed"))
+    }
+}
+
+// What processing a single task may use, unlimited where unset
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResourceLimits {
+    pub memory_bytes: Option<u64>,
+    pub cpu_time: Option<Duration>,
+}
+
+impl ResourceLimits {
+    fn new(memory_limit_mb: Option<u64>, cpu_limit_secs: Option<u64>) -> Self {
+        Self {
+            memory_bytes: memory_limit_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+            cpu_time: cpu_limit_secs.map(Duration::from_secs),
+        }
+    }
+
+    // Limits of its own where there are any, these where not
+    fn or(self, defaults: Self) -> Self {
+        Self {
+            memory_bytes: self.memory_bytes.or(defaults.memory_bytes),
+            cpu_time: self.cpu_time.or(defaults.cpu_time),
+        }
+    }
+}
+
+// Runs tasks: fetches the source file into a scratch directory of the task's own, processes it
+// there (with the task type's command or plugin if it has one) and uploads the result. The
+// directory is removed afterwards however it went, and processing is stopped if it outgrows
+// its cap.
+pub struct Processor {
+    storage: ObjectStore,
+    result_prefix: String,
+    workspaces: Workspaces,
+    attempts: u32,
+    retry_delay_ms: u64,
+    default_timeout: Duration,
+    timeouts: HashMap<String, Duration>,
+    commands: HashMap<String, CommandConfig>,
+    plugins: Plugins,
+    limits: ResourceLimits,
+    result_formats: HashMap<String, ResultFormat>,
+}
+
+impl Processor {
+    pub fn new(
+        storage: ObjectStore,
+        storage_config: &StorageConfig,
+        config: &WorkerConfig,
+        plugins: Plugins,
+        workspaces: Workspaces,
+    ) -> Self {
+        Self {
+            storage,
+            result_prefix: storage_config.result_prefix.clone(),
+            workspaces,
+            attempts: config.processing_attempts,
+            retry_delay_ms: config.processing_retry_delay_ms,
+            default_timeout: Duration::from_secs(config.task_timeout_secs),
+            timeouts: config
+                .task_timeouts
+                .iter()
+                .map(|(task_type, secs)| (task_type.clone(), Duration::from_secs(*secs)))
+                .collect(),
+            commands: config.commands.clone(),
+            plugins,
+            limits: ResourceLimits::new(config.task_memory_limit_mb, config.task_cpu_limit_secs),
+            result_formats: config.result_formats.clone(),
+        }
+    }
+
+    // Processes the task, trying again after retryable errors until processing_attempts runs
+    // out. Returns the object key of the result along with its checksum. Past the task type's
+    // timeout the processing is dropped where it stands, which removes its workspace too.
+    pub async fn process(
+        &self,
+        task: &Task,
+        progress: &ProgressReporter,
+        checkpoints: &Checkpoints,
+    ) -> Result<TaskCompletionRequest, ProcessingError> {
+        let limit = self.timeout_for(task.task_type.as_str());
+        time::timeout(
+            limit,
+            self.process_with_retries(task, progress, checkpoints),
+        )
+        .await
+        .unwrap_or(Err(ProcessingError::TimedOut(limit)))
+    }
+
+    // Runs process() as a task of its own, so a panic in it fails this task instead of taking
+    // down the worker along with every other task it has in flight
+    pub async fn process_isolated(
+        self: &Arc<Self>,
+        task: Task,
+        progress: ProgressReporter,
+        checkpoints: Checkpoints,
+    ) -> Result<TaskCompletionRequest, ProcessingError> {
+        let processor = self.clone();
+        let processing = tokio::spawn(
+            async move { processor.process(&task, &progress, &checkpoints).await }
+                .instrument(Span::current()),
+        );
+        match processing.await {
+            Ok(processed) => processed,
+            Err(err) => {
+                let message = match err.try_into_panic() {
+                    Ok(panic) => panic
+                        .downcast_ref::<&str>()
+                        .map(|message| message.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown cause".to_string()),
+                    Err(err) => err.to_string(),
+                };
+                Err(ProcessingError::Panicked(message))
+            }
+        }
+    }
+
+    // The task types this worker can process: the built-in ones and those with a command or a
+    // plugin
+    pub fn processors(&self) -> Vec<TaskType> {
+        let configured: BTreeSet<&str> = self
+            .commands
+            .keys()
+            .map(String::as_str)
+            .chain(self.plugins.task_types())
+            .collect();
+        let mut processors = TaskType::BUILT_IN.to_vec();
+        for task_type in configured.into_iter().map(TaskType::from) {
+            if !processors.contains(&task_type) {
+                processors.push(task_type);
+            }
+        }
+        processors
+    }
+
+    fn timeout_for(&self, task_type: &str) -> Duration {
+        self.timeouts
+            .get(task_type)
+            .copied()
+            .unwrap_or(self.default_timeout)
+    }
+
+    async fn process_with_retries(
+        &self,
+        task: &Task,
+        progress: &ProgressReporter,
+        checkpoints: &Checkpoints,
+    ) -> Result<TaskCompletionRequest, ProcessingError> {
+        let mut attempt = 1;
+        loop {
+            match self.execute(task, progress, checkpoints).await {
+                Err(ProcessingError::Retryable(err)) if attempt < self.attempts => {
+                    let delay = self.retry_delay(attempt);
+                    warn!(
+                        "Processing task {} failed ({:#}), trying again in {:?}",
+                        task.task_uuid, err, delay
+                    );
+                    time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Doubles with every try, from the configured delay after the first
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        Duration::from_millis(self.retry_delay_ms.saturating_mul(factor))
+    }
+
+    async fn execute(
+        &self,
+        task: &Task,
+        progress: &ProgressReporter,
+        checkpoints: &Checkpoints,
+    ) -> Result<TaskCompletionRequest, ProcessingError> {
+        let (bucket, key) = storage::locate(&task.source_file, self.storage.bucket());
+        let Some(file_name) = key.rsplit('/').next().filter(|name| !name.is_empty()) else {
+            return Err(ProcessingError::Permanent(anyhow!(
+                "Task has no source file: {:?}",
+                task.source_file
+            )));
+        };
+
+        let workspace = self.workspaces.create()?;
+        let source = workspace.path().join(file_name);
+        let output = workspace.path().join(format!("processed_{}", file_name));
+
+        workspace
+            .capped(async {
+                info!("Downloading s3://{}/{}", bucket, key);
+                progress.report(0, "downloading source");
+                self.storage.download(bucket, key, &source).await?;
+                let task_type = task.task_type.as_str();
+                match self.commands.get(task_type) {
+                    Some(command) => {
+                        progress.report(10, format!("running {}", command.program));
+                        let limits =
+                            ResourceLimits::new(command.memory_limit_mb, command.cpu_limit_secs)
+                                .or(self.limits);
+                        command::run(
+                            command,
+                            limits,
+                            progress,
+                            workspace.path(),
+                            &source,
+                            &output,
+                        )
+                        .await
+                    }
+                    None if self.plugins.handles(task_type) => {
+                        progress.report(10, "running plugin");
+                        self.plugins
+                            .run(
+                                task_type,
+                                self.limits,
+                                &source,
+                                &output,
+                                progress,
+                                checkpoints,
+                            )
+                            .await
+                    }
+                    None if task.task_type == TaskType::Render => {
+                        render(&source, &output, progress, checkpoints).await
+                    }
+                    None => Err(ProcessingError::Permanent(anyhow!(
+                        "No processor for task type {}",
+                        task_type
+                    ))),
+                }
+            })
+            .await?;
+        let result_checksum = validation::check_result(
+            &output,
+            task.task_type.as_str(),
+            self.result_formats.get(task.task_type.as_str()).copied(),
+        )
+        .await?;
+        progress.report(90, "uploading result");
+
+        let result_key = format!(
+            "{}{}/{}/processed_{}",
+            self.result_prefix, task.user_uuid, task.task_uuid, file_name
+        );
+        self.storage.upload(&output, &result_key).await?;
+        info!(
+            "Uploaded result to s3://{}/{}",
+            self.storage.bucket(),
+            result_key
+        );
+
+        workspace.close()?;
+        Ok(TaskCompletionRequest {
+            result_file: result_key,
+            result_checksum: Some(result_checksum),
+        })
+    }
+}
+
+// This function would contain your actual task processing logic, e.g. rendering a 3D model.
+// For this example the source is copied through as the result.
+async fn render(
+    source: &Path,
+    output: &Path,
+    progress: &ProgressReporter,
+    checkpoints: &Checkpoints,
+) -> Result<(), ProcessingError> {
+    // Simulate processing time, in steps as a real render would go frame by frame. The checkpoint
+    // is the number of steps done, so a retry skips those.
+    const STEPS: u8 = 8;
+    let done = checkpoints
+        .last()
+        .and_then(|checkpoint| checkpoint.first().copied())
+        .unwrap_or(0)
+        .min(STEPS);
+    if done > 0 {
+        info!("Resuming the render after step {}", done);
+    }
+    for step in done..STEPS {
+        progress.report(
+            10 + step * 80 / STEPS,
+            format!("rendering step {} of {}", step + 1, STEPS),
+        );
+        time::sleep(Duration::from_millis(250)).await;
+        // Losing a checkpoint only costs redoing the step, not worth failing over
+        if let Err(err) = checkpoints.save(vec![step + 1]).await {
+            warn!("Failed to save a checkpoint: {:#}", err);
+        }
+    }
+
+    tokio::fs::copy(source, output).await?;
+    Ok(())
+}
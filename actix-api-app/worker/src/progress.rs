@@ -0,0 +1,75 @@
+use crate::api_client::HttpClient;
+use log::warn;
+use reqwest::header::HeaderMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use task_types::task::TaskProgress;
+use tracing::Instrument;
+
+// Handed to the processing so long tasks can say how far along they are. Updates go to the API's
+// progress endpoint, at most one per min_interval: the ones in between are dropped, apart from
+// reaching 100%, which always goes out. Sending never holds up the processing. Clones share the
+// throttling.
+#[derive(Clone)]
+pub struct ProgressReporter {
+    http_client: HttpClient,
+    url: String,
+    headers: HeaderMap,
+    min_interval: Duration,
+    last_sent: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ProgressReporter {
+    pub fn new(
+        http_client: HttpClient,
+        api_base_url: &str,
+        task_id: &str,
+        headers: &HeaderMap,
+        min_interval: Duration,
+    ) -> Self {
+        Self {
+            http_client,
+            url: format!("{}/task/{}/progress", api_base_url, task_id),
+            headers: headers.clone(),
+            min_interval,
+            last_sent: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn report(&self, percent: u8, message: impl Into<String>) {
+        let percent = percent.min(100);
+        {
+            let mut last_sent = self
+                .last_sent
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            if percent < 100 && last_sent.is_some_and(|at| now - at < self.min_interval) {
+                return;
+            }
+            *last_sent = Some(now);
+        }
+
+        let request = self
+            .http_client
+            .put(&self.url)
+            .headers(self.headers.clone())
+            .json(&TaskProgress {
+                percent,
+                message: Some(message.into()),
+            });
+        let url = self.url.clone();
+        tokio::spawn(
+            async move {
+                let sent = request
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status().map_err(Into::into));
+                if let Err(err) = sent {
+                    warn!("Failed to report progress to {}: {}", url, err);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
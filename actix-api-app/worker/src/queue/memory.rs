@@ -0,0 +1,118 @@
+use crate::queue::{Delivery, TaskMessage, TaskQueue};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use task_types::task::TaskGlobalId;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time;
+
+// What the worker did with a delivery, as the queue saw it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Settled {
+    Acked(TaskGlobalId),
+    Retried {
+        task_global_id: TaskGlobalId,
+        delay: Duration,
+    },
+    DeadLettered {
+        task_global_id: TaskGlobalId,
+        error: String,
+    },
+}
+
+// Queue for worker tests, so deliveries can go through the processing loop without a Redis to
+// hand and the test can check what became of each. Messages wait on a channel, a retried one goes
+// back on it straight away with its attempts counted up (the delay is only recorded).
+pub struct InMemoryQueue {
+    sender: UnboundedSender<TaskMessage>,
+    receiver: tokio::sync::Mutex<UnboundedReceiver<TaskMessage>>,
+    settled: Mutex<Vec<Settled>>,
+    // Receipts are handed out in order, like Redis entry ids
+    receipts: AtomicU64,
+}
+
+impl InMemoryQueue {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: tokio::sync::Mutex::new(receiver),
+            settled: Mutex::new(Vec::new()),
+            receipts: AtomicU64::new(0),
+        }
+    }
+
+    pub fn push(&self, message: TaskMessage) {
+        self.sender
+            .send(message)
+            .expect("the receiver lives as long as the queue");
+    }
+
+    // Everything acked, retried or dead-lettered so far, oldest first
+    pub fn settled(&self) -> Vec<Settled> {
+        self.settled
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn settle(&self, settled: Settled) {
+        self.settled
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(settled);
+    }
+}
+
+#[async_trait]
+impl TaskQueue for InMemoryQueue {
+    async fn next(&self, block: Duration) -> Result<Option<Delivery>> {
+        let mut receiver = self.receiver.lock().await;
+        let message = match time::timeout(block, receiver.recv()).await {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err(anyhow!("The in-memory queue was closed")),
+            Err(_) => return Ok(None),
+        };
+        let receipt = self.receipts.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(Delivery {
+            queue: "memory".to_string(),
+            receipt: receipt.to_string(),
+            message,
+        }))
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<()> {
+        self.settle(Settled::Acked(delivery.message.task_global_id.clone()));
+        Ok(())
+    }
+
+    async fn retry(&self, delivery: &Delivery, delay: Duration) -> Result<()> {
+        self.settle(Settled::Retried {
+            task_global_id: delivery.message.task_global_id.clone(),
+            delay,
+        });
+        self.push(TaskMessage {
+            attempts: delivery.message.attempts + 1,
+            ..delivery.message.clone()
+        });
+        Ok(())
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, error: &str) -> Result<()> {
+        self.settle(Settled::DeadLettered {
+            task_global_id: delivery.message.task_global_id.clone(),
+            error: error.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn heartbeat(&self, _delivery: &Delivery) -> Result<()> {
+        Ok(())
+    }
+
+    async fn waiting(&self) -> Result<u64> {
+        Ok(self.receiver.lock().await.len() as u64)
+    }
+}
@@ -0,0 +1,52 @@
+#[cfg(test)]
+pub mod memory;
+pub mod prefetch;
+pub mod redis;
+pub mod sqs;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use task_types::message::TaskMessage;
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64)
+}
+
+// A message taken off the queue. Nobody else is given it while this worker has it, and it's
+// handed out again if the worker never acks it.
+pub struct Delivery {
+    // The stream or queue it came from
+    pub queue: String,
+    // What acks it, the entry id in Redis or the receipt handle in SQS
+    pub receipt: String,
+    pub message: TaskMessage,
+}
+
+#[async_trait]
+pub trait TaskQueue: Send + Sync {
+    // The next message for this worker, waiting up to `block` for one. None if nothing came or
+    // what came was dropped.
+    async fn next(&self, block: Duration) -> Result<Option<Delivery>>;
+
+    // Marks the message done, it won't be handed out again
+    async fn ack(&self, delivery: &Delivery) -> Result<()>;
+
+    // Gives the message back after a failed attempt, to be handed out again once the delay has
+    // passed with its attempts counted up
+    async fn retry(&self, delivery: &Delivery, delay: Duration) -> Result<()>;
+
+    // Gives up on the message after its last attempt failed, moving it to the dead letter queue
+    // along with the error that attempt ended in
+    async fn dead_letter(&self, delivery: &Delivery, error: &str) -> Result<()>;
+
+    // Called every so often while the message is being processed, so it isn't taken for
+    // abandoned and handed to another worker. Fails if that's already happened.
+    async fn heartbeat(&self, delivery: &Delivery) -> Result<()>;
+
+    // Messages on the queues this worker reads that haven't been handed to any worker yet
+    async fn waiting(&self) -> Result<u64>;
+}
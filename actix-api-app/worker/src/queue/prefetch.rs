@@ -0,0 +1,98 @@
+use crate::queue::{Delivery, TaskQueue};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{error, warn};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time;
+
+// Keeps up to `capacity` messages from the inner queue waiting locally, read ahead while every
+// task slot is busy, so a worker on a slow link to the queue has its next task to hand as soon as
+// one finishes. Waiting messages are already claimed (leased in Redis, hidden in SQS), so one
+// that's waited stale_after or longer has its lease renewed before it's handed out, and is
+// dropped if that had already run out, as another worker may have it by now.
+pub struct PrefetchQueue {
+    inner: Arc<dyn TaskQueue>,
+    waiting: Mutex<mpsc::Receiver<(Delivery, Instant)>>,
+    stale_after: Duration,
+}
+
+impl PrefetchQueue {
+    pub fn spawn(
+        inner: Arc<dyn TaskQueue>,
+        capacity: usize,
+        poll_timeout: Duration,
+        error_backoff: Duration,
+        stale_after: Duration,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let reader = inner.clone();
+        tokio::spawn(async move {
+            // Room is reserved before reading, so no more than capacity are ever claimed and
+            // waiting. Stops once the queue is dropped.
+            while let Ok(slot) = sender.reserve().await {
+                match reader.next(poll_timeout).await {
+                    Ok(Some(delivery)) => slot.send((delivery, Instant::now())),
+                    Ok(None) => {}
+                    Err(err) => {
+                        error!("Error reading ahead from the task queue: {:?}", err);
+                        time::sleep(error_backoff).await;
+                    }
+                }
+            }
+        });
+
+        Self {
+            inner,
+            waiting: Mutex::new(receiver),
+            stale_after,
+        }
+    }
+}
+
+#[async_trait]
+impl TaskQueue for PrefetchQueue {
+    async fn next(&self, block: Duration) -> Result<Option<Delivery>> {
+        let mut waiting = self.waiting.lock().await;
+        let (delivery, read_at) = match time::timeout(block, waiting.recv()).await {
+            Ok(Some(waited)) => waited,
+            Ok(None) => return Err(anyhow!("The read-ahead of the task queue stopped")),
+            Err(_) => return Ok(None),
+        };
+
+        if read_at.elapsed() >= self.stale_after {
+            if let Err(err) = self.inner.heartbeat(&delivery).await {
+                warn!(
+                    "Dropping task {} read ahead {:?} ago, its lease ran out: {:?}",
+                    delivery.message.task_global_id,
+                    read_at.elapsed(),
+                    err
+                );
+                return Ok(None);
+            }
+        }
+        Ok(Some(delivery))
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<()> {
+        self.inner.ack(delivery).await
+    }
+
+    async fn retry(&self, delivery: &Delivery, delay: Duration) -> Result<()> {
+        self.inner.retry(delivery, delay).await
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, error: &str) -> Result<()> {
+        self.inner.dead_letter(delivery, error).await
+    }
+
+    async fn heartbeat(&self, delivery: &Delivery) -> Result<()> {
+        self.inner.heartbeat(delivery).await
+    }
+
+    // Not counting what's been read ahead, those are already handed to this worker
+    async fn waiting(&self) -> Result<u64> {
+        self.inner.waiting().await
+    }
+}
@@ -0,0 +1,641 @@
+use crate::config::RedisConfig;
+use crate::queue::{now_millis, Delivery, TaskMessage, TaskQueue};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use deadpool_redis::{Config, Connection, Pool, PoolConfig, Runtime};
+use log::{error, info, warn};
+use redis::streams::{
+    StreamClaimOptions, StreamId, StreamPendingCountReply, StreamPendingReply, StreamReadOptions,
+    StreamReadReply,
+};
+use redis::{AsyncCommands, RedisError, Script};
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::iter;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Mirrors the API's queue module: a stream whose entries carry a JSON TaskMessage in this field
+const MESSAGE_FIELD: &str = "message";
+// Why a dead-lettered entry was given up on
+const ERROR_FIELD: &str = "error";
+
+// Beside each stream, as in the API. Entries to retry wait in {stream}:delayed until the API
+// moves them back, ones that ran out of attempts end up on the {stream}:dead stream.
+const DELAYED_SUFFIX: &str = "delayed";
+const DEAD_SUFFIX: &str = "dead";
+// The priority streams beside each stream with redis.priority_streams on, also as in the API
+const HIGH_SUFFIX: &str = "high";
+const LOW_SUFFIX: &str = "low";
+// How much each priority multiplies a type's weight by, high to low. High priority tasks are read
+// first most of the time, but a busy high stream can't starve the low one.
+const PRIORITY_WEIGHTS: [(Option<&str>, u32); 3] =
+    [(Some(HIGH_SUFFIX), 4), (None, 2), (Some(LOW_SUFFIX), 1)];
+
+// Entries looked at per XPENDING call
+const CLAIM_BATCH: usize = 100;
+
+// Refreshes a lease, but only if it's still ours. Returns 0 if it ran out or went to another
+// worker in the meantime.
+const RENEW_LEASE_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+// The task streams this worker takes work from, read as one consumer of the workers' consumer
+// group. Redis hands each entry to a single consumer and keeps it pending until that consumer
+// acks it, so a worker that dies mid-task hasn't lost the entry.
+//
+// Each entry handed out also gets a lease, a {stream}:lease:{id} key naming the consumer that
+// expires unless the worker's heartbeat keeps renewing it. Pending entries whose lease has run out
+// are taken over by reclaim(), however long their task normally takes.
+pub struct RedisQueue {
+    // Connections are checked with a PING before being reused, so one Redis dropped is replaced
+    pool: Pool,
+    // Highest priority first, one chunk of a stream per task type for each priority there is
+    streams: Vec<String>,
+    group: String,
+    consumer: String,
+    lease: Duration,
+    renew_lease: Script,
+    // Per stream, where reading back the entries this consumer had pending before a restart has
+    // got to. A stream is removed once they've all been handed out again.
+    backlog: Mutex<HashMap<String, String>>,
+    // Entries read in the same call as the one handed out, a read across several streams can
+    // return one from each. They're already pending against us, so they go out next.
+    buffered: Mutex<VecDeque<(String, StreamId)>>,
+    // Per stream, how often it's read first relative to the others (its type's weight, times its
+    // priority's), and the running credit the weighted round robin picks by
+    weights: Vec<u32>,
+    credits: Mutex<Vec<i64>>,
+}
+
+impl RedisQueue {
+    // Consumes the streams of the given task types (which the API has to route, see
+    // redis.routed_task_types), or the default stream if there are none. A type's weight (1 if it
+    // has none) sets its share of the reads while several streams have tasks waiting. With
+    // redis.priority_streams each also has a high and a low priority stream.
+    pub fn new(
+        config: &RedisConfig,
+        task_types: &[String],
+        weights: &HashMap<String, u32>,
+        consumer: String,
+        lease: Duration,
+    ) -> Result<Self> {
+        let pool = Config {
+            pool: Some(PoolConfig::new(config.pool_size.max(2))),
+            ..Config::from_url(config.uri.clone())
+        }
+        .create_pool(Some(Runtime::Tokio1))
+        .context("Failed to set up Redis pool")?;
+
+        let (streams, weights): (Vec<String>, Vec<u32>) = if task_types.is_empty() {
+            (vec![config.queue_name.clone()], vec![1])
+        } else {
+            task_types
+                .iter()
+                .map(|task_type| {
+                    (
+                        format!("{}:{}", config.queue_name, task_type),
+                        weights.get(task_type).copied().unwrap_or(1),
+                    )
+                })
+                .unzip()
+        };
+        let (streams, weights) = if config.priority_streams {
+            PRIORITY_WEIGHTS
+                .into_iter()
+                .flat_map(|(suffix, factor)| {
+                    streams.iter().zip(&weights).map(move |(stream, weight)| {
+                        let stream = match suffix {
+                            Some(suffix) => format!("{stream}:{suffix}"),
+                            None => stream.clone(),
+                        };
+                        (stream, weight.saturating_mul(factor))
+                    })
+                })
+                .unzip()
+        } else {
+            (streams, weights)
+        };
+        let backlog = streams
+            .iter()
+            .map(|stream| (stream.clone(), "0".to_string()))
+            .collect();
+        Ok(Self {
+            pool,
+            streams,
+            group: config.consumer_group.clone(),
+            consumer,
+            lease,
+            renew_lease: Script::new(RENEW_LEASE_SCRIPT),
+            backlog: Mutex::new(backlog),
+            buffered: Mutex::new(VecDeque::new()),
+            credits: Mutex::new(vec![0; weights.len()]),
+            weights,
+        })
+    }
+
+    pub fn streams(&self) -> &[String] {
+        &self.streams
+    }
+
+    // Creates the consumer group on each stream unless it's already there. It starts from the
+    // beginning of the stream, so tasks queued before any worker came up aren't skipped.
+    pub async fn ensure_group(&self) -> Result<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("Failed to get Redis connection")?;
+        for stream in &self.streams {
+            let created: Result<(), RedisError> =
+                conn.xgroup_create_mkstream(stream, &self.group, "0").await;
+            match created {
+                Ok(()) => info!("Created consumer group {} on {}", self.group, stream),
+                Err(e) if e.code() == Some("BUSYGROUP") => {}
+                Err(e) => return Err(e).context("Failed to create consumer group"),
+            }
+        }
+        Ok(())
+    }
+
+    // Takes over every pending entry whose lease has run out, whichever consumer it was given
+    // to, and queues them to be handed out by next() again. Leases only run out when their worker
+    // died or hung. Returns how many were taken over.
+    pub async fn reclaim(&self) -> Result<usize> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("Failed to get Redis connection")?;
+
+        let mut claimed = 0;
+        for stream in &self.streams {
+            let taken = self.reclaim_stream(&mut conn, stream).await?;
+            if taken > 0 {
+                // Anything claimed may sort before where the backlog read had got to
+                self.backlog
+                    .lock()
+                    .unwrap()
+                    .insert(stream.clone(), "0".to_string());
+            }
+            claimed += taken;
+        }
+
+        if claimed > 0 {
+            warn!("Took over {} abandoned queue entries", claimed);
+        }
+        Ok(claimed)
+    }
+
+    async fn reclaim_stream(&self, conn: &mut Connection, stream: &str) -> Result<usize> {
+        let min_idle = self.lease.as_millis() as usize;
+        let mut claimed = 0;
+        let mut start = "-".to_string();
+        loop {
+            let pending: StreamPendingCountReply = conn
+                .xpending_count(stream, &self.group, &start, "+", CLAIM_BATCH)
+                .await
+                .context("Error executing XPENDING")?;
+            let Some(last) = pending.ids.last() else {
+                break;
+            };
+            start = format!("({}", last.id);
+
+            // Entries handed out less than a lease ago may not have had theirs taken yet
+            let idle: Vec<&str> = pending
+                .ids
+                .iter()
+                .filter(|pending| pending.last_delivered_ms >= min_idle)
+                .map(|pending| pending.id.as_str())
+                .collect();
+            if !idle.is_empty() {
+                let mut pipe = redis::pipe();
+                for id in &idle {
+                    pipe.exists(lease_key(stream, id));
+                }
+                let leased: Vec<bool> = pipe
+                    .query_async(conn)
+                    .await
+                    .context("Failed to look up leases")?;
+                let expired: Vec<&str> = idle
+                    .into_iter()
+                    .zip(leased)
+                    .filter(|(_, leased)| !leased)
+                    .map(|(id, _)| id)
+                    .collect();
+
+                if !expired.is_empty() {
+                    // The idle time is checked again, so an entry another worker took over since
+                    // XPENDING is left to it. JUSTID skips the payloads, next() reads them anyway.
+                    let ids: Vec<String> = conn
+                        .xclaim_options(
+                            stream,
+                            &self.group,
+                            &self.consumer,
+                            min_idle,
+                            &expired,
+                            StreamClaimOptions::default().with_justid(),
+                        )
+                        .await
+                        .context("Error executing XCLAIM")?;
+                    claimed += ids.len();
+                }
+            }
+
+            if pending.ids.len() < CLAIM_BATCH {
+                break;
+            }
+        }
+        Ok(claimed)
+    }
+
+    // Leases the entry to this worker until it's acked or the lease runs out
+    async fn take_lease(&self, stream: &str, id: &str) -> Result<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("Failed to get Redis connection")?;
+        conn.pset_ex::<_, _, ()>(
+            lease_key(stream, id),
+            &self.consumer,
+            self.lease.as_millis() as usize,
+        )
+        .await
+        .context("Failed to take lease")?;
+        Ok(())
+    }
+
+    // Acks the entry and deletes it from the stream, so the stream only ever holds tasks that are
+    // waiting or in progress
+    async fn remove(&self, stream: &str, id: &str) -> Result<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("Failed to get Redis connection")?;
+        redis::pipe()
+            .atomic()
+            .xack(stream, &self.group, &[id])
+            .ignore()
+            .xdel(stream, &[id])
+            .ignore()
+            .del(lease_key(stream, id))
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .context("Failed to ack queue entry")?;
+        Ok(())
+    }
+
+    // The streams in the order to try them: the one the smooth weighted round robin picks, then
+    // the rest heaviest first. Over many reads each stream goes first in proportion to its weight,
+    // so a busy heavy stream is drained first without starving a light one. Priority is part of
+    // the weight rather than strict, so low priority tasks still get their share of the reads
+    // while high priority ones keep coming.
+    fn read_order(&self) -> Vec<&str> {
+        let mut credits = self.credits.lock().unwrap();
+        let total: i64 = self.weights.iter().map(|&weight| weight as i64).sum();
+        for (credit, &weight) in credits.iter_mut().zip(&self.weights) {
+            *credit += weight as i64;
+        }
+        let picked = (0..credits.len())
+            .max_by_key(|&i| (credits[i], Reverse(i)))
+            .unwrap_or(0);
+        credits[picked] -= total;
+
+        // Stable, so of two streams as heavy the higher priority one is tried first
+        let mut rest: Vec<usize> = (0..self.weights.len()).filter(|&i| i != picked).collect();
+        rest.sort_by_key(|&i| Reverse(self.weights[i]));
+        iter::once(picked)
+            .chain(rest)
+            .map(|i| self.streams[i].as_str())
+            .collect()
+    }
+
+    // An entry that can't be decoded would never get any further, so it's dropped
+    async fn decode(&self, stream: String, entry: StreamId) -> Result<Option<Delivery>> {
+        let message = entry
+            .get::<String>(MESSAGE_FIELD)
+            .and_then(|message| serde_json::from_str::<TaskMessage>(&message).ok());
+        match message {
+            Some(message) => {
+                self.take_lease(&stream, &entry.id).await?;
+                Ok(Some(Delivery {
+                    queue: stream,
+                    receipt: entry.id,
+                    message,
+                }))
+            }
+            None => {
+                error!("Dropping malformed queue entry {} on {}", entry.id, stream);
+                self.remove(&stream, &entry.id).await?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TaskQueue for RedisQueue {
+    // The next entry for this worker, waiting up to `block` for one. Entries it was given before
+    // a restart and never acked come first.
+    async fn next(&self, block: Duration) -> Result<Option<Delivery>> {
+        let buffered = self.buffered.lock().unwrap().pop_front();
+        if let Some((stream, entry)) = buffered {
+            return self.decode(stream, entry).await;
+        }
+
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("Failed to get Redis connection")?;
+        let options = StreamReadOptions::default()
+            .group(&self.group, &self.consumer)
+            .count(1);
+
+        let backlog: Vec<(String, String)> = self
+            .backlog
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(stream, after)| (stream.clone(), after.clone()))
+            .collect();
+        if !backlog.is_empty() {
+            // An id rather than ">" reads back this consumer's own pending entries after it. Each
+            // is handed out once, one that fails again is left for the reaper.
+            let (streams, ids): (Vec<_>, Vec<_>) = backlog.iter().cloned().unzip();
+            let reply: StreamReadReply = conn
+                .xread_options(&streams, &ids, &options)
+                .await
+                .context("Error executing XREADGROUP")?;
+            let entries = entries(reply);
+            {
+                let mut cursors = self.backlog.lock().unwrap();
+                for (stream, after) in &backlog {
+                    // Unless a reclaim restarted it in the meantime
+                    if cursors.get(stream) != Some(after) {
+                        continue;
+                    }
+                    match entries.iter().find(|(from, _)| from == stream) {
+                        Some((_, entry)) => {
+                            cursors.insert(stream.clone(), entry.id.clone());
+                        }
+                        None => {
+                            cursors.remove(stream);
+                        }
+                    }
+                }
+            }
+            // Every stream's cursor has moved past its entry, so the others are handed out next
+            let mut entries = entries.into_iter();
+            if let Some((stream, entry)) = entries.next() {
+                self.buffered.lock().unwrap().extend(entries);
+                return self.decode(stream, entry).await;
+            }
+        }
+
+        // With several streams, each is tried without waiting in weighted order before waiting
+        // on all of them at once
+        if self.streams.len() > 1 {
+            for stream in self.read_order() {
+                let reply: Option<StreamReadReply> = conn
+                    .xread_options(&[stream], &[">"], &options)
+                    .await
+                    .context("Error executing XREADGROUP")?;
+                if let Some((stream, entry)) =
+                    reply.map(entries).unwrap_or_default().into_iter().next()
+                {
+                    return self.decode(stream, entry).await;
+                }
+            }
+        }
+
+        let options = options.block(block.as_millis() as usize);
+        let newest = vec![">"; self.streams.len()];
+        let reply: Option<StreamReadReply> = conn
+            .xread_options(&self.streams, &newest, &options)
+            .await
+            .context("Error executing XREADGROUP")?;
+        let mut entries = reply.map(entries).unwrap_or_default().into_iter();
+        match entries.next() {
+            Some((stream, entry)) => {
+                self.buffered.lock().unwrap().extend(entries);
+                self.decode(stream, entry).await
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<()> {
+        self.remove(&delivery.queue, &delivery.receipt).await
+    }
+
+    async fn retry(&self, delivery: &Delivery, delay: Duration) -> Result<()> {
+        let message = retried(delivery)?;
+        let due = now_millis().saturating_add(delay.as_millis().min(i64::MAX as u128) as i64);
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("Failed to get Redis connection")?;
+        redis::pipe()
+            .atomic()
+            .zadd(
+                format!("{}:{}", delivery.queue, DELAYED_SUFFIX),
+                message,
+                due,
+            )
+            .ignore()
+            .xack(&delivery.queue, &self.group, &[&delivery.receipt])
+            .ignore()
+            .xdel(&delivery.queue, &[&delivery.receipt])
+            .ignore()
+            .del(lease_key(&delivery.queue, &delivery.receipt))
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .context("Failed to schedule retry")?;
+        Ok(())
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, error: &str) -> Result<()> {
+        let message = retried(delivery)?;
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("Failed to get Redis connection")?;
+        redis::pipe()
+            .atomic()
+            .xadd(
+                format!("{}:{}", delivery.queue, DEAD_SUFFIX),
+                "*",
+                &[(MESSAGE_FIELD, message.as_str()), (ERROR_FIELD, error)],
+            )
+            .ignore()
+            .xack(&delivery.queue, &self.group, &[&delivery.receipt])
+            .ignore()
+            .xdel(&delivery.queue, &[&delivery.receipt])
+            .ignore()
+            .del(lease_key(&delivery.queue, &delivery.receipt))
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .context("Failed to dead-letter queue entry")?;
+        Ok(())
+    }
+
+    async fn heartbeat(&self, delivery: &Delivery) -> Result<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("Failed to get Redis connection")?;
+        let renewed: i64 = self
+            .renew_lease
+            .key(lease_key(&delivery.queue, &delivery.receipt))
+            .arg(&self.consumer)
+            .arg(self.lease.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await
+            .context("Failed to renew lease")?;
+        if renewed == 0 {
+            anyhow::bail!(
+                "Lost the lease on queue entry {} on {}",
+                delivery.receipt,
+                delivery.queue
+            );
+        }
+        Ok(())
+    }
+
+    // Acked entries are deleted, so whatever is in a stream and not pending is waiting
+    async fn waiting(&self) -> Result<u64> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("Failed to get Redis connection")?;
+        let mut waiting = 0;
+        for stream in &self.streams {
+            let length: u64 = conn
+                .xlen(stream)
+                .await
+                .context("Failed to read stream length")?;
+            let pending = match conn
+                .xpending::<_, _, StreamPendingReply>(stream, &self.group)
+                .await
+            {
+                Ok(reply) => reply.count() as u64,
+                Err(e) if e.code() == Some("NOGROUP") => 0,
+                Err(e) => return Err(e).context("Failed to read pending entries"),
+            };
+            waiting += length.saturating_sub(pending);
+        }
+        Ok(waiting)
+    }
+}
+
+fn lease_key(stream: &str, id: &str) -> String {
+    format!("{}:lease:{}", stream, id)
+}
+
+// The delivered message with the failed attempt counted
+fn retried(delivery: &Delivery) -> Result<String> {
+    let message = TaskMessage {
+        attempts: delivery.message.attempts + 1,
+        ..delivery.message.clone()
+    };
+    serde_json::to_string(&message).context("Failed to serialize task message")
+}
+
+// Every entry in the reply along with the stream it came from
+fn entries(reply: StreamReadReply) -> Vec<(String, StreamId)> {
+    reply
+        .keys
+        .into_iter()
+        .flat_map(|key| {
+            let stream = key.key;
+            key.ids
+                .into_iter()
+                .map(move |entry| (stream.clone(), entry))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The pool only connects when a connection is asked for, so nothing needs to be listening
+    fn queue(priority_streams: bool, task_types: &[&str], weights: &[(&str, u32)]) -> RedisQueue {
+        let config = RedisConfig {
+            uri: "redis://127.0.0.1:1".to_string(),
+            priority_streams,
+            ..RedisConfig::default()
+        };
+        let task_types: Vec<String> = task_types.iter().map(|t| t.to_string()).collect();
+        let weights = weights.iter().map(|&(t, w)| (t.to_string(), w)).collect();
+        RedisQueue::new(
+            &config,
+            &task_types,
+            &weights,
+            "test".to_string(),
+            Duration::from_secs(60),
+        )
+        .unwrap()
+    }
+
+    // How often each stream is tried first over `reads` reads, and the most reads in a row any
+    // stream went without being first
+    fn first_picks(queue: &RedisQueue, reads: usize) -> (HashMap<String, usize>, usize) {
+        let mut picks = HashMap::new();
+        let mut last_first: HashMap<String, usize> = HashMap::new();
+        let mut longest_wait = 0;
+        for read in 0..reads {
+            let order = queue.read_order();
+            assert_eq!(order.len(), queue.streams().len());
+            *picks.entry(order[0].to_string()).or_default() += 1;
+            let since = last_first
+                .insert(order[0].to_string(), read)
+                .map_or(read + 1, |last| read - last);
+            longest_wait = longest_wait.max(since);
+        }
+        (picks, longest_wait)
+    }
+
+    #[test]
+    fn streams_go_first_in_proportion_to_their_weights() {
+        let queue = queue(false, &["render", "thumbnail"], &[("render", 3)]);
+        let (picks, longest_wait) = first_picks(&queue, 400);
+        assert_eq!(picks["task_queue:render"], 300);
+        assert_eq!(picks["task_queue:thumbnail"], 100);
+        // The light stream goes first at least once in every round of 3 + 1
+        assert!(longest_wait <= 4, "{longest_wait}");
+    }
+
+    #[test]
+    fn busy_high_priority_streams_do_not_starve_low_ones() {
+        let queue = queue(true, &[], &[]);
+        assert_eq!(
+            queue.streams(),
+            ["task_queue:high", "task_queue", "task_queue:low"]
+        );
+        let (picks, longest_wait) = first_picks(&queue, 700);
+        assert_eq!(picks["task_queue:high"], 400);
+        assert_eq!(picks["task_queue"], 200);
+        assert_eq!(picks["task_queue:low"], 100);
+        assert!(longest_wait <= 7, "{longest_wait}");
+        // After the pick, the rest are tried heaviest (highest priority) first
+        assert_eq!(
+            queue.read_order(),
+            ["task_queue:high", "task_queue", "task_queue:low"]
+        );
+    }
+}
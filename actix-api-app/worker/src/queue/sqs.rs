@@ -0,0 +1,235 @@
+use crate::config::SqsConfig;
+use crate::queue::{now_millis, Delivery, TaskMessage, TaskQueue};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use aws_config::{timeout::TimeoutConfig, BehaviorVersion, Region};
+use aws_sdk_sqs::types::{MessageAttributeValue, MessageSystemAttributeName, QueueAttributeName};
+use aws_sdk_sqs::Client;
+use log::{error, info};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+// The longest SQS long polls and holds a message back for
+const MAX_WAIT: Duration = Duration::from_secs(20);
+const MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+// The longest a received message can be kept hidden
+const MAX_VISIBILITY: Duration = Duration::from_secs(12 * 60 * 60);
+// Message attribute carrying why a dead-lettered message was given up on
+const ERROR_ATTRIBUTE: &str = "error";
+
+// Receives from the SQS queue. A message stays hidden from other workers for the visibility
+// timeout and comes back once that runs out unless it's deleted, which is what acking does. The
+// queue's redrive policy (set by the API) moves it to the dead letter queue after enough tries,
+// unless the worker gives up on it first.
+pub struct SqsQueue {
+    client: Client,
+    queue_url: String,
+    visibility_timeout_secs: i32,
+    dead_letter_queue_arn: Option<String>,
+    // Looked up from the ARN the first time a message is dead-lettered
+    dead_letter_queue_url: OnceCell<String>,
+}
+
+impl SqsQueue {
+    pub async fn init(config: &SqsConfig) -> Self {
+        // Long polls wait up to MAX_WAIT on top of the time the call itself may take
+        let timeouts = TimeoutConfig::builder()
+            .operation_timeout(Duration::from_secs(config.operation_timeout_secs) + MAX_WAIT)
+            .build();
+        let mut loader = aws_config::defaults(BehaviorVersion::latest()).timeout_config(timeouts);
+        if let Some(region) = &config.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(endpoint_url) = &config.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+
+        Self {
+            client: Client::new(&loader.load().await),
+            queue_url: config.queue_url.clone(),
+            visibility_timeout_secs: config.visibility_timeout_secs.min(i32::MAX as u32) as i32,
+            dead_letter_queue_arn: config.dead_letter_queue_arn.clone(),
+            dead_letter_queue_url: OnceCell::new(),
+        }
+    }
+
+    async fn delete(&self, receipt: &str) -> Result<()> {
+        self.client
+            .delete_message()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt)
+            .send()
+            .await
+            .context("Failed to delete SQS message")?;
+        Ok(())
+    }
+
+    // The dead letter queue's URL, from the name and account in its ARN
+    // (arn:aws:sqs:{region}:{account}:{name})
+    async fn dead_letter_queue_url(&self, arn: &str) -> Result<&str> {
+        let url = self
+            .dead_letter_queue_url
+            .get_or_try_init(|| async {
+                let mut parts = arn.rsplitn(3, ':');
+                let (Some(name), Some(account)) = (parts.next(), parts.next()) else {
+                    anyhow::bail!("Invalid dead letter queue ARN {}", arn);
+                };
+                let output = self
+                    .client
+                    .get_queue_url()
+                    .queue_name(name)
+                    .queue_owner_aws_account_id(account)
+                    .send()
+                    .await
+                    .context("Failed to look up the dead letter queue")?;
+                output
+                    .queue_url()
+                    .map(str::to_string)
+                    .context("No URL for the dead letter queue")
+            })
+            .await?;
+        Ok(url)
+    }
+
+    // Sends a message that arrived before its due_at back for the rest of its delay
+    async fn postpone(&self, body: &str, receipt: &str, due_at: i64) -> Result<()> {
+        let remaining = Duration::from_millis(due_at.saturating_sub(now_millis()).max(0) as u64);
+        self.client
+            .send_message()
+            .queue_url(&self.queue_url)
+            .message_body(body)
+            .delay_seconds(remaining.min(MAX_DELAY).as_secs() as i32)
+            .send()
+            .await
+            .context("Failed to postpone SQS message")?;
+        self.delete(receipt).await
+    }
+}
+
+#[async_trait]
+impl TaskQueue for SqsQueue {
+    async fn next(&self, block: Duration) -> Result<Option<Delivery>> {
+        let output = self
+            .client
+            .receive_message()
+            .queue_url(&self.queue_url)
+            .max_number_of_messages(1)
+            .wait_time_seconds(block.min(MAX_WAIT).as_secs() as i32)
+            .visibility_timeout(self.visibility_timeout_secs)
+            .message_system_attribute_names(MessageSystemAttributeName::ApproximateReceiveCount)
+            .send()
+            .await
+            .context("Failed to receive from SQS")?;
+        let Some(received) = output.messages().first() else {
+            return Ok(None);
+        };
+        let (Some(body), Some(receipt)) = (received.body(), received.receipt_handle()) else {
+            return Ok(None);
+        };
+
+        // A message that can't be decoded would never get any further, so it's dropped
+        let Ok(mut message) = serde_json::from_str::<TaskMessage>(body) else {
+            error!(
+                "Dropping malformed SQS message {}",
+                received.message_id().unwrap_or("-")
+            );
+            self.delete(receipt).await?;
+            return Ok(None);
+        };
+
+        if let Some(due_at) = message.due_at.filter(|&at| at > now_millis()) {
+            info!("Task {} isn't due yet, postponing", message.task_global_id);
+            self.postpone(body, receipt, due_at).await?;
+            return Ok(None);
+        }
+
+        // SQS counts receives itself, the message can't be changed to carry them
+        if let Some(received) = received
+            .attributes()
+            .and_then(|attributes| {
+                attributes.get(&MessageSystemAttributeName::ApproximateReceiveCount)
+            })
+            .and_then(|count| count.parse::<u32>().ok())
+        {
+            message.attempts = received.saturating_sub(1);
+        }
+
+        Ok(Some(Delivery {
+            queue: self.queue_url.clone(),
+            receipt: receipt.to_string(),
+            message,
+        }))
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<()> {
+        self.delete(&delivery.receipt).await
+    }
+
+    // Keeps the message hidden for the delay instead of the rest of its visibility timeout
+    async fn retry(&self, delivery: &Delivery, delay: Duration) -> Result<()> {
+        self.client
+            .change_message_visibility()
+            .queue_url(&self.queue_url)
+            .receipt_handle(&delivery.receipt)
+            .visibility_timeout(delay.min(MAX_VISIBILITY).as_secs() as i32)
+            .send()
+            .await
+            .context("Failed to change SQS message visibility")?;
+        Ok(())
+    }
+
+    // Starts the visibility timeout over, SQS's own lease on a received message
+    async fn heartbeat(&self, delivery: &Delivery) -> Result<()> {
+        self.client
+            .change_message_visibility()
+            .queue_url(&self.queue_url)
+            .receipt_handle(&delivery.receipt)
+            .visibility_timeout(self.visibility_timeout_secs)
+            .send()
+            .await
+            .context("Failed to extend SQS message visibility")?;
+        Ok(())
+    }
+
+    // Sent to the dead letter queue with the error as a message attribute, rather than left to
+    // the redrive policy: the task has been failed by then, so the receives it would take to get
+    // there would only be acked. Without a dead letter queue it's dropped.
+    async fn dead_letter(&self, delivery: &Delivery, error: &str) -> Result<()> {
+        if let Some(arn) = &self.dead_letter_queue_arn {
+            let dead_letter_queue_url = self.dead_letter_queue_url(arn).await?;
+            let body = serde_json::to_string(&delivery.message)
+                .context("Failed to serialize task message")?;
+            let error = MessageAttributeValue::builder()
+                .data_type("String")
+                .string_value(error)
+                .build()
+                .context("Failed to build the error attribute")?;
+            self.client
+                .send_message()
+                .queue_url(dead_letter_queue_url)
+                .message_body(body)
+                .message_attributes(ERROR_ATTRIBUTE, error)
+                .send()
+                .await
+                .context("Failed to send to the dead letter queue")?;
+        }
+        self.delete(&delivery.receipt).await
+    }
+
+    // SQS only keeps an approximate count
+    async fn waiting(&self) -> Result<u64> {
+        let output = self
+            .client
+            .get_queue_attributes()
+            .queue_url(&self.queue_url)
+            .attribute_names(QueueAttributeName::ApproximateNumberOfMessages)
+            .send()
+            .await
+            .context("Failed to read SQS queue attributes")?;
+        Ok(output
+            .attributes()
+            .and_then(|attributes| attributes.get(&QueueAttributeName::ApproximateNumberOfMessages))
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0))
+    }
+}
@@ -0,0 +1,199 @@
+use crate::api_client::HttpClient;
+use crate::queue::TaskQueue;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use reqwest::StatusCode;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use task_types::requests::{LoadReport, RegisterWorkerRequest, WorkerLoad, WorkerStatusRequest};
+use task_types::task::TaskType;
+use tokio::time;
+
+// This worker's entry in the API's list of workers. Tasks are tracked from when they're taken off
+// the queue until they're acked, and sent along with every status report.
+pub struct Registration {
+    http_client: HttpClient,
+    api_base_url: String,
+    worker_id: String,
+    hostname: String,
+    task_types: Vec<String>,
+    processors: Vec<TaskType>,
+    concurrency: usize,
+    current_tasks: Mutex<BTreeSet<String>>,
+    // Total time taken and count of the tasks finished since the last load report
+    finished: Mutex<(Duration, u32)>,
+}
+
+// Drops the task from the current ones when processing ends, however it ends, counting the time
+// it took towards the next load report
+pub struct Tracked {
+    registration: Arc<Registration>,
+    task_id: String,
+    started_at: Instant,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.registration.tasks().remove(&self.task_id);
+        let mut finished = self
+            .registration
+            .finished
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        finished.0 += self.started_at.elapsed();
+        finished.1 += 1;
+    }
+}
+
+impl Registration {
+    pub fn new(
+        http_client: HttpClient,
+        api_base_url: String,
+        worker_id: String,
+        hostname: String,
+        task_types: Vec<String>,
+        processors: Vec<TaskType>,
+        concurrency: usize,
+    ) -> Self {
+        // Only the types it takes off the queue count, whatever else it could process
+        let processors = processors
+            .into_iter()
+            .filter(|processor| {
+                task_types.is_empty()
+                    || task_types
+                        .iter()
+                        .any(|task_type| task_type == processor.as_str())
+            })
+            .collect();
+        Self {
+            http_client,
+            api_base_url,
+            worker_id,
+            hostname,
+            task_types,
+            processors,
+            concurrency,
+            current_tasks: Mutex::new(BTreeSet::new()),
+            finished: Mutex::new((Duration::ZERO, 0)),
+        }
+    }
+
+    fn tasks(&self) -> MutexGuard<'_, BTreeSet<String>> {
+        self.current_tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn track(self: &Arc<Self>, task_id: &str) -> Tracked {
+        self.tasks().insert(task_id.to_string());
+        Tracked {
+            registration: self.clone(),
+            task_id: task_id.to_string(),
+            started_at: Instant::now(),
+        }
+    }
+
+    // The share of task slots in use, and the mean time the tasks finished since the last call
+    // took, starting the count over
+    fn take_load(&self, queue_depth: u64) -> WorkerLoad {
+        let (total, count) =
+            std::mem::take(&mut *self.finished.lock().unwrap_or_else(PoisonError::into_inner));
+        WorkerLoad {
+            queue_depth,
+            avg_processing_secs: (count > 0).then(|| total.as_secs_f64() / f64::from(count)),
+            utilization: (self.tasks().len() as f64 / self.concurrency.max(1) as f64).min(1.0),
+        }
+    }
+
+    pub async fn register(&self) -> Result<()> {
+        let url = format!("{}/workers", self.api_base_url);
+        self.http_client
+            .post(&url)
+            .json(&RegisterWorkerRequest {
+                worker_id: self.worker_id.clone(),
+                hostname: self.hostname.clone(),
+                task_types: self.task_types.clone(),
+                processors: self.processors.clone(),
+                concurrency: self.concurrency as u32,
+            })
+            .send()
+            .await
+            .context("Failed to send registration")?
+            .error_for_status()
+            .context("Registration rejected")?;
+
+        info!("Registered with the API as {}", self.worker_id);
+        Ok(())
+    }
+
+    // Sends the current tasks, registering again if the API has no record of this worker (the
+    // registration at startup failed, or its storage was reset)
+    pub async fn report_status(&self) -> Result<()> {
+        let url = format!("{}/workers/{}/status", self.api_base_url, self.worker_id);
+        let current_tasks = self.tasks().iter().cloned().collect();
+        let response = self
+            .http_client
+            .put(&url)
+            .json(&WorkerStatusRequest { current_tasks })
+            .send()
+            .await
+            .context("Failed to send status")?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return self.register().await;
+        }
+        response.error_for_status().context("Status rejected")?;
+        Ok(())
+    }
+
+    // Reports status every interval for as long as the worker runs
+    pub fn spawn_status_reports(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            // Registering just now counts as the first report
+            let mut interval = time::interval_at(time::Instant::now() + interval, interval);
+            loop {
+                interval.tick().await;
+                if let Err(err) = self.report_status().await {
+                    warn!("Failed to report worker status: {:?}", err);
+                }
+            }
+        });
+    }
+
+    // Sends the worker's load, which the API works its autoscaling hint out from. Like a status
+    // report, registers again if the API has no record of this worker.
+    pub async fn report_load(&self, task_queue: &dyn TaskQueue) -> Result<()> {
+        let queue_depth = task_queue.waiting().await?;
+        let url = format!("{}/autoscale/hint", self.api_base_url);
+        let response = self
+            .http_client
+            .put(&url)
+            .json(&LoadReport {
+                worker_id: self.worker_id.clone(),
+                load: self.take_load(queue_depth),
+            })
+            .send()
+            .await
+            .context("Failed to send load report")?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return self.register().await;
+        }
+        response
+            .error_for_status()
+            .context("Load report rejected")?;
+        Ok(())
+    }
+
+    // Reports load every interval for as long as the worker runs
+    pub fn spawn_load_reports(self: Arc<Self>, task_queue: Arc<dyn TaskQueue>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = time::interval_at(time::Instant::now() + interval, interval);
+            loop {
+                interval.tick().await;
+                if let Err(err) = self.report_load(task_queue.as_ref()).await {
+                    warn!("Failed to report worker load: {:?}", err);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,231 @@
+use crate::config::StorageConfig;
+use crate::processing::ProcessingError;
+use anyhow::anyhow;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use log::{info, warn};
+use std::error::Error;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+const MIB: u64 = 1024 * 1024;
+// S3 won't take parts smaller than this, other than the last
+const MIN_PART_SIZE: u64 = 5 * MIB;
+
+// Source files and results in S3, or anything that speaks its API (MinIO). Objects are streamed
+// to and from disk, so a large artifact never has to fit in memory.
+pub struct ObjectStore {
+    client: Client,
+    bucket: String,
+    multipart_threshold: u64,
+    part_size: u64,
+}
+
+impl ObjectStore {
+    pub async fn init(config: &StorageConfig) -> Self {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = &config.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(endpoint_url) = &config.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+        if let (Some(access_key_id), Some(secret_access_key)) =
+            (&config.access_key_id, &config.secret_access_key)
+        {
+            loader = loader.credentials_provider(Credentials::new(
+                access_key_id,
+                secret_access_key,
+                None,
+                None,
+                "worker config",
+            ));
+        }
+        // MinIO serves buckets as paths rather than subdomains
+        let s3_config = aws_sdk_s3::config::Builder::from(&loader.load().await)
+            .force_path_style(config.force_path_style)
+            .build();
+
+        info!("Using object storage bucket {}", config.bucket);
+        Self {
+            client: Client::from_conf(s3_config),
+            bucket: config.bucket.clone(),
+            multipart_threshold: config.multipart_threshold_mb.saturating_mul(MIB),
+            part_size: config.part_size_mb.saturating_mul(MIB).max(MIN_PART_SIZE),
+        }
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    // Streams the object to a file at path
+    pub async fn download(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+    ) -> Result<(), ProcessingError> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| classify(e, format!("Failed to download s3://{}/{}", bucket, key)))?;
+
+        let mut file = File::create(path).await?;
+        let mut body = object.body;
+        while let Some(chunk) = body
+            .try_next()
+            .await
+            .map_err(|e| ProcessingError::Retryable(anyhow!(e).context("Download cut short")))?
+        {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+
+    // Uploads the file at path as the object key in the bucket, in parts once it's past the
+    // multipart threshold
+    pub async fn upload(&self, path: &Path, key: &str) -> Result<(), ProcessingError> {
+        let size = tokio::fs::metadata(path).await?.len();
+        if size <= self.multipart_threshold {
+            let body = ByteStream::from_path(path)
+                .await
+                .map_err(|e| ProcessingError::Retryable(anyhow!(e)))?;
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| classify(e, format!("Failed to upload {}", key)))?;
+            return Ok(());
+        }
+
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| classify(e, format!("Failed to start upload of {}", key)))?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| ProcessingError::Retryable(anyhow!("No upload id for {}", key)))?;
+
+        match self.upload_parts(path, key, upload_id, size).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // Otherwise the parts already sent are kept (and billed) indefinitely
+                if let Err(abort) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    warn!("Failed to abort upload of {}: {}", key, abort);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Each part is read straight from the file as it's sent
+    async fn upload_parts(
+        &self,
+        path: &Path,
+        key: &str,
+        upload_id: &str,
+        size: u64,
+    ) -> Result<(), ProcessingError> {
+        let mut parts = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let length = self.part_size.min(size - offset);
+            let part_number = parts.len() as i32 + 1;
+            let body = ByteStream::read_from()
+                .path(path)
+                .offset(offset)
+                .length(Length::Exact(length))
+                .build()
+                .await
+                .map_err(|e| ProcessingError::Retryable(anyhow!(e)))?;
+            let part = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| classify(e, format!("Failed to upload part of {}", key)))?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(str::to_string))
+                    .part_number(part_number)
+                    .build(),
+            );
+            offset += length;
+        }
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| classify(e, format!("Failed to finish upload of {}", key)))?;
+        Ok(())
+    }
+}
+
+// Where a task's source_file lives: an s3://bucket/key URL, or a key in the configured bucket
+pub fn locate<'a>(source_file: &'a str, default_bucket: &'a str) -> (&'a str, &'a str) {
+    match source_file
+        .strip_prefix("s3://")
+        .and_then(|rest| rest.split_once('/'))
+    {
+        Some((bucket, key)) => (bucket, key),
+        None => (default_bucket, source_file),
+    }
+}
+
+// A 4xx (a missing object, no access) will come back the same next time. Anything else, a 5xx,
+// a timeout or a dropped connection, may not.
+fn classify<E>(e: SdkError<E, HttpResponse>, context: String) -> ProcessingError
+where
+    E: Error + Send + Sync + 'static,
+{
+    let client_error = e
+        .raw_response()
+        .is_some_and(|response| response.status().is_client_error());
+    let e = anyhow!(e).context(context);
+    if client_error {
+        ProcessingError::Permanent(e)
+    } else {
+        ProcessingError::Retryable(e)
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+const WINDOW: Duration = Duration::from_secs(60);
+const MB: u64 = 1024 * 1024;
+
+// Holds the processing loop back before it takes the next task: to at most max_per_minute tasks
+// started in any minute, and while the workspace's disk or the machine's memory is running low,
+// so a burst of queued tasks can't take over a machine the worker shares with other services.
+pub struct Throttle {
+    max_per_minute: Option<u32>,
+    started: Mutex<VecDeque<Instant>>,
+    workspace_dir: PathBuf,
+    min_free_disk_mb: Option<u64>,
+    min_free_memory_mb: Option<u64>,
+    check_interval: Duration,
+}
+
+impl Throttle {
+    pub fn new(
+        max_per_minute: Option<u32>,
+        workspace_dir: PathBuf,
+        min_free_disk_mb: Option<u64>,
+        min_free_memory_mb: Option<u64>,
+        check_interval: Duration,
+    ) -> Self {
+        Self {
+            max_per_minute,
+            started: Mutex::new(VecDeque::new()),
+            workspace_dir,
+            min_free_disk_mb,
+            min_free_memory_mb,
+            check_interval,
+        }
+    }
+
+    // Returns once the loop may take the next task. Only the loop waits, so that's still true
+    // when the queue hands it one.
+    pub async fn wait(&self) {
+        self.wait_for_resources().await;
+        self.wait_for_rate().await;
+    }
+
+    // Counts a task as started, towards the rate limit
+    pub fn record_start(&self) {
+        if self.max_per_minute.is_some() {
+            self.started.lock().unwrap().push_back(Instant::now());
+        }
+    }
+
+    async fn wait_for_rate(&self) {
+        let Some(max_per_minute) = self.max_per_minute else {
+            return;
+        };
+        loop {
+            let wait = {
+                let mut started = self.started.lock().unwrap();
+                let now = Instant::now();
+                while started
+                    .front()
+                    .is_some_and(|at| now.duration_since(*at) >= WINDOW)
+                {
+                    started.pop_front();
+                }
+                if started.len() < max_per_minute as usize {
+                    return;
+                }
+                // Until the oldest start in the window drops out of it
+                started
+                    .front()
+                    .map_or(Duration::ZERO, |at| WINDOW - now.duration_since(*at))
+            };
+            time::sleep(wait).await;
+        }
+    }
+
+    async fn wait_for_resources(&self) {
+        let mut paused = false;
+        loop {
+            match self.shortage() {
+                Ok(None) => break,
+                Ok(Some(shortage)) => {
+                    if !paused {
+                        warn!("Pausing consumption, {}", shortage);
+                        paused = true;
+                    }
+                }
+                // Better to keep going than stall on a check that can't be made
+                Err(err) => {
+                    warn!("Failed to check free resources: {:?}", err);
+                    break;
+                }
+            }
+            time::sleep(self.check_interval).await;
+        }
+        if paused {
+            info!("Resources freed up, resuming consumption");
+        }
+    }
+
+    // What's running low, if anything
+    fn shortage(&self) -> Result<Option<String>> {
+        if let Some(min_mb) = self.min_free_disk_mb {
+            let free_mb = free_disk(&self.workspace_dir)? / MB;
+            if free_mb < min_mb {
+                return Ok(Some(format!(
+                    "{} MB free on {}, below {} MB",
+                    free_mb,
+                    self.workspace_dir.display(),
+                    min_mb
+                )));
+            }
+        }
+        if let Some(min_mb) = self.min_free_memory_mb {
+            let free_mb = available_memory()? / MB;
+            if free_mb < min_mb {
+                return Ok(Some(format!(
+                    "{} MB of memory available, below {} MB",
+                    free_mb, min_mb
+                )));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Bytes available to unprivileged users on the filesystem holding the path
+fn free_disk(path: &Path) -> Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).context("Invalid workspace path")?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is NUL-terminated and statvfs only writes to the struct passed in
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to stat {}", path.display()));
+    }
+    // SAFETY: statvfs succeeded, so it filled the struct in
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+// MemAvailable from /proc/meminfo, the kernel's estimate of what can be allocated without
+// swapping
+fn available_memory() -> Result<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").context("Failed to read /proc/meminfo")?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .context("No MemAvailable in /proc/meminfo")
+}
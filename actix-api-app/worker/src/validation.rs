@@ -0,0 +1,91 @@
+use crate::processing::ProcessingError;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+// Bytes of the result looked at to tell its format
+const HEAD_LEN: usize = 512;
+
+// What a task type's result is expected to be, told by its first bytes
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultFormat {
+    // UTF-8 starting with an object or array
+    Json,
+    // UTF-8, the start of it anyway
+    Text,
+    Png,
+    Jpeg,
+    Pdf,
+    Zip,
+    Gzip,
+    // ISO base media, e.g. MP4 or MOV
+    Mp4,
+}
+
+impl ResultFormat {
+    fn matches(self, head: &[u8]) -> bool {
+        match self {
+            Self::Json => std::str::from_utf8(utf8_prefix(head))
+                .is_ok_and(|text| text.trim_start().starts_with(['{', '['])),
+            Self::Text => std::str::from_utf8(utf8_prefix(head)).is_ok(),
+            Self::Png => head.starts_with(b"\x89PNG\r\n\x1a\n"),
+            Self::Jpeg => head.starts_with(&[0xff, 0xd8, 0xff]),
+            Self::Pdf => head.starts_with(b"%PDF-"),
+            Self::Zip => head.starts_with(b"PK\x03\x04"),
+            Self::Gzip => head.starts_with(&[0x1f, 0x8b]),
+            Self::Mp4 => head.get(4..8) == Some(b"ftyp"),
+        }
+    }
+}
+
+// The head with any multi-byte character cut off at the end dropped, so it's only invalid UTF-8
+// if the file is
+fn utf8_prefix(head: &[u8]) -> &[u8] {
+    match std::str::from_utf8(head) {
+        Err(err) if err.error_len().is_none() => &head[..err.valid_up_to()],
+        _ => head,
+    }
+}
+
+// Checks the result isn't empty and, if the task type has a format, looks like it. Returns the
+// hex SHA-256 of the file, which goes to the API with the completion so a download can be checked
+// against it.
+pub async fn check_result(
+    path: &Path,
+    task_type: &str,
+    format: Option<ResultFormat>,
+) -> Result<String, ProcessingError> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        if head.len() < HEAD_LEN {
+            let take = read.min(HEAD_LEN - head.len());
+            head.extend_from_slice(&buffer[..take]);
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    if head.is_empty() {
+        return Err(ProcessingError::Permanent(anyhow!(
+            "Processing left an empty result"
+        )));
+    }
+    if let Some(format) = format.filter(|format| !format.matches(&head)) {
+        return Err(ProcessingError::Permanent(anyhow!(
+            "The result isn't {:?} as {} results should be",
+            format,
+            task_type
+        )));
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,165 @@
+use crate::processing::ProcessingError;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+
+// How often a running task's workspace is measured against its cap
+const SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const MB: u64 = 1024 * 1024;
+
+// Where tasks get their scratch directories: under a directory of this worker's own in
+// storage.workspace_dir, so clearing out what a crashed run left behind can't touch another
+// worker's sharing the disk
+pub struct Workspaces {
+    root: PathBuf,
+    max_bytes: Option<u64>,
+}
+
+impl Workspaces {
+    // Sets up the worker's directory, removing anything left in it from before
+    pub fn init(workspace_dir: &Path, worker_id: &str, max_mb: Option<u64>) -> Result<Self> {
+        let root = workspace_dir.join(format!("worker-{}", file_name(worker_id)));
+        if let Ok(leftovers) = fs::read_dir(&root) {
+            let count = leftovers.count();
+            if count > 0 {
+                warn!(
+                    "Removing {} task workspaces left in {} by an earlier run",
+                    count,
+                    root.display()
+                );
+            }
+            fs::remove_dir_all(&root)
+                .with_context(|| format!("Failed to clear {}", root.display()))?;
+        }
+        fs::create_dir_all(&root)
+            .with_context(|| format!("Failed to create {}", root.display()))?;
+        Ok(Self {
+            root,
+            max_bytes: max_mb.map(|mb| mb.saturating_mul(MB)),
+        })
+    }
+
+    // A new, empty directory for a task, removed when it's dropped
+    pub fn create(&self) -> io::Result<TaskWorkspace> {
+        let dir = tempfile::Builder::new()
+            .prefix("task-")
+            .tempdir_in(&self.root)?;
+        Ok(TaskWorkspace {
+            dir,
+            max_bytes: self.max_bytes,
+        })
+    }
+
+    // Removes every task's workspace and exits when the worker is told to stop. Tasks being
+    // processed are left unacked, the queue hands them out again.
+    pub fn spawn_cleanup_on_shutdown(&self) -> Result<()> {
+        let root = self.root.clone();
+        let mut terminate =
+            signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            info!("Shutting down, removing task workspaces");
+            if let Err(err) = fs::remove_dir_all(&root) {
+                warn!("Failed to remove {}: {}", root.display(), err);
+            }
+            std::process::exit(0);
+        });
+        Ok(())
+    }
+}
+
+// A task's scratch directory, removed however its processing ends: finishing, failing, or being
+// dropped at the timeout
+pub struct TaskWorkspace {
+    dir: TempDir,
+    max_bytes: Option<u64>,
+}
+
+impl TaskWorkspace {
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    // Runs the processing, stopping it if the workspace grows past its cap. Measured every
+    // SIZE_CHECK_INTERVAL and once more when it's done.
+    pub async fn capped<T>(
+        &self,
+        processing: impl Future<Output = Result<T, ProcessingError>>,
+    ) -> Result<T, ProcessingError> {
+        if self.max_bytes.is_none() {
+            return processing.await;
+        }
+        tokio::pin!(processing);
+        let mut checks = time::interval_at(
+            time::Instant::now() + SIZE_CHECK_INTERVAL,
+            SIZE_CHECK_INTERVAL,
+        );
+        loop {
+            tokio::select! {
+                output = &mut processing => {
+                    self.check_size()?;
+                    return output;
+                }
+                _ = checks.tick() => self.check_size()?,
+            }
+        }
+    }
+
+    // Fails once the files in the workspace take up more than its cap
+    pub fn check_size(&self) -> Result<(), ProcessingError> {
+        let Some(max_bytes) = self.max_bytes else {
+            return Ok(());
+        };
+        let size = dir_size(self.path())?;
+        if size > max_bytes {
+            return Err(ProcessingError::ResourceLimit(format!(
+                "workspace grew to {} MB, its limit is {} MB",
+                size / MB,
+                max_bytes / MB
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn close(self) -> io::Result<()> {
+        self.dir.close()
+    }
+}
+
+// The worker id with anything that doesn't belong in a file name replaced
+pub fn file_name(worker_id: &str) -> String {
+    worker_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
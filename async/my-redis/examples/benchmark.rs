@@ -0,0 +1,240 @@
+use my_redis::pipeline::Client;
+use my_redis::resp::Frame;
+use my_redis::{Pipeline, TlsOptions};
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+usage: benchmark [-c clients] [-n requests] [-d bytes] [-P pipeline] [-r keyspace] [-t mix]
+
+  -c  connections sending at once (50)
+  -n  requests in total (100000)
+  -d  size of the values SET, pushed and published (3)
+  -P  requests sent per round trip (1)
+  -r  how many different keys are used, picked at random (10000)
+  -t  the commands to send, with optional weights, e.g. lpush:3,lpop:3,get (set,get)
+      set, get, incr, lpush, rpush, lpop and publish are known
+
+REDIS_ADDR picks the server and REDIS_TLS connects over TLS, as in the other examples.";
+
+const COMMANDS: [&str; 7] = ["set", "get", "incr", "lpush", "rpush", "lpop", "publish"];
+
+// Load like redis-benchmark's against the my-redis server or a real Redis, reporting throughput
+// and latency percentiles for each command. Pushes and pops share one list, so a push/pop mix
+// behaves like a queue.
+#[tokio::main]
+async fn main() -> mini_redis::Result<()> {
+    let args = parse_args();
+    let addr = env::var("REDIS_ADDR").unwrap_or_else(|_| "127.0.0.1:6379".to_string());
+    let tls = TlsOptions::from_env()
+        .map(|tls| tls.client_config())
+        .transpose()?;
+
+    // Connected up front so connecting isn't timed
+    let mut clients = Vec::new();
+    for _ in 0..args.clients {
+        clients.push(Client::connect_with(&addr, tls.clone()).await?);
+    }
+
+    println!(
+        "{} requests, {} clients, {} byte values, {} per round trip, {} keys",
+        args.requests, args.clients, args.data_size, args.pipeline, args.keyspace
+    );
+
+    let args = Arc::new(args);
+    // Requests are claimed a round trip at a time, until they're all gone
+    let claimed = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let tasks: Vec<_> = clients
+        .into_iter()
+        .enumerate()
+        .map(|(n, client)| tokio::spawn(run(client, args.clone(), claimed.clone(), n as u64)))
+        .collect();
+
+    let mut results = Results::default();
+    for task in tasks {
+        results.merge(task.await??);
+    }
+    let elapsed = start.elapsed();
+
+    for (command, latencies) in &mut results.latencies {
+        report(command, latencies, elapsed);
+    }
+    let mut all: Vec<_> = results.latencies.into_values().flatten().collect();
+    report("total", &mut all, elapsed);
+    if results.errors > 0 {
+        println!("{} requests got an error reply", results.errors);
+    }
+    Ok(())
+}
+
+struct Args {
+    clients: usize,
+    requests: usize,
+    data_size: usize,
+    pipeline: usize,
+    keyspace: u64,
+    // Each command with its share of the requests
+    mix: Vec<(&'static str, u64)>,
+}
+
+#[derive(Default)]
+struct Results {
+    // How long each request took, by command
+    latencies: BTreeMap<&'static str, Vec<Duration>>,
+    errors: usize,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        for (command, latencies) in other.latencies {
+            self.latencies.entry(command).or_default().extend(latencies);
+        }
+        self.errors += other.errors;
+    }
+}
+
+async fn run(
+    mut client: Client,
+    args: Arc<Args>,
+    claimed: Arc<AtomicUsize>,
+    seed: u64,
+) -> mini_redis::Result<Results> {
+    let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1);
+    let value = vec![b'x'; args.data_size];
+    let total_weight: u64 = args.mix.iter().map(|(_, weight)| weight).sum();
+    let mut results = Results::default();
+    let mut pipeline = Pipeline::new();
+
+    loop {
+        let first = claimed.fetch_add(args.pipeline, Ordering::Relaxed);
+        if first >= args.requests {
+            return Ok(results);
+        }
+        let count = args.pipeline.min(args.requests - first);
+
+        let mut sent = Vec::with_capacity(count);
+        for _ in 0..count {
+            let command = pick(&args.mix, rng.below(total_weight));
+            let key = format!("key:{}", rng.below(args.keyspace));
+            match command {
+                "set" => pipeline.cmd(&[&b"SET"[..], key.as_bytes(), &value[..]]),
+                "get" => pipeline.cmd(&["GET", &key]),
+                "incr" => pipeline.cmd(&["INCR", &format!("counter:{}", key)]),
+                "lpush" => pipeline.cmd(&[&b"LPUSH"[..], b"queue", &value[..]]),
+                "rpush" => pipeline.cmd(&[&b"RPUSH"[..], b"queue", &value[..]]),
+                "lpop" => pipeline.cmd(&["LPOP", "queue"]),
+                _ => pipeline.cmd(&[&b"PUBLISH"[..], b"channel", &value[..]]),
+            };
+            sent.push(command);
+        }
+
+        // Every request in a round trip is counted as taking the whole of it, as redis-benchmark
+        // does
+        let start = Instant::now();
+        let replies = pipeline.execute(&mut client).await?;
+        let latency = start.elapsed();
+        for (command, reply) in sent.into_iter().zip(replies) {
+            results.latencies.entry(command).or_default().push(latency);
+            if let Frame::Error(_) = reply {
+                results.errors += 1;
+            }
+        }
+    }
+}
+
+fn pick(mix: &[(&'static str, u64)], mut at: u64) -> &'static str {
+    for &(command, weight) in mix {
+        if at < weight {
+            return command;
+        }
+        at -= weight;
+    }
+    unreachable!("the weights add up to more than was picked from")
+}
+
+fn report(command: &str, latencies: &mut [Duration], elapsed: Duration) {
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+    let per_second = latencies.len() as f64 / elapsed.as_secs_f64();
+    println!(
+        "{:<8} {:>8} requests {:>10.0}/s   p50 {:>9.2?}  p90 {:>9.2?}  p99 {:>9.2?}  p99.9 {:>9.2?}  max {:>9.2?}",
+        command,
+        latencies.len(),
+        per_second,
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(0.999),
+        latencies[latencies.len() - 1],
+    );
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        clients: 50,
+        requests: 100_000,
+        data_size: 3,
+        pipeline: 1,
+        keyspace: 10_000,
+        mix: vec![("set", 1), ("get", 1)],
+    };
+    let mut options = env::args().skip(1);
+    while let Some(option) = options.next() {
+        let Some(value) = options.next() else {
+            usage();
+        };
+        match option.as_str() {
+            "-c" => args.clients = number(&value),
+            "-n" => args.requests = number(&value),
+            "-d" => args.data_size = number(&value),
+            "-P" => args.pipeline = number(&value),
+            "-r" => args.keyspace = number(&value),
+            "-t" => args.mix = parse_mix(&value),
+            _ => usage(),
+        }
+    }
+    if args.clients == 0 || args.requests == 0 || args.pipeline == 0 || args.keyspace == 0 {
+        usage();
+    }
+    args
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> T {
+    value.parse().unwrap_or_else(|_| usage())
+}
+
+fn parse_mix(mix: &str) -> Vec<(&'static str, u64)> {
+    mix.split(',')
+        .map(|entry| {
+            let (name, weight) = entry.split_once(':').unwrap_or((entry, "1"));
+            let name = name.to_lowercase();
+            let command = COMMANDS
+                .into_iter()
+                .find(|&command| command == name)
+                .unwrap_or_else(|| usage());
+            let weight = weight.parse().ok().filter(|&weight| weight > 0);
+            (command, weight.unwrap_or_else(|| usage()))
+        })
+        .collect()
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+// A small xorshift generator, plenty for picking keys and commands
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
@@ -0,0 +1,50 @@
+use my_redis::pipeline::Client;
+use my_redis::{Pipeline, TlsOptions};
+use std::env;
+use std::time::Instant;
+
+// How many INCRs each run makes, and how many go in each pipeline
+const COMMANDS: usize = 20_000;
+const BATCH: usize = 500;
+
+// Times the same INCRs sent one per round trip and then pipelined, run against the my-redis
+// server (or a real Redis) at REDIS_ADDR, the default port if unset. Setting REDIS_TLS (or
+// REDIS_TLS_CA etc, see TlsOptions::from_env) connects over TLS.
+#[tokio::main]
+async fn main() -> mini_redis::Result<()> {
+    let addr = env::var("REDIS_ADDR").unwrap_or_else(|_| "127.0.0.1:6379".to_string());
+    let tls = TlsOptions::from_env()
+        .map(|tls| tls.client_config())
+        .transpose()?;
+    let mut client = Client::connect_with(&addr, tls).await?;
+    client.command(&["DEL", "bench"]).await?;
+
+    let start = Instant::now();
+    for _ in 0..COMMANDS {
+        client.command(&["INCR", "bench"]).await?;
+    }
+    report("one per round trip", start);
+
+    let start = Instant::now();
+    let mut pipeline = Pipeline::new();
+    for _ in 0..COMMANDS / BATCH {
+        for _ in 0..BATCH {
+            pipeline.cmd(&["INCR", "bench"]);
+        }
+        pipeline.execute(&mut client).await?;
+    }
+    report(&format!("pipelined, {} at a time", BATCH), start);
+
+    let total = client.command(&["GET", "bench"]).await?;
+    println!("bench = {:?}", total);
+    Ok(())
+}
+
+fn report(how: &str, start: Instant) {
+    let elapsed = start.elapsed();
+    let per_second = COMMANDS as f64 / elapsed.as_secs_f64();
+    println!(
+        "{:<28} {:>8.1?} {:>10.0} commands/s",
+        how, elapsed, per_second
+    );
+}
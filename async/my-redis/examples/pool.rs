@@ -0,0 +1,66 @@
+use my_redis::pipeline::Client;
+use my_redis::{Pool, PoolOptions, TlsOptions};
+use std::env;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+// How many tasks run at once, and how many INCRs each makes
+const TASKS: usize = 64;
+const COMMANDS: usize = 200;
+
+// Many tasks sharing the server, first through one client behind a mutex, where they take turns,
+// then through a pool, where up to max_size of them have a connection each. REDIS_ADDR and
+// REDIS_TLS pick the server and how to connect, as in the pipeline-bench example.
+#[tokio::main]
+async fn main() -> mini_redis::Result<()> {
+    let addr = env::var("REDIS_ADDR").unwrap_or_else(|_| "127.0.0.1:6379".to_string());
+    let tls = TlsOptions::from_env()
+        .map(|tls| tls.client_config())
+        .transpose()?;
+
+    let client = Client::connect_with(&addr, tls.clone()).await?;
+    let shared = Arc::new(Mutex::new(client));
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|_| {
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                for _ in 0..COMMANDS {
+                    let mut client = shared.lock().await;
+                    client.command(&["INCR", "pooled"]).await.unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await?;
+    }
+    println!("one shared client   {:>8.1?}", start.elapsed());
+
+    let options = PoolOptions {
+        tls,
+        ..PoolOptions::default()
+    };
+    let pool = Pool::new(addr, options);
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|_| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                for _ in 0..COMMANDS {
+                    // Checked back in at the end of each iteration
+                    let mut client = pool.get().await.unwrap();
+                    client.command(&["INCR", "pooled"]).await.unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await?;
+    }
+    println!("pool of 8           {:>8.1?}", start.elapsed());
+    println!("{} connections left idle in the pool", pool.idle());
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use bytes::Bytes;
+use mini_redis::{client, Result};
+use tokio::sync::oneshot;
+
+// Fans task events out to several subscribers over pub/sub, the way the task service's API
+// instances each follow every task change. Run the server first, then this example.
+
+const CHANNEL: &str = "task_events";
+const EVENTS: [&str; 3] = [
+    r#"{"kind":"created","task":{"task_uuid":"1","state":"NotStarted"}}"#,
+    r#"{"kind":"updated","task":{"task_uuid":"1","state":"InProgress"}}"#,
+    r#"{"kind":"updated","task":{"task_uuid":"1","state":"Completed"}}"#,
+];
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut subscribers = Vec::new();
+    let mut ready = Vec::new();
+
+    for id in 0..3 {
+        let (ready_tx, ready_rx) = oneshot::channel();
+        ready.push(ready_rx);
+
+        subscribers.push(tokio::spawn(async move {
+            let client = client::connect("127.0.0.1:6379").await?;
+            // Returns once the server has confirmed the subscription
+            let mut subscriber = client.subscribe(vec![CHANNEL.to_string()]).await?;
+            let _ = ready_tx.send(());
+
+            for _ in 0..EVENTS.len() {
+                match subscriber.next_message().await? {
+                    Some(message) => println!(
+                        "subscriber {} got {:?}",
+                        id,
+                        String::from_utf8_lossy(&message.content)
+                    ),
+                    None => break,
+                }
+            }
+            Ok::<_, mini_redis::Error>(())
+        }));
+    }
+
+    // Anything published before a subscriber is listening never reaches it
+    for ready_rx in ready {
+        let _ = ready_rx.await;
+    }
+
+    let mut publisher = client::connect("127.0.0.1:6379").await?;
+    for event in EVENTS {
+        let reached = publisher.publish(CHANNEL, Bytes::from(event)).await?;
+        println!("published to {} subscribers", reached);
+    }
+
+    for subscriber in subscribers {
+        subscriber.await??;
+    }
+    Ok(())
+}
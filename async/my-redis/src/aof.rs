@@ -0,0 +1,114 @@
+use crate::resp::Frame;
+use bytes::{Bytes, BytesMut};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// An append-only file: every command that changes the data is written to it as it's made, and
+/// replaying them on startup puts the data back as it was. Commands are stored just as they're
+/// sent over the wire, as arrays of bulk strings.
+pub struct Aof {
+    file: Mutex<File>,
+    fsync: Fsync,
+}
+
+/// How often the file is synced to disk, Redis's `appendfsync` settings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fsync {
+    /// After every command, before it's answered
+    Always,
+    /// Once a second by whoever owns the `Aof`, so a crash loses at most a second of changes
+    EverySec,
+    /// Whenever the OS gets round to it
+    No,
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySec),
+            "no" => Ok(Fsync::No),
+            _ => Err(format!(
+                "unknown fsync policy '{}', expected always, everysec or no",
+                s
+            )),
+        }
+    }
+}
+
+impl Aof {
+    /// Opens the file, creating it if needed, and returns the commands already in it to be
+    /// replayed. A command cut off part way through (the server died while writing it) is
+    /// dropped and truncated away, anything else that doesn't parse is an error.
+    pub fn open(path: &Path, fsync: Fsync) -> io::Result<(Aof, Vec<Frame>)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let contents = std::fs::read(path)?;
+
+        let mut start = 0;
+        let mut commands = Vec::new();
+        loop {
+            match Frame::parse(&contents[start..]) {
+                Ok(Some((command, len))) => {
+                    commands.push(command);
+                    start += len;
+                }
+                Ok(None) => {
+                    if start < contents.len() {
+                        eprintln!(
+                            "Dropping a partly written command at the end of {}",
+                            path.display()
+                        );
+                        file.set_len(start as u64)?;
+                    }
+                    break;
+                }
+                Err(e) => return Err(invalid(e)),
+            }
+        }
+
+        let aof = Aof {
+            file: Mutex::new(file),
+            fsync,
+        };
+        Ok((aof, commands))
+    }
+
+    pub fn fsync(&self) -> Fsync {
+        self.fsync
+    }
+
+    /// Writes a command to the end of the file, syncing it too with `Fsync::Always`
+    pub fn append(&self, name: &str, args: &[Bytes]) -> io::Result<()> {
+        let mut parts = vec![Bytes::copy_from_slice(name.as_bytes())];
+        parts.extend_from_slice(args);
+        let mut command = BytesMut::new();
+        Frame::command(&parts).encode(&mut command);
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&command)?;
+        if self.fsync == Fsync::Always {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.lock().unwrap().sync_data()
+    }
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the append-only file is corrupt: {}", e.to_string()),
+    )
+}
@@ -1,63 +1,630 @@
 use bytes::Bytes;
-use mini_redis::{Connection, Frame};
-use std::collections::HashMap;
+use my_redis::aof::{Aof, Fsync};
+use my_redis::connection::Connection;
+use my_redis::resp::{Frame, ProtocolError};
+use my_redis::tls;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use tokio::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, Notify};
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{StreamExt, StreamMap};
 
-type Db = Arc<Mutex<HashMap<String, Bytes>>>;
+type Db = Arc<Mutex<HashMap<String, Value>>>;
+
+enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+}
+
+// A broadcast channel per pub/sub channel with anyone subscribed
+type Channels = Arc<Mutex<HashMap<String, broadcast::Sender<Bytes>>>>;
+
+// How many messages a subscriber can fall behind before it starts missing them
+const CHANNEL_CAPACITY: usize = 1024;
+
+// Everything the connections share
+#[derive(Clone)]
+struct State {
+    db: Db,
+    channels: Channels,
+    // Woken on every push, so blocked BLPOPs look again
+    pushed: Arc<Notify>,
+    aof: Option<Arc<Aof>>,
+}
+
+impl State {
+    // Appends a command that changed the data to the append-only file, if there is one. Called
+    // with the db lock still held, so the file has the changes in the order they were made.
+    fn log(&self, name: &str, args: &[Bytes]) {
+        if let Some(aof) = &self.aof {
+            if let Err(e) = aof.append(name, args) {
+                eprintln!("Failed to write to the append-only file: {}", e);
+            }
+        }
+    }
+}
+
+// What the server's started with
+struct Args {
+    aof: Option<(PathBuf, Fsync)>,
+    // Certificate chain and key, for serving TLS instead of plain TCP
+    tls: Option<(PathBuf, PathBuf)>,
+}
 
 #[tokio::main]
 async fn main() {
+    let args = parse_args();
+
     // Bind listener to the address
     let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
 
     println!("Listening");
 
-    let db = Arc::new(Mutex::new(HashMap::new()));
+    let mut state = State {
+        db: Arc::new(Mutex::new(HashMap::new())),
+        channels: Arc::new(Mutex::new(HashMap::new())),
+        pushed: Arc::new(Notify::new()),
+        aof: None,
+    };
+
+    // With an append-only file the data it holds is put back before anyone connects
+    if let Some((path, fsync)) = args.aof {
+        let (aof, commands) = Aof::open(&path, fsync).unwrap();
+        let replayed = commands.len();
+        for command in commands {
+            execute(command, &state);
+        }
+        println!("Replayed {} commands from {}", replayed, path.display());
+
+        let aof = Arc::new(aof);
+        if fsync == Fsync::EverySec {
+            let aof = aof.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(1));
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = aof.sync() {
+                        eprintln!("Failed to sync the append-only file: {}", e);
+                    }
+                }
+            });
+        }
+        state.aof = Some(aof);
+    }
+
+    let acceptor = args.tls.map(|(cert_path, key_path)| {
+        println!("Serving TLS");
+        TlsAcceptor::from(tls::server_config(&cert_path, &key_path).unwrap())
+    });
 
     loop {
         // The second item contains the IP and port of the new connection.
         let (socket, _) = listener.accept().await.unwrap();
+        // Replies go out one small write at a time, Nagle would hold each back waiting for an ack
+        socket.set_nodelay(true).unwrap();
 
-        // Clone the handle to the hash map
-        let db = db.clone();
+        // Clone the handles to the shared state
+        let state = state.clone();
+        let acceptor = acceptor.clone();
 
         // A new task is spawned for each inbound socket. The socket is
         // moved to the new task and processed there.
         println!("Accepted");
         tokio::spawn(async move {
-            process(socket, db).await;
+            // The handshake's done here too, so a slow client can't hold up the accept loop
+            let socket: Box<dyn tls::Stream> = match acceptor {
+                Some(acceptor) => match acceptor.accept(socket).await {
+                    Ok(socket) => Box::new(socket),
+                    Err(e) => return eprintln!("TLS handshake failed: {}", e),
+                },
+                None => Box::new(socket),
+            };
+            process(socket, state).await;
         });
     }
 }
 
-async fn process(socket: TcpStream, db: Db) {
-    use mini_redis::Command::{self, Get, Set};
-
-    // Connection, provided by `mini-redis`, handles parsing frames from
-    // the socket
+async fn process(socket: Box<dyn tls::Stream>, state: State) {
+    // Connection handles parsing requests from the socket
     let mut connection = Connection::new(socket);
 
-    // Use `read_frame` to receive a command from the connection.
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
-                let mut db = db.lock().unwrap();
-                db.insert(cmd.key().to_string(), cmd.value().clone());
-                Frame::Simple("OK".to_string())
+    loop {
+        let frame = match connection.read_request().await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            Err(e) => return refuse(&mut connection, e).await,
+        };
+
+        // Commands that block or take over the connection are run here, the rest by execute
+        if let Some((name, args)) = command_parts(&frame) {
+            if name == "subscribe" && !args.is_empty() {
+                if !subscribed(&mut connection, args, &state.channels).await {
+                    return;
+                }
+                continue;
+            }
+            if name == "blpop" {
+                let Some(response) = blpop(&args, &state, &mut connection).await else {
+                    return;
+                };
+                if connection.write_frame(&response).await.is_err() {
+                    // Nothing popped may be lost with the client
+                    return unpop(response, &state);
+                }
+                continue;
+            }
+        }
+
+        let response = execute(frame, &state);
+
+        // Write the response to the client, a client that's gone just ends the connection
+        if connection.write_frame(&response).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Anything that isn't a request gets an error, and the connection is closed as there's no
+// knowing where the next request would start
+async fn refuse(connection: &mut Connection, e: mini_redis::Error) {
+    if let Some(e) = e.downcast_ref::<ProtocolError>() {
+        let _ = connection
+            .write_frame(&Frame::Error(format!("ERR {}", e)))
+            .await;
+    }
+}
+
+// Runs a command that neither blocks nor takes over the connection, as replayed from the
+// append-only file too
+fn execute(frame: Frame, state: &State) -> Frame {
+    match command_parts(&frame) {
+        Some((name, args)) => apply(&name, &args, state),
+        None => Frame::Error("ERR Protocol error: expected a command".to_string()),
+    }
+}
+
+// The command name (lowercased) and its arguments, if the frame is a command at all
+fn command_parts(frame: &Frame) -> Option<(String, Vec<Bytes>)> {
+    let Frame::Array(parts) = frame else {
+        return None;
+    };
+    let mut parts = parts.iter().map(|part| match part {
+        Frame::Bulk(bytes) => Some(bytes.clone()),
+        _ => None,
+    });
+    let name = String::from_utf8_lossy(&parts.next()??).to_lowercase();
+    let args = parts.collect::<Option<Vec<_>>>()?;
+    Some((name, args))
+}
+
+fn apply(name: &str, args: &[Bytes], state: &State) -> Frame {
+    let State { db, channels, .. } = state;
+    match name {
+        "get" if args.len() != 1 => wrong_arguments(name),
+        "get" => {
+            let db = db.lock().unwrap();
+            match db.get(&key_string(&args[0])) {
+                Some(Value::String(value)) => Frame::Bulk(value.clone()),
+                Some(Value::List(_)) => wrong_type(),
+                None => Frame::Null,
+            }
+        }
+        "set" if args.len() < 2 => wrong_arguments(name),
+        // Expiry and the other options aren't supported
+        "set" if args.len() > 2 => Frame::Error("ERR syntax error".to_string()),
+        "set" => {
+            let mut db = db.lock().unwrap();
+            db.insert(key_string(&args[0]), Value::String(args[1].clone()));
+            state.log(name, args);
+            Frame::Simple("OK".to_string())
+        }
+        "subscribe" => wrong_arguments(name),
+        "ping" if args.len() > 1 => wrong_arguments(name),
+        "ping" => args
+            .first()
+            .map_or(Frame::Simple("PONG".to_string()), |message| {
+                Frame::Bulk(message.clone())
+            }),
+        "publish" if args.len() != 2 => wrong_arguments(name),
+        // Replies with how many subscribers the message reached
+        "publish" => {
+            let mut channels = channels.lock().unwrap();
+            let channel = key_string(&args[0]);
+            let reached = match channels
+                .get(&channel)
+                .map(|sender| sender.send(args[1].clone()))
+            {
+                Some(Ok(reached)) => reached,
+                // Everyone subscribed has disconnected since
+                Some(Err(_)) => {
+                    channels.remove(&channel);
+                    0
+                }
+                None => 0,
+            };
+            Frame::Integer(reached as i64)
+        }
+        "del" | "exists" if args.is_empty() => wrong_arguments(name),
+        "del" => {
+            let mut db = db.lock().unwrap();
+            let removed = args
+                .iter()
+                .filter(|key| db.remove(&key_string(key)).is_some())
+                .count();
+            if removed > 0 {
+                state.log(name, args);
+            }
+            Frame::Integer(removed as i64)
+        }
+        // A key given more than once is counted each time, as Redis does
+        "exists" => {
+            let db = db.lock().unwrap();
+            let found = args.iter().filter(|key| db.contains_key(&key_string(key)));
+            Frame::Integer(found.count() as i64)
+        }
+        "incr" | "decr" if args.len() != 1 => wrong_arguments(name),
+        "incr" => add(&args[0], 1, state),
+        "decr" => add(&args[0], -1, state),
+        "lpush" | "rpush" if args.len() < 2 => wrong_arguments(name),
+        "lpush" => push(&args[0], &args[1..], true, state),
+        "rpush" => push(&args[0], &args[1..], false, state),
+        "lpop" if args.is_empty() || args.len() > 2 => wrong_arguments(name),
+        "lpop" => lpop(&args[0], args.get(1), state),
+        _ => Frame::Error(format!("ERR unknown command '{}'", name)),
+    }
+}
+
+// Subscriber mode: the connection is sent whatever is published on its channels until it's
+// unsubscribed from all of them, and can only (UN)SUBSCRIBE or PING meanwhile. Returns false if
+// the client goes away.
+async fn subscribed(connection: &mut Connection, to: Vec<Bytes>, channels: &Channels) -> bool {
+    let mut subscriptions = StreamMap::new();
+    if subscribe(connection, &mut subscriptions, to, channels)
+        .await
+        .is_err()
+    {
+        return false;
+    }
+
+    while !subscriptions.is_empty() {
+        tokio::select! {
+            Some((channel, message)) = subscriptions.next() => {
+                // A subscriber that fell too far behind skips what it missed
+                let Ok(message) = message else {
+                    continue;
+                };
+                let frame = Frame::Array(vec![
+                    Frame::Bulk(Bytes::from("message")),
+                    Frame::Bulk(Bytes::from(channel)),
+                    Frame::Bulk(message),
+                ]);
+                if connection.write_frame(&frame).await.is_err() {
+                    return false;
+                }
+            }
+            frame = connection.read_request() => {
+                let frame = match frame {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => return false,
+                    Err(e) => {
+                        refuse(connection, e).await;
+                        return false;
+                    }
+                };
+                let written = match command_parts(&frame) {
+                    Some((name, args)) if name == "subscribe" && !args.is_empty() => {
+                        subscribe(connection, &mut subscriptions, args, channels).await
+                    }
+                    Some((name, args)) if name == "unsubscribe" => {
+                        unsubscribe(connection, &mut subscriptions, args, channels).await
+                    }
+                    Some((name, _)) if name == "ping" => {
+                        let pong = Frame::Array(vec![
+                            Frame::Bulk(Bytes::from("pong")),
+                            Frame::Bulk(Bytes::new()),
+                        ]);
+                        connection.write_frame(&pong).await
+                    }
+                    _ => {
+                        let error = "ERR only (UN)SUBSCRIBE and PING are allowed while subscribed";
+                        connection.write_frame(&Frame::Error(error.to_string())).await
+                    }
+                };
+                if written.is_err() {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+// Confirms each channel with how many the connection is now subscribed to, as Redis does
+async fn subscribe(
+    connection: &mut Connection,
+    subscriptions: &mut StreamMap<String, BroadcastStream<Bytes>>,
+    to: Vec<Bytes>,
+    channels: &Channels,
+) -> io::Result<()> {
+    for channel in to {
+        let channel = key_string(&channel);
+        let receiver = channels
+            .lock()
+            .unwrap()
+            .entry(channel.clone())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe();
+        subscriptions.insert(channel.clone(), BroadcastStream::new(receiver));
+        let count = subscriptions.len();
+        connection
+            .write_frame(&subscription_frame("subscribe", channel, count))
+            .await?;
+    }
+    Ok(())
+}
+
+// Unsubscribes from the channels given, or from every one if none are
+async fn unsubscribe(
+    connection: &mut Connection,
+    subscriptions: &mut StreamMap<String, BroadcastStream<Bytes>>,
+    from: Vec<Bytes>,
+    channels: &Channels,
+) -> io::Result<()> {
+    let from: Vec<String> = if from.is_empty() {
+        subscriptions.keys().cloned().collect()
+    } else {
+        from.iter().map(key_string).collect()
+    };
+    for channel in from {
+        subscriptions.remove(&channel);
+        // The channel goes once nobody's listening
+        {
+            let mut channels = channels.lock().unwrap();
+            if channels
+                .get(&channel)
+                .is_some_and(|sender| sender.receiver_count() == 0)
+            {
+                channels.remove(&channel);
+            }
+        }
+
+        let count = subscriptions.len();
+        connection
+            .write_frame(&subscription_frame("unsubscribe", channel, count))
+            .await?;
+    }
+    Ok(())
+}
+
+fn subscription_frame(kind: &str, channel: String, count: usize) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from(kind.to_string())),
+        Frame::Bulk(Bytes::from(channel)),
+        Frame::Integer(count as i64),
+    ])
+}
+
+fn key_string(key: &Bytes) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
+
+fn wrong_type() -> Frame {
+    Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+}
+
+fn wrong_arguments(name: &str) -> Frame {
+    Frame::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name
+    ))
+}
+
+// Adds to the integer stored at the key, a missing key counting as 0. The lock is held
+// throughout, so concurrent increments can't lose an update.
+fn add(key: &Bytes, by: i64, state: &State) -> Frame {
+    let mut db = state.db.lock().unwrap();
+    let key = key_string(key);
+
+    let current = match db.get(&key) {
+        Some(Value::List(_)) => return wrong_type(),
+        Some(Value::String(value)) => match std::str::from_utf8(value)
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+        {
+            Some(current) => current,
+            None => return Frame::Error("ERR value is not an integer or out of range".to_string()),
+        },
+        None => 0,
+    };
+    let Some(value) = current.checked_add(by) else {
+        return Frame::Error("ERR increment or decrement would overflow".to_string());
+    };
+
+    // Logged as a SET of the value it ends up with
+    let stored = Bytes::from(value.to_string());
+    state.log("set", &[Bytes::from(key.clone()), stored.clone()]);
+    db.insert(key, Value::String(stored));
+    Frame::Integer(value)
+}
+
+// Pushes each value in turn onto the head (LPUSH) or tail (RPUSH) of the list, creating it if
+// needed, and replies with the list's new length
+fn push(key: &Bytes, values: &[Bytes], head: bool, state: &State) -> Frame {
+    let length = {
+        let mut db = state.db.lock().unwrap();
+        let value = db
+            .entry(key_string(key))
+            .or_insert_with(|| Value::List(VecDeque::new()));
+        let Value::List(list) = value else {
+            return wrong_type();
+        };
+        for value in values {
+            if head {
+                list.push_front(value.clone());
+            } else {
+                list.push_back(value.clone());
+            }
+        }
+        let length = list.len();
+        let name = if head { "lpush" } else { "rpush" };
+        state.log(name, &[std::slice::from_ref(key), values].concat());
+        length
+    };
+    state.pushed.notify_waiters();
+    Frame::Integer(length as i64)
+}
+
+// Without a count the first element comes back on its own (Null if there's no list), with one
+// it's an array of up to that many (the null array). Empty lists are removed, as Redis does.
+fn lpop(key: &Bytes, count: Option<&Bytes>, state: &State) -> Frame {
+    let count = match count {
+        Some(count) => match std::str::from_utf8(count)
+            .ok()
+            .and_then(|c| c.parse::<usize>().ok())
+        {
+            Some(count) => Some(count),
+            None => return Frame::Error("ERR value is out of range, must be positive".to_string()),
+        },
+        None => None,
+    };
+
+    let mut db = state.db.lock().unwrap();
+    let key = key_string(key);
+    let list = match db.get_mut(&key) {
+        Some(Value::List(list)) => list,
+        Some(Value::String(_)) => return wrong_type(),
+        None if count.is_some() => return Frame::NullArray,
+        None => return Frame::Null,
+    };
+    let response = match count {
+        Some(count) => {
+            let popped = list.drain(..count.min(list.len()));
+            Frame::Array(popped.map(Frame::Bulk).collect())
+        }
+        None => list.pop_front().map_or(Frame::Null, Frame::Bulk),
+    };
+    let mut logged = vec![Bytes::from(key.clone())];
+    logged.extend(count.map(|count| Bytes::from(count.to_string())));
+    state.log("lpop", &logged);
+    if list.is_empty() {
+        db.remove(&key);
+    }
+    response
+}
+
+// BLPOP key [key ...] timeout: pops from the first of the keys holding a non-empty list, waiting
+// for a push if none do. A timeout of 0 waits forever, otherwise it's in (fractional) seconds and
+// the null array comes back if nothing turns up. None if the client disconnects while waiting,
+// so nothing is popped for a client that isn't there to take it.
+async fn blpop(args: &[Bytes], state: &State, connection: &mut Connection) -> Option<Frame> {
+    let Some((timeout, keys)) = args.split_last().filter(|(_, keys)| !keys.is_empty()) else {
+        return Some(wrong_arguments("blpop"));
+    };
+    let timeout = match std::str::from_utf8(timeout)
+        .ok()
+        .and_then(|t| t.parse::<f64>().ok())
+    {
+        Some(timeout) if timeout.is_finite() && timeout >= 0.0 => timeout,
+        _ => {
+            let error = "ERR timeout is not a float or out of range";
+            return Some(Frame::Error(error.to_string()));
+        }
+    };
+    let deadline = (timeout > 0.0).then(|| Instant::now() + Duration::from_secs_f64(timeout));
+
+    loop {
+        // Registered before looking, so a push between the look and the wait still wakes us
+        let pushed = state.pushed.notified();
+        tokio::pin!(pushed);
+        pushed.as_mut().enable();
+
+        {
+            let mut db = state.db.lock().unwrap();
+            for key in keys {
+                let key = key_string(key);
+                let value = match db.get_mut(&key) {
+                    Some(Value::List(list)) => list.pop_front(),
+                    Some(Value::String(_)) => return Some(wrong_type()),
+                    None => None,
+                };
+                if let Some(value) = value {
+                    // Logged as the LPOP it came to, replaying it mustn't block
+                    state.log("lpop", &[Bytes::from(key.clone())]);
+                    if matches!(db.get(&key), Some(Value::List(list)) if list.is_empty()) {
+                        db.remove(&key);
+                    }
+                    let popped = vec![Frame::Bulk(Bytes::from(key)), Frame::Bulk(value)];
+                    return Some(Frame::Array(popped));
+                }
             }
-            Get(cmd) => {
-                let db = db.lock().unwrap();
-                if let Some(value) = db.get(cmd.key()) {
-                    Frame::Bulk(value.clone())
-                } else {
-                    Frame::Null
+        }
+
+        let woken = async {
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, pushed).await.is_ok(),
+                None => {
+                    pushed.await;
+                    true
                 }
             }
-            cmd => panic!("unimplemented {:?}", cmd),
         };
+        tokio::select! {
+            woken = woken => {
+                if !woken {
+                    return Some(Frame::NullArray);
+                }
+            }
+            _ = connection.closed() => return None,
+        }
+    }
+}
+
+// Puts back what BLPOP popped for a client that went away before it could be told, at the head
+// of the list where it came from
+fn unpop(response: Frame, state: &State) {
+    let Frame::Array(popped) = response else {
+        return;
+    };
+    if let [Frame::Bulk(key), Frame::Bulk(value)] = &popped[..] {
+        if let Frame::Error(e) = push(key, std::slice::from_ref(value), true, state) {
+            eprintln!("Lost a value popped from {}: {}", key_string(key), e);
+        }
+    }
+}
+
+// `server [--appendonly <path>] [--appendfsync always|everysec|no] [--tls-cert <path>
+// --tls-key <path>]`. The append-only file is off unless a path is given, and is synced once a
+// second by default as in Redis. TLS needs both the certificate chain and its key.
+fn parse_args() -> Args {
+    let (mut aof_path, mut fsync) = (None, Fsync::EverySec);
+    let (mut cert_path, mut key_path) = (None, None);
+    let mut options = std::env::args().skip(1);
+    while let Some(option) = options.next() {
+        let value = options
+            .next()
+            .unwrap_or_else(|| panic!("{} needs a value", option));
+        match option.as_str() {
+            "--appendonly" => aof_path = Some(PathBuf::from(value)),
+            "--appendfsync" => fsync = value.parse().unwrap(),
+            "--tls-cert" => cert_path = Some(PathBuf::from(value)),
+            "--tls-key" => key_path = Some(PathBuf::from(value)),
+            _ => panic!("unknown option {}", option),
+        }
+    }
 
-        // Write the response to the client
-        connection.write_frame(&response).await.unwrap();
+    let tls = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => Some((cert_path, key_path)),
+        (None, None) => None,
+        _ => panic!("--tls-cert and --tls-key go together"),
+    };
+    Args {
+        aof: aof_path.map(|path| (path, fsync)),
+        tls,
     }
 }
@@ -0,0 +1,71 @@
+use crate::resp::Frame;
+use crate::tls::Stream;
+use bytes::{Buf, BytesMut};
+use mini_redis::Result;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// How much a client can send while its last request is still being answered before it's no
+// longer read, so one that floods a blocked connection can't use up the server's memory
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+/// The server's end of a client connection: reads requests, RESP or inline, and writes replies
+pub struct Connection {
+    // Plain TCP or TLS
+    stream: Box<dyn Stream>,
+    // Requests read but not parsed yet
+    buffer: BytesMut,
+}
+
+impl Connection {
+    pub fn new(stream: Box<dyn Stream>) -> Connection {
+        Connection {
+            stream,
+            buffer: BytesMut::with_capacity(4 * 1024),
+        }
+    }
+
+    /// The next request as an array of bulk strings, blank inline lines skipped. None once the
+    /// client disconnects between requests, a `ProtocolError` if it sends something that isn't a
+    /// request. Safe to cancel, nothing read is lost.
+    pub async fn read_request(&mut self) -> Result<Option<Frame>> {
+        loop {
+            if let Some((request, len)) = Frame::parse_request(&self.buffer)? {
+                self.buffer.advance(len);
+                match request {
+                    Frame::Array(args) if args.is_empty() => continue,
+                    request => return Ok(Some(request)),
+                }
+            }
+
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                let reset =
+                    io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer");
+                return Err(reset.into());
+            }
+        }
+    }
+
+    /// Resolves once the client disconnects or the connection fails, for noticing a client that
+    /// goes away while its request waits (BLPOP). Anything it sends meanwhile is kept for
+    /// `read_request`. Safe to cancel.
+    pub async fn closed(&mut self) {
+        while self.buffer.len() < MAX_BUFFERED {
+            match self.stream.read_buf(&mut self.buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+        std::future::pending().await
+    }
+
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut encoded = BytesMut::new();
+        frame.encode(&mut encoded);
+        self.stream.write_all(&encoded).await?;
+        self.stream.flush().await
+    }
+}
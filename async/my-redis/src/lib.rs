@@ -0,0 +1,14 @@
+//! Pieces shared by the binaries and examples
+
+pub mod aof;
+pub mod connection;
+pub mod manager;
+pub mod pipeline;
+pub mod pool;
+pub mod resp;
+pub mod tls;
+
+pub use manager::{ConnectionManager, Health, Options, WhileDisconnected};
+pub use pipeline::Pipeline;
+pub use pool::{Pool, PoolOptions, PooledClient};
+pub use tls::TlsOptions;
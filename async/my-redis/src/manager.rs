@@ -0,0 +1,233 @@
+use bytes::Bytes;
+use mini_redis::client::{self, Client};
+use mini_redis::Result;
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time;
+
+/// A client that keeps itself connected. Commands go over a channel to a task that owns the
+/// connection, which reconnects with exponential backoff whenever it's lost. Cloning is cheap,
+/// every clone shares the one connection.
+#[derive(Clone)]
+pub struct ConnectionManager {
+    commands: mpsc::Sender<Command>,
+    health: watch::Receiver<Health>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    /// Still making the first connection
+    Connecting,
+    Connected,
+    /// The connection was lost (or never made) and this many attempts have failed since
+    Reconnecting {
+        attempt: u32,
+    },
+}
+
+/// What happens to commands sent while there's no connection
+#[derive(Clone, Copy, Debug)]
+pub enum WhileDisconnected {
+    /// Hold up to `limit` of them and send them once reconnected, failing any past that
+    Queue { limit: usize },
+    /// Fail them straight away
+    Fail,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub while_disconnected: WhileDisconnected,
+    /// The first wait between attempts, doubled after each failure up to `max_backoff`
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    pub connect_timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            while_disconnected: WhileDisconnected::Queue { limit: 1024 },
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Get {
+        key: String,
+        resp: Responder<Option<Bytes>>,
+    },
+    Set {
+        key: String,
+        val: Bytes,
+        resp: Responder<()>,
+    },
+    Publish {
+        channel: String,
+        message: Bytes,
+        resp: Responder<u64>,
+    },
+}
+
+type Responder<T> = oneshot::Sender<Result<T>>;
+
+impl ConnectionManager {
+    /// Starts the connection task, so must be called from within a Tokio runtime. It connects in
+    /// the background, commands sent before then are handled as `while_disconnected` says.
+    pub fn new(addr: impl Into<String>, options: Options) -> ConnectionManager {
+        let (commands, rx) = mpsc::channel(32);
+        let (health_tx, health) = watch::channel(Health::Connecting);
+        tokio::spawn(run(addr.into(), options, rx, health_tx));
+        ConnectionManager { commands, health }
+    }
+
+    pub fn health(&self) -> Health {
+        *self.health.borrow()
+    }
+
+    /// Waits for the health to change, returning the new one
+    pub async fn health_changed(&mut self) -> Health {
+        let _ = self.health.changed().await;
+        self.health()
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        let key = key.to_string();
+        self.send(|resp| Command::Get { key, resp }).await
+    }
+
+    pub async fn set(&self, key: &str, val: Bytes) -> Result<()> {
+        let key = key.to_string();
+        self.send(|resp| Command::Set { key, val, resp }).await
+    }
+
+    /// Replies with how many subscribers the message reached
+    pub async fn publish(&self, channel: &str, message: Bytes) -> Result<u64> {
+        let channel = channel.to_string();
+        self.send(|resp| Command::Publish {
+            channel,
+            message,
+            resp,
+        })
+        .await
+    }
+
+    async fn send<T>(&self, command: impl FnOnce(Responder<T>) -> Command) -> Result<T> {
+        let (resp, result) = oneshot::channel();
+        if self.commands.send(command(resp)).await.is_err() {
+            return Err("connection task shut down".into());
+        }
+        result
+            .await
+            .unwrap_or_else(|_| Err("connection task shut down".into()))
+    }
+}
+
+// Owns the connection until every ConnectionManager is dropped
+async fn run(
+    addr: String,
+    options: Options,
+    mut commands: mpsc::Receiver<Command>,
+    health: watch::Sender<Health>,
+) {
+    let mut client: Option<Client> = None;
+    // Commands held while disconnected, oldest first
+    let mut waiting = VecDeque::new();
+    let mut backoff = options.min_backoff;
+    let mut attempt = 0;
+
+    loop {
+        let Some(connection) = client.as_mut() else {
+            match time::timeout(options.connect_timeout, client::connect(&addr)).await {
+                Ok(Ok(connected)) => {
+                    client = Some(connected);
+                    backoff = options.min_backoff;
+                    attempt = 0;
+                    health.send_replace(Health::Connected);
+                }
+                _ => {
+                    attempt += 1;
+                    health.send_replace(Health::Reconnecting { attempt });
+
+                    // Keep taking commands while waiting to try again
+                    let retry = time::sleep(backoff);
+                    tokio::pin!(retry);
+                    loop {
+                        tokio::select! {
+                            _ = &mut retry => break,
+                            command = commands.recv() => match command {
+                                Some(command) => hold(command, &mut waiting, options.while_disconnected),
+                                // Nobody's left to answer, the waiting commands are dropped and
+                                // their senders see the task shut down
+                                None => return,
+                            },
+                        }
+                    }
+                    backoff = (backoff * 2).min(options.max_backoff);
+                }
+            }
+            continue;
+        };
+
+        let command = match waiting.pop_front() {
+            Some(command) => command,
+            None => match commands.recv().await {
+                Some(command) => command,
+                None => return,
+            },
+        };
+        // A command in flight when the connection drops is failed rather than retried, as it may
+        // already have been applied
+        if !command.apply(connection).await {
+            client = None;
+            health.send_replace(Health::Reconnecting { attempt: 0 });
+        }
+    }
+}
+
+fn hold(command: Command, waiting: &mut VecDeque<Command>, policy: WhileDisconnected) {
+    match policy {
+        WhileDisconnected::Queue { limit } if waiting.len() < limit => waiting.push_back(command),
+        WhileDisconnected::Queue { .. } => {
+            command.fail("not connected to the server and too many commands are waiting")
+        }
+        WhileDisconnected::Fail => command.fail("not connected to the server"),
+    }
+}
+
+impl Command {
+    // Returns false if the connection was lost
+    async fn apply(self, client: &mut Client) -> bool {
+        match self {
+            Command::Get { key, resp } => reply(resp, client.get(&key).await),
+            Command::Set { key, val, resp } => reply(resp, client.set(&key, val).await),
+            Command::Publish {
+                channel,
+                message,
+                resp,
+            } => reply(resp, client.publish(&channel, message).await),
+        }
+    }
+
+    fn fail(self, reason: &str) {
+        // The requester may have given up waiting, so errors sending are ignored
+        match self {
+            Command::Get { resp, .. } => drop(resp.send(Err(reason.into()))),
+            Command::Set { resp, .. } => drop(resp.send(Err(reason.into()))),
+            Command::Publish { resp, .. } => drop(resp.send(Err(reason.into()))),
+        }
+    }
+}
+
+// Errors from the server itself come back as strings, anything that went wrong with the socket
+// is an io::Error
+fn reply<T>(resp: Responder<T>, result: Result<T>) -> bool {
+    let connected = !matches!(&result, Err(e) if e.downcast_ref::<io::Error>().is_some());
+    let _ = resp.send(result);
+    connected
+}
@@ -0,0 +1,146 @@
+use crate::resp::Frame;
+use crate::tls::Stream;
+use bytes::{Buf, BytesMut};
+use mini_redis::Result;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::TlsConnector;
+
+/// Commands batched up to go out in a single write, their replies coming back in the same order.
+/// mini-redis's client flushes every command on its own, so this goes through its own `Client`.
+#[derive(Default)]
+pub struct Pipeline {
+    // The commands already encoded, ready to write
+    buffer: BytesMut,
+    count: usize,
+}
+
+/// A connection that sends commands as they're given, for running a pipeline (or single commands)
+pub struct Client {
+    // Plain TCP or TLS
+    stream: Box<dyn Stream>,
+    // Replies read but not parsed yet
+    buffer: BytesMut,
+    // Set while a command is waiting for its reply, left set if it never comes (an error, or the
+    // future dropped part way through), as the next reply read would then be the wrong one
+    in_flight: bool,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Adds a command, name first then its arguments, e.g. `pipeline.cmd(&["SET", "key", "1"])`
+    pub fn cmd<A: AsRef<[u8]>>(&mut self, args: &[A]) -> &mut Pipeline {
+        Frame::command(args).encode(&mut self.buffer);
+        self.count += 1;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Sends every command and returns a reply for each, in order. A command the server rejects
+    /// gets a `Frame::Error` in its place rather than failing the rest. The pipeline is empty
+    /// afterwards, ready to be reused.
+    pub async fn execute(&mut self, client: &mut Client) -> Result<Vec<Frame>> {
+        let commands = self.buffer.split().freeze();
+        let count = std::mem::take(&mut self.count);
+
+        // Replies are read while the commands are still being written, so a big batch can't
+        // fill both sides' socket buffers and stall
+        client.in_flight = true;
+        let (mut reader, mut writer) = tokio::io::split(&mut client.stream);
+        let buffer = &mut client.buffer;
+        let write = async {
+            writer.write_all(&commands).await?;
+            writer.flush().await.map_err(Into::into)
+        };
+        let read = async {
+            let mut replies = Vec::with_capacity(count);
+            while replies.len() < count {
+                replies.push(read_frame(&mut reader, buffer).await?);
+            }
+            Ok::<_, mini_redis::Error>(replies)
+        };
+        let ((), replies) = tokio::try_join!(write, read)?;
+        client.in_flight = false;
+        Ok(replies)
+    }
+}
+
+impl Client {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Client> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Client::new(Box::new(stream)))
+    }
+
+    /// Connects over TLS when given a config (see `TlsOptions`), checking the server's
+    /// certificate is for the host in `addr`, and over plain TCP otherwise
+    pub async fn connect_with(addr: &str, tls: Option<Arc<ClientConfig>>) -> Result<Client> {
+        let Some(tls) = tls else {
+            return Client::connect(addr).await;
+        };
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_string())?;
+
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let stream = TlsConnector::from(tls).connect(server_name, stream).await?;
+        Ok(Client::new(Box::new(stream)))
+    }
+
+    fn new(stream: Box<dyn Stream>) -> Client {
+        Client {
+            stream,
+            buffer: BytesMut::with_capacity(4 * 1024),
+            in_flight: false,
+        }
+    }
+
+    /// Sends one command and waits for its reply, a full round trip each time
+    pub async fn command<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Result<Frame> {
+        let mut command = BytesMut::new();
+        Frame::command(args).encode(&mut command);
+        self.in_flight = true;
+        self.stream.write_all(&command).await?;
+        self.stream.flush().await?;
+        let reply = read_frame(&mut self.stream, &mut self.buffer).await?;
+        self.in_flight = false;
+        Ok(reply)
+    }
+
+    /// False once a command has been left without its reply, after which the connection can't
+    /// be used
+    pub fn is_usable(&self) -> bool {
+        !self.in_flight
+    }
+}
+
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin), buffer: &mut BytesMut) -> Result<Frame> {
+    loop {
+        // Parse a frame once a whole one has arrived
+        if let Some((frame, len)) = Frame::parse(buffer)? {
+            buffer.advance(len);
+            return Ok(frame);
+        }
+
+        if reader.read_buf(buffer).await? == 0 {
+            let reset =
+                io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by server");
+            return Err(reset.into());
+        }
+    }
+}
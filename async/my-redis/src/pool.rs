@@ -0,0 +1,163 @@
+use crate::pipeline::Client;
+use crate::resp::Frame;
+use mini_redis::Result;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tokio_rustls::rustls::ClientConfig;
+
+/// A bounded pool of connections. Each user checks one out for as long as it needs it and it's
+/// checked back in when dropped, so concurrent users each get their own connection instead of
+/// queueing for a shared one. Once `max_size` are checked out, `get` waits for one to come back.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolOptions {
+    pub max_size: usize,
+    /// Connections older than this are closed rather than reused
+    pub max_lifetime: Duration,
+    /// Connections idle for longer than this are sent a PING when checked out, and replaced if
+    /// they don't answer within `health_check_timeout`
+    pub health_check_after: Duration,
+    pub health_check_timeout: Duration,
+    /// Connects over TLS with this config, plain TCP without one
+    pub tls: Option<Arc<ClientConfig>>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_size: 8,
+            max_lifetime: Duration::from_secs(30 * 60),
+            health_check_after: Duration::from_secs(1),
+            health_check_timeout: Duration::from_secs(1),
+            tls: None,
+        }
+    }
+}
+
+struct Inner {
+    addr: String,
+    options: PoolOptions,
+    // Most recently checked in last, so the warmest connection is reused first
+    idle: Mutex<Vec<Idle>>,
+    // One per connection allowed out at once
+    permits: Arc<Semaphore>,
+}
+
+struct Idle {
+    client: Client,
+    created: Instant,
+    checked_in: Instant,
+}
+
+/// A checked out connection, derefs to the `Client` and goes back to the pool when dropped
+pub struct PooledClient {
+    // Only None once dropped
+    idle: Option<Idle>,
+    pool: Arc<Inner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pool {
+    /// Connections are only made as they're needed
+    pub fn new(addr: impl Into<String>, options: PoolOptions) -> Pool {
+        let permits = Arc::new(Semaphore::new(options.max_size));
+        Pool {
+            inner: Arc::new(Inner {
+                addr: addr.into(),
+                options,
+                idle: Mutex::new(Vec::new()),
+                permits,
+            }),
+        }
+    }
+
+    /// Checks out an idle connection that still answers, or opens a new one
+    pub async fn get(&self) -> Result<PooledClient> {
+        let permit = self
+            .inner
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the pool's semaphore is never closed");
+
+        loop {
+            let idle = self.inner.idle.lock().unwrap().pop();
+            let Some(mut idle) = idle else {
+                break;
+            };
+            if idle.created.elapsed() >= self.inner.options.max_lifetime {
+                continue;
+            }
+            let checked = idle.checked_in.elapsed() >= self.inner.options.health_check_after;
+            if !checked || self.healthy(&mut idle.client).await {
+                return Ok(self.checked_out(idle, permit));
+            }
+        }
+
+        let tls = self.inner.options.tls.clone();
+        let client = Client::connect_with(&self.inner.addr, tls).await?;
+        let idle = Idle {
+            client,
+            created: Instant::now(),
+            checked_in: Instant::now(),
+        };
+        Ok(self.checked_out(idle, permit))
+    }
+
+    /// How many connections are open and waiting to be checked out
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    fn checked_out(&self, idle: Idle, permit: OwnedSemaphorePermit) -> PooledClient {
+        PooledClient {
+            idle: Some(idle),
+            pool: self.inner.clone(),
+            _permit: permit,
+        }
+    }
+
+    // Catches connections the server closed while they sat idle
+    async fn healthy(&self, client: &mut Client) -> bool {
+        let ping = client.command(&["PING"]);
+        matches!(
+            time::timeout(self.inner.options.health_check_timeout, ping).await,
+            Ok(Ok(Frame::Simple(pong))) if pong == "PONG"
+        )
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.idle.as_ref().unwrap().client
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.idle.as_mut().unwrap().client
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let mut idle = self.idle.take().unwrap();
+        idle.checked_in = Instant::now();
+        // One left part way through a command is closed rather than handing its reply to the next
+        // user
+        if idle.client.is_usable() && idle.created.elapsed() < self.pool.options.max_lifetime {
+            self.pool.idle.lock().unwrap().push(idle);
+        }
+        // The permit's dropped after this, so a waiting `get` finds the connection idle
+    }
+}
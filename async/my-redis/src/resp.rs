@@ -0,0 +1,457 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt;
+
+/// A RESP2 value, what goes over the wire in both directions. Unlike mini-redis's `Frame` this
+/// carries negative integers and the null array, and requests can be read as inline commands too.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    /// The null bulk string, `$-1`
+    Null,
+    /// The null array, `*-1`, as Redis replies when BLPOP times out
+    NullArray,
+    Array(Vec<Frame>),
+}
+
+/// Input that isn't RESP. Redis answers it with an error and closes the connection, as there's
+/// no telling where the next command starts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolError(String);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Protocol error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+// The same limits Redis puts on requests, so a bad length can't have us allocating without end
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+const MAX_ARRAY_LEN: i64 = 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
+// Replies nest arrays, but nowhere near this deep
+const MAX_DEPTH: usize = 32;
+
+impl Frame {
+    /// Parses the frame at the start of `src`, returning it with how many bytes it took up, or
+    /// None if it hasn't all arrived yet
+    pub fn parse(src: &[u8]) -> Result<Option<(Frame, usize)>, ProtocolError> {
+        let mut parser = Parser { src, pos: 0 };
+        finish(parser.frame(0), &parser)
+    }
+
+    /// Parses a request: an array of bulk strings as clients send, or an inline command, words
+    /// on a line as typed into telnet. Either way it comes back as an array of bulk strings, empty
+    /// for a blank line.
+    pub fn parse_request(src: &[u8]) -> Result<Option<(Frame, usize)>, ProtocolError> {
+        let mut parser = Parser { src, pos: 0 };
+        let request = match src.first() {
+            None => Err(Stop::Incomplete),
+            Some(b'*') => parser.frame(0).and_then(|frame| match frame {
+                Frame::NullArray => Ok(Frame::Array(Vec::new())),
+                Frame::Array(args) if args.iter().all(|arg| matches!(arg, Frame::Bulk(_))) => {
+                    Ok(Frame::Array(args))
+                }
+                _ => Err(invalid("expected an array of bulk strings")),
+            }),
+            Some(_) => parser.inline(),
+        };
+        finish(request, &parser)
+    }
+
+    /// A command as clients send it, an array of bulk strings
+    pub fn command<A: AsRef<[u8]>>(args: &[A]) -> Frame {
+        let args = args
+            .iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_ref())));
+        Frame::Array(args.collect())
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            Frame::Simple(string) => line(dst, b'+', string),
+            Frame::Error(message) => line(dst, b'-', message),
+            Frame::Integer(n) => line(dst, b':', &n.to_string()),
+            Frame::Bulk(bytes) => {
+                line(dst, b'$', &bytes.len().to_string());
+                dst.put_slice(bytes);
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Null => dst.put_slice(b"$-1\r\n"),
+            Frame::NullArray => dst.put_slice(b"*-1\r\n"),
+            Frame::Array(frames) => {
+                line(dst, b'*', &frames.len().to_string());
+                for frame in frames {
+                    frame.encode(dst);
+                }
+            }
+        }
+    }
+}
+
+// Simple strings and errors can't hold a line break, it would end them early
+fn line(dst: &mut BytesMut, kind: u8, text: &str) {
+    dst.put_u8(kind);
+    for byte in text.bytes() {
+        dst.put_u8(if byte == b'\r' || byte == b'\n' {
+            b' '
+        } else {
+            byte
+        });
+    }
+    dst.put_slice(b"\r\n");
+}
+
+enum Stop {
+    Incomplete,
+    Invalid(ProtocolError),
+}
+
+fn invalid(message: impl Into<String>) -> Stop {
+    Stop::Invalid(ProtocolError(message.into()))
+}
+
+fn finish(
+    parsed: Result<Frame, Stop>,
+    parser: &Parser,
+) -> Result<Option<(Frame, usize)>, ProtocolError> {
+    match parsed {
+        Ok(frame) => Ok(Some((frame, parser.pos))),
+        Err(Stop::Incomplete) => Ok(None),
+        Err(Stop::Invalid(e)) => Err(e),
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn frame(&mut self, depth: usize) -> Result<Frame, Stop> {
+        let kind = *self.src.get(self.pos).ok_or(Stop::Incomplete)?;
+        self.pos += 1;
+        match kind {
+            b'+' => Ok(Frame::Simple(lossy(self.line()?))),
+            b'-' => Ok(Frame::Error(lossy(self.line()?))),
+            b':' => Ok(Frame::Integer(self.integer()?)),
+            b'$' => match self.integer()? {
+                -1 => Ok(Frame::Null),
+                len if (0..=MAX_BULK_LEN).contains(&len) => {
+                    let len = len as usize;
+                    let end = self.pos + len;
+                    if self.src.len() < end + 2 {
+                        return Err(Stop::Incomplete);
+                    }
+                    if &self.src[end..end + 2] != b"\r\n" {
+                        return Err(invalid("bulk string not followed by CRLF"));
+                    }
+                    let bytes = Bytes::copy_from_slice(&self.src[self.pos..end]);
+                    self.pos = end + 2;
+                    Ok(Frame::Bulk(bytes))
+                }
+                _ => Err(invalid("invalid bulk length")),
+            },
+            b'*' => match self.integer()? {
+                -1 => Ok(Frame::NullArray),
+                len if (0..=MAX_ARRAY_LEN).contains(&len) => {
+                    if depth == MAX_DEPTH {
+                        return Err(invalid("arrays nested too deeply"));
+                    }
+                    // Not allocated up front, the length hasn't been backed by any data yet
+                    let mut frames = Vec::new();
+                    for _ in 0..len {
+                        frames.push(self.frame(depth + 1)?);
+                    }
+                    Ok(Frame::Array(frames))
+                }
+                _ => Err(invalid("invalid multibulk length")),
+            },
+            other => Err(invalid(format!(
+                "unexpected '{}' at the start of a frame",
+                other.escape_ascii()
+            ))),
+        }
+    }
+
+    // Up to the next CRLF, which is skipped
+    fn line(&mut self) -> Result<&[u8], Stop> {
+        let rest = &self.src[self.pos..];
+        match rest.windows(2).position(|pair| pair == b"\r\n") {
+            Some(end) => {
+                self.pos += end + 2;
+                Ok(&rest[..end])
+            }
+            None if rest.len() > MAX_INLINE_LEN => Err(invalid("line too long")),
+            None => Err(Stop::Incomplete),
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64, Stop> {
+        let line = self.line()?;
+        std::str::from_utf8(line)
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| invalid("invalid integer"))
+    }
+
+    // A line of space separated words, ending at LF with or without the CR before it
+    fn inline(&mut self) -> Result<Frame, Stop> {
+        let rest = &self.src[self.pos..];
+        let Some(end) = rest.iter().position(|&byte| byte == b'\n') else {
+            return if rest.len() > MAX_INLINE_LEN {
+                Err(invalid("too big inline request"))
+            } else {
+                Err(Stop::Incomplete)
+            };
+        };
+        self.pos += end + 1;
+        let line = rest[..end].strip_suffix(b"\r").unwrap_or(&rest[..end]);
+        let args = split_inline(line).ok_or_else(|| invalid("unbalanced quotes in request"))?;
+        Ok(Frame::Array(args.into_iter().map(Frame::Bulk).collect()))
+    }
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// Splits an inline command into its words as Redis does. A word can be "double quoted", with
+// \n, \r, \t, \b, \a, \xHH and backslash escapes inside, or 'single quoted', where only \' is.
+// None if a quote isn't closed, or is followed by anything but a space.
+fn split_inline(line: &[u8]) -> Option<Vec<Bytes>> {
+    let mut words = Vec::new();
+    let mut rest = line;
+    loop {
+        rest = rest.trim_ascii_start();
+        let Some(&first) = rest.first() else {
+            return Some(words);
+        };
+
+        let mut word = Vec::new();
+        match first {
+            b'"' => {
+                let mut i = 1;
+                loop {
+                    match *rest.get(i)? {
+                        b'"' => break,
+                        b'\\' => {
+                            let escaped = *rest.get(i + 1)?;
+                            let hex = rest.get(i + 2..i + 4).and_then(|hex| {
+                                u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+                            });
+                            match (escaped, hex) {
+                                (b'x', Some(byte)) => {
+                                    word.push(byte);
+                                    i += 4;
+                                    continue;
+                                }
+                                (b'n', _) => word.push(b'\n'),
+                                (b'r', _) => word.push(b'\r'),
+                                (b't', _) => word.push(b'\t'),
+                                (b'b', _) => word.push(8),
+                                (b'a', _) => word.push(7),
+                                (other, _) => word.push(other),
+                            }
+                            i += 2;
+                        }
+                        byte => {
+                            word.push(byte);
+                            i += 1;
+                        }
+                    }
+                }
+                rest = closed(&rest[i + 1..])?;
+            }
+            b'\'' => {
+                let mut i = 1;
+                loop {
+                    match *rest.get(i)? {
+                        b'\'' => break,
+                        b'\\' if rest.get(i + 1) == Some(&b'\'') => {
+                            word.push(b'\'');
+                            i += 2;
+                        }
+                        byte => {
+                            word.push(byte);
+                            i += 1;
+                        }
+                    }
+                }
+                rest = closed(&rest[i + 1..])?;
+            }
+            _ => {
+                let end = rest
+                    .iter()
+                    .position(u8::is_ascii_whitespace)
+                    .unwrap_or(rest.len());
+                word.extend_from_slice(&rest[..end]);
+                rest = &rest[end..];
+            }
+        }
+        words.push(Bytes::from(word));
+    }
+}
+
+// What follows a closing quote, which has to be a space or the end of the line
+fn closed(after: &[u8]) -> Option<&[u8]> {
+    match after.first() {
+        Some(byte) if !byte.is_ascii_whitespace() => None,
+        _ => Some(after),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(frame: &Frame) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        frame.encode(&mut dst);
+        dst.to_vec()
+    }
+
+    fn bulk(text: &str) -> Frame {
+        Frame::Bulk(Bytes::from(text.to_string()))
+    }
+
+    // A small xorshift generator, so the "fuzzing" is the same every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn random_frame(rng: &mut Rng, depth: usize) -> Frame {
+        match rng.below(if depth < 3 { 7 } else { 6 }) {
+            0 => Frame::Simple("OK".to_string()),
+            1 => Frame::Error("ERR something".to_string()),
+            2 => Frame::Integer(rng.next() as i64),
+            3 => Frame::Bulk(Bytes::from(
+                (0..rng.below(40))
+                    .map(|_| rng.next() as u8)
+                    .collect::<Vec<_>>(),
+            )),
+            4 => Frame::Null,
+            5 => Frame::NullArray,
+            _ => Frame::Array(
+                (0..rng.below(5))
+                    .map(|_| random_frame(rng, depth + 1))
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn frames_survive_being_encoded_and_parsed() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..500 {
+            let frame = random_frame(&mut rng, 0);
+            let bytes = encoded(&frame);
+            assert_eq!(Frame::parse(&bytes), Ok(Some((frame, bytes.len()))));
+            // Every prefix is just not there yet
+            for len in 0..bytes.len() {
+                assert_eq!(Frame::parse(&bytes[..len]), Ok(None));
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_input_is_an_error_never_a_panic() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..2000 {
+            // Valid frames with bytes flipped, cut short or run on, and plain noise
+            let mut bytes = encoded(&random_frame(&mut rng, 0));
+            for _ in 0..rng.below(4) {
+                let at = rng.below(bytes.len());
+                bytes[at] = b"*$:+-\r\n-1x0"[rng.below(11)];
+            }
+            bytes.truncate(rng.below(bytes.len() + 1));
+            let noise: Vec<u8> = (0..rng.below(30)).map(|_| rng.next() as u8).collect();
+            for input in [&bytes, &noise] {
+                if let Ok(Some((_, used))) = Frame::parse(input) {
+                    assert!(used <= input.len());
+                }
+                if let Ok(Some((Frame::Array(args), used))) = Frame::parse_request(input) {
+                    assert!(used <= input.len());
+                    assert!(args.iter().all(|arg| matches!(arg, Frame::Bulk(_))));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bad_lengths_and_types_are_rejected() {
+        for input in [
+            &b"$-2\r\n"[..],
+            b"$abc\r\n",
+            b"*-5\r\n",
+            // Past Redis's limits, refused before any of the data arrives
+            b"$536870913\r\n",
+            b"*1048577\r\n",
+            b":12a\r\n",
+            b"$3\r\nabcde\r\n",
+            b"?what\r\n",
+        ] {
+            assert!(Frame::parse(input).is_err(), "{:?}", input.escape_ascii());
+        }
+        let nested = b"*1\r\n".repeat(MAX_DEPTH + 1);
+        assert!(Frame::parse(&nested).is_err());
+        assert!(Frame::parse(&vec![b'+'; MAX_INLINE_LEN + 2]).is_err());
+
+        // Requests are arrays of bulk strings, nothing else
+        assert!(Frame::parse_request(b"*1\r\n:1\r\n").is_err());
+        assert!(Frame::parse_request(b"*1\r\n*0\r\n").is_err());
+        assert!(Frame::parse_request(&vec![b'a'; MAX_INLINE_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn inline_commands_are_split_like_redis_does() {
+        let request = |input: &[u8]| Frame::parse_request(input).unwrap().unwrap();
+
+        assert_eq!(
+            request(b"SET  key value\r\n"),
+            (
+                Frame::Array(vec![bulk("SET"), bulk("key"), bulk("value")]),
+                16
+            )
+        );
+        assert_eq!(request(b"PING\n"), (Frame::Array(vec![bulk("PING")]), 5));
+        assert_eq!(request(b"\r\n"), (Frame::Array(vec![]), 2));
+        assert_eq!(
+            request(b"set \"a key\" \"tab\\there\\x41\" 'it\\'s'\n").0,
+            Frame::Array(vec![
+                bulk("set"),
+                bulk("a key"),
+                bulk("tab\thereA"),
+                bulk("it's")
+            ])
+        );
+
+        assert_eq!(Frame::parse_request(b"GET key"), Ok(None));
+        assert!(Frame::parse_request(b"set \"open\n").is_err());
+        assert!(Frame::parse_request(b"set 'a'b\n").is_err());
+    }
+
+    #[test]
+    fn simple_strings_cannot_break_the_stream() {
+        assert_eq!(
+            encoded(&Frame::Error("ERR bad\r\nthing".to_string())),
+            b"-ERR bad  thing\r\n"
+        );
+        assert_eq!(encoded(&Frame::Integer(-3)), b":-3\r\n");
+    }
+}
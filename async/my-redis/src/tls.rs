@@ -0,0 +1,100 @@
+use rustls_pemfile::{certs, private_key};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+/// A connection either side can run over, plain TCP or TLS
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// What a client trusts and presents when connecting over TLS
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// PEM certificates of the CAs to trust, instead of the public ones managed Redis services
+    /// use. Needed for a server with a self-signed certificate.
+    pub ca_path: Option<PathBuf>,
+    /// A PEM certificate and key to present, for servers that want clients to authenticate
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    /// From REDIS_TLS_CA, REDIS_TLS_CERT and REDIS_TLS_KEY, None unless one of those or REDIS_TLS
+    /// is set
+    pub fn from_env() -> Option<TlsOptions> {
+        let path = |name| env::var_os(name).map(PathBuf::from);
+        let options = TlsOptions {
+            ca_path: path("REDIS_TLS_CA"),
+            cert_path: path("REDIS_TLS_CERT"),
+            key_path: path("REDIS_TLS_KEY"),
+        };
+        let wanted = env::var_os("REDIS_TLS").is_some()
+            || options.ca_path.is_some()
+            || options.cert_path.is_some();
+        wanted.then_some(options)
+    }
+
+    pub fn client_config(&self) -> io::Result<Arc<ClientConfig>> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_path {
+            Some(ca_path) => {
+                for cert in load_certs(ca_path)? {
+                    roots.add(cert).map_err(io::Error::other)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = match (&self.cert_path, &self.key_path) {
+            (Some(cert_path), Some(key_path)) => builder
+                .with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)
+                .map_err(io::Error::other)?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a client certificate needs its key, and a key its certificate",
+                ))
+            }
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+// Builds the server's config from a PEM certificate chain and private key
+pub fn server_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_key(key_path)?)
+        .map_err(io::Error::other)?;
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    private_key(&mut reader)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No private key found in {}", path.display()),
+        )
+    })
+}
@@ -1,20 +1,24 @@
 use std::env;
-use std::process;
+use std::process::ExitCode;
 
 use cat::run;
+use coreutils_common::{input, Input, Status, Stdout};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Too few arguments");
-    } else if args.len() > 2 {
-        eprintln!("Too many arguments");
-    }
+fn main() -> ExitCode {
+    let files = input::paths(env::args().skip(1).collect());
+    let mut out = Stdout::new();
+    let mut status = Status::new();
 
-    let file = &args[1];
+    for file in &files {
+        let Some(mut input) = status.check(file, Input::open(file)) else {
+            continue;
+        };
+        // Write errors end the program, so this can only be a read error
+        if let Err(e) = run(&mut input, &mut out) {
+            status.fail(file, &e);
+        }
+    }
 
-    if let Err(e) = run(file) {
-        eprintln!("Error reading file: {e}");
-        process::exit(1)
-    };
+    out.finish();
+    status.into()
 }
@@ -0,0 +1,51 @@
+use std::env;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The name the tool was run as, which starts every error message
+pub fn program() -> &'static str {
+    static PROGRAM: OnceLock<String> = OnceLock::new();
+    PROGRAM.get_or_init(|| {
+        env::args_os()
+            .next()
+            .as_deref()
+            .and_then(|arg0| Path::new(arg0).file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "coreutils".to_string())
+    })
+}
+
+/// The error as GNU tools word it: the OS's description without Rust's " (os error N)"
+pub fn message(e: &io::Error) -> String {
+    let message = e.to_string();
+    match message.find(" (os error ") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+/// Prints `program: subject: message` to stderr, e.g. `cat: poem.txt: No such file or directory`
+pub fn report(subject: &str, e: &io::Error) {
+    eprintln!("{}: {}: {}", program(), subject, message(e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_errors_lose_their_number() {
+        let e = io::Error::from_raw_os_error(2);
+        assert_eq!(message(&e), "No such file or directory");
+    }
+
+    #[test]
+    fn other_errors_are_left_alone() {
+        let e = io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        );
+        assert_eq!(message(&e), "stream did not contain valid UTF-8");
+    }
+}
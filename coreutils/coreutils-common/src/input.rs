@@ -0,0 +1,51 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, StdinLock};
+
+/// Somewhere to read from: a file, or stdin when the path is `-`
+pub enum Input {
+    Stdin(StdinLock<'static>),
+    File(BufReader<File>),
+}
+
+impl Input {
+    pub fn open(path: &str) -> io::Result<Input> {
+        if path == "-" {
+            return Ok(Input::Stdin(io::stdin().lock()));
+        }
+        Ok(Input::File(BufReader::new(File::open(path)?)))
+    }
+}
+
+/// The files named on the command line, or just stdin when there weren't any
+pub fn paths(files: Vec<String>) -> Vec<String> {
+    if files.is_empty() {
+        vec!["-".to_string()]
+    } else {
+        files
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Stdin(stdin) => stdin.read(buf),
+            Input::File(file) => file.read(buf),
+        }
+    }
+}
+
+impl BufRead for Input {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Input::Stdin(stdin) => stdin.fill_buf(),
+            Input::File(file) => file.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            Input::Stdin(stdin) => stdin.consume(amt),
+            Input::File(file) => file.consume(amt),
+        }
+    }
+}
@@ -0,0 +1,13 @@
+//! What the tools share: reading files or stdin, reporting errors the way GNU coreutils does,
+//! working out the exit code, parsing sizes like `10K` and writing bytes to stdout as they are
+
+pub mod error;
+pub mod input;
+pub mod output;
+pub mod size;
+pub mod status;
+
+pub use input::Input;
+pub use output::Stdout;
+pub use size::parse_size;
+pub use status::Status;
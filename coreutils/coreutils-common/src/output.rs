@@ -0,0 +1,48 @@
+use crate::error;
+use std::io::{self, BufWriter, StdoutLock, Write};
+use std::process;
+
+/// Buffered stdout that writes bytes as they are, with no UTF-8 checks or added newlines.
+/// A write that fails ends the program, so an error from copying into it was a read error.
+/// A closed pipe (`cat big.txt | head`) ends it quietly, as SIGPIPE would.
+pub struct Stdout {
+    inner: BufWriter<StdoutLock<'static>>,
+}
+
+impl Stdout {
+    pub fn new() -> Stdout {
+        Stdout {
+            inner: BufWriter::with_capacity(64 * 1024, io::stdout().lock()),
+        }
+    }
+
+    /// Writes out whatever's still buffered, failing the same way a write would
+    pub fn finish(mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl Default for Stdout {
+    fn default() -> Self {
+        Stdout::new()
+    }
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).or_else(|e| fail(e))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().or_else(|e| fail(e))
+    }
+}
+
+fn fail(e: io::Error) -> ! {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        // What a shell reports for a process killed by SIGPIPE
+        process::exit(141);
+    }
+    error::report("write error", &e);
+    process::exit(1);
+}
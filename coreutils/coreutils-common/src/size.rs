@@ -0,0 +1,71 @@
+/// Parses a count the way GNU's `head -c` and `tail -n` do: a number with an optional suffix,
+/// `b` for 512, `K`, `M`, `G`... for powers of 1024 (`KiB` too) and `KB`, `MB`, `GB`... for
+/// powers of 1000
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size '{}'", s);
+
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+
+    let multiplier = match suffix {
+        "" => 1,
+        "b" => 512,
+        _ => {
+            let mut chars = suffix.chars();
+            let unit = chars.next().unwrap();
+            let power = "KMGTPE"
+                .find(unit.to_ascii_uppercase())
+                // GNU only takes a lowercase k
+                .filter(|_| unit.is_ascii_uppercase() || unit == 'k')
+                .ok_or_else(invalid)? as u32
+                + 1;
+            let base: u64 = match chars.as_str() {
+                "" | "iB" => 1024,
+                "B" => 1000,
+                _ => return Err(invalid()),
+            };
+            base.pow(power)
+        }
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_numbers() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("42"), Ok(42));
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(parse_size("2b"), Ok(1024));
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("1KiB"), Ok(1024));
+        assert_eq!(parse_size("1KB"), Ok(1000));
+        assert_eq!(parse_size("3M"), Ok(3 * 1024 * 1024));
+        assert_eq!(parse_size("2GB"), Ok(2_000_000_000));
+        assert_eq!(parse_size("1E"), Ok(1 << 60));
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        for s in ["", "K", "-1", "1.5", "1m", "1KX", "1Z", "12 K"] {
+            assert!(parse_size(s).is_err(), "{s} parsed");
+        }
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert!(parse_size("16E").is_err());
+        assert!(parse_size("99999999999999999999").is_err());
+    }
+}
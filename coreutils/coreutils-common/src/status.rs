@@ -0,0 +1,42 @@
+use crate::error;
+use std::io;
+use std::process::ExitCode;
+
+/// The exit code, built up as the tool goes: a file that can't be read is reported and the rest
+/// are still processed, but the tool exits with 1 at the end, as GNU tools do
+#[derive(Default)]
+pub struct Status {
+    failed: bool,
+}
+
+impl Status {
+    pub fn new() -> Status {
+        Status::default()
+    }
+
+    /// The value if it worked, otherwise reports the error against `subject` and returns None
+    pub fn check<T>(&mut self, subject: &str, result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.fail(subject, &e);
+                None
+            }
+        }
+    }
+
+    pub fn fail(&mut self, subject: &str, e: &io::Error) {
+        error::report(subject, e);
+        self.failed = true;
+    }
+}
+
+impl From<Status> for ExitCode {
+    fn from(status: Status) -> ExitCode {
+        if status.failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+}
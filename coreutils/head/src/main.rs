@@ -1,36 +1,47 @@
 use clap::Parser;
-use std::fs::File;
-use std::io::BufRead;
-use std::io::BufReader;
-use std::process;
+use coreutils_common::{input, parse_size, Input, Status, Stdout};
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
 
 #[derive(Parser)]
 #[command(name = "head")]
 #[command(about = "Displays file contents from the start of the file")]
 pub struct Args {
-    file: String,
+    /// Files to read, stdin if none are given or for -
+    files: Vec<String>,
 
-    #[arg(short = 'n', long, default_value = "10")]
-    lines: usize,
+    #[arg(short = 'n', long, default_value = "10", value_parser = parse_size)]
+    lines: u64,
 }
 
-fn main() {
+fn main() -> ExitCode {
     let args = Args::parse();
+    let files = input::paths(args.files);
+    let mut out = Stdout::new();
+    let mut status = Status::new();
 
-    let file = File::open(args.file).unwrap_or_else(|err| {
-        eprintln!("Failed to open file: {err}");
-        process::exit(1);
-    });
-    let reader = BufReader::new(&file);
-
-    for (num, line) in reader.lines().enumerate() {
-        if num == args.lines {
-            process::exit(0);
+    for file in &files {
+        let Some(mut input) = status.check(file, Input::open(file)) else {
+            continue;
+        };
+        if let Err(e) = copy_lines(&mut input, args.lines, &mut out) {
+            status.fail(file, &e);
         }
+    }
+
+    out.finish();
+    status.into()
+}
 
-        match line {
-            Ok(x) => println!("{x}"),
-            Err(_) => process::exit(0),
+// Line by line as bytes, so a file that isn't UTF-8 still comes out as it went in
+fn copy_lines(input: &mut Input, lines: u64, out: &mut Stdout) -> io::Result<()> {
+    let mut line = Vec::new();
+    for _ in 0..lines {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            break;
         }
+        out.write_all(&line)?;
     }
+    Ok(())
 }
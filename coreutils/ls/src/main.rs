@@ -1,6 +1,8 @@
 use clap::Parser;
-use std::error::Error;
+use coreutils_common::{Status, Stdout};
 use std::fs;
+use std::io::{self, Write};
+use std::process::ExitCode;
 
 #[derive(Parser)]
 #[command(name = "ls")]
@@ -13,33 +15,42 @@ pub struct Args {
     all: bool,
 }
 
-pub fn ls(args: Args) -> Result<(), Box<dyn Error>> {
+pub fn ls(args: Args, out: &mut Stdout, status: &mut Status) -> io::Result<()> {
     for dir in &args.dests {
-        let dir_contents = fs::read_dir(dir)?;
+        let subject = format!("cannot access '{dir}'");
+        let Some(dir_contents) = status.check(&subject, fs::read_dir(dir)) else {
+            continue;
+        };
 
         if args.dests.len() > 1 {
-            println!("{dir}:");
+            writeln!(out, "{dir}:")?;
         }
 
         for entry in dir_contents {
-            let file_name = entry.unwrap().file_name().into_string().unwrap();
-
-            if !file_name.starts_with(".") {
-                print!("{file_name}  ");
-            } else if args.all {
-                print!("{file_name}  ");
+            let subject = format!("reading directory '{dir}'");
+            let Some(entry) = status.check(&subject, entry) else {
+                continue;
+            };
+            let file_name = entry.file_name();
+
+            if !file_name.as_encoded_bytes().starts_with(b".") || args.all {
+                // Names that aren't UTF-8 are printed as they are
+                out.write_all(file_name.as_encoded_bytes())?;
+                out.write_all(b"  ")?;
             }
         }
-        println!("\n");
+        out.write_all(b"\n\n")?;
     }
-
     Ok(())
 }
 
-fn main() {
+fn main() -> ExitCode {
     let args = Args::parse();
+    let mut out = Stdout::new();
+    let mut status = Status::new();
 
-    if let Err(e) = ls(args) {
-        eprintln!("Error listing directory: {e}");
-    }
+    ls(args, &mut out, &mut status).expect("a failed write to stdout exits instead");
+
+    out.finish();
+    status.into()
 }
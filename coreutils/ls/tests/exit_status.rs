@@ -0,0 +1,37 @@
+use std::process::Command;
+
+fn ls(dests: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_ls"))
+        .args(dests)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn lists_every_directory_it_can() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("listed"), "").unwrap();
+    let dir = dir.path().to_str().unwrap();
+
+    let output = ls(&[dir]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"listed  \n\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn a_missing_directory_fails_after_the_rest_are_listed() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("listed"), "").unwrap();
+    let missing = dir.path().join("missing");
+    let missing = missing.to_str().unwrap();
+    let dir = dir.path().to_str().unwrap();
+
+    let output = ls(&[missing, dir]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stdout, format!("{dir}:\nlisted  \n\n").as_bytes());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        format!("ls: cannot access '{missing}': No such file or directory\n")
+    );
+}
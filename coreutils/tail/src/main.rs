@@ -1,79 +1,178 @@
-use std::error::Error;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
-use std::process;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::process::ExitCode;
 
 use clap::Parser;
+use coreutils_common::{input, parse_size, Input, Status, Stdout};
 
 #[derive(Parser)]
 #[command(name = "tail")]
 #[command(about = "Displays file contents from the end of the file")]
 pub struct Args {
-    file: String,
+    /// Files to read, stdin if none are given or for -
+    files: Vec<String>,
 
-    #[arg(short = 'n', long, default_value = "10")]
-    lines: usize,
+    #[arg(short = 'n', long, default_value = "10", value_parser = parse_size)]
+    lines: u64,
 }
 
-fn main() {
+fn main() -> ExitCode {
     let args = Args::parse();
+    let files = input::paths(args.files);
+    let mut out = Stdout::new();
+    let mut status = Status::new();
+
+    for file in &files {
+        let Some(input) = status.check(file, Input::open(file)) else {
+            continue;
+        };
+        if let Err(e) = tail(input, args.lines, &mut out) {
+            status.fail(file, &e);
+        }
+    }
+
+    out.finish();
+    status.into()
+}
 
-    if let Err(e) = read_from_end(args) {
-        eprintln!("{e}");
-        process::exit(1);
+fn tail(mut input: Input, lines: u64, out: &mut Stdout) -> io::Result<()> {
+    // Regular files are read from the end, anything else (stdin, pipes) has to be read through
+    if let Input::File(reader) = input {
+        let mut file = reader.into_inner();
+        if file.metadata()?.is_file() {
+            let position = read_from_end(&mut file, lines)?;
+            file.seek(SeekFrom::Start(position))?;
+            io::copy(&mut file, out)?;
+            return Ok(());
+        }
+        return read_through(&mut io::BufReader::new(file), lines, out);
     }
+    read_through(&mut input, lines, out)
 }
 
-fn read_from_end(args: Args) -> Result<(), Box<dyn Error>> {
-    let mut file = File::open(&args.file)?;
-    let file_size = file.metadata()?.len() as usize;
+// Where the last `lines` lines of the file start
+fn read_from_end(file: &mut File, lines: u64) -> io::Result<u64> {
+    let file_size = file.metadata()?.len();
 
     // If the file is empty, return early
     if file_size == 0 {
-        return Ok(());
+        return Ok(0);
     }
 
     // We need to find the start of the Nth line from the end
     let mut buffer = [0; 4096];
-    let mut newline_count = 0;
     let mut position = file_size;
 
+    // A last line without a newline still counts as a line
+    file.seek(SeekFrom::Start(file_size - 1))?;
+    file.read_exact(&mut buffer[..1])?;
+    let mut newline_count = u64::from(buffer[0] != b'\n');
+
     // Count newlines from the end
-    while position > 0 && newline_count <= args.lines {
-        let bytes_to_read = std::cmp::min(position, buffer.len());
+    while position > 0 && newline_count <= lines {
+        let bytes_to_read = std::cmp::min(position, buffer.len() as u64);
         position -= bytes_to_read;
 
-        file.seek(SeekFrom::Start(position as u64))?;
-        let bytes_read = file.read(&mut buffer[..bytes_to_read])?;
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut buffer[..bytes_to_read as usize])?;
 
-        for i in (0..bytes_read).rev() {
+        for i in (0..bytes_to_read as usize).rev() {
             if buffer[i] == b'\n' {
                 newline_count += 1;
-                if newline_count > args.lines {
+                if newline_count > lines {
                     // We found one more newline than needed - this is our starting point
-                    position += i + 1; // Start after this newline
+                    position += i as u64 + 1; // Start after this newline
                     break;
                 }
             }
         }
 
-        if newline_count > args.lines {
+        if newline_count > lines {
+            break;
+        }
+    }
+
+    Ok(position)
+}
+
+// Keeps only the last lines while reading to the end of the input
+fn read_through(input: &mut impl BufRead, lines: u64, out: &mut impl Write) -> io::Result<()> {
+    let mut last = VecDeque::new();
+    loop {
+        let mut line = Vec::new();
+        if input.read_until(b'\n', &mut line)? == 0 {
             break;
         }
+        last.push_back(line);
+        if last.len() as u64 > lines {
+            last.pop_front();
+        }
+    }
+    for line in last {
+        out.write_all(&line)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What tail prints for the contents, read from the end of a file and read through as stdin
+    // would be
+    fn tails(contents: &[u8], lines: u64) -> (Vec<u8>, Vec<u8>) {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        let position = read_from_end(&mut file, lines).unwrap();
+        file.seek(SeekFrom::Start(position)).unwrap();
+        let mut from_end = Vec::new();
+        file.read_to_end(&mut from_end).unwrap();
+
+        let mut stdin = Vec::new();
+        read_through(&mut &contents[..], lines, &mut stdin).unwrap();
+        (from_end, stdin)
     }
 
-    // Position is now at the start of the lines we want to print
-    file.seek(SeekFrom::Start(position as u64))?;
+    fn tail_of(contents: &[u8], lines: u64) -> Vec<u8> {
+        let (from_end, read_through) = tails(contents, lines);
+        assert_eq!(from_end, read_through, "the seek and stdin paths disagree");
+        from_end
+    }
 
-    // Read each line and print it (to avoid any issues with newlines)
-    let mut reader = BufReader::new(file);
-    let mut line = String::new();
+    #[test]
+    fn last_lines() {
+        assert_eq!(tail_of(b"a\nb\nc\n", 2), b"b\nc\n");
+        assert_eq!(tail_of(b"a\nb\nc\n", 10), b"a\nb\nc\n");
+        assert_eq!(tail_of(b"a\nb\nc\n", 0), b"");
+        assert_eq!(tail_of(b"", 3), b"");
+        assert_eq!(tail_of(b"\n\n\n", 2), b"\n\n");
+    }
 
-    while reader.read_line(&mut line)? > 0 {
-        // Avoid printing the trailing newline on the last line
-        print!("{}", line);
-        line.clear();
+    #[test]
+    fn a_last_line_without_a_newline_counts() {
+        assert_eq!(tail_of(b"a\nb\nc", 1), b"c");
+        assert_eq!(tail_of(b"a\nb\nc", 2), b"b\nc");
+        assert_eq!(tail_of(b"abc", 1), b"abc");
+        assert_eq!(tail_of(b"abc", 0), b"");
     }
 
-    Ok(())
+    #[test]
+    fn lines_across_read_chunks() {
+        // Long enough that the lines wanted span several of read_from_end's 4096 byte reads
+        let line = [b'x'; 3000];
+        let mut contents = Vec::new();
+        for n in 0..10 {
+            contents.extend_from_slice(&line[..1000 + n * 200]);
+            contents.push(b'\n');
+        }
+        for lines in [1, 3, 7, 10, 11] {
+            let tail = tail_of(&contents, lines);
+            assert_eq!(
+                tail.iter().filter(|&&b| b == b'\n').count() as u64,
+                lines.min(10)
+            );
+            assert!(contents.ends_with(&tail));
+        }
+    }
 }